@@ -66,7 +66,14 @@ HG_INIT = $(HG) init $1
 
 check: hg.empty.git
 check: hg.git hg.git.nobundle2
-check: hg.unbundle.git
+check: hg.unbundle.git hg.unbundle-cmd.git
+check: hg.bundle-bz2.hg
+check: hg.import-metadata.git
+check: hg.filtered.git
+check: hg.convert.git
+check: hg.revlog.git
+check: hg.fetch-revset.git
+check: hg.fetch-tags.git
 check: hg.incr.git hg.incr.hg.nobundle2
 ifndef NO_CLONEBUNDLES
 check: hg.clonebundles.git
@@ -111,6 +118,12 @@ hg.empty.push.hg: hg.empty.git
 hg.bundle: hg.git
 	$(GIT) -C $< cinnabar bundle $(CURDIR)/$@ -- --remotes
 
+hg.bundle-bz2.hg: hg.git hg.hg
+	$(GIT) -C $< cinnabar bundle --compression bzip2 $(CURDIR)/$@.bundle -- --remotes
+	$(call HG_INIT, $@)
+	$(HG) -R $@ unbundle $(CURDIR)/$@.bundle
+	$(call COMPARE_COMMANDS,$(HG) -R $@ log -r 'all()' -T '{node}\n' | sort,$(HG) -R $(word 2,$^) log -r 'all()' -T '{node}\n' | sort)
+
 hg.git hg.git.nobundle2: hg.git%: hg.hg% hg.upgraded.git
 hg.unbundle.git: hg.bundle hg.git
 hg.unbundle.git hg.git hg.git.nobundle2:
@@ -118,6 +131,53 @@ hg.unbundle.git hg.git hg.git.nobundle2:
 	$(call COMPARE_REFS, $(word 2,$^), $@)
 	$(GIT) -C $@ cinnabar fsck
 	$(GIT) -C $@ cinnabar fsck --full
+	test "$$(echo 0123456789012345678901234567890123456789 | $(GIT) -C $@ cinnabar hg2git --batch --json)" = '{"error": "unknown", "git": null, "hg": "0123456789012345678901234567890123456789"}'
+	test "$$(echo git2hg HEAD | $(GIT) -C $@ cinnabar daemon --stdio)" = "$$($(GIT) -C $@ cinnabar git2hg HEAD)"
+
+hg.unbundle-cmd.git: hg.bundle hg.git
+	$(GIT) init $@
+	$(GIT) -C $@ cinnabar unbundle $(CURDIR)/$<
+	$(call COMPARE_COMMANDS,$(GIT) -C $(word 2,$^) log --format=%H --remotes | sort,$(GIT) -C $@ log --format=%H --branches | sort)
+	$(GIT) -C $@ cinnabar fsck --full
+
+hg.import-metadata.git: hg.git
+	$(GIT) -C $< cinnabar export-metadata $(CURDIR)/$@.bundle
+	$(GIT) init $@
+	$(GIT) -C $@ cinnabar import-metadata $(CURDIR)/$@.bundle
+	$(GIT) -C $@ cinnabar fsck --full
+	$(GIT) -C $@ remote add origin hg::$(CURDIR)/hg.hg
+	$(GIT) -C $@ remote update
+	$(call COMPARE_REFS, $<, $@)
+
+# pipefail, so that a failing fsck isn't hidden by grep.
+hg.filtered.git: SHELL := bash
+hg.filtered.git: hg.hg
+	$(GIT) -c fetch.prune=true -c cinnabar.filters='exclude:*.c,max-size:1k' clone -n hg::$(PATH_URL)/$< $@
+	! $(GIT) -C $@ log --remotes --name-only --format= | grep '\.c$$'
+	set -o pipefail; $(GIT) -C $@ cinnabar fsck --full | grep 'exclude:\*\.c,max-size:1024'
+	$(GIT) -C $@ fetch origin
+
+hg.convert.git: hg.hg hg.git
+	$(GIT) cinnabar convert --repository $(CURDIR)/$@.work --marks $(CURDIR)/$@.marks $(PATH_URL)/$< > $@.stream
+	$(GIT) init $@
+	$(GIT) -C $@ fast-import < $@.stream
+	$(call COMPARE_COMMANDS,$(GIT) -C $(word 2,$^) log --format=%H --remotes,$(GIT) -C $@ log --format=%H --branches)
+	$(GIT) cinnabar convert --repository $(CURDIR)/$@.work --marks $(CURDIR)/$@.marks $(PATH_URL)/$< | { ! grep -q '^commit '; }
+
+hg.revlog.git: hg.hg hg.git
+	$(GIT) -c fetch.prune=true -c cinnabar.experiments=revlog clone -n hg::$(PATH_URL)/$< $@
+	$(call COMPARE_REFS, $(word 2,$^), $@)
+	$(GIT) -C $@ cinnabar fsck --full
+
+hg.fetch-revset.git: hg.hg
+	$(GIT) init $@
+	$(GIT) -C $@ cinnabar fetch hg::$(PATH_URL)/$< -r 'branch(default)'
+	$(call COMPARE_COMMANDS,$(GIT) -C $@ cinnabar git2hg $$(cut -f 1 $@/.git/FETCH_HEAD) | sort,$(HG) -R $< log -r 'heads(branch(default))' -T '{node}\n' | sort)
+
+hg.fetch-tags.git: hg.hg
+	$(GIT) init $@
+	$(GIT) -C $@ cinnabar fetch --tags hg::$(PATH_URL)/$<
+	$(call COMPARE_COMMANDS,$(GIT) -C $@ for-each-ref --format='%(refname:strip=2)' refs/tags/ | sort,$(HG) -R $< tags -T '{tag}\n' | grep -vx tip | sort)
 
 hg.incr.hg hg.incr.hg.nobundle2: hg.incr.hg%: hg.hg%
 	$(call HG_INIT, $@)
@@ -128,8 +188,11 @@ hg.incr.git hg.incr.git.nobundle2: hg.incr.git%: hg.incr.hg% hg.hg% hg.git%
 	$(HG) clone -U $< $@.hg
 	$(GIT) -c fetch.prune=true clone -n hg::$(PATH_URL)/$@.hg $@
 	$(HG) -R $@.hg pull $(CURDIR)/$(word 2,$^)
+	test "$$($(GIT) -C $@ cinnabar incoming origin | cut -d ' ' -f 1 | sort)" = "$$($(HG) -R $@.hg log -r 'all() - ::(c262fcbf0656 + 46585998e744)' -T '{node}\n' | sort)"
 	$(GIT) -C $@ remote update
+	! $(GIT) -C $@ cinnabar incoming origin
 	$(call COMPARE_REFS, $(word 3,$^), $@)
+	$(GIT) -C $@ cinnabar gc --keep 1
 	$(GIT) -C $@ cinnabar fsck
 	$(GIT) -C $@ cinnabar fsck --full
 
@@ -176,8 +239,14 @@ hg.pure.git: hg.git
 	$(GIT) clone -n $< $@ || exit 1
 
 hg.push.hg hg.push.hg.nobundle2: GIT_CINNABAR_EXPERIMENTS:=$(GIT_CINNABAR_EXPERIMENTS:%=%,)merge
+hg.push.hg hg.push.hg.nobundle2: SHELL := bash
 hg.push.hg hg.push.hg.nobundle2: hg.pure.git
 	$(call HG_INIT, $@)
+	# A dry run converts the commits without pushing anything
+	set -o pipefail; $(GIT) -C $< push --dry-run hg::$(PATH_URL)/$@ --all 2>&1 | grep '^Would push changeset' > /dev/null
+	test -z "$$($(HG) -R $@ log -r 'all()' -T '{node}\n')"
+	# Pushing to an empty repository creates new branches
+	! $(GIT) -C $< -c cinnabar.push.allow-new-branch=deny push hg::$(PATH_URL)/$@ --all
 	# Push everything, including merges
 	$(GIT) -C $< push hg::$(PATH_URL)/$@ --all
 
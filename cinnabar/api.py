@@ -0,0 +1,87 @@
+'''Programmatic interface to git-cinnabar.
+
+This module gives other python tools access to the mercurial metadata of the
+git repository in the current directory (or $GIT_DIR), without going through
+the `git cinnabar` command line or the remote helper. The functions here are
+the supported entry points; the rest of the cinnabar package is considered
+internal and may change at any time.
+
+Example:
+
+    import sys
+    sys.path.append('/path/to/git-cinnabar')
+    from cinnabar import api
+
+    commit = api.hg2git(b'<mercurial changeset sha1>')
+'''
+from __future__ import absolute_import, unicode_literals
+from cinnabar.git import NULL_NODE_ID
+from cinnabar.githg import (
+    BranchMap,
+    GitHgStore,
+)
+from cinnabar.helper import GitHgHelper
+from cinnabar.hg.repo import (
+    BundleApplier,
+    get_bundle,
+    get_repo,
+    getbundle,
+    Remote,
+    unbundler,
+)
+
+
+def hg2git(node):
+    '''Returns the git commit sha1 for the given, possibly abbreviated,
+    mercurial changeset sha1, or None if it is unknown.'''
+    result = GitHgHelper.hg2git(node)
+    if result == NULL_NODE_ID:
+        return None
+    return result
+
+
+def git2hg(commit):
+    '''Returns the mercurial changeset sha1 for the given git commit, or
+    None if it doesn't correspond to a mercurial changeset.'''
+    data = GitHgHelper.git2hg(commit)
+    if not data:
+        return None
+    assert data.startswith(b'changeset ')
+    return data[10:50]
+
+
+def open_store():
+    '''Returns the metadata store of the current git repository. It needs
+    to be closed with its `close` method for any change to be recorded.'''
+    return GitHgStore()
+
+
+def connect(url, remote=None):
+    '''Returns a connection to the mercurial repository at the given url
+    (without the `hg::` prefix). When given, the name of the corresponding
+    git remote is used for per-remote configuration.'''
+    return get_repo(Remote(remote or b'hg::' + url, url))
+
+
+def pull(store, repo, heads=None):
+    '''Imports the changesets from the given repository connection into the
+    given store. By default, all the remote heads are imported, otherwise,
+    only the given heads (mercurial sha1s) and their ancestors. Returns the
+    git commits corresponding to the imported heads. Refs are not updated.
+    '''
+    branchmap = BranchMap(store, repo.branchmap(), repo.heads())
+    if heads is None:
+        heads = branchmap.heads()
+    missing = [h for h in heads if not store.changeset_ref(h)]
+    if missing:
+        getbundle(repo, store, missing, branchmap.names())
+    return [store.changeset_ref(h) for h in heads]
+
+
+def import_bundle(store, url):
+    '''Imports the changesets from the mercurial bundle at the given path or
+    url into the given store.'''
+    bundle = unbundler(get_bundle(url))
+    apply_bundle = BundleApplier(bundle, url)
+    del bundle
+    apply_bundle(store)
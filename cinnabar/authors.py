@@ -0,0 +1,53 @@
+from __future__ import absolute_import, unicode_literals
+import logging
+import os
+from cinnabar.exceptions import Abort
+from cinnabar.git import Git
+from cinnabar.util import fsdecode
+
+
+def parse_authors(fh):
+    '''Parses an authors file in the format git-svn uses, i.e. lines of the
+    form `hg-user = Name <email>`. Empty lines and lines starting with `#`
+    are ignored. Returns a dict mapping mercurial users to git authors.'''
+    authors = {}
+    for num, line in enumerate(fh, 1):
+        line = line.strip()
+        if not line or line.startswith(b'#'):
+            continue
+        user, sep, author = line.partition(b'=')
+        user = user.strip()
+        author = author.strip()
+        if not sep or not user or not author.endswith(b'>') or \
+                b'<' not in author:
+            logging.warning('Ignoring invalid line %d in authors file: %s',
+                            num, line.decode('utf-8', 'replace'))
+            continue
+        authors[user] = author
+    return authors
+
+
+class AuthorMap(object):
+    '''Maps mercurial users to git authors according to the authors file
+    given with the cinnabar.authors-file configuration. Users that are not
+    in the file are left untouched.'''
+    def __init__(self, authors=None):
+        if authors is None:
+            authors = {}
+            path = Git.config('cinnabar.authors-file')
+            if path:
+                path = os.path.expanduser(fsdecode(path))
+                try:
+                    with open(path, 'rb') as fh:
+                        authors = parse_authors(fh)
+                except (IOError, OSError) as e:
+                    raise Abort('Cannot read authors file: %s' % e)
+        self._authors = authors
+
+    def __call__(self, user):
+        return self._authors.get(user.strip(), user)
+
+    def __bool__(self):
+        return bool(self._authors)
+
+    __nonzero__ = __bool__
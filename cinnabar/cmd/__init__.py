@@ -1,16 +1,42 @@
 from __future__ import absolute_import
 from .data import data  # noqa: F401
+from .daemon import daemon  # noqa: F401
 from .fsck import fsck  # noqa: F401
 from .upgrade import upgrade  # noqa: F401
 from .reclone import reclone  # noqa: F401
 from .fetch import fetch  # noqa: F401
 from .convert import (  # noqa: F401
+    convert,
     hg2git,
     git2hg,
 )
 from .bundle import bundle  # noqa: F401
+from .metadata import (  # noqa: F401
+    export_metadata,
+    import_metadata,
+    share,
+)
 from .rollback import rollback  # noqa: F401
+from .gc import gc  # noqa: F401
+from .prune import prune  # noqa: F401
 from .python import python  # noqa: F401
 from .download import download  # noqa: F401
+from .self_update import self_update  # noqa: F401
+from .clone import clone  # noqa: F401
+from .push import push  # noqa: F401
+from .config import config  # noqa: F401
+from .prompt import prompt_info  # noqa: F401
+from .status import status  # noqa: F401
+from .phase import phase  # noqa: F401
+from .obsolete import obsolete  # noqa: F401
+from .branches import branches  # noqa: F401
+from .annotate import annotate  # noqa: F401
+from .incoming import (  # noqa: F401
+    incoming,
+    outgoing,
+)
+from .largefiles import largefiles  # noqa: F401
+from .lfs import lfs  # noqa: F401
+from .serve import serve  # noqa: F401
 
 from .util import CLI  # noqa: F401
@@ -0,0 +1,112 @@
+from __future__ import absolute_import, print_function, unicode_literals
+import logging
+import os
+import re
+from cinnabar.cmd.util import CLI
+from cinnabar.git import (
+    Git,
+    GitProcess,
+    NULL_NODE_ID,
+)
+from cinnabar.githg import GitHgStore
+from cinnabar.helper import GitHgHelper
+from cinnabar.phases import (
+    draft_commits,
+    stored_draft_roots,
+)
+from cinnabar.remotes import (
+    REMOTES_REF_PREFIX,
+    stored_remote_heads,
+)
+from cinnabar.util import (
+    bytes_stdout,
+    fsencode,
+    strip_suffix,
+)
+
+FIELDS = ('git', 'hg', 'branch', 'phase', 'extras', 'remote')
+
+FIELD_RE = re.compile(br'\{([a-z]+)\}')
+
+DEFAULT_FORMAT = '{git} {hg} {branch} {phase} {remote} {extras}'
+
+
+def is_ancestor(commit, head):
+    return GitProcess('merge-base', '--is-ancestor', commit, head,
+                      stdout=open(os.devnull, 'wb')).wait() == 0
+
+
+class RemoteFinder(object):
+    '''Finds the mercurial remotes a git commit was last seen on, as per
+    the heads recorded for each remote.'''
+    def __init__(self, store):
+        self._remotes = []
+        for _, ref in Git.for_each_ref(REMOTES_REF_PREFIX):
+            name = strip_suffix(ref[len(REMOTES_REF_PREFIX):], b'/heads')
+            if name == ref[len(REMOTES_REF_PREFIX):]:
+                continue
+            url = Git.config('remote.%s.url' % name.decode('utf-8'))
+            if not url:
+                continue
+            if url.startswith(b'hg::'):
+                url = url[4:]
+            heads = [store.changeset_ref(h) for h in stored_remote_heads(name)]
+            self._remotes.append((url, [h for h in heads if h]))
+
+    def __call__(self, commit):
+        return [url for url, heads in self._remotes
+                if any(is_ancestor(commit, h) for h in heads)]
+
+
+@CLI.subcommand
+@CLI.argument('--format', default=DEFAULT_FORMAT,
+              help='format of the output for each commit, where {git}, {hg},'
+                   ' {branch}, {phase}, {extras} and {remote} are replaced'
+                   ' with the corresponding information (default: %s)'
+                   % DEFAULT_FORMAT.replace('%', '%%'))
+@CLI.argument('rev', nargs='+',
+              help='git revision or range (see the Specifying Ranges'
+                   ' section of gitrevisions(7))')
+def annotate(args):
+    '''show mercurial information about git commits'''
+
+    fmt = fsencode(args.format)
+    wanted = set(FIELD_RE.findall(fmt))
+    for field in wanted:
+        if field.decode('ascii') not in FIELDS:
+            logging.error('Unknown field in format: {%s}',
+                          field.decode('ascii'))
+            return 1
+
+    commits = [c for c, _, _ in GitHgHelper.rev_list(
+        b'--no-walk=unsorted', *(fsencode(r) for r in args.rev))]
+    if not commits:
+        logging.error('No commits to annotate.')
+        return 1
+
+    store = GitHgStore()
+    drafts = set()
+    if b'phase' in wanted:
+        drafts = draft_commits(store, commits, stored_draft_roots())
+    find_remotes = RemoteFinder(store) if b'remote' in wanted else None
+
+    for commit in commits:
+        node = store.hg_changeset(commit)
+        fields = {b'git': commit, b'hg': node or NULL_NODE_ID}
+        if node:
+            changeset = store.changeset(node)
+            fields[b'branch'] = changeset.branch or b'default'
+            fields[b'phase'] = b'draft' if commit in drafts else b'public'
+            extra = changeset.extra
+            if extra:
+                # Show the extras as stored in the changeset, which keeps
+                # their order and duplicate keys.
+                fields[b'extras'] = b','.join(
+                    i.replace(b':', b'=', 1)
+                    for i in extra.to_str().split(b'\0') if i)
+            if find_remotes:
+                fields[b'remote'] = b','.join(find_remotes(commit))
+        bytes_stdout.write(FIELD_RE.sub(
+            lambda m: fields.get(m.group(1)) or b'-', fmt))
+        bytes_stdout.write(b'\n')
+    store.close()
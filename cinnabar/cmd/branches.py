@@ -0,0 +1,25 @@
+from __future__ import absolute_import, print_function, unicode_literals
+from cinnabar.cmd.util import CLI
+from cinnabar.githg import GitHgStore
+from cinnabar.util import (
+    bytes_stdout,
+    iteritems,
+)
+
+
+@CLI.subcommand
+@CLI.argument('--closed', action='store_true',
+              help='also show closed branches')
+def branches(args):
+    '''list mercurial branches and their most recent head'''
+
+    store = GitHgStore()
+    for branch, heads in sorted(iteritems(store.branch_heads())):
+        closed = [h for h in heads if store.changeset(h).close]
+        if len(closed) == len(heads):
+            if args.closed:
+                bytes_stdout.write(b'%s %s (closed)\n' % (heads[-1], branch))
+            continue
+        tip = [h for h in heads if h not in closed][-1]
+        bytes_stdout.write(b'%s %s\n' % (tip, branch))
+    store.close()
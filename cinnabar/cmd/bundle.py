@@ -1,5 +1,6 @@
 from __future__ import absolute_import, unicode_literals
 import logging
+import os
 from cinnabar.cmd.util import CLI
 from cinnabar.git import (
     Git,
@@ -9,6 +10,7 @@ from cinnabar.git import (
 from cinnabar.githg import GitHgStore
 from cinnabar.helper import GitHgHelper
 from cinnabar.hg.bundle import (
+    compress,
     create_bundle,
     PushStore,
 )
@@ -18,58 +20,181 @@ from cinnabar.hg.repo import (
     get_clonebundle,
     get_repo,
     Remote,
-    unbundle20,
     unbundler,
 )
-from cinnabar.util import fsencode
+from cinnabar.util import (
+    bytes_stdout,
+    fsencode,
+)
+
+
+BUNDLE_SIGNATURE = b'# v2 git bundle\n'
+
+
+def read_git_bundle_refs(path):
+    '''Returns the set of (sha1, ref) in the header of the given git bundle,
+    or None if it is not a git bundle without prerequisites.'''
+    refs = set()
+    with open(path, 'rb') as fh:
+        if fh.readline() != BUNDLE_SIGNATURE:
+            return None
+        for line in fh:
+            line = line.rstrip(b'\n')
+            if not line:
+                return refs
+            if line.startswith(b'-'):
+                return None
+            refs.add(tuple(line.split(b' ', 1)))
+    return None
+
+
+def cinnabarclone_bundle(path):
+    refs = set(
+        (sha1, ref) for sha1, ref in Git.for_each_ref(
+            b'refs/cinnabar/metadata', b'refs/cinnabar/replace/'))
+    if not any(ref == b'refs/cinnabar/metadata' for _, ref in refs):
+        logging.error('There is no cinnabar metadata to bundle.')
+        return 1
+    if os.path.exists(path) and read_git_bundle_refs(path) == refs:
+        logging.info('%s is already up to date.', path)
+        return 0
+    # The replace refs are needed for the cinnabarclone client to find
+    # the replacement commits the metadata refers to.
+    proc = GitProcess('bundle', 'create', path,
+                      *(ref for _, ref in sorted(refs, key=lambda r: r[1])))
+    return proc.wait()
+
+
+BUNDLE_COMPRESSIONS = {
+    'none': None,
+    'gzip': b'GZ',
+    'bzip2': b'BZ',
+}
 
 
 @CLI.subcommand
-@CLI.argument('--version', choices=(1, 2), type=int,
-              default=2 if unbundle20 else 1,
-              help='bundle version')
+@CLI.argument('--version', choices=(1, 2), type=int, default=2,
+              help='bundle version (default: %(default)s)')
+@CLI.argument('--compression', choices=sorted(BUNDLE_COMPRESSIONS),
+              default='none',
+              help='bundle compression (default: %(default)s)')
+@CLI.argument('--cinnabarclone', action='store_true',
+              help='create a git bundle of the cinnabar metadata, for use '
+                   'with the cinnabarclone mercurial extension, instead of '
+                   'a mercurial bundle. The bundle is only recreated when '
+                   'the metadata changed')
 @CLI.argument('path', help='path of the bundle')
-@CLI.argument('rev', nargs='+',
+@CLI.argument('rev', nargs='*',
               help='git revision range (see the Specifying Ranges'
                    ' section of gitrevisions(7))')
 def bundle(args):
     '''create a mercurial bundle'''
 
+    if args.cinnabarclone:
+        if args.rev:
+            logging.error('Revisions cannot be given with --cinnabarclone.')
+            return 1
+        return cinnabarclone_bundle(args.path)
+    if not args.rev:
+        logging.error('At least one revision is required.')
+        return 1
+
     revs = [fsencode(r) for r in args.rev]
     bundle_commits = list((c, p) for c, t, p in GitHgHelper.rev_list(
         b'--topo-order', b'--full-history', b'--parents', b'--reverse', *revs))
-    if bundle_commits:
-        # TODO: better UX. For instance, this will fail with an exception when
-        # the parent commit doesn't have mercurial metadata.
-        GRAFT = {
-            None: False,
-            b'false': False,
-            b'true': True,
+    if not bundle_commits:
+        logging.error('No commits to bundle.')
+        return 1
+
+    GRAFT = {
+        None: False,
+        b'false': False,
+        b'true': True,
+        b'fuzzy': 'fuzzy',
+        b'patch-id': 'patch-id',
+    }
+    try:
+        graft = Git.config('cinnabar.graft', values=GRAFT)
+    except InvalidConfig as e:
+        logging.error(str(e))
+        return 1
+    store = PushStore(graft=graft)
+    # The parents of the bundled commits that are not bundled themselves
+    # need to be known mercurial changesets, since the bundle only contains
+    # deltas against them.
+    bundled = set(c for c, _ in bundle_commits)
+    for _, parents in bundle_commits:
+        for parent in parents:
+            if parent not in bundled and not store.hg_changeset(parent):
+                logging.error(
+                    'Commit %s is not bundled and has no mercurial '
+                    'metadata. Please include it in the revision range.',
+                    parent.decode('ascii'))
+                store.close(rollback=True)
+                return 1
+    if args.version == 1:
+        b2caps = {}
+    elif args.version == 2:
+        b2caps = {
+            b'HG20': (),
+            b'changegroup': (b'01', b'02'),
         }
-        try:
-            graft = Git.config('cinnabar.graft', values=GRAFT)
-        except InvalidConfig as e:
-            logging.error(str(e))
-            return 1
-        store = PushStore(graft=graft)
-        if args.version == 1:
-            b2caps = {}
-        elif args.version == 2:
-            b2caps = {
-                b'HG20': (),
-                b'changegroup': (b'01', b'02'),
-            }
-        with open(args.path, 'wb') as fh:
-            if not b2caps:
-                fh.write(b'HG10UN')
-            for data in create_bundle(store, bundle_commits, b2caps):
-                fh.write(data)
-        store.close(rollback=True)
+    compression = BUNDLE_COMPRESSIONS[args.compression]
+    with open(args.path, 'wb') as fh:
+        data = create_bundle(store, bundle_commits, b2caps,
+                             compression=compression)
+        if not b2caps:
+            fh.write(b'HG10%s' % (compression or b'UN'))
+            if compression:
+                data = compress(data, compression)
+            if compression == b'BZ':
+                # Version 1 bundles don't contain the bzip2 magic, which
+                # is implied by the header.
+                data = strip_bz2_magic(data)
+        for chunk in data:
+            fh.write(chunk)
+    store.close(rollback=True)
+    return 0
+
+
+def strip_bz2_magic(chunks):
+    magic = b''
+    for chunk in chunks:
+        if len(magic) < 2:
+            skip = 2 - len(magic)
+            magic += chunk[:skip]
+            chunk = chunk[skip:]
+        if chunk:
+            yield chunk
+
+
+def create_bundle_refs(store, heads, prefix):
+    '''Creates refs of the form <prefix>/<branch>/<hg sha1> for the given
+    (node, branch) heads, and returns them.'''
+    refs = []
+    for node, branch in heads:
+        commit = store.changeset_ref(node)
+        if not commit:
+            continue
+        ref = b'%s/%s/%s' % (prefix.rstrip(b'/'), branch, node)
+        if GitProcess('check-ref-format', ref).wait():
+            logging.warning('Not creating a ref for %s: invalid ref name %s',
+                            node.decode('ascii'),
+                            ref.decode('utf-8', 'replace'))
+            continue
+        Git.update_ref(ref, commit)
+        refs.append(ref)
+    return refs
 
 
 @CLI.subcommand
 @CLI.argument('--clonebundle', action='store_true',
               help='get clone bundle from given repository')
+@CLI.argument('--ref-prefix', default='refs/heads/bundle',
+              help='prefix of the refs created for the heads of the bundle,'
+                   ' as <prefix>/<branch>/<hg sha1> (default: %(default)s)')
+@CLI.argument('--no-refs', action='store_true',
+              help='only import the bundle, without creating refs')
 @CLI.argument('url', help='url of the bundle')
 def unbundle(args):
     '''apply a mercurial bundle to the repository'''
@@ -96,6 +221,8 @@ def unbundle(args):
         None: False,
         b'false': False,
         b'true': True,
+        b'fuzzy': 'fuzzy',
+        b'patch-id': 'patch-id',
     }
     try:
         graft = Git.config('cinnabar.graft', values=GRAFT)
@@ -103,9 +230,15 @@ def unbundle(args):
         logging.error(str(e))
         return 1
     if graft:
-        store.prepare_graft()
+        store.prepare_graft(graft)
     bundle = unbundler(bundle)
-    apply_bundle = BundleApplier(bundle)
+    apply_bundle = BundleApplier(bundle, remote.url)
     del bundle
     apply_bundle(store)
+    refs = []
+    if not args.no_refs:
+        refs = create_bundle_refs(store, apply_bundle.heads,
+                                  fsencode(args.ref_prefix))
     store.close()
+    for ref in refs:
+        bytes_stdout.write(b'%s\n' % ref)
@@ -0,0 +1,59 @@
+from __future__ import absolute_import, print_function, unicode_literals
+import os
+import sys
+from cinnabar.cmd.util import CLI
+from cinnabar.git import GitProcess
+
+
+def guess_directory(url):
+    '''Guess the directory `git clone` would create for the given url.'''
+    path = url.rstrip('/')
+    if path.endswith('/.hg'):
+        path = path[:-4]
+    return os.path.basename(path.rstrip('/')) or 'repo'
+
+
+@CLI.subcommand
+@CLI.argument('--stats', action='store_true',
+              help='display timings and statistics at the end')
+@CLI.argument('--sparse', nargs='+', metavar='PATH',
+              help='only check out files at the top-level and in the given '
+                   'directories (cone-mode sparse checkout)')
+@CLI.argument('--include', nargs='+', metavar='PATH',
+              help='only convert the files in the given directories '
+                   '(narrow clone)')
+@CLI.argument('--exclude', nargs='+', metavar='PATH',
+              help='don\'t convert the files in the given directories '
+                   '(narrow clone)')
+@CLI.argument('url', help='url of the mercurial repository')
+@CLI.argument('directory', nargs='?', help='directory to clone into')
+def clone(args):
+    '''clone a mercurial repository'''
+
+    url = args.url
+    if not url.startswith(('hg::', 'hg://')):
+        url = 'hg::' + url
+    directory = args.directory or guess_directory(args.url)
+
+    clone_args = ['clone']
+    # The narrow spec is stored in the configuration of the new repository,
+    # where it applies to subsequent fetches too.
+    for kind in ('include', 'exclude'):
+        paths = getattr(args, kind)
+        if paths:
+            clone_args.extend(('-c', 'cinnabar.narrow-%s=%s'
+                               % (kind, ','.join(paths))))
+    if args.sparse:
+        # With --sparse, the initial checkout only contains the files at
+        # the top-level of the repository.
+        clone_args.append('--sparse')
+    config = {'cinnabar.showstats': 'true'} if args.stats else {}
+    proc = GitProcess(*(clone_args + ['--', url, directory]),
+                      stdout=sys.stdout, config=config)
+    retcode = proc.wait()
+    if retcode or not args.sparse:
+        return retcode
+
+    proc = GitProcess('-C', directory, 'sparse-checkout', 'set', '--cone',
+                      '--', *args.sparse, stdout=sys.stdout)
+    return proc.wait()
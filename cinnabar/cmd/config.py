@@ -0,0 +1,91 @@
+from __future__ import absolute_import, print_function, unicode_literals
+import os
+import sys
+from cinnabar.cmd.util import CLI
+from cinnabar.config import (
+    INTERNAL,
+    OPTIONS,
+)
+from cinnabar.git import Git
+from cinnabar.util import (
+    fsdecode,
+    fsencode,
+    one,
+)
+
+
+def describe(name, remote):
+    '''Returns the effective value of the given option, and where it comes
+    from, or None when it is not set.'''
+    option = OPTIONS.get(name)
+    var, value, origin = Git.config_lookup(
+        name, remote if option and option.per_remote else None)
+    if value is None:
+        return None
+    value = value.split(b'\0')[-1]
+    var = fsdecode(var)
+    if origin == 'environment':
+        where = 'environment variable %s' % var
+    else:
+        source = one(Git.iter('config', '--show-origin', '--get', var,
+                              stderr=open(os.devnull, 'wb')))
+        where = var
+        if source and b'\t' in source:
+            where = '%s, %s' % (var, fsdecode(source.split(b'\t', 1)[0]))
+    return fsdecode(value), where
+
+
+@CLI.subcommand
+@CLI.argument('-r', '--remote',
+              help='resolve per-remote options for the given remote')
+@CLI.argument('--all', action='store_true',
+              help='with --list, also show options that are not set')
+@CLI.argument('--list', action='store_true',
+              help='list the effective value of all options, and where '
+                   'they come from')
+@CLI.argument('name', nargs='?', help='option to show')
+def config(args):
+    '''show the effective git-cinnabar configuration'''
+
+    remote = fsencode(args.remote) if args.remote else None
+    if args.name:
+        if not args.name.startswith('cinnabar.'):
+            args.name = 'cinnabar.%s' % args.name
+        if args.name not in OPTIONS:
+            print('Unknown option: %s' % args.name, file=sys.stderr)
+            return 1
+        result = describe(args.name, remote)
+        if result is None:
+            return 1
+        print(result[0])
+        return 0
+
+    if not args.list:
+        print('Either --list or an option name is required.',
+              file=sys.stderr)
+        return 1
+
+    for name, option in OPTIONS.items():
+        result = describe(name, remote)
+        if result:
+            print('%s=%s (%s)' % (name, result[0], result[1]))
+        elif args.all:
+            print('%s is not set: %s' % (name, option.help))
+
+    # Point out options that are set but that git-cinnabar doesn't know,
+    # which are likely typos.
+    known = set(n.encode('ascii') for n in OPTIONS)
+    known |= set(n.encode('ascii') for n in INTERNAL)
+    known_remote = set(b'cinnabar-' + n[len('cinnabar.'):].replace(
+        '.', '-').encode('ascii') for n, o in OPTIONS.items() if o.per_remote)
+    for var in sorted(Git._config):
+        if var.startswith(b'cinnabar.'):
+            unknown = var not in known
+        elif var.startswith(b'remote.') and b'.cinnabar-' in var:
+            unknown = var.rpartition(b'.')[2] not in known_remote
+        else:
+            continue
+        if unknown:
+            print('warning: unknown option %s' % fsdecode(var),
+                  file=sys.stderr)
+    return 0
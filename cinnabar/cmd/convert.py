@@ -1,12 +1,21 @@
 from __future__ import absolute_import, print_function, unicode_literals
 import argparse
+import hashlib
+import json
+import os
 import re
+import sys
+from cinnabar import api
+from cinnabar.cmd.daemon import DaemonClient
 from cinnabar.cmd.util import CLI
-from cinnabar.git import NULL_NODE_ID
-from cinnabar.helper import GitHgHelper
+from cinnabar.git import (
+    GitProcess,
+    NULL_NODE_ID,
+)
 from cinnabar.util import (
     bytes_stdin,
     bytes_stdout,
+    cachedir,
 )
 
 
@@ -48,6 +57,7 @@ class SHA1Action(argparse.Action):
 
 
 SHA1_RE = re.compile(r'[0-9a-fA-F]{1,40}$')
+BYTES_SHA1_RE = re.compile(br'[0-9a-fA-F]{1,40}$')
 
 
 def sha1_value(value):
@@ -56,6 +66,23 @@ def sha1_value(value):
     return value.encode('ascii')
 
 
+def write_result(args, key, arg, other_key, result, error=None):
+    '''Writes the result of a conversion. Unknown or invalid sha1s are
+    given as null sha1s, or, with --json, as null with an error marker.'''
+    if args.json:
+        data = {
+            key: arg.decode('ascii', 'replace'),
+            other_key: result[:args.abbrev].decode('ascii')
+            if result else None,
+        }
+        if error:
+            data['error'] = error
+        bytes_stdout.write(json.dumps(data, sort_keys=True).encode('ascii'))
+    else:
+        bytes_stdout.write((result or NULL_NODE_ID)[:args.abbrev])
+    bytes_stdout.write(b'\n')
+
+
 def do_all(args, callback):
     for arg in args.sha1:
         callback(arg)
@@ -74,15 +101,29 @@ def do_all(args, callback):
 @CLI.subcommand
 @CLI.argument('--abbrev', action=AbbrevAction)
 @CLI.argument('--batch', action='store_true', help='read sha1s on stdin')
+@CLI.argument('--json', action='store_true',
+              help='output a json object per sha1')
 @CLI.argument('sha1', action=SHA1Action, nargs='*', type=sha1_value,
               help='mercurial sha1')
 def hg2git(args):
     '''convert mercurial sha1 to corresponding git sha1'''
 
+    client = DaemonClient.connect()
+    if client:
+        def lookup(arg):
+            return client.request(b'hg2git', arg)
+    else:
+        lookup = api.hg2git
+
     def do_one(arg):
-        bytes_stdout.write(
-            GitHgHelper.hg2git(arg)[:args.abbrev])
-        bytes_stdout.write(b'\n')
+        if not BYTES_SHA1_RE.match(arg):
+            write_result(args, 'hg', arg, 'git', None, 'invalid sha1')
+            return
+        result = lookup(arg)
+        if not result:
+            write_result(args, 'hg', arg, 'git', None, 'unknown')
+        else:
+            write_result(args, 'hg', arg, 'git', result)
 
     do_all(args, do_one)
 
@@ -90,19 +131,68 @@ def hg2git(args):
 @CLI.subcommand
 @CLI.argument('--abbrev', action=AbbrevAction)
 @CLI.argument('--batch', action='store_true', help='read sha1s on stdin')
+@CLI.argument('--json', action='store_true',
+              help='output a json object per sha1')
 @CLI.argument('sha1', action=SHA1Action, nargs='*', help='git sha1')
 def git2hg(args):
     '''convert git sha1 to corresponding mercurial sha1'''
 
+    client = DaemonClient.connect()
+    if client:
+        def lookup(arg):
+            return client.request(b'git2hg', arg)
+    else:
+        lookup = api.git2hg
+
     def do_one(arg):
         if isinstance(arg, str):
             arg = arg.encode('ascii')
-        data = GitHgHelper.git2hg(arg)
-        if data:
-            assert data.startswith(b'changeset ')
-            bytes_stdout.write(data[10:10 + args.abbrev])
+        result = lookup(arg)
+        if result:
+            write_result(args, 'git', arg, 'hg', result)
         else:
-            bytes_stdout.write(NULL_NODE_ID[:args.abbrev])
-        bytes_stdout.write(b'\n')
+            write_result(args, 'git', arg, 'hg', None, 'unknown')
 
     do_all(args, do_one)
+
+
+@CLI.subcommand
+@CLI.argument('--marks', metavar='FILE',
+              help='file where marks are read from and written to, so that '
+                   'subsequent runs only output what was not output before')
+@CLI.argument('--repository', metavar='DIR',
+              help='git repository where the conversion happens (default: '
+                   'a directory under cinnabar.cachedir)')
+@CLI.argument('url', help='url of the mercurial repository')
+def convert(args):
+    '''convert a mercurial repository to a git fast-import stream'''
+
+    url = args.url
+    if not url.startswith(('hg::', 'hg://')):
+        url = 'hg::' + url
+    # The conversion still needs a git repository, but not the current one,
+    # so that it can be reused by subsequent runs.
+    repository = args.repository or os.path.join(
+        cachedir(), 'convert',
+        hashlib.sha1(url.encode('utf-8')).hexdigest())
+    git_dir = '--git-dir=%s' % repository
+    if not os.path.isdir(repository):
+        proc = GitProcess('init', '--bare', '-q', repository,
+                          stdout=sys.stderr)
+        retcode = proc.wait()
+        if retcode:
+            return retcode
+    proc = GitProcess(git_dir, 'fetch', '--prune', url,
+                      '+refs/heads/*:refs/heads/*', stdout=sys.stderr,
+                      config={'cinnabar.auto-tags': 'true'}, logger='fetch')
+    retcode = proc.wait()
+    if retcode:
+        return retcode
+
+    export_args = ['fast-export', '--signed-tags=strip']
+    if args.marks:
+        export_args += ['--import-marks-if-exists=%s' % args.marks,
+                        '--export-marks=%s' % args.marks]
+    proc = GitProcess(git_dir, *(export_args + ['--branches', '--tags']),
+                      stdout=sys.stdout, logger='fast-export')
+    return proc.wait()
@@ -0,0 +1,196 @@
+from __future__ import absolute_import, print_function, unicode_literals
+import logging
+import os
+import re
+import socket
+import time
+from threading import (
+    Lock,
+    Thread,
+)
+from cinnabar import api
+from cinnabar.cmd.util import CLI
+from cinnabar.git import (
+    Git,
+    NULL_NODE_ID,
+)
+from cinnabar.helper import GitHgHelper
+from cinnabar.util import (
+    bytes_stdin,
+    bytes_stdout,
+    fsdecode,
+    one,
+)
+
+# How often, in seconds, to check whether the metadata changed.
+RELOAD_INTERVAL = 1
+
+SHA1_RE = re.compile(br'^[0-9a-fA-F]{1,40}$')
+
+
+class DaemonServer(object):
+    '''Serves requests of the form `<command> <argument>`, one per line,
+    with one line of response each:
+    - `hg2git <hg sha1>` returns the corresponding git sha1.
+    - `git2hg <git sha1>` returns the corresponding mercurial sha1.
+    Unknown sha1s give a null sha1, and invalid requests an `error` line.
+    Requests can come from several threads.
+    '''
+    def __init__(self):
+        self._metadata = Git.resolve_ref(b'refs/cinnabar/metadata')
+        self._checked = time.time()
+        # There is only one helper process, which handles one request at
+        # a time.
+        self._lock = Lock()
+
+    def _maybe_reload(self):
+        # Fetches and pushes from other processes change the metadata,
+        # which the helper doesn't notice by itself.
+        now = time.time()
+        if now - self._checked < RELOAD_INTERVAL:
+            return
+        self._checked = now
+        metadata = Git.resolve_ref(b'refs/cinnabar/metadata')
+        if metadata != self._metadata:
+            self._metadata = metadata
+            GitHgHelper.reload()
+
+    def handle(self, line):
+        command, _, arg = line.strip().partition(b' ')
+        arg = arg.strip()
+        if command == b'quit':
+            return None
+        # Anything else than a sha1 would be interpreted by the helper,
+        # e.g. as a ref, or trip its assertions.
+        if not SHA1_RE.match(arg):
+            return b'error invalid request'
+        if command not in (b'hg2git', b'git2hg'):
+            return b'error unknown command'
+        with self._lock:
+            self._maybe_reload()
+            if command == b'hg2git':
+                return api.hg2git(arg) or NULL_NODE_ID
+            return api.git2hg(arg) or NULL_NODE_ID
+
+    def serve(self, stdin, stdout):
+        while True:
+            line = stdin.readline()
+            if not line:
+                break
+            response = self.handle(line)
+            if response is None:
+                break
+            stdout.write(response + b'\n')
+            stdout.flush()
+
+    def serve_connection(self, conn):
+        reader = conn.makefile('rb')
+        writer = conn.makefile('wb')
+        try:
+            self.serve(reader, writer)
+        except socket.error:
+            pass
+        finally:
+            reader.close()
+            writer.close()
+            conn.close()
+
+    def serve_socket(self, sock):
+        '''Serves the connections to the given listening socket, each in
+        its own thread, so that idle clients don't block the others.'''
+        while True:
+            conn, _ = sock.accept()
+            thread = Thread(target=self.serve_connection, args=(conn,))
+            thread.daemon = True
+            thread.start()
+
+
+# Paths of the daemon socket, depending on what rev-parse uses to find the
+# git directory.
+_daemon_sockets = {}
+
+
+def daemon_socket():
+    '''Returns the path of the socket the daemon for the current
+    repository listens on.'''
+    key = (os.environ.get('GIT_DIR'), os.getcwd())
+    if key not in _daemon_sockets:
+        git_dir = one(Git.iter('rev-parse', '--git-dir'))
+        _daemon_sockets[key] = os.path.join(
+            fsdecode(git_dir), 'cinnabar-daemon.sock') if git_dir else None
+    return _daemon_sockets[key]
+
+
+class DaemonClient(object):
+    '''Sends requests to a running `git cinnabar daemon`.'''
+    def __init__(self, sock):
+        self._sock = sock
+        self._reader = sock.makefile('rb')
+
+    @classmethod
+    def connect(cls, path=None):
+        '''Returns a client for the daemon listening on the given socket,
+        by default, the one for the current repository, or None if there
+        is none.'''
+        if not hasattr(socket, 'AF_UNIX'):
+            return None
+        path = path or daemon_socket()
+        if not path or not os.path.exists(path):
+            return None
+        sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+        try:
+            sock.connect(path)
+        except socket.error:
+            sock.close()
+            return None
+        return cls(sock)
+
+    def request(self, command, arg):
+        self._sock.sendall(b'%s %s\n' % (command, arg))
+        response = self._reader.readline().rstrip(b'\n')
+        if not response or response.startswith(b'error'):
+            return None
+        return response if response != NULL_NODE_ID else None
+
+    def close(self):
+        self._reader.close()
+        self._sock.close()
+
+
+@CLI.subcommand
+@CLI.argument('--stdio', action='store_true',
+              help='serve requests from the standard input instead of '
+                   'listening on a unix socket')
+def daemon(args):
+    '''serve sha1 lookups from a long-running process'''
+
+    server = DaemonServer()
+    if args.stdio:
+        server.serve(bytes_stdin, bytes_stdout)
+        return 0
+
+    if not hasattr(socket, 'AF_UNIX'):
+        logging.error('Unix sockets are not supported on this system.')
+        return 1
+    path = daemon_socket()
+    if not path:
+        logging.error('Not in a git repository.')
+        return 1
+    if os.path.exists(path):
+        client = DaemonClient.connect(path)
+        if client:
+            client.close()
+            logging.error('A daemon is already listening on %s.', path)
+            return 1
+        os.unlink(path)
+    sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+    sock.bind(path)
+    sock.listen(5)
+    try:
+        server.serve_socket(sock)
+    except KeyboardInterrupt:
+        pass
+    finally:
+        sock.close()
+        os.unlink(path)
+    return 0
@@ -151,7 +151,8 @@ def download(args):
             self._reader = reader
             self._length = length
             self._read = 0
-            self._progress = Progress(' {}%' if self._length else ' {} bytes')
+            self._progress = Progress(' {}%' if self._length else ' {} bytes',
+                                      rate=False)
 
         def read(self, length):
             data = self._reader.read(length)
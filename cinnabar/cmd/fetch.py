@@ -2,51 +2,253 @@ from __future__ import absolute_import, print_function, unicode_literals
 import re
 import sys
 from binascii import hexlify
+from cinnabar.api import hg2git
 from cinnabar.cmd.util import CLI
+from cinnabar.exceptions import Abort
 from cinnabar.git import (
     Git,
     GitProcess,
 )
+from cinnabar.githg import (
+    BranchMap,
+    GitHgStore,
+)
+from cinnabar.hg.changegroup import extstored_nodes
 from cinnabar.hg.repo import (
+    getbundle,
     get_repo,
     Remote,
+    remote_heads,
+    remote_tags,
+    revset_heads,
+)
+from cinnabar.lfs import store_lfs_nodes
+from cinnabar.obsolete import (
+    remote_markers,
+    store_markers,
+)
+from cinnabar.phases import (
+    draft_roots,
+    store_draft_roots,
+)
+from cinnabar.remotes import (
+    store_remote_heads,
+)
+from cinnabar.util import (
+    fsdecode,
+    fsencode,
 )
-from cinnabar.util import fsencode
 
 
 @CLI.subcommand
-@CLI.argument('remote', help='mercurial remote name or url')
-@CLI.argument('revs', nargs='+', help='mercurial changeset to fetch')
+@CLI.argument('-r', '--rev', dest='revsets', action='append', default=[],
+              metavar='REVSET',
+              help='fetch the changesets matching the given mercurial '
+                   'revset. Only local repositories support revsets, '
+                   'other remotes only support what `hg lookup` does')
+@CLI.argument('--stats', action='store_true',
+              help='display timings and statistics at the end')
+@CLI.argument('--tags', action='store_true',
+              help='fetch the changesets the remote tags point to, and '
+                   'create the corresponding git tags')
+@CLI.argument('--all-remotes', action='store_true',
+              help='fetch from all the mercurial remotes in one pass')
+@CLI.argument('remote', nargs='?',
+              help='mercurial remote name or url, or group of remotes')
+@CLI.argument('revs', nargs='*', help='mercurial changeset to fetch')
 def fetch(args):
     '''fetch a changeset from a mercurial remote'''
 
     remote = args.remote
-    revs = args.revs
-    full_revs = []
-    for rev in revs:
-        if not re.match('[0-9a-f]{40}$', rev.lower()):
-            if remote.startswith('hg:'):
-                url = fsencode(remote)
-            else:
-                url = Git.config('remote.%s.url' % remote)
-            if not url:
-                print("Unknown remote:", remote, file=sys.stderr)
-                return 1
-            if url.startswith(b'hg::'):
-                url = url[4:]
-            repo = get_repo(Remote(fsencode(remote), url))
-            if repo.capable(b'lookup'):
-                rev = hexlify(repo.lookup(fsencode(rev))).decode('ascii')
-            else:
-                print('Remote repository does not support the "lookup" '
-                      'command. Please use a non-abbreviated mercurial '
-                      'revision.',
+    if args.all_remotes or (remote and not args.revs and not args.revsets
+                            and not args.tags and remote_group(remote)):
+        if args.revs or args.revsets or args.tags:
+            print('Cannot fetch revisions or tags from several remotes.',
+                  file=sys.stderr)
+            return 1
+        if args.all_remotes:
+            if remote:
+                print('Cannot use --all-remotes along a remote.',
                       file=sys.stderr)
                 return 1
-        full_revs.append(rev)
+            remotes = [r for r in Git.iter('remote')
+                       if mercurial_remote(r)]
+        else:
+            remotes = remote_group(remote)
+        return union_fetch(remotes, args.stats)
+    if not remote:
+        print('A remote is required.', file=sys.stderr)
+        return 1
+    if not args.revs and not args.revsets and not args.tags:
+        print('At least one revision is required.', file=sys.stderr)
+        return 1
+    revsets = [r for r in args.revs
+               if not re.match('[0-9a-f]{40}$', r.lower())] + args.revsets
+    full_revs = [r for r in args.revs if r not in revsets]
+    tags = {}
+    if revsets or args.tags:
+        if remote.startswith('hg:'):
+            url = fsencode(remote)
+        else:
+            url = Git.config('remote.%s.url' % remote)
+        if not url:
+            print("Unknown remote:", remote, file=sys.stderr)
+            return 1
+        if url.startswith(b'hg::'):
+            url = url[4:]
+        hg_remote = Remote(fsencode(remote), url)
+        repo = get_repo(hg_remote)
+        for revset in revsets:
+            try:
+                heads = revset_heads(hg_remote, repo, fsencode(revset))
+            except Exception as e:
+                print(e, file=sys.stderr)
+                return 1
+            if not heads:
+                print('No changesets match %s' % revset, file=sys.stderr)
+                return 1
+            full_revs.extend(hexlify(h).decode('ascii') for h in heads)
+        if args.tags:
+            try:
+                tags = remote_tags(hg_remote, repo)
+            except Exception as e:
+                print(e, file=sys.stderr)
+                return 1
+            if not tags:
+                print('No tags on the remote repository.', file=sys.stderr)
+                if not full_revs:
+                    return 0
+            full_revs.extend(node.decode('ascii')
+                             for node in sorted(set(tags.values()))
+                             if node.decode('ascii') not in full_revs)
 
     refs = ['hg/revs/%s' % r for r in full_revs]
 
+    config = {'cinnabar.fetch': ' '.join(full_revs)}
+    if args.stats:
+        config['cinnabar.showstats'] = 'true'
     proc = GitProcess('fetch', remote, *refs, stdout=sys.stdout,
-                      config={'cinnabar.fetch': ' '.join(full_revs)})
-    return proc.wait()
+                      config=config)
+    retcode = proc.wait()
+    if retcode or not tags:
+        return retcode
+    return create_tags(tags)
+
+
+def remote_group(name):
+    '''Returns the names of the remotes in the given group, as per the
+    remotes.<group> configuration git fetch also uses.'''
+    return [r for value in Git.config('remotes.%s' % name,
+                                      multiple=True) or ()
+            for r in value.split()]
+
+
+def mercurial_remote(name):
+    '''Returns the Remote for the given git remote, or None when it is not
+    a mercurial remote.'''
+    url = Git.config('remote.%s.url' % fsdecode(name))
+    if not url:
+        return None
+    if url.startswith(b'hg::'):
+        url = url[4:]
+    elif not url.startswith(b'hg://'):
+        return None
+    return Remote(name, url)
+
+
+def union_fetch(names, stats=False):
+    '''Fetches from all the given mercurial remotes, in one pass: the
+    changesets are imported with a single metadata generation, and those a
+    remote has in common with the remotes fetched before are neither
+    negotiated again nor downloaded twice. Git is then only left to update
+    the remote refs.'''
+    remotes = []
+    for name in names:
+        remote = mercurial_remote(name)
+        if not remote:
+            print('Skipping %s: not a mercurial remote.' % fsdecode(name),
+                  file=sys.stderr)
+            continue
+        remotes.append(remote)
+    if not remotes:
+        print('No mercurial remote to fetch from.', file=sys.stderr)
+        return 1
+
+    if any(Git.config('cinnabar.graft', remote=r.name) not in
+           (None, b'false') for r in remotes):
+        print('Grafting is not supported when fetching from several '
+              'remotes in one pass. Fetching from each of them in turn.',
+              file=sys.stderr)
+    else:
+        store = GitHgStore()
+        if store._broken:
+            raise Abort('Cannot fetch with broken metadata. '
+                        'Please fix your clone first.')
+        store.action = b'fetch %s' % b' '.join(r.name for r in remotes)
+        fetched = []
+        try:
+            for remote in remotes:
+                repo = get_repo(remote)
+                branchmap, heads, _ = remote_heads(repo)
+                if any(len(h) != 20 for h in heads):
+                    raise Abort('The remote repository %s does not use SHA-1 '
+                                'node ids, which git-cinnabar does not '
+                                'support.' % fsdecode(remote.name))
+                branchmap = BranchMap(store, branchmap, heads)
+                unknown_heads = (set(branchmap.heads()) &
+                                 branchmap.unknown_heads())
+                print('%s: %d new head%s' % (
+                    fsdecode(remote.name), len(unknown_heads),
+                    '' if len(unknown_heads) == 1 else 's'), file=sys.stderr)
+                if unknown_heads:
+                    getbundle(repo, store, unknown_heads, branchmap.names())
+                fetched.append((remote, repo, branchmap))
+        finally:
+            # Keep what was fetched from the remotes before a failure.
+            store.close()
+
+        for remote, repo, branchmap in fetched:
+            store_remote_heads(remote.name, [
+                h for h in branchmap.heads() if store.changeset_ref(h)])
+            store_draft_roots(remote.name,
+                              draft_roots(repo.listkeys(b'phases')))
+            store_markers(remote_markers(repo))
+        store_lfs_nodes(extstored_nodes)
+
+    config = {}
+    if stats:
+        config['cinnabar.showstats'] = 'true'
+    retcode = 0
+    for remote in remotes:
+        proc = GitProcess('fetch', remote.name, stdout=sys.stdout,
+                          config=config)
+        retcode = proc.wait() or retcode
+    return retcode
+
+
+def create_tags(tags):
+    '''Creates refs/tags/<name> for the given {name: hg sha1} tags, leaving
+    existing tags pointing to other commits alone.'''
+    retcode = 0
+    for name, node in sorted(tags.items()):
+        ref = b'refs/tags/%s' % name
+        commit = hg2git(node)
+        if not commit:
+            print('Could not find changeset %s for tag %s'
+                  % (node.decode('ascii'), fsdecode(name)), file=sys.stderr)
+            retcode = 1
+            continue
+        if GitProcess('check-ref-format', ref).wait():
+            print('Not creating tag %s: invalid ref name' % fsdecode(name),
+                  file=sys.stderr)
+            continue
+        existing = Git.resolve_ref(b'%s^{commit}' % ref)
+        if existing == commit:
+            continue
+        if existing:
+            print('Not updating tag %s: it already exists and points to '
+                  'another commit' % fsdecode(name), file=sys.stderr)
+            continue
+        Git.run('update-ref', ref, commit, b'')
+        print(' * [new tag] %s' % fsdecode(name))
+    return retcode
@@ -1,7 +1,9 @@
 from __future__ import absolute_import, print_function, unicode_literals
 import logging
+import random
 import re
 import sys
+from binascii import unhexlify
 from cinnabar.cmd.util import CLI
 from cinnabar.githg import (
     Changeset,
@@ -11,6 +13,7 @@ from cinnabar.githg import (
     GitHgStore,
     HG_EMPTY_FILE,
 )
+from cinnabar.hg.objects import File
 from cinnabar.dag import gitdag
 from cinnabar.git import (
     Git,
@@ -18,6 +21,7 @@ from cinnabar.git import (
 )
 from cinnabar.util import (
     fsdecode,
+    fsencode,
     interval_expired,
     iteritems,
     Progress,
@@ -25,6 +29,14 @@ from cinnabar.util import (
 )
 from cinnabar.helper import GitHgHelper
 from cinnabar.hg.bundle import get_changes
+from cinnabar.hg.repo import (
+    get_repo,
+    narrow_match,
+    narrow_spec,
+    Remote,
+    unbundler,
+)
+from cinnabar.lfs import stored_lfs_nodes
 from collections import (
     defaultdict,
     OrderedDict,
@@ -39,15 +51,16 @@ SHA1_RE = re.compile(b'[0-9a-f]{40}$')
 
 
 class FsckStatus(object):
-    def __init__(self):
+    def __init__(self, out=None):
         self.status = 'ok'
+        self._out = out
 
     def __call__(self, status):
         return self.status == status
 
     def info(self, message):
         sys.stderr.write('\r')
-        print(message)
+        print(message, file=self._out or sys.stdout)
 
     def fix(self, message):
         self.status = 'fixed'
@@ -64,21 +77,62 @@ def check_replace(store):
         del store._replace[r]
 
 
-def fsck_quick(force=False):
-    status = FsckStatus()
+def report_filters(store, status):
+    if store._filters:
+        status.info(
+            'This repository was converted with the following content '
+            'filters:\n  %s\n'
+            'Its git trees intentionally differ from the mercurial '
+            'manifests.' % store._filters.decode('utf-8', 'replace'))
+
+
+def find_manifest_commits():
+    '''Returns a dict associating the manifest nodes with the git commits
+    storing them in the metadata.'''
+    metadata = GitCommit(Git.resolve_ref('refs/cinnabar/metadata'))
+    return dict(
+        (GitCommit(m).body, m)
+        for m, _, _ in progress_iter(
+            'Finding {} manifests', GitHgHelper.rev_list(
+                b'--full-history', b'%s^@' % metadata.parents[1])))
+
+
+def report_no_metadata(status):
+    status.info(
+        'There does not seem to be any git-cinnabar metadata.\n'
+        'Is this a git-cinnabar clone?'
+    )
+
+
+def last_checked_metadata():
+    '''Returns the metadata commit of the last successful check, if any.'''
+    broken_metadata = Git.resolve_ref('refs/cinnabar/broken')
+    checked_metadata = Git.resolve_ref('refs/cinnabar/checked')
+    if checked_metadata != broken_metadata:
+        return checked_metadata
+
+
+def fsck_quick(force=False, incremental=False, status=None):
+    status = status or FsckStatus()
     store = GitHgStore()
 
     metadata_commit = Git.resolve_ref('refs/cinnabar/metadata')
     if not metadata_commit:
-        status.info(
-            'There does not seem to be any git-cinnabar metadata.\n'
-            'Is this a git-cinnabar clone?'
-        )
+        report_no_metadata(status)
         return 1
-    broken_metadata = Git.resolve_ref('refs/cinnabar/broken')
-    checked_metadata = Git.resolve_ref('refs/cinnabar/checked')
-    if checked_metadata == broken_metadata:
-        checked_metadata = None
+    checked_metadata = last_checked_metadata()
+    if incremental:
+        # Only check what was added since the last successful check, and
+        # nothing at all when there wasn't one, because that would mean
+        # checking everything.
+        if not checked_metadata:
+            status.info(
+                'The git-cinnabar metadata was never checked.\n'
+                'Please run `git cinnabar fsck` first.'
+            )
+            return 1
+        if metadata_commit == checked_metadata:
+            return 0
     if metadata_commit == checked_metadata and not force:
         status.info(
             'The git-cinnabar metadata was already checked and is '
@@ -90,17 +144,22 @@ def fsck_quick(force=False):
         checked_metadata = None
 
     commit = GitCommit(metadata_commit)
-    if commit.body != b'files-meta unified-manifests-v2':
+    flags = set(commit.body.split()) - set(GitHgStore.OPTIONAL_FLAGS)
+    if flags != set(GitHgStore.FLAGS):
         status.info(
             'The git-cinnabar metadata is incompatible with this version.\n'
             'Please use the git-cinnabar version it was used with last.\n'
         )
         return 1
+    if not incremental:
+        report_filters(store, status)
     if len(commit.parents) > 6 or len(commit.parents) < 5:
         status.report('The git-cinnabar metadata seems to be corrupted in '
                       'unexpected ways.\n')
         return 1
     changesets, manifests, hg2git, git2hg, files_meta = commit.parents[:5]
+    lfs_nodes = stored_lfs_nodes()
+    include, exclude = narrow_spec()
 
     commit = GitCommit(changesets)
     heads = OrderedDict(
@@ -299,6 +358,13 @@ def fsck_quick(force=False):
             if (path, hg_file) not in all_interesting:
                 continue
             all_interesting.remove((path, hg_file))
+            # The sha1 of lfs files is that of contents we don't have.
+            if hg_file in lfs_nodes:
+                continue
+            # Files outside a narrow clone are not available.
+            if (include or exclude) and not narrow_match(
+                    include, exclude, store.manifest_path(path)):
+                continue
             if not GitHgHelper.check_file(hg_file, *hg_fileparents):
                 p = store.manifest_path(path)
                 status.report(
@@ -333,6 +399,9 @@ def fsck_quick(force=False):
             'Please open an issue, with the information above, on\n'
             'https://github.com/glandium/git-cinnabar/issues')
         Git.update_ref(b'refs/cinnabar/broken', metadata_commit)
+        status.info(
+            '\nSome corruptions may be repaired with '
+            '`git cinnabar fsck --repair`.')
         if checked_metadata:
             status.info(
                 '\nThen please try to run `git cinnabar rollback --fsck` to '
@@ -361,23 +430,176 @@ def fsck_quick(force=False):
     return 0
 
 
+def fsck_files_remote(store, remote, commits, sample, status):
+    '''Checks the contents of the files changed in the given commits, or
+    in a random sample of the changesets when none is given, against the
+    file revisions the mercurial server sends for them.'''
+    metadata = Git.resolve_ref('refs/cinnabar/metadata')
+    if not metadata:
+        report_no_metadata(status)
+        return False
+    if remote.startswith('hg:'):
+        url = fsencode(remote)
+    else:
+        url = Git.config('remote.%s.url' % remote)
+    if not url:
+        status.info('Unknown remote: %s' % remote)
+        return False
+    if url.startswith(b'hg::'):
+        url = url[4:]
+    repo = get_repo(Remote(fsencode(remote), url))
+
+    if commits:
+        # Changesets are accepted as well as git commits and ranges.
+        args = [b'--topo-order'] + [
+            (SHA1_RE.match(c) and store.changeset_ref(c)) or c
+            for c in (fsencode(c) for c in commits)]
+    else:
+        args = [b'--topo-order', b'--full-history',
+                b'%s^^@' % metadata]
+    changesets = [store.hg_changeset(store._replace.get(c, c))
+                  for c, _, _ in GitHgHelper.rev_list(*args)]
+    changesets = [c for c in changesets if c]
+    if not commits and len(changesets) > sample:
+        changesets = random.sample(changesets, sample)
+
+    lfs_nodes = stored_lfs_nodes()
+    include, exclude = narrow_spec()
+    checked = 0
+    for changeset in progress_iter(
+            'Checking files from {} changesets against the remote',
+            changesets):
+        parents = [p for p in store.changeset(
+            changeset, include_parents=True).parents if p != NULL_NODE_ID]
+        # Getting a bundle for the changeset, from its parents, gives the
+        # file revisions it introduced, possibly as deltas against file
+        # revisions from the parents.
+        bundle = unbundler(repo.getbundle(
+            b'bundle', heads=[unhexlify(changeset)],
+            common=[unhexlify(p) for p in parents]))
+        for _ in next(bundle, None) or ():
+            pass
+        for _ in next(bundle, None) or ():
+            pass
+        chunks = list(next(bundle, None) or ())
+        for _ in bundle:
+            pass
+
+        files = {}
+        for path, chunk in chunks:
+            # Deltas are always against revisions of the same file, so
+            # excluded files can be skipped before resolving them.
+            if (include or exclude) and not narrow_match(
+                    include, exclude, path):
+                continue
+            if chunk.delta_node == NULL_NODE_ID:
+                file = File.from_chunk(chunk)
+            else:
+                delta_file = files.get(chunk.delta_node)
+                if delta_file is None:
+                    if not store.git_file_ref(chunk.delta_node):
+                        status.report(
+                            'Missing file %s, needed to check file %s'
+                            % (chunk.delta_node.decode('ascii'),
+                               chunk.node.decode('ascii')))
+                        continue
+                    delta_file = store.file(chunk.delta_node)
+                file = File.from_chunk(chunk, delta_file)
+            files[file.node] = file
+            if file.node in lfs_nodes:
+                continue
+            checked += 1
+            if file.node != file.sha1:
+                status.report(
+                    'Cannot check file %s in changeset %s: the file it is '
+                    'a delta against (%s) has the wrong contents'
+                    % (file.node.decode('ascii'), changeset.decode('ascii'),
+                       chunk.delta_node.decode('ascii')))
+                continue
+            if not store.git_file_ref(file.node):
+                status.report('Missing file %s in changeset %s'
+                              % (file.node.decode('ascii'),
+                                 changeset.decode('ascii')))
+                continue
+            if store.file(file.node).raw_data != file.raw_data:
+                status.report(
+                    'Contents mismatch for file %s (%s) in changeset %s'
+                    % (file.node.decode('ascii'), fsdecode(path),
+                       changeset.decode('ascii')))
+    status.info('Checked %d files from %d changesets.'
+                % (checked, len(changesets)))
+    return True
+
+
 @CLI.subcommand
 @CLI.argument('--force', action='store_true',
               help='Force check, even when metadata was already checked. '
                    'Also disables incremental fsck')
 @CLI.argument('--full', action='store_true',
               help='Check more thoroughly')
+@CLI.argument('--incremental', action='store_true',
+              help='Only check the metadata added since the last successful '
+                   'check, if there was one')
+@CLI.argument('--repair', action='store_true',
+              help='Repair the metadata that can be recovered from other '
+                   'metadata. Implies --full')
+@CLI.argument('--files', action='store_true',
+              help='Check the contents of files against the mercurial '
+                   'server given with --remote')
+@CLI.argument('--remote', metavar='REMOTE',
+              help='mercurial remote name or url to check files against')
+@CLI.argument('--sample', metavar='COUNT', type=int, default=100,
+              help='Number of random changesets to check files from with '
+                   '--files, when no commit is given (default: '
+                   '%(default)s)')
 @CLI.argument('commit', nargs='*',
-              help='Specific commit or changeset to check')
+              help='Specific commit or changeset to check. With --files, '
+                   'commits and ranges to check files from')
 def fsck(args):
     '''check cinnabar metadata consistency'''
 
+    if args.files or args.remote:
+        if not (args.files and args.remote):
+            logging.error('--files and --remote must be used together')
+            return 1
+        if args.force or args.full or args.incremental or args.repair:
+            logging.error('Cannot pass --files with other options')
+            return 1
+        status = FsckStatus()
+        store = GitHgStore()
+        if not fsck_files_remote(store, args.remote, args.commit,
+                                 args.sample, status):
+            return 1
+        if status('broken'):
+            Git.update_ref(b'refs/cinnabar/broken',
+                           Git.resolve_ref('refs/cinnabar/metadata'))
+            status.info(
+                'Your git-cinnabar repository appears to be corrupted.\n'
+                'Please try running the following command to reset:\n'
+                '  git cinnabar reclone')
+            return 1
+        store.close()
+        return 0
+
+    if args.incremental:
+        if args.force or args.full or args.repair or args.commit:
+            logging.error('Cannot pass --incremental with other options')
+            return 1
+        return fsck_quick(incremental=True)
+
+    if args.repair:
+        if args.commit:
+            logging.error('Cannot pass both --repair and a commit')
+            return 1
+        args.full = True
+
     if not args.commit and not args.full:
         return fsck_quick(args.force)
 
     status = FsckStatus()
 
     store = GitHgStore()
+    report_filters(store, status)
 
     if args.full and args.commit:
         logging.error('Cannot pass both --full and a commit')
@@ -409,7 +631,8 @@ def fsck(args):
         if b'refs/cinnabar/metadata' in all_refs:
             git_heads = b'%s^^@' % all_refs[b'refs/cinnabar/metadata']
         else:
-            assert False
+            report_no_metadata(status)
+            return 1
 
         all_git_commits = GitHgHelper.rev_list(
             b'--topo-order', b'--full-history', b'--reverse', git_heads)
@@ -418,7 +641,11 @@ def fsck(args):
 
     GitHgHelper.reset_heads(b'manifests')
 
+    manifest_commits = None
+
     full_file_check = FileFindParents.logger.isEnabledFor(logging.DEBUG)
+    lfs_nodes = stored_lfs_nodes()
+    include, exclude = narrow_spec()
 
     for node, tree, parents in progress_iter('Checking {} changesets',
                                              all_git_commits):
@@ -430,11 +657,24 @@ def fsck(args):
             continue
         GitHgHelper.seen(b'git2hg', node)
 
-        changeset_data = store.changeset(hg_node)
+        changeset_data = store._changeset(node)
         changeset = changeset_data.node
+        hg_changeset = store._changeset(node, include_parents=True)
 
         GitHgHelper.seen(b'hg2git', changeset)
         changeset_ref = store.changeset_ref(changeset)
+        # When the hg2git metadata is missing, or points to a commit that
+        # doesn't map back to the changeset, the git2hg metadata is the
+        # one to trust, provided it gives the right changeset.
+        if args.repair and hg_changeset.node == hg_changeset.sha1 and (
+                not changeset_ref or (
+                    bytes(changeset_ref) != node and
+                    store.hg_changeset(changeset_ref) != changeset)):
+            status.fix('Restoring hg2git metadata for changeset %s'
+                       % changeset.decode('ascii'))
+            GitHgHelper.set(b'changeset', changeset, NULL_NODE_ID)
+            GitHgHelper.set(b'changeset', changeset, node)
+            changeset_ref = node
         if not changeset_ref:
             status.report('Missing changeset in hg2git branch: %s'
                           % changeset.decode('ascii'))
@@ -446,7 +686,6 @@ def fsck(args):
                              changeset_ref.decode('ascii'),
                              node.decode('ascii')))
 
-        hg_changeset = store.changeset(changeset, include_parents=True)
         if hg_changeset.node != hg_changeset.sha1:
             status.report('Sha1 mismatch for changeset %s'
                           % changeset.decode('ascii'))
@@ -463,6 +702,8 @@ def fsck(args):
             GitHgHelper.set(b'changeset', changeset, NULL_NODE_ID)
             GitHgHelper.set(b'changeset', changeset, node)
             GitHgHelper.put_blob(patcher, want_sha1=False)
+            if patcher.has_extra_raw:
+                store._extra_raw = True
             GitHgHelper.set(b'changeset-metadata', changeset, NULL_NODE_ID)
             GitHgHelper.set(b'changeset-metadata', changeset, b':1')
 
@@ -470,9 +711,18 @@ def fsck(args):
         if GitHgHelper.seen(b'hg2git', manifest) or manifest == NULL_NODE_ID:
             continue
         manifest_ref = store.manifest_ref(manifest)
+        if not manifest_ref and args.repair:
+            if manifest_commits is None:
+                manifest_commits = find_manifest_commits()
+            manifest_ref = manifest_commits.get(manifest)
+            # The hg2git metadata is set further below.
+            if manifest_ref:
+                status.fix('Restoring hg2git metadata for manifest %s'
+                           % manifest.decode('ascii'))
         if not manifest_ref:
             status.report('Missing manifest in hg2git branch: %s'
                           % manifest.decode('ascii'))
+            continue
 
         parents = tuple(
             store.changeset(p).manifest
@@ -502,6 +752,11 @@ def fsck(args):
 
         changes = get_changes(manifest_ref, git_parents)
         for path, hg_file, hg_fileparents in changes:
+            if hg_file in lfs_nodes:
+                continue
+            if (include or exclude) and not narrow_match(
+                    include, exclude, store.manifest_path(path)):
+                continue
             if hg_file != NULL_NODE_ID and (hg_file == HG_EMPTY_FILE or
                                             GitHgHelper.seen(b'hg2git',
                                                              hg_file)):
@@ -563,6 +818,10 @@ def fsck(args):
 
     check_replace(store)
 
+    if status('broken') and args.repair:
+        status.info(
+            'Some of the corruptions above could not be repaired from the\n'
+            'remaining metadata.\n')
     if status('broken'):
         status.info(
             'Your git-cinnabar repository appears to be corrupted. There\n'
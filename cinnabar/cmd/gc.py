@@ -0,0 +1,139 @@
+from __future__ import absolute_import, print_function, unicode_literals
+import logging
+import os
+from cinnabar.cmd.metadata import rewrite_metadata
+from cinnabar.cmd.rollback import (
+    generations_in_use,
+    get_previous_metadata,
+)
+from cinnabar.cmd.util import CLI
+from cinnabar.git import (
+    Git,
+    GitProcess,
+)
+from cinnabar.githg import (
+    METADATA_LOG_REF,
+    metadata_log,
+    metadata_log_lines,
+)
+from cinnabar.helper import GitHgHelper
+from cinnabar.util import (
+    fsdecode,
+    iteritems,
+    one,
+)
+
+
+DEFAULT_RETENTION = 10
+
+
+@CLI.subcommand
+@CLI.argument('--keep', type=int, metavar='N',
+              help='number of metadata generations to keep. Defaults to '
+                   'the value of cinnabar.metadata-retention, or %d'
+                   % DEFAULT_RETENTION)
+def gc(args):
+    '''prune old cinnabar metadata generations'''
+
+    keep = args.keep
+    if keep is None:
+        try:
+            keep = int(Git.config('cinnabar.metadata-retention') or
+                       DEFAULT_RETENTION)
+        except ValueError:
+            logging.error('Invalid value for cinnabar.metadata-retention')
+            return 1
+    if keep < 1:
+        logging.error('At least one metadata generation must be kept.')
+        return 1
+
+    metadata = Git.resolve_ref('refs/cinnabar/metadata')
+    if not metadata:
+        logging.error('There is no cinnabar metadata.')
+        return 1
+    checked = Git.resolve_ref('refs/cinnabar/checked')
+    broken = Git.resolve_ref('refs/cinnabar/broken')
+
+    generations = [metadata]
+    while True:
+        previous = get_previous_metadata(generations[-1])
+        if not previous:
+            break
+        generations.append(previous)
+
+    # Keep the last checked metadata, for `git cinnabar rollback --fsck`.
+    if checked in generations:
+        keep = max(keep, generations.index(checked) + 1)
+
+    # Pruning rewrites all the kept generations, which repositories sharing
+    # the metadata might be using.
+    in_use = generations_in_use(generations) \
+        if keep < len(generations) else []
+    if in_use:
+        logging.error('Cannot prune metadata generations: they are used by '
+                      '%s, which share%s the metadata.',
+                      ', '.join(fsdecode(p) for p in in_use),
+                      's' if len(in_use) == 1 else '')
+        return 1
+
+    # Rewrite the kept generations, from the oldest, such that the oldest
+    # doesn't refer to the older ones anymore.
+    rewritten = {}
+    previous = None
+    for m in reversed(generations[:keep]):
+        previous = rewritten[m] = rewrite_metadata(m, previous)
+
+    # Nothing went through the helper, so there is nothing for it to write.
+    GitHgHelper.close()
+
+    # Update all the refs at once, so that an interruption doesn't leave
+    # them inconsistent.
+    updates = []
+    if rewritten[metadata] != metadata:
+        updates.append(b'update refs/cinnabar/metadata %s %s'
+                       % (rewritten[metadata], metadata))
+    if checked in rewritten:
+        if rewritten[checked] != checked:
+            updates.append(b'update refs/cinnabar/checked %s %s'
+                           % (rewritten[checked], checked))
+    elif checked:
+        updates.append(b'delete refs/cinnabar/checked %s' % checked)
+    # The broken metadata only matters when it is the current one.
+    if broken == metadata:
+        if rewritten[broken] != broken:
+            updates.append(b'update refs/cinnabar/broken %s %s'
+                           % (rewritten[broken], broken))
+    elif broken:
+        updates.append(b'delete refs/cinnabar/broken %s' % broken)
+    log = metadata_log()
+    if log:
+        lines = metadata_log_lines(dict(
+            (rewritten[m], entry) for m, entry in iteritems(log)
+            if m in rewritten))
+        updates.append(b'update %s %s' % (METADATA_LOG_REF, one(Git.iter(
+            'hash-object', '-w', '--stdin', stdin=lines))))
+    with open(os.devnull, 'wb') as devnull:
+        failed = updates and GitProcess(
+            'update-ref', '--stdin', stdin=updates, stdout=devnull).wait()
+    if failed:
+        logging.error('Could not update the metadata refs.')
+        return 1
+
+    pruned = len(generations) - len(rewritten)
+    if not pruned and broken in (None, metadata):
+        print('Nothing to prune.')
+        return 0
+    print('Pruned %d metadata generation%s.'
+          % (pruned, '' if pruned == 1 else 's'))
+    # The reflogs of the metadata refs, when there are any, would keep the
+    # pruned generations, and the kept ones before they were rewritten,
+    # reachable.
+    for ref in ('refs/cinnabar/metadata', 'refs/cinnabar/checked',
+                'refs/cinnabar/broken'):
+        if not GitProcess('reflog', 'exists', ref).wait():
+            Git.run('reflog', 'expire', '--expire=never',
+                    '--expire-unreachable=now', ref)
+    # Let git remove the objects that are not referenced anymore, according
+    # to its own configuration, e.g. gc.pruneExpire.
+    Git.run('gc', '--quiet')
+    return 0
@@ -0,0 +1,134 @@
+from __future__ import absolute_import, print_function, unicode_literals
+import logging
+import os
+import sys
+from binascii import (
+    hexlify,
+    unhexlify,
+)
+from cinnabar.cmd.util import CLI
+from cinnabar.git import (
+    Git,
+    GitProcess,
+)
+from cinnabar.githg import (
+    GitCommit,
+    GitHgStore,
+)
+from cinnabar.hg.objects import Changeset
+from cinnabar.hg.repo import (
+    bundlerepo,
+    commit_branches,
+    discovery_heads,
+    findcommon,
+    get_repo,
+    iter_initialized,
+    outgoing as outgoing_commits,
+    Remote,
+    unbundler,
+)
+from cinnabar.util import (
+    bytes_stdout,
+    fsdecode,
+    fsencode,
+)
+
+
+def summary(body):
+    return body.strip().split(b'\n', 1)[0]
+
+
+def get_remote_repo(remote):
+    url = Git.config('remote.%s.url' % remote) or fsencode(remote)
+    if url.startswith(b'hg::'):
+        url = url[4:]
+    return get_repo(Remote(fsencode(remote), url))
+
+
+@CLI.subcommand
+@CLI.argument('remote', help='mercurial remote name or url')
+def incoming(args):
+    '''show the changesets a fetch from a mercurial remote would bring'''
+
+    store = GitHgStore()
+    try:
+        repo = get_remote_repo(args.remote)
+        if isinstance(repo, bundlerepo):
+            logging.error('Bundles are not supported.')
+            return 1
+        branchmap = repo.branchmap()
+        heads = [h for h in repo.heads() if h != b'\0' * 20]
+        missing = [h for h in heads if not store.changeset_ref(hexlify(h))]
+        if not missing:
+            return 1
+        common = findcommon(
+            repo, store, discovery_heads(repo, store, branchmap.keys()))
+        bundle = repo.getbundle(b'bundle', heads=missing,
+                                common=[unhexlify(h) for h in common])
+        # Only the changesets are read from the bundle, and nothing is
+        # stored.
+        changesets = next(unbundler(bundle))
+        for cs in iter_initialized(store.changeset, changesets,
+                                   Changeset.from_chunk):
+            bytes_stdout.write(b'%s %s %s\n' % (
+                cs.node, cs.branch or b'default', summary(cs.body)))
+        return 0
+    finally:
+        store.close()
+
+
+@CLI.subcommand
+@CLI.argument('remote', help='mercurial remote name or url')
+@CLI.argument('rev', nargs='*',
+              help='git revisions that would be pushed (default: HEAD)')
+def outgoing(args):
+    '''show the commits a push to a mercurial remote would create
+    changesets for'''
+
+    store = GitHgStore()
+    try:
+        what = []
+        for rev in args.rev or ('HEAD',):
+            commit = Git.resolve_ref(fsencode(rev))
+            if not commit:
+                logging.error('Unknown revision: %s', rev)
+                return 1
+            what.append((commit, None, False))
+        repo = get_remote_repo(args.remote)
+        branchmap = repo.branchmap()
+        heads = [hexlify(h) for h in repo.heads() if h != b'\0' * 20]
+        try:
+            commits = outgoing_commits(repo, store, what, heads,
+                                       branchmap.keys())
+        except Exception as e:
+            logging.error(str(e))
+            return 1
+        if not commits:
+            return 1
+
+        branches = commit_branches(store, commits)
+        children = set()
+        for commit, parents in commits:
+            children.update(parents)
+            bytes_stdout.write(b'%s %s %s\n' % (
+                commit, branches[commit], summary(GitCommit(commit).body)))
+
+        for commit, _ in commits:
+            if commit in children:
+                continue
+            branch = branches[commit]
+            for head in branchmap.get(branch, ()):
+                head = store.changeset_ref(hexlify(head))
+                if head and GitProcess(
+                        'merge-base', '--is-ancestor', head, commit,
+                        stdout=open(os.devnull, 'wb')).wait() == 0:
+                    print('%s would update head %s of branch %s' % (
+                        fsdecode(commit), fsdecode(store.hg_changeset(head)),
+                        fsdecode(branch)), file=sys.stderr)
+                    break
+            else:
+                print('%s would create a new head on branch %s' % (
+                    fsdecode(commit), fsdecode(branch)), file=sys.stderr)
+        return 0
+    finally:
+        store.close()
@@ -0,0 +1,141 @@
+from __future__ import absolute_import, print_function, unicode_literals
+import hashlib
+import logging
+import os
+from cinnabar.cmd.util import CLI
+from cinnabar.git import (
+    Git,
+    GitProcess,
+)
+from cinnabar.hg.repo import (
+    get_repo,
+    Remote,
+)
+from cinnabar.largefiles import (
+    fetch_largefile,
+    standin_path,
+    standins,
+    store_largefiles,
+    stored_largefiles,
+)
+from cinnabar.util import (
+    fsdecode,
+    fsencode,
+    one,
+)
+
+
+def file_sha1(path):
+    h = hashlib.sha1()
+    with open(path, 'rb') as fh:
+        for data in iter(lambda: fh.read(32768), b''):
+            h.update(data)
+    return h.hexdigest().encode('ascii')
+
+
+def resolve(rev):
+    commit = Git.resolve_ref(rev)
+    if not commit:
+        raise Exception('Unknown revision: %s' % fsdecode(rev))
+    return commit
+
+
+def largefiles_fetch(args, stored):
+    remote = args.remote
+    url = Git.config('remote.%s.url' % remote) or fsencode(remote)
+    if url.startswith(b'hg::'):
+        url = url[4:]
+    remote = Remote(fsencode(remote), url)
+    store_url = Git.config('cinnabar.largefiles-url', remote=remote.name)
+    wanted = set()
+    for rev in args.args or (b'HEAD',):
+        wanted.update(standins(resolve(fsencode(rev))).values())
+    missing = sorted(wanted - set(stored))
+    if not missing:
+        return 0
+    repo = None if store_url else get_repo(remote)
+    try:
+        for sha in missing:
+            logging.info('Fetching largefile %s', sha.decode('ascii'))
+            stored[sha] = fetch_largefile(repo, sha, store_url)
+    finally:
+        store_largefiles(stored)
+    return 0
+
+
+def largefiles_checkout(args, stored, top):
+    missing = 0
+    for path, sha in sorted(standins(resolve(b'HEAD')).items()):
+        full_path = os.path.join(top, fsdecode(path))
+        if os.path.exists(full_path) and not args.force:
+            if file_sha1(full_path) != sha:
+                logging.warning('Not overwriting modified largefile %s. '
+                                'Use --force to overwrite it.',
+                                fsdecode(path))
+            continue
+        blob = stored.get(sha)
+        if not blob:
+            missing += 1
+            continue
+        directory = os.path.dirname(full_path)
+        if not os.path.isdir(directory):
+            os.makedirs(directory)
+        with open(full_path, 'wb') as fh:
+            GitProcess('cat-file', 'blob', blob, stdout=fh).wait()
+    if missing:
+        logging.error('%d largefiles have not been fetched. Please run '
+                      '`git cinnabar largefiles fetch` first.', missing)
+        return 1
+    return 0
+
+
+def largefiles_update(args, stored, top):
+    current = standins(resolve(b'HEAD'))
+    prefix = one(Git.iter('rev-parse', '--show-prefix')) or b''
+    paths = [os.path.normpath(prefix + fsencode(p)).replace(b'\\', b'/')
+             for p in args.args] or sorted(current)
+    for path in paths:
+        full_path = os.path.join(top, fsdecode(path))
+        if not os.path.isfile(full_path):
+            logging.warning('%s does not exist.', fsdecode(path))
+            continue
+        sha = file_sha1(full_path)
+        if sha not in stored:
+            stored[sha] = one(Git.iter('hash-object', '-w', '--no-filters',
+                                       '--', full_path))
+        if current.get(path) != sha:
+            standin = os.path.join(top, fsdecode(standin_path(path)))
+            directory = os.path.dirname(standin)
+            if not os.path.isdir(directory):
+                os.makedirs(directory)
+            with open(standin, 'wb') as fh:
+                fh.write(b'%s\n' % sha)
+            print('Updated', fsdecode(standin_path(path)))
+    store_largefiles(stored)
+    return 0
+
+
+@CLI.subcommand
+@CLI.argument('--remote', default='origin',
+              help='remote to fetch largefiles from (default: origin)')
+@CLI.argument('--force', action='store_true',
+              help='overwrite modified largefiles on checkout')
+@CLI.argument('action', choices=('fetch', 'checkout', 'update'),
+              help='fetch the largefiles used in the given revisions '
+                   '(default: HEAD), write the largefiles of HEAD in the '
+                   'working tree, or update the standins of the given '
+                   'largefiles (default: all those in HEAD) after they '
+                   'were modified')
+@CLI.argument('args', nargs='*', metavar='rev|path',
+              help='revisions to fetch largefiles for, or paths of '
+                   'largefiles to update')
+def largefiles(args):
+    '''handle the files of the mercurial largefiles extension'''
+
+    stored = stored_largefiles()
+    if args.action == 'fetch':
+        return largefiles_fetch(args, stored)
+    top = fsdecode(one(Git.iter('rev-parse', '--show-toplevel')))
+    if args.action == 'checkout':
+        return largefiles_checkout(args, stored, top)
+    return largefiles_update(args, stored, top)
@@ -0,0 +1,82 @@
+from __future__ import absolute_import, print_function, unicode_literals
+import logging
+import os
+from cinnabar.cmd.util import CLI
+from cinnabar.git import Git
+from cinnabar.hg.repo import Remote
+from cinnabar.lfs import (
+    fetch_lfs_objects,
+    lfs_endpoint,
+    lfs_pointers,
+)
+from cinnabar.util import (
+    fsdecode,
+    fsencode,
+    one,
+)
+
+ATTRIBUTES = b'filter=lfs diff=lfs merge=lfs -text'
+
+
+def attributes_pattern(path):
+    # This is how `git lfs track` escapes spaces.
+    return b'/' + path.replace(b' ', b'[[:space:]]')
+
+
+def update_attributes(paths):
+    '''Adds the given paths to the git attributes handled by git-lfs, such
+    that git-lfs replaces the pointers in the working tree.'''
+    common_dir = fsdecode(one(Git.iter('rev-parse', '--git-common-dir')))
+    attributes = os.path.join(common_dir, 'info', 'attributes')
+    lines = []
+    if os.path.exists(attributes):
+        with open(attributes, 'rb') as fh:
+            lines = fh.read().splitlines()
+    patterns = set(line.split(b' ', 1)[0] for line in lines)
+    added = [b'%s %s' % (p, ATTRIBUTES)
+             for p in sorted(set(attributes_pattern(p) for p in paths))
+             if p not in patterns]
+    if not added:
+        return
+    if not os.path.isdir(os.path.dirname(attributes)):
+        os.makedirs(os.path.dirname(attributes))
+    with open(attributes, 'wb') as fh:
+        fh.write(b''.join(b'%s\n' % line for line in lines + added))
+
+
+@CLI.subcommand
+@CLI.argument('--remote', default='origin',
+              help='remote to fetch lfs objects from (default: origin)')
+@CLI.argument('action', choices=('fetch',),
+              help='fetch the objects of the lfs files used in the given '
+                   'revisions (default: HEAD) into the git-lfs store')
+@CLI.argument('revs', nargs='*', metavar='rev',
+              help='revisions to fetch lfs objects for')
+def lfs(args):
+    '''handle the files of the mercurial lfs extension'''
+
+    remote = args.remote
+    url = Git.config('remote.%s.url' % remote) or fsencode(remote)
+    if url.startswith(b'hg::'):
+        url = url[4:]
+    remote = Remote(fsencode(remote), url)
+    pointers = {}
+    for rev in args.revs or ('HEAD',):
+        commit = Git.resolve_ref(fsencode(rev))
+        if not commit:
+            logging.error('Unknown revision: %s', rev)
+            return 1
+        pointers.update(lfs_pointers(commit))
+    if not pointers:
+        return 0
+    try:
+        missing = fetch_lfs_objects(lfs_endpoint(remote), pointers.values())
+    except Exception as e:
+        logging.error(str(e))
+        return 1
+    update_attributes(pointers)
+    if missing:
+        logging.error('%d lfs objects could not be fetched.', missing)
+        return 1
+    print('Run `git lfs checkout` to update the working tree.')
+    return 0
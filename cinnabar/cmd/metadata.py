@@ -0,0 +1,157 @@
+from __future__ import absolute_import, print_function, unicode_literals
+import logging
+import os
+import subprocess
+import cinnabar.util
+from cinnabar.cmd.bundle import BUNDLE_SIGNATURE
+from cinnabar.cmd.util import CLI
+from cinnabar.git import (
+    Git,
+    GitProcess,
+)
+from cinnabar.githg import (
+    adopt_shared_metadata,
+    GitHgStore,
+    shared_metadata,
+)
+from cinnabar.helper import GitHgHelper
+from cinnabar.util import (
+    fsdecode,
+    fsencode,
+    one,
+)
+
+
+def rewrite_metadata(metadata, previous=None):
+    '''Returns a metadata commit equivalent to the given one, but with the
+    given previous metadata, or without a reference to a previous metadata
+    if none is given.'''
+    commit = GitHgHelper.cat_file(b'commit', metadata)
+    header, body = commit.split(b'\n\n', 1)
+    lines = header.split(b'\n')
+    parents = [line for line in lines if line.startswith(b'parent ')]
+    if len(parents) > len(GitHgStore.METADATA_REFS):
+        if previous and parents[-1] == b'parent %s' % previous:
+            return metadata
+        lines.remove(parents.pop())
+    elif not previous:
+        return metadata
+    if previous:
+        lines.insert(lines.index(parents[-1]) + 1, b'parent %s' % previous)
+    proc = GitProcess('hash-object', '-t', 'commit', '-w', '--stdin',
+                      stdin=subprocess.PIPE)
+    proc.stdin.write(b'\n'.join(lines) + b'\n\n' + body)
+    proc.stdin.close()
+    sha1 = proc.stdout.read().strip()
+    proc.wait()
+    return sha1
+
+
+@CLI.subcommand
+@CLI.argument('path', help='path of the file to create')
+def export_metadata(args):
+    '''export the cinnabar metadata to a file'''
+
+    metadata = Git.resolve_ref('refs/cinnabar/metadata')
+    if not metadata:
+        logging.error('There is no cinnabar metadata to export.')
+        return 1
+    # The file is a git bundle of the metadata and the replace refs, like
+    # the ones `git cinnabar bundle --cinnabarclone` creates, which makes
+    # it usable as a cinnabarclone bundle as well. Only the current state
+    # is exported, not the history of the metadata.
+    refs = [(rewrite_metadata(metadata), b'refs/cinnabar/metadata')]
+    refs.extend(sorted(
+        (sha1, ref) for sha1, ref in Git.for_each_ref(
+            b'refs/cinnabar/replace/')))
+    with open(args.path, 'wb') as fh:
+        fh.write(BUNDLE_SIGNATURE)
+        for sha1, ref in refs:
+            fh.write(b'%s %s\n' % (sha1, ref))
+        fh.write(b'\n')
+        fh.flush()
+        proc = GitProcess('pack-objects', '--stdout', '--revs',
+                          '--progress' if cinnabar.util.progress else '-q',
+                          stdin=[sha1 for sha1, _ in refs], stdout=fh)
+        ret = proc.wait()
+    if ret:
+        logging.error('Failed to export cinnabar metadata.')
+        os.unlink(args.path)
+    return ret
+
+
+@CLI.subcommand
+@CLI.argument('path', help='path of a file created with export-metadata')
+def import_metadata(args):
+    '''import cinnabar metadata from a file'''
+
+    if Git.resolve_ref('refs/cinnabar/metadata'):
+        logging.error('There is already cinnabar metadata in this '
+                      'repository.')
+        return 1
+    store = GitHgStore()
+    if not store.merge(fsencode(os.path.abspath(args.path)), b'',
+                       b'refs/cinnabar/metadata'):
+        return 1
+    store.close()
+    return 0
+
+
+def objects_dir():
+    '''Returns the absolute path of the objects directory of the current
+    repository.'''
+    common_dir = one(Git.iter('rev-parse', '--git-common-dir'))
+    return os.path.abspath(os.path.join(common_dir, b'objects'))
+
+
+def repository_git_dir(path):
+    '''Returns the git directory of the repository at the given path,
+    which may be a work tree or a bare repository. Commands run on other
+    repositories use it with --git-dir, because -C wouldn't override the
+    GIT_DIR of the current one.'''
+    dot_git = os.path.join(path, b'.git')
+    return dot_git if os.path.isdir(dot_git) else path
+
+
+@CLI.subcommand
+@CLI.argument('path', help='path of the reference repository')
+def share(args):
+    '''use the cinnabar metadata from a reference repository'''
+
+    path = repository_git_dir(fsencode(os.path.abspath(args.path)))
+    if not shared_metadata(path):
+        logging.error('There is no cinnabar metadata in %s.', args.path)
+        return 1
+    if Git.resolve_ref('refs/cinnabar/metadata'):
+        logging.error('There is already cinnabar metadata in this '
+                      'repository.')
+        return 1
+    # Make the objects from the reference repository available here.
+    alternates = os.path.join(objects_dir(), b'info', b'alternates')
+    shared_objects = os.path.join(path, b'objects')
+    try:
+        with open(alternates, 'rb') as fh:
+            known = [os.path.abspath(l) for l in fh.read().splitlines()]
+    except (IOError, OSError):
+        known = []
+    if shared_objects not in known:
+        if not os.path.isdir(os.path.dirname(alternates)):
+            os.makedirs(os.path.dirname(alternates))
+        with open(alternates, 'ab') as fh:
+            fh.write(shared_objects + b'\n')
+    # Register this repository in the reference repository, so that its
+    # gc and rollback don't drop metadata generations used here.
+    git_dir = os.path.dirname(objects_dir())
+    if git_dir not in Git.iter('--git-dir', path, 'config', '--get-all',
+                               'cinnabar.shared-by'):
+        if GitProcess('--git-dir', path, 'config', '--add',
+                      'cinnabar.shared-by', git_dir).wait():
+            logging.error('Could not register this repository in %s.',
+                          args.path)
+            return 1
+    Git.run('config', 'cinnabar.shared-metadata', path)
+    adopt_shared_metadata()
+    print('Using the cinnabar metadata from %s.' % fsdecode(path))
+    print('Do not remove the reference repository, nor any of its objects '
+          'with e.g. `git gc --prune=now`: this repository depends on them.')
+    return 0
@@ -0,0 +1,26 @@
+from __future__ import absolute_import, print_function, unicode_literals
+from cinnabar.cmd.convert import sha1_value
+from cinnabar.cmd.util import CLI
+from cinnabar.obsolete import (
+    stored_markers,
+    successors,
+)
+from cinnabar.util import bytes_stdout
+
+
+@CLI.subcommand
+@CLI.argument('sha1', type=sha1_value, nargs='+',
+              help='mercurial sha1')
+def obsolete(args):
+    '''show whether mercurial changesets were superseded, according to the
+    obsolescence markers fetched from remotes'''
+
+    markers = stored_markers()
+    for sha1 in args.sha1:
+        succ = successors(markers, sha1)
+        if succ is None:
+            bytes_stdout.write(b'current\n')
+        elif not succ:
+            bytes_stdout.write(b'pruned\n')
+        else:
+            bytes_stdout.write(b'superseded %s\n' % b' '.join(succ))
@@ -0,0 +1,30 @@
+from __future__ import absolute_import, print_function, unicode_literals
+import logging
+from cinnabar.cmd.convert import sha1_value
+from cinnabar.cmd.util import CLI
+from cinnabar.githg import GitHgStore
+from cinnabar.phases import (
+    draft_commits,
+    stored_draft_roots,
+)
+from cinnabar.util import bytes_stdout
+
+
+@CLI.subcommand
+@CLI.argument('sha1', type=sha1_value, nargs='+',
+              help='mercurial sha1')
+def phase(args):
+    '''show the phase of mercurial changesets, as last seen on remotes'''
+
+    store = GitHgStore()
+    commits = []
+    for sha1 in args.sha1:
+        commit = store.changeset_ref(sha1)
+        if not commit:
+            logging.error('Unknown changeset: %s', sha1.decode('ascii'))
+            return 1
+        commits.append(commit)
+    drafts = draft_commits(store, commits, stored_draft_roots())
+    for commit in commits:
+        bytes_stdout.write(b'draft\n' if commit in drafts else b'public\n')
+    store.close()
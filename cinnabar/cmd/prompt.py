@@ -0,0 +1,66 @@
+from __future__ import absolute_import, print_function, unicode_literals
+import os
+from cinnabar.cmd.util import CLI
+from cinnabar.git import Git
+from cinnabar.util import (
+    bytes_stdout,
+    one,
+)
+
+
+def git2hg_note(metadata, commit, stderr=None):
+    '''Returns the first line of the git2hg note for the given commit, read
+    directly from the notes tree of the given metadata commit. Going through
+    the helper would load the whole notes tree.'''
+    # Notes are stored in directories named after the first bytes of the
+    # annotated commit sha1, as many levels deep as the notes tree needs.
+    paths = (b'/'.join([commit[i:i + 2] for i in range(0, n, 2)] +
+                       [commit[n:]])
+             for n in range(0, 8, 2))
+    # The git2hg notes are the fourth parent of the metadata commit.
+    lines = Git.iter('cat-file', '--batch', stderr=stderr,
+                     stdin=(b'%s^4:%s' % (metadata, p) for p in paths))
+    for line in lines:
+        if not line.endswith(b' missing'):
+            return next(lines, None)
+
+
+@CLI.subcommand
+@CLI.argument('--abbrev', type=int, default=12, metavar='N',
+              help='length of the mercurial changeset prefix')
+def prompt_info(args):
+    '''print compact cinnabar state for shell prompts'''
+
+    # This is meant to be called from shell prompts, so it needs to be
+    # fast: no network access, and no helper, which would load the cinnabar
+    # metadata.
+    with open(os.devnull, 'wb') as devnull:
+        refs = dict((ref, sha1) for sha1, ref in Git.for_each_ref(
+            'refs/cinnabar/metadata', 'refs/cinnabar/broken'))
+        metadata = refs.get(b'refs/cinnabar/metadata')
+        if not metadata:
+            return 0
+        head = one(Git.iter('rev-parse', '--verify', '-q', 'HEAD',
+                            stderr=devnull))
+        if not head:
+            return 0
+
+        info = []
+        data = git2hg_note(metadata, head, stderr=devnull)
+        if data and data.startswith(b'changeset '):
+            info.append(b'hg:%s' % data[10:10 + min(args.abbrev, 40)])
+        else:
+            info.append(b'hg:-')
+
+        # The first parent of the metadata commit has all the changeset heads
+        # as parents, so anything not reachable from it was not pushed.
+        unpushed = one(Git.iter('rev-list', '--count', head, '--not',
+                                b'%s^1' % metadata, stderr=devnull))
+        if unpushed and unpushed != b'0':
+            info.append(b'+%s' % unpushed)
+
+    if refs.get(b'refs/cinnabar/broken') == metadata:
+        info.append(b'!')
+
+    bytes_stdout.write(b' '.join(info) + b'\n')
+    return 0
@@ -0,0 +1,130 @@
+from __future__ import absolute_import, print_function, unicode_literals
+import logging
+import re
+from cinnabar.cmd.util import CLI
+from cinnabar.git import (
+    Git,
+    NULL_NODE_ID,
+)
+from cinnabar.githg import GitHgStore
+from cinnabar.helper import GitHgHelper
+from cinnabar.hg.repo import (
+    findcommon,
+    get_repo,
+    Remote,
+)
+from cinnabar.util import (
+    fsencode,
+    progress_iter,
+)
+
+
+SHA1_RE = re.compile(b'[0-9a-f]{40}$')
+
+
+def remote_unknown_commits(store, remote):
+    '''Returns the git commits for the changesets the given remote doesn't
+    know about, or None when it doesn't know about any.'''
+    url = Git.config('remote.%s.url' % remote) or fsencode(remote)
+    if url.startswith(b'hg::'):
+        url = url[4:]
+    repo = get_repo(Remote(fsencode(remote), url))
+    heads = store.heads()
+    common = findcommon(repo, store, heads)
+    if heads and not common:
+        return None
+    return [
+        c for c, _, _ in GitHgHelper.rev_list(
+            b'--full-history',
+            *[store.changeset_ref(h) for h in heads] +
+            [b'^%s' % store.changeset_ref(h) for h in common])
+    ]
+
+
+def prune_changesets(store, commits):
+    '''Removes the metadata for the changesets corresponding to the given
+    git commits, as well as for their descendants. Returns the set of git
+    commits that were pruned.'''
+    heads = [store.changeset_ref(h) for h in store.heads()]
+    pruned = set(commits)
+    pruned.update(c for c, _, _ in GitHgHelper.rev_list(
+        b'--ancestry-path', b'--full-history',
+        *heads + [b'^%s' % c for c in commits]))
+
+    new_heads = set()
+    for c, _, parents in progress_iter(
+            'Pruning {} changesets',
+            GitHgHelper.rev_list(b'--no-walk=unsorted', *pruned)):
+        new_heads.update(p for p in parents if p not in pruned)
+        node = store.hg_changeset(c)
+        # The git2hg metadata is removed through the hg2git metadata, so
+        # it needs to go first.
+        GitHgHelper.set(b'changeset-metadata', node, NULL_NODE_ID)
+        GitHgHelper.set(b'changeset', node, NULL_NODE_ID)
+        if node in store._hgheads:
+            del store._hgheads[node]
+
+    # Some of those may not actually be heads, but that is fixed by
+    # `git cinnabar fsck --full`.
+    for c in new_heads:
+        store.add_head(store.hg_changeset(c))
+
+    return pruned
+
+
+@CLI.subcommand
+@CLI.argument('--remote', metavar='REMOTE',
+              help='prune the changesets the given mercurial remote, name '
+                   'or url, doesn\'t have. Only use this when all the '
+                   'changesets in the repository come from that remote')
+@CLI.argument('rev', nargs='*',
+              help='mercurial changeset or git commit to prune')
+def prune(args):
+    '''remove metadata for changesets that were stripped from the remote'''
+
+    if not args.rev and not args.remote:
+        logging.error('At least one revision or --remote is required.')
+        return 1
+
+    store = GitHgStore()
+    commits = []
+    for rev in args.rev:
+        rev = fsencode(rev)
+        if not SHA1_RE.match(rev):
+            logging.error('Invalid sha1: %s', rev.decode('ascii', 'replace'))
+            return 1
+        if store.hg_changeset(rev):
+            commits.append(rev)
+            continue
+        commit = GitHgHelper.hg2git(rev)
+        if commit == NULL_NODE_ID:
+            logging.error('Unknown commit or changeset: %s',
+                          rev.decode('ascii'))
+            return 1
+        commits.append(commit)
+    if args.remote:
+        unknown = remote_unknown_commits(store, args.remote)
+        if unknown is None:
+            logging.error('The remote doesn\'t have any of the changesets '
+                          'in this repository. Not pruning anything.')
+            return 1
+        commits.extend(unknown)
+
+    if not commits:
+        print('Nothing to prune.')
+        return 0
+
+    pruned = prune_changesets(store, commits)
+
+    # Remote refs pointing to the pruned commits don't make sense anymore.
+    for sha1, ref in Git.for_each_ref('refs/remotes/'):
+        # Symbolic refs like refs/remotes/origin/HEAD are left alone.
+        if sha1 in pruned and not ref.endswith(b'/HEAD'):
+            print('Removing %s' % ref.decode('utf-8', 'replace'))
+            Git.delete_ref(ref)
+
+    store.close()
+    print('Pruned %d changeset%s. Please run `git cinnabar fsck --full` to '
+          'remove the\nmetadata for manifests and files that are not used '
+          'anymore.' % (len(pruned), '' if len(pruned) == 1 else 's'))
+    return 0
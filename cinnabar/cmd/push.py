@@ -0,0 +1,32 @@
+from __future__ import absolute_import, unicode_literals
+import sys
+from cinnabar.cmd.util import CLI
+from cinnabar.git import GitProcess
+
+
+@CLI.subcommand
+@CLI.argument('--stats', action='store_true',
+              help='display timings and statistics at the end')
+@CLI.argument('-n', '--dry-run', action='store_true',
+              help='convert the commits and report the changesets that '
+                   'would be pushed, without sending them')
+@CLI.argument('-f', '--force', action='store_true',
+              help='allow pushing new roots and new heads')
+@CLI.argument('remote', help='mercurial remote name or url')
+@CLI.argument('refspec', nargs='*',
+              help='refs to push (see git-push(1))')
+def push(args):
+    '''push to a mercurial remote'''
+
+    remote = args.remote
+    if '://' in remote and not remote.startswith(('hg::', 'hg://')):
+        remote = 'hg::' + remote
+    push_args = ['push']
+    if args.dry_run:
+        push_args.append('--dry-run')
+    if args.force:
+        push_args.append('--force')
+    config = {'cinnabar.showstats': 'true'} if args.stats else {}
+    proc = GitProcess(*(push_args + ['--', remote] + args.refspec),
+                      stdout=sys.stdout, config=config)
+    return proc.wait()
@@ -1,13 +1,145 @@
 from __future__ import absolute_import, print_function, unicode_literals
+import sys
+from cinnabar.api import (
+    git2hg,
+    hg2git,
+)
 from cinnabar.cmd.util import CLI
 from cinnabar.git import (
     Git,
+    GitProcess,
     NULL_NODE_ID,
 )
-from cinnabar.util import fsdecode
+from cinnabar.githg import GitCommit
+from cinnabar.helper import GitHgHelper
+from cinnabar.util import (
+    fsdecode,
+    one,
+)
+
+
+def local_refs():
+    '''Returns the {ref: (commit, base, hg changeset, local commits)} of the
+    local branches and tags, where base is the commit for the mercurial
+    changeset the local commits are based on, and local commits those that
+    are not mercurial changesets, from the oldest. The base and hg
+    changeset are None when the ref can't be moved to the new conversion,
+    and local commits None for annotated tags.
+    '''
+    result = {}
+    commits = {}
+    for line in Git.iter('for-each-ref', '--format',
+                         '%(objectname) %(objecttype) %(refname)',
+                         'refs/heads', 'refs/tags'):
+        sha1, typ, ref = line.split(b' ', 2)
+        if typ != b'commit':
+            # Annotated tags can't be moved without being recreated.
+            result[ref] = (sha1, None, None, None)
+        else:
+            commits[ref] = sha1
+    if not commits:
+        return result
+
+    # Walk all the commits that are not mercurial changesets at once. The
+    # boundary commits are the changesets they are based on.
+    parents = {}
+    order = []
+    args = ['rev-list', '--topo-order', '--parents', '--boundary']
+    args.extend(set(commits.values()))
+    args.extend(('--not', 'refs/cinnabar/metadata^'))
+    for line in Git.iter(*args):
+        if line.startswith(b'-'):
+            continue
+        line = line.split()
+        parents[line[0]] = line[1:]
+        order.append(line[0])
+
+    for ref, commit in commits.items():
+        local = set()
+        bases = set()
+        stack = [commit]
+        while stack:
+            c = stack.pop()
+            if c in local:
+                continue
+            if c not in parents:
+                bases.add(c)
+                continue
+            local.add(c)
+            stack.extend(parents[c])
+        # Only local commits on top of a single changeset can be moved.
+        base = bases.pop() if len(bases) == 1 else None
+        node = git2hg(base) if base else None
+        if not node:
+            base = None
+        result[ref] = (commit, base, node,
+                       tuple(c for c in reversed(order) if c in local))
+    return result
+
+
+def rewrite_commit(commit, parents):
+    '''Creates a copy of the given commit with different parents. Any
+    signature is dropped, since it wouldn't be valid for the new commit.'''
+    raw = GitHgHelper.cat_file(b'commit', commit)
+    header, body = raw.split(b'\n\n', 1)
+    lines = []
+    in_signature = False
+    for line in header.split(b'\n'):
+        if in_signature and line.startswith(b' '):
+            continue
+        in_signature = line.startswith(b'gpgsig')
+        if in_signature or line.startswith(b'parent '):
+            continue
+        lines.append(line)
+        if line.startswith(b'tree '):
+            lines.extend(b'parent %s' % p for p in parents)
+    data = b'\n'.join(lines) + b'\n\n' + body
+    return one(Git.iter('hash-object', '-t', 'commit', '-w', '--stdin',
+                        stdin=data))
+
+
+def update_ref(ref, new, old):
+    '''Updates the given ref. Returns an error message when it failed.'''
+    if GitProcess('update-ref', ref, new, old, stdout=sys.stdout).wait():
+        return 'updating the ref failed'
+    return None
+
+
+def move_ref(ref, commit, old_base, new_base, local):
+    '''Moves the given ref, pointing to the given commit, from the old
+    conversion to the new one. Returns an error message when it couldn't.'''
+    if not local:
+        return update_ref(ref, new_base, commit)
+    if GitCommit(old_base).tree == GitCommit(new_base).tree:
+        # The new conversion has the same contents, so the local commits
+        # can be copied with their parents replaced, without going through
+        # the work tree.
+        mapping = {old_base: new_base}
+        for c in local:
+            mapping[c] = rewrite_commit(c, tuple(
+                mapping.get(p, p) for p in GitCommit(c).parents))
+        return update_ref(ref, mapping[local[-1]], commit)
+    if not ref.startswith(b'refs/heads/'):
+        return 'the new conversion has different contents'
+    if tuple(Git.iter('status', '--porcelain', '--untracked-files=no')):
+        return 'the work tree has local changes, and the branch needs a rebase'
+    head = one(Git.iter('symbolic-ref', '-q', 'HEAD'))
+    branch = ref[len(b'refs/heads/'):]
+    if GitProcess('rebase', '-q', '--onto', new_base, old_base, branch,
+                  stdout=sys.stdout).wait():
+        return 'rebasing failed. Finish the rebase in progress, or abort it'
+    if head and head != ref and \
+            GitProcess('checkout', '-q', head[len(b'refs/heads/'):],
+                       stdout=sys.stdout).wait():
+        return 'it was rebased, but checking out %s again failed' % (
+            fsdecode(head[len(b'refs/heads/'):]))
+    return None
 
 
 @CLI.subcommand
+@CLI.argument('--rebase-local', action='store_true',
+              help='move local branches and tags to the new conversion, '
+                   'rebasing local commits as necessary')
 def reclone(args):
     '''reclone all mercurial remotes'''
 
@@ -17,6 +149,9 @@ def reclone(args):
     if metadata_commit:
         git_config['cinnabar.previous-metadata'] = \
             metadata_commit.decode('ascii')
+    # The mercurial changesets local refs are based on need to be found
+    # with the current metadata.
+    refs = local_refs() if args.rebase_local and metadata_commit else {}
     # TODO: Avoid resetting at all, possibly leaving the repo with no metadata
     # if this is interrupted somehow.
     do_rollback(NULL_NODE_ID.decode('ascii'))
@@ -30,5 +165,28 @@ def reclone(args):
                     config=git_config)
             git_config = {}
 
-    print('Please note that reclone left your local branches untouched.')
-    print('They may be based on entirely different commits.')
+    if not args.rebase_local:
+        print('Please note that reclone left your local branches untouched.')
+        print('They may be based on entirely different commits.')
+        return 0
+
+    retcode = 0
+    for ref, (commit, old_base, node, local) in sorted(refs.items()):
+        new_base = hg2git(node) if node else None
+        if new_base and new_base in (old_base, Git.resolve_ref(ref)):
+            continue
+        if local is None:
+            error = 'annotated tags can\'t be moved'
+        elif not node:
+            error = 'it is not based on a single mercurial changeset'
+        elif not new_base:
+            error = 'changeset %s is not in the new conversion' % (
+                node.decode('ascii'))
+        else:
+            error = move_ref(ref, commit, old_base, new_base, local)
+        if error:
+            print('Left %s untouched: %s.' % (fsdecode(ref), error))
+            retcode = 1
+        else:
+            print('Moved %s to the new conversion.' % fsdecode(ref))
+    return retcode
@@ -1,13 +1,31 @@
-from __future__ import absolute_import, unicode_literals
+from __future__ import absolute_import, print_function, unicode_literals
 import logging
+import time
+from cinnabar.api import (
+    git2hg,
+    hg2git,
+)
 from cinnabar.cmd.util import CLI
-from cinnabar.githg import GitCommit
+from cinnabar.githg import (
+    GitCommit,
+    METADATA_LOG_REF,
+    metadata_log,
+    SHARED_METADATA_REF,
+    sharers,
+)
 from cinnabar.git import (
     Git,
+    GitProcess,
     NULL_NODE_ID,
 )
 from cinnabar.helper import GitHgHelper
-from cinnabar.util import VersionedDict
+from cinnabar.util import (
+    fsdecode,
+    fsencode,
+    iteritems,
+    prompt,
+    VersionedDict,
+)
 
 
 def get_previous_metadata(metadata):
@@ -52,7 +70,9 @@ def do_rollback(ref):
     )
     for ref in refs:
         if sha1 == NULL_NODE_ID or ref not in (b'refs/cinnabar/checked',
-                                               b'refs/cinnabar/broken'):
+                                               b'refs/cinnabar/broken',
+                                               METADATA_LOG_REF,
+                                               SHARED_METADATA_REF):
             del refs[ref]
     if sha1 != NULL_NODE_ID:
         refs[b'refs/cinnabar/metadata'] = sha1
@@ -72,21 +92,145 @@ def do_rollback(ref):
     return 0
 
 
+def metadata_generations():
+    '''Returns the metadata generations, from the most recent.'''
+    generations = []
+    metadata = Git.resolve_ref('refs/cinnabar/metadata')
+    while metadata:
+        generations.append(metadata)
+        metadata = get_previous_metadata(metadata)
+    return generations
+
+
+def generations_in_use(generations):
+    '''Returns the paths of the repositories sharing the metadata of this
+    one that use one of the given metadata generations.'''
+    return sorted(path for path, metadata in iteritems(sharers())
+                  if metadata in generations)
+
+
+def changeset_heads(metadata):
+    changesets = GitCommit(metadata).parents[0]
+    if changesets == NULL_NODE_ID:
+        return set()
+    return set(GitCommit(changesets).parents)
+
+
+def list_generations(generations):
+    log = metadata_log()
+    checked = Git.resolve_ref('refs/cinnabar/checked')
+    for n, metadata in enumerate(generations):
+        timestamp, command = log.get(metadata, (None, None))
+        if timestamp:
+            date = time.strftime('%Y-%m-%d %H:%M:%S',
+                                 time.localtime(timestamp))
+        else:
+            date = 'unknown date'
+        line = '%d: %s %s %s' % (
+            n, metadata[:12].decode('ascii'), date,
+            fsdecode(command) if command else 'unknown command')
+        if n == 0:
+            line += ' (current)'
+        if metadata == checked:
+            line += ' (checked)'
+        print(line)
+        previous = generations[n + 1] if n + 1 < len(generations) else None
+        heads = changeset_heads(metadata)
+        if previous:
+            heads -= changeset_heads(previous)
+        if heads:
+            nodes = sorted(git2hg(h).decode('ascii')[:12] for h in heads)
+            print('    %d new head%s: %s%s' % (
+                len(nodes), 's' if len(nodes) > 1 else '',
+                ', '.join(nodes[:5]), ', ...' if len(nodes) > 5 else ''))
+
+
+def generation_before(generations, node):
+    '''Returns the most recent metadata generation that doesn't contain
+    the given changeset, or None if it is in all of them.'''
+    commit = hg2git(node)
+    for metadata in generations:
+        changesets = GitCommit(metadata).parents[0]
+        if changesets == NULL_NODE_ID or GitProcess(
+                'merge-base', '--is-ancestor', commit, changesets).wait():
+            return metadata
+    return None
+
+
 @CLI.subcommand
 @CLI.argument('--fsck', action='store_true',
               help='rollback to the last successful fsck state')
+@CLI.argument('--list', action='store_true',
+              help='list the metadata generations that can be rolled '
+                   'back to')
+@CLI.argument('-i', '--interactive', action='store_true',
+              help='choose the metadata generation to rollback to from a '
+                   'list')
+@CLI.argument('--to', metavar='CHANGESET',
+              help='rollback to the state before the given mercurial '
+                   'changeset was imported')
 @CLI.argument('committish', nargs='?',
               help='committish of the state to rollback to')
 def rollback(args):
     '''rollback cinnabar metadata state'''
-    if args.fsck and args.committish:
-        logging.error('Cannot use --fsck along a commit.')
+    if sum(bool(a) for a in (args.fsck, args.committish, args.list,
+                             args.interactive, args.to)) > 1:
+        logging.error('Only one of --fsck, --list, --interactive, --to or '
+                      'a commit can be given.')
         return 1
-    if args.fsck:
+    if args.list or args.interactive:
+        generations = metadata_generations()
+        if not generations:
+            logging.error('There is no cinnabar metadata.')
+            return 1
+        list_generations(generations)
+        if args.list:
+            return 0
+        if len(generations) == 1:
+            logging.error('There is no previous metadata generation.')
+            return 1
+        answer = prompt('Rollback to which generation? [1-%d, empty to '
+                        'cancel]' % (len(generations) - 1))
+        if not answer:
+            return 1
+        try:
+            n = int(answer)
+        except ValueError:
+            n = None
+        if n is None or not 1 <= n < len(generations):
+            logging.error('Invalid generation: %s', answer)
+            return 1
+        committish = generations[n]
+    elif args.to:
+        node = fsencode(args.to)
+        if not hg2git(node):
+            logging.error('Unknown changeset: %s', args.to)
+            return 1
+        committish = generation_before(metadata_generations(), node)
+        if not committish:
+            logging.error('Changeset %s was imported with the oldest '
+                          'metadata generation. Cannot rollback.', args.to)
+            return 1
+    elif args.fsck:
         committish = Git.resolve_ref('refs/cinnabar/checked')
         if not committish:
             logging.error('No successful fsck has been recorded. '
                           'Cannot rollback.')
     else:
         committish = args.committish
+    generations = metadata_generations()
+    if committish:
+        target = Git.resolve_ref(committish)
+    else:
+        target = generations[1] if len(generations) > 1 else NULL_NODE_ID
+    if target == NULL_NODE_ID or target in generations:
+        in_use = generations_in_use(
+            generations[:generations.index(target)]
+            if target in generations else generations)
+        if in_use:
+            logging.error('Cannot rollback: the metadata is used by %s, '
+                          'which share%s it.',
+                          ', '.join(fsdecode(p) for p in in_use),
+                          's' if len(in_use) == 1 else '')
+            return 1
     return do_rollback(committish)
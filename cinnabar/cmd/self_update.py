@@ -0,0 +1,166 @@
+from __future__ import absolute_import, print_function, unicode_literals
+import os
+import re
+import sys
+from cinnabar.cmd.util import CLI
+from cinnabar.git import (
+    Git,
+    GitProcess,
+)
+from cinnabar.util import (
+    Process,
+    fsdecode,
+    one,
+)
+
+RELEASE_TAG_RE = re.compile(br'^(\d+)\.(\d+)\.(\d+)$')
+
+
+def release_version(tag):
+    '''Returns a tuple for comparing the versions of release tags, or None
+    if the tag is not a release tag.'''
+    m = RELEASE_TAG_RE.match(tag)
+    if m:
+        return tuple(int(n) for n in m.groups())
+
+
+def update(args, install_dir, devnull):
+    '''Updates the git-cinnabar clone in the given directory. Returns an
+    exit code when there is nothing more to do, and None after updating.'''
+    # Run git on the git-cinnabar clone, not on the repository we were
+    # invoked from.
+    env = {'GIT_DIR': os.path.join(install_dir, '.git'),
+           'GIT_WORK_TREE': install_dir}
+
+    def git(*args, **kwargs):
+        return Git.iter(*args, cwd=install_dir, env=env, **kwargs)
+
+    def run(*args):
+        # Unlike Git.iter, this returns the exit status of the command.
+        return GitProcess(*args, cwd=install_dir, env=env,
+                          stdout=devnull).wait()
+
+    head = one(git('rev-parse', 'HEAD'))
+    tag = one(git('describe', '--tags', '--exact-match', 'HEAD',
+                  stderr=devnull))
+    branch = one(git('symbolic-ref', '-q', '--short', 'HEAD',
+                     stderr=devnull))
+
+    # Stay on the same channel as the current installation: the upstream of
+    # the current branch, or the latest release when on a release tag.
+    if branch:
+        upstream = one(git('rev-parse', '--abbrev-ref', '@{upstream}',
+                           stderr=devnull))
+        if not upstream:
+            print('The %s branch of the git-cinnabar clone has no upstream.'
+                  % fsdecode(branch), file=sys.stderr)
+            return 1
+        remote = upstream.split(b'/', 1)[0]
+        if run('fetch', '-q', fsdecode(remote)):
+            print('Could not fetch from %s.' % fsdecode(remote),
+                  file=sys.stderr)
+            return 1
+        target = one(git('rev-parse', '@{upstream}'))
+        if target == head or not tuple(
+                git('rev-list', '-1', head + b'..' + target)):
+            print('Already up to date (%s).' % fsdecode(branch))
+            return 0
+        if tuple(git('rev-list', '-1', target + b'..' + head)):
+            print('The %s branch of the git-cinnabar clone has diverged from '
+                  '%s.' % (fsdecode(branch), fsdecode(upstream)),
+                  file=sys.stderr)
+            return 1
+        description = '%s (%s)' % (fsdecode(upstream), fsdecode(target[:12]))
+        verify = 'verify-commit'
+        # All the commits the fast-forward brings in need to be signed,
+        # not only the new tip.
+        to_verify = tuple(git('rev-list', '--reverse',
+                              head + b'..' + target))
+    elif tag and release_version(tag):
+        if run('fetch', '-q', '--tags', 'origin'):
+            print('Could not fetch from origin.', file=sys.stderr)
+            return 1
+        tags = [t for t in git('tag', '--list') if release_version(t)]
+        latest = max(tags, key=release_version)
+        if release_version(latest) <= release_version(tag):
+            print('Already up to date (%s).' % fsdecode(tag))
+            return 0
+        target = latest
+        description = fsdecode(latest)
+        verify = 'verify-tag'
+        to_verify = (target,)
+    else:
+        print('The git-cinnabar clone is neither on a release tag nor on a '
+              'branch, and can\'t be updated.', file=sys.stderr)
+        return 1
+
+    if args.check:
+        print('Update available: %s' % description)
+        return 0
+
+    if args.verify_signature:
+        for obj in to_verify:
+            if Process('git', verify, obj, cwd=install_dir, env=env,
+                       stdout=devnull, stderr=devnull).wait():
+                print('Could not verify the signature of %s.'
+                      % (description if obj == target else fsdecode(obj)),
+                      file=sys.stderr)
+                return 1
+
+    if tuple(git('status', '--porcelain', '--untracked-files=no')):
+        print('The git-cinnabar clone has local changes. Not updating.',
+              file=sys.stderr)
+        return 1
+
+    print('Updating to %s...' % description)
+    if branch:
+        failed = run('merge', '-q', '--ff-only', fsdecode(target))
+    else:
+        failed = run('checkout', '-q', fsdecode(target))
+    if failed:
+        print('Could not update to %s.' % description, file=sys.stderr)
+        return 1
+
+
+@CLI.subcommand
+@CLI.argument('--check', action='store_true',
+              help='only report whether an update is available')
+@CLI.argument('--verify-signature', action='store_true',
+              help='refuse to update to a release tag without a valid gpg '
+                   'signature, or to a branch with any new commit without '
+                   'one')
+@CLI.argument('--no-helper', action='store_true',
+              help='do not download the helper matching the new version')
+def self_update(args):
+    '''update git-cinnabar'''
+
+    install_dir = os.path.dirname(os.path.abspath(sys.argv[0]))
+    if not os.path.exists(os.path.join(install_dir, '.git')):
+        print('git-cinnabar was not installed from a git clone, and can\'t '
+              'update itself. Please download a new release instead.',
+              file=sys.stderr)
+        return 1
+    with open(os.devnull, 'wb') as devnull:
+        retcode = update(args, install_dir, devnull)
+    if retcode is not None:
+        return retcode
+
+    if args.no_helper:
+        return 0
+
+    # The helper needs to match the new version of git-cinnabar, which the
+    # new git-cinnabar knows best.
+    git_cinnabar = os.path.join(install_dir, 'git-cinnabar')
+    if Process(sys.executable, git_cinnabar, 'download',
+               stdout=None).wait():
+        print('Could not download the helper for the new version. Please '
+              'build it with `make` in %s.' % install_dir, file=sys.stderr)
+        return 1
+    proc = Process(sys.executable, git_cinnabar, '--version=helper')
+    version = proc.stdout.read().strip()
+    proc.wait()
+    if version and b'/' in version:
+        print('The downloaded helper doesn\'t match the new version of '
+              'git-cinnabar (%s).' % fsdecode(version), file=sys.stderr)
+        return 1
+    return 0
@@ -0,0 +1,391 @@
+from __future__ import absolute_import, print_function, unicode_literals
+import logging
+import re
+import sys
+import zlib
+try:
+    from BaseHTTPServer import (
+        BaseHTTPRequestHandler,
+        HTTPServer,
+    )
+except ImportError:
+    from http.server import (
+        BaseHTTPRequestHandler,
+        HTTPServer,
+    )
+try:
+    from urllib.parse import (
+        parse_qs,
+        quote_from_bytes,
+        unquote_to_bytes,
+    )
+except ImportError:
+    from urllib import quote as quote_from_bytes
+    from urllib import unquote as unquote_to_bytes
+    from urlparse import parse_qs
+from cinnabar.cmd.util import CLI
+from cinnabar.exceptions import Abort
+from cinnabar.git import (
+    Git,
+    NULL_NODE_ID,
+)
+from cinnabar.helper import GitHgHelper
+from cinnabar.hg.bundle import (
+    create_bundle,
+    decodecaps,
+    encodecaps,
+    PushStore,
+)
+from cinnabar.util import (
+    bytes_stdin,
+    bytes_stdout,
+    experiment,
+    fsencode,
+    one,
+    progress_iter,
+)
+
+
+# Arguments of the wire protocol commands we support, as sent by clients.
+# `*` stands for a set of arbitrary arguments.
+COMMANDS = {
+    b'batch': b'cmds *',
+    b'between': b'pairs',
+    b'branchmap': b'',
+    b'capabilities': b'',
+    b'getbundle': b'*',
+    b'heads': b'',
+    b'hello': b'',
+    b'known': b'nodes *',
+    b'listkeys': b'namespace',
+    b'lookup': b'key',
+}
+
+CHANGEGROUP_VERSIONS = (b'01', b'02')
+
+NODE_RE = re.compile(b'^[0-9a-f]{40}$')
+
+
+def escape_batch_arg(arg):
+    return (arg.replace(b':', b':c').replace(b',', b':o')
+            .replace(b';', b':s').replace(b'=', b':e'))
+
+
+def unescape_batch_arg(arg):
+    return (arg.replace(b':e', b'=').replace(b':s', b';')
+            .replace(b':o', b',').replace(b':c', b':'))
+
+
+class HgServer(object):
+    '''Answers mercurial wire protocol commands from the cinnabar metadata.
+    Only what's necessary for clients to pull is supported. All the
+    changesets are served as public.'''
+    def __init__(self, store):
+        self._store = store
+        self._branchmap = store.branch_heads()
+        commits = dict((store.changeset_ref(h), h)
+                       for h in store.heads())
+        if commits:
+            self._heads = sorted(
+                commits[c] for c in Git.iter(
+                    'merge-base', '--independent', *commits))
+            # There is no revision order to take the last changeset from,
+            # like mercurial does, so use the most recent one.
+            self._tip = commits[one(Git.iter(
+                'rev-list', '--max-count=1', *commits))]
+        else:
+            self._heads = [NULL_NODE_ID]
+            self._tip = NULL_NODE_ID
+
+    def _commit(self, node):
+        '''Returns the git commit for the given mercurial changeset, or None
+        if it is unknown.'''
+        if not NODE_RE.match(node):
+            return None
+        commit = self._store.changeset_ref(node)
+        if commit and self._store.read_changeset_data(commit) is not None:
+            return commit
+
+    def capabilities(self):
+        bundle2caps = encodecaps({
+            b'HG20': (),
+            b'changegroup': CHANGEGROUP_VERSIONS,
+        })
+        return b' '.join((
+            b'batch',
+            b'branchmap',
+            b'bundle2=%s' % quote_from_bytes(bundle2caps).encode('ascii'),
+            b'getbundle',
+            b'known',
+            b'lookup',
+        ))
+
+    def __call__(self, command, args):
+        '''Returns the response to the given command, either as bytes or,
+        for getbundle, as an iterator of chunks.'''
+        handler = getattr(self, 'do_%s' % command.decode('ascii'), None)
+        if command not in COMMANDS or not handler:
+            raise Abort('Unsupported command: %s'
+                        % command.decode('ascii', 'replace'))
+        return handler(args)
+
+    def do_hello(self, args):
+        return b'capabilities: %s\n' % self.capabilities()
+
+    def do_capabilities(self, args):
+        return self.capabilities()
+
+    def do_between(self, args):
+        # Only used by clients as part of the ssh handshake, with a pair
+        # of null nodes.
+        pairs = args.get(b'pairs', b'').split()
+        return b'\n' * len(pairs)
+
+    def do_batch(self, args):
+        results = []
+        for op in args.get(b'cmds', b'').split(b';'):
+            command, _, op_args = op.partition(b' ')
+            if command == b'getbundle':
+                raise Abort('getbundle is not supported in batches')
+            op_args = dict(
+                (unescape_batch_arg(k), unescape_batch_arg(v))
+                for k, _, v in (a.partition(b'=')
+                                for a in op_args.split(b',') if a))
+            results.append(escape_batch_arg(self(command, op_args)))
+        return b';'.join(results)
+
+    def do_heads(self, args):
+        return b'%s\n' % b' '.join(self._heads)
+
+    def do_branchmap(self, args):
+        return b'\n'.join(
+            b'%s %s' % (quote_from_bytes(branch).encode('ascii'),
+                        b' '.join(heads))
+            for branch, heads in sorted(self._branchmap.items()))
+
+    def do_known(self, args):
+        return b''.join(
+            b'1' if self._commit(node) else b'0'
+            for node in args.get(b'nodes', b'').split())
+
+    def do_listkeys(self, args):
+        if args.get(b'namespace') == b'namespaces':
+            return b'bookmarks\t\nnamespaces\t\nphases\t'
+        if args.get(b'namespace') == b'phases':
+            return b'publishing\tTrue'
+        return b''
+
+    def do_lookup(self, args):
+        key = args.get(b'key', b'')
+        if key == b'tip':
+            return b'1 %s\n' % self._tip
+        if key in self._branchmap:
+            return b'1 %s\n' % self._branchmap[key][-1]
+        if self._commit(key):
+            return b'1 %s\n' % key
+        return b'0 unknown revision %s\n' % key
+
+    def do_getbundle(self, args):
+        heads = args.get(b'heads', b'').split()
+        common = args.get(b'common', b'').split()
+        bundlecaps = args.get(b'bundlecaps', b'').split(b',')
+        bundle2caps = {}
+        if b'HG20' in bundlecaps:
+            for cap in bundlecaps:
+                if cap.startswith(b'bundle2='):
+                    bundle2caps = decodecaps(unquote_to_bytes(cap[8:]))
+            versions = [v for v in bundle2caps.get(b'changegroup', ())
+                        if v in CHANGEGROUP_VERSIONS] or [b'01']
+            bundle2caps = {
+                b'HG20': (),
+                b'changegroup': versions,
+            }
+
+        revs = []
+        for head in heads or self._heads:
+            commit = self._commit(head)
+            if commit:
+                revs.append(commit)
+            elif head != NULL_NODE_ID:
+                raise Abort('Unknown changeset: %s'
+                            % head.decode('ascii', 'replace'))
+        for node in common:
+            commit = self._commit(node)
+            if commit:
+                revs.append(b'^%s' % commit)
+
+        commits = []
+        if any(not r.startswith(b'^') for r in revs):
+            commits = list((c, p) for c, _, p in GitHgHelper.rev_list(
+                b'--topo-order', b'--full-history', b'--parents',
+                b'--reverse', *revs))
+        return create_bundle(self._store, commits, bundle2caps)
+
+
+def read_args(fh, spec):
+    '''Reads the arguments of a command from a ssh protocol stream.'''
+    args = {}
+    keys = spec.split()
+    for _ in keys:
+        key, length = fh.readline().rstrip(b'\n').split(b' ', 1)
+        if key not in keys:
+            raise Abort('Unexpected argument: %s'
+                        % key.decode('ascii', 'replace'))
+        if key == b'*':
+            for _ in range(int(length)):
+                k, l = fh.readline().rstrip(b'\n').split(b' ', 1)
+                args[k] = fh.read(int(l))
+        else:
+            args[key] = fh.read(int(length))
+    return args
+
+
+def serve_stdio(server, stdin, stdout, stderr=None):
+    '''Serves the version 1 of the mercurial ssh protocol.'''
+    stderr = stderr or sys.stderr
+    while True:
+        command = stdin.readline()
+        if not command:
+            break
+        command = command.rstrip(b'\n')
+        if command not in COMMANDS:
+            # This is how mercurial itself replies to unknown commands,
+            # which clients use to probe for other versions of the
+            # protocol.
+            stdout.write(b'0\n')
+            stdout.flush()
+            continue
+        try:
+            result = server(command, read_args(stdin, COMMANDS[command]))
+        except Abort as e:
+            # Report the error the way mercurial does for out of band
+            # errors, which clients display before aborting.
+            stderr.write('abort: %s\n-\n' % e)
+            stderr.flush()
+            stdout.write(b'\n')
+            stdout.flush()
+            continue
+        if isinstance(result, bytes):
+            stdout.write(b'%d\n' % len(result))
+            stdout.write(result)
+        else:
+            for chunk in result:
+                stdout.write(chunk)
+        stdout.flush()
+
+
+class HgHTTPRequestHandler(BaseHTTPRequestHandler):
+    '''Serves the mercurial http protocol. Without the httpheader and
+    httppostargs capabilities, clients send all the command arguments in
+    the query string.'''
+    server_version = 'git-cinnabar'
+
+    def do_GET(self):
+        path = self.path
+        if not isinstance(path, bytes):
+            path = path.encode('latin-1')
+        query = dict((k, v[-1]) for k, v in parse_qs(
+            path.partition(b'?')[2], keep_blank_values=True).items())
+        command = query.pop(b'cmd', None)
+        if command not in COMMANDS:
+            self.send_error(400, 'Unsupported command')
+            return
+        try:
+            result = self.server.hg_server(command, query)
+        except Abort as e:
+            self.send_error(500, str(e))
+            return
+        self.send_response(200)
+        self.send_header('Content-Type', 'application/mercurial-0.1')
+        if isinstance(result, bytes):
+            self.send_header('Content-Length', str(len(result)))
+            self.end_headers()
+            self.wfile.write(result)
+            return
+        # Clients expect bundles to be zlib-compressed with this version
+        # of the protocol.
+        self.end_headers()
+        compressor = zlib.compressobj()
+        for chunk in result:
+            data = compressor.compress(chunk)
+            if data:
+                self.wfile.write(data)
+        self.wfile.write(compressor.flush())
+
+    do_POST = do_GET
+
+    def log_message(self, format, *args):
+        logging.getLogger('serve').info(format, *args)
+
+
+def create_metadata(store, revs):
+    '''Creates the mercurial metadata for the ancestors of the given git
+    revisions that don't have any, the same way pushing would, and returns
+    whether there were any.'''
+    revs = list(revs)
+    if not revs:
+        return False
+    revs.extend(b'^%s' % store.changeset_ref(h) for h in store.heads())
+    commits = list((c, p) for c, _, p in GitHgHelper.rev_list(
+        b'--topo-order', b'--full-history', b'--parents', b'--reverse',
+        *revs))
+    created = False
+    for commit, parents in progress_iter('Creating metadata for {} commits',
+                                         commits):
+        if len(parents) > 2:
+            raise Abort('Serving octopus merges is not supported')
+        store.create_hg_metadata(commit, parents)
+        changeset = store._changeset(commit, include_parents=True)
+        store.add_head(changeset.node, changeset.parent1, changeset.parent2)
+        created = True
+    return created
+
+
+@CLI.subcommand
+@CLI.argument('--stdio', action='store_true',
+              help='serve the mercurial ssh protocol on the standard input '
+                   'and output instead of listening for http connections')
+@CLI.argument('--address', default='localhost',
+              help='address to listen on (default: %(default)s)')
+@CLI.argument('--port', type=int, default=8000,
+              help='port to listen on (default: %(default)s)')
+@CLI.argument('rev', nargs='*',
+              help='git revisions to serve in addition to the changesets '
+                   'known to the metadata. Mercurial metadata is created for '
+                   'them as needed, like when pushing')
+def serve(args):
+    '''serve the repository to mercurial clients (experimental)'''
+
+    if not experiment('serve'):
+        logging.error('Serving is experimental. Add "serve" to the '
+                      'cinnabar.experiments configuration to enable it.')
+        return 1
+
+    store = PushStore()
+    store.action = b'serve'
+    try:
+        created = create_metadata(store, (fsencode(r) for r in args.rev))
+        server = HgServer(store)
+        if args.stdio:
+            serve_stdio(server, bytes_stdin, bytes_stdout)
+        else:
+            httpd = HTTPServer((args.address, args.port),
+                               HgHTTPRequestHandler)
+            httpd.hg_server = server
+            print('Listening on http://%s:%d/' % (args.address, args.port),
+                  file=sys.stderr)
+            # Requests are served one at a time, because there is only one
+            # helper process.
+            try:
+                httpd.serve_forever()
+            except KeyboardInterrupt:
+                pass
+            finally:
+                httpd.server_close()
+    except Exception:
+        store.close(rollback=True)
+        raise
+    # Keep the metadata that was created, so that the changesets keep the
+    # same nodes the next time they are served.
+    store.close(rollback=not created)
+    return 0
@@ -0,0 +1,131 @@
+from __future__ import absolute_import, print_function, unicode_literals
+import os
+from cinnabar.cmd.rollback import get_previous_metadata
+from cinnabar.cmd.util import (
+    CLI,
+    Version,
+)
+from cinnabar.git import Git
+from cinnabar.githg import (
+    GitCommit,
+    GitHgStore,
+)
+from cinnabar.helper import GitHgHelper
+from cinnabar.remotes import (
+    REMOTES_REF_PREFIX,
+    stored_remote_heads,
+)
+from cinnabar.util import (
+    format_size,
+    fsdecode,
+    one,
+    strip_suffix,
+)
+
+
+def metadata_generations(metadata):
+    count = 0
+    while metadata:
+        count += 1
+        metadata = get_previous_metadata(metadata)
+    return count
+
+
+def count_entries(metadata):
+    '''Returns the number of changesets, manifests and files tracked in the
+    given metadata.'''
+    changesets, _, hg2git = GitCommit(metadata).parents[:3]
+    # The hg2git tree maps changesets and manifests to commits, and files
+    # to blobs.
+    commits = blobs = 0
+    for mode, typ, sha1, path in Git.ls_tree(hg2git, recursive=True):
+        if typ == b'commit':
+            commits += 1
+        elif typ == b'blob':
+            blobs += 1
+    # The changesets metadata commit has all the changeset heads as parents.
+    changesets = int(one(Git.iter('rev-list', '--count',
+                                  b'%s^@' % changesets)) or 0)
+    return changesets, commits - changesets, blobs
+
+
+def metadata_size(metadata):
+    '''Returns the disk usage of the objects only reachable from the
+    metadata, or None if git is too old to tell.'''
+    size = one(Git.iter('rev-list', '--objects', '--disk-usage', metadata,
+                        '--not', b'%s^1^@' % metadata,
+                        stderr=open(os.devnull, 'wb')))
+    return int(size) if size else None
+
+
+@CLI.subcommand
+@CLI.argument('--quick', action='store_true',
+              help='skip the statistics that require going through the '
+                   'whole metadata')
+def status(args):
+    '''show the state of the mercurial metadata'''
+
+    refs = dict((ref, sha1) for sha1, ref in Git.for_each_ref(
+        'refs/cinnabar/metadata', 'refs/cinnabar/checked',
+        'refs/cinnabar/broken'))
+
+    version, expected = Version.helper_version()
+    print('git-cinnabar:', Version.cinnabar_version())
+    if version == 'unknown':
+        print('helper: not found')
+    else:
+        helper = version
+        if version != expected:
+            helper += ' (outdated, expected %s)' % expected
+        if GitHgHelper._version < GitHgHelper.VERSION:
+            helper += ' (protocol %d, expected %d)' % (
+                GitHgHelper._version, GitHgHelper.VERSION)
+        print('helper:', helper)
+
+    metadata = refs.get(b'refs/cinnabar/metadata')
+    if not metadata:
+        print('metadata: none')
+        return 0
+
+    print('metadata:', metadata.decode('ascii'))
+    flags = set(GitCommit(metadata).body.split())
+    features = flags - set(GitHgStore.OPTIONAL_FLAGS)
+    if features == set(GitHgStore.FLAGS):
+        compat = 'current'
+    elif len(features) > len(GitHgStore.FLAGS):
+        compat = 'newer than this version of git-cinnabar supports'
+    else:
+        compat = 'needs `git cinnabar upgrade`'
+    print('metadata format:', ' '.join(sorted(
+        f.decode('ascii') for f in flags)), '(%s)' % compat)
+    print('metadata generations:', metadata_generations(metadata))
+
+    if refs.get(b'refs/cinnabar/broken') == metadata:
+        fsck = 'broken, see `git cinnabar fsck`'
+    elif refs.get(b'refs/cinnabar/checked') == metadata:
+        fsck = 'checked'
+    elif refs.get(b'refs/cinnabar/broken'):
+        fsck = 'not checked since it last reported problems'
+    elif refs.get(b'refs/cinnabar/checked'):
+        fsck = 'changed since last checked'
+    else:
+        fsck = 'never checked'
+    print('fsck:', fsck)
+
+    if compat == 'current' and not args.quick:
+        changesets, manifests, files = count_entries(metadata)
+        print('changesets:', changesets)
+        print('manifests:', manifests)
+        print('files:', files)
+        size = metadata_size(metadata)
+        print('metadata size:', format_size(size) if size is not None
+              else 'unknown (requires git 2.31 or newer)')
+
+    for _, ref in sorted(Git.for_each_ref(REMOTES_REF_PREFIX),
+                         key=lambda x: x[1]):
+        name = strip_suffix(ref[len(REMOTES_REF_PREFIX):], b'/heads')
+        if name == ref[len(REMOTES_REF_PREFIX):]:
+            continue
+        print('remote %s: %d heads last synced' % (
+            fsdecode(name), len(stored_remote_heads(name))))
+    return 0
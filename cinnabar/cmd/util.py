@@ -4,6 +4,7 @@ import os
 import sys
 from cinnabar.helper import helper_hash, tree_hash
 from cinnabar import VERSION
+import cinnabar.util
 
 
 class CLI(object):
@@ -21,7 +22,8 @@ class CLI(object):
 
     @staticmethod
     def subcommand(func):
-        subparser = CLI.subparsers.add_parser(func.__name__, help=func.__doc__)
+        subparser = CLI.subparsers.add_parser(
+            func.__name__.replace('_', '-'), help=func.__doc__)
         if hasattr(func, 'cli_arguments'):
             # Because argparse.REMAINDER can't be used as first argument
             # without making flags emit a "unrecognized argument" error,
@@ -39,8 +41,16 @@ class CLI(object):
     @staticmethod
     def prepare(argv):
         CLI.parser.add_argument('--version', action=Version)
+        CLI.parser.add_argument(
+            '--progress', action='store_true', default=None,
+            help='force progress reporting even if stderr is not a terminal')
+        CLI.parser.add_argument(
+            '--no-progress', action='store_false', dest='progress',
+            help='disable progress reporting')
 
         args, leftovers = CLI.parser.parse_known_args(argv)
+        if args.progress is not None:
+            cinnabar.util.progress = args.progress
 
         if not hasattr(args, 'callback'):
             CLI.parser.print_help()
@@ -0,0 +1,137 @@
+'''Registry of the git-cinnabar configuration options.
+
+All options are read with `Git.config`, which resolves them, in order of
+precedence, from:
+- the GIT_CINNABAR_* environment variable (e.g. GIT_CINNABAR_EXPERIMENTS for
+  cinnabar.experiments),
+- the remote.<name>.cinnabar-* git configuration, for options that can be
+  set per remote (e.g. remote.origin.cinnabar-graft for cinnabar.graft),
+- the cinnabar.* git configuration.
+
+New options need to be added here for `git cinnabar config` to know about
+them.'''
+from __future__ import absolute_import, unicode_literals
+from collections import OrderedDict
+
+
+class Option(object):
+    __slots__ = ('per_remote', 'help')
+
+    def __init__(self, help, per_remote=False):
+        self.help = help
+        self.per_remote = per_remote
+
+
+OPTIONS = OrderedDict((
+    ('cinnabar.auth-command', Option(
+        'command printing credentials for mercurial servers',
+        per_remote=True)),
+    ('cinnabar.authors-file', Option(
+        'file mapping mercurial authors to git authors')),
+    ('cinnabar.auto-publish', Option(
+        'what to do when a push publishes draft changesets '
+        '(warn, abort, publish)', per_remote=True)),
+    ('cinnabar.auto-tags', Option(
+        'whether to create git tags from .hgtags', per_remote=True)),
+    ('cinnabar.branch-map', Option(
+        'file mapping mercurial branch names to git ref names')),
+    ('cinnabar.buffer-limit', Option(
+        'memory the helper can use to buffer data')),
+    ('cinnabar.cachedir', Option(
+        'directory for persistent caches')),
+    ('cinnabar.check', Option(
+        'comma-separated list of consistency checks to enable')),
+    ('cinnabar.clone', Option(
+        'url of a cinnabarclone manifest or bundle', per_remote=True)),
+    ('cinnabar.clone-checkpoint', Option(
+        'number of changesets between checkpoints on initial clones')),
+    ('cinnabar.clonebundle', Option(
+        'url of a mercurial clone bundle to use', per_remote=True)),
+    ('cinnabar.clonebundle-prefers', Option(
+        'preferred attributes when choosing a clone bundle', per_remote=True)),
+    ('cinnabar.collapse-branches', Option(
+        'whether to only expose one head per mercurial branch',
+        per_remote=True)),
+    ('cinnabar.commit-graph', Option(
+        'whether to maintain a commit-graph for the metadata')),
+    ('cinnabar.data', Option(
+        'when to store mercurial data (never, always, phase)',
+        per_remote=True)),
+    ('cinnabar.experiments', Option(
+        'comma-separated list of experimental features to enable')),
+    ('cinnabar.fetch', Option(
+        'mercurial changesets to fetch explicitly')),
+    ('cinnabar.filters', Option(
+        'content filters applied to files')),
+    ('cinnabar.fsck-after-fetch', Option(
+        'whether to check the metadata after fetches', per_remote=True)),
+    ('cinnabar.graft', Option(
+        'whether to graft mercurial changesets on existing git commits '
+        '(true, fuzzy, patch-id)', per_remote=True)),
+    ('cinnabar.graft-refs', Option(
+        'refs to consider for grafting')),
+    ('cinnabar.helper', Option(
+        'path to the native helper')),
+    ('cinnabar.hide-closed', Option(
+        'whether to hide closed branch heads', per_remote=True)),
+    ('cinnabar.largefiles-url', Option(
+        'url of the largefiles store', per_remote=True)),
+    ('cinnabar.lfs-url', Option(
+        'url of the lfs store', per_remote=True)),
+    ('cinnabar.log', Option(
+        'logging configuration')),
+    ('cinnabar.maxbandwidth', Option(
+        'maximum download rate, in bytes per second')),
+    ('cinnabar.memory-limit', Option(
+        'hard limit on the memory the conversion can use')),
+    ('cinnabar.metadata-retention', Option(
+        'number of previous metadata generations to keep')),
+    ('cinnabar.narrow-exclude', Option(
+        'directories to exclude from narrow clones')),
+    ('cinnabar.narrow-include', Option(
+        'directories to include in narrow clones')),
+    ('cinnabar.optimize-on-clone', Option(
+        'whether to optimize the metadata after initial clones',
+        per_remote=True)),
+    ('cinnabar.push.allow-new-branch', Option(
+        'what to do when a push creates a new branch (allow, ask, deny)',
+        per_remote=True)),
+    ('cinnabar.push.allow-new-head', Option(
+        'what to do when a push creates a new head (allow, ask, deny)',
+        per_remote=True)),
+    ('cinnabar.pushrefs', Option(
+        'refs style to use when pushing', per_remote=True)),
+    ('cinnabar.pushvars', Option(
+        'variables to send with pushes', per_remote=True)),
+    ('cinnabar.refs', Option(
+        'refs style to expose mercurial heads as', per_remote=True)),
+    ('cinnabar.shared-by', Option(
+        'paths of the repositories sharing the metadata of this one')),
+    ('cinnabar.shared-metadata', Option(
+        'path of a reference repository whose metadata to use as a base')),
+    ('cinnabar.showstats', Option(
+        'whether to display timings and statistics at the end of clones, '
+        'fetches and pushes')),
+    ('cinnabar.ssh', Option(
+        'ssh command to use for mercurial servers', per_remote=True)),
+    ('cinnabar.ssh-timeout', Option(
+        'timeout for ssh connections', per_remote=True)),
+    ('cinnabar.stream-clone', Option(
+        'whether to use stream clones', per_remote=True)),
+    ('cinnabar.subrepos', Option(
+        'how to handle mercurial subrepositories')),
+    ('cinnabar.tag-branch', Option(
+        'branch to commit .hgtags changes to', per_remote=True)),
+    ('cinnabar.tag-message', Option(
+        'commit message template for tags')),
+    ('cinnabar.tmpdir', Option(
+        'directory for large temporary files')),
+))
+
+# Configurations git-cinnabar sets for its own use, that are not meant to be
+# set by users.
+INTERNAL = (
+    'cinnabar.fsck',
+    'cinnabar.previous-metadata',
+    'cinnabar.version-check',
+)
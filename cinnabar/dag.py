@@ -75,6 +75,28 @@ class gitdag(object):
             for o in other.get(node, ()):
                 queue.append(o)
 
+    def sample(self, nodes, tag=None, children=False):
+        '''Yields, among the nodes with the given tag, those at distance 1,
+        2, 4, 8, etc. from the given nodes, following parents, or children
+        if `children` is True. This is the same kind of sampling as
+        mercurial's set discovery does.'''
+        other = self._children if children else self._parents
+        seen = set()
+        queue = deque((n, 1) for n in nodes)
+        factor = 1
+        while queue:
+            node, distance = queue.popleft()
+            if node in seen:
+                continue
+            seen.add(node)
+            if distance > factor:
+                factor *= 2
+            if distance == factor:
+                yield node
+            for o in other.get(node, ()):
+                if o in self._parents and self._tags.get(o) == tag:
+                    queue.append((o, distance + 1))
+
     def iternodes(self, tag=None):
         if tag is None:
             for n in self._parents:
@@ -9,6 +9,16 @@ class NoHelperAbort(Abort):
     """No helper has been found."""
 
 
+class PushRejectedAbort(Abort):
+    """The server rejected a push."""
+    def __init__(self, message):
+        super(PushRejectedAbort, self).__init__(
+            'The server rejected the push: %s\n'
+            'This typically happens when someone else pushed in the '
+            'meantime. Please pull and try again.' % message
+        )
+
+
 class HelperClosedError(RuntimeError):
     """Running a query with a closed helper."""
 
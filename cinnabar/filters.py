@@ -0,0 +1,177 @@
+from __future__ import absolute_import, unicode_literals
+import fnmatch
+import logging
+import subprocess
+from cinnabar.exceptions import Abort
+from cinnabar.git import (
+    EMPTY_TREE,
+    Git,
+    GitProcess,
+)
+from cinnabar.helper import GitHgHelper
+
+SIZE_UNITS = {
+    b'': 1,
+    b'k': 1024,
+    b'm': 1024 * 1024,
+    b'g': 1024 * 1024 * 1024,
+}
+
+PLACEHOLDER = (b'This file was replaced during the conversion from mercurial '
+               b'because it was\nlarger than %d bytes.\n'
+               b'Original size: %d bytes\n'
+               b'Original git blob: %s\n')
+
+
+def parse_size(value):
+    value = value.strip().lower()
+    unit = value[-1:] if value[-1:] in SIZE_UNITS else b''
+    number = value[:len(value) - len(unit)]
+    if not number.isdigit():
+        raise ValueError(value)
+    return int(number) * SIZE_UNITS[unit]
+
+
+class ContentFilter(object):
+    '''Rewrites the git trees of the commits created for imported changesets
+    according to a comma-separated list of rules:
+    - `exclude:<pattern>` drops the files matching the pattern. Patterns
+      without a slash match file names in any directory, others match full
+      paths.
+    - `max-size:<size>` replaces files larger than the given size, with an
+      optional k, m or g suffix, with a short placeholder.
+    - `crlf` converts CRLF line endings to LF in text files.
+    '''
+    def __init__(self, spec=b''):
+        self.exclude = []
+        self.max_size = None
+        self.crlf = False
+        for rule in spec.split(b','):
+            rule = rule.strip()
+            if not rule:
+                continue
+            name, _, value = rule.partition(b':')
+            try:
+                if name == b'exclude' and value:
+                    self.exclude.append(value)
+                elif name == b'max-size':
+                    self.max_size = parse_size(value)
+                elif name == b'crlf' and not value:
+                    self.crlf = True
+                else:
+                    raise ValueError(rule)
+            except ValueError:
+                raise Abort('Invalid content filter rule: %s'
+                            % rule.decode('utf-8', 'replace'))
+        self._blobs = {}
+        self._trees = {}
+        self._mktree = None
+
+    @property
+    def spec(self):
+        rules = [b'exclude:%s' % p for p in self.exclude]
+        if self.max_size is not None:
+            rules.append(b'max-size:%d' % self.max_size)
+        if self.crlf:
+            rules.append(b'crlf')
+        return b','.join(rules)
+
+    def __bool__(self):
+        return bool(self.spec)
+
+    __nonzero__ = __bool__
+
+    @classmethod
+    def for_store(cls, recorded, has_metadata):
+        '''Returns the content filter to use for a store, given the filter
+        spec recorded in its metadata, if any. Filters can only be set with
+        the cinnabar.filters configuration when cloning, after which the
+        recorded spec is always used.'''
+        config = Git.config('cinnabar.filters') or b''
+        if recorded is not None:
+            if config and cls(config).spec != recorded:
+                logging.warning(
+                    'Ignoring cinnabar.filters, which differs from the '
+                    'content filters used for the initial conversion: %s',
+                    recorded.decode('utf-8', 'replace'))
+            return cls(recorded)
+        result = cls(config)
+        if result and has_metadata:
+            raise Abort('Content filters can only be enabled when cloning.')
+        return result
+
+    def excluded(self, path):
+        name = path.rpartition(b'/')[2]
+        return any(fnmatch.fnmatchcase(path if b'/' in p else name, p)
+                   for p in self.exclude)
+
+    def filter_data(self, data, sha1):
+        '''Returns the filtered contents for a blob.'''
+        if self.max_size is not None and len(data) > self.max_size:
+            return PLACEHOLDER % (self.max_size, len(data), sha1)
+        if self.crlf and b'\0' not in data[:8000]:
+            return data.replace(b'\r\n', b'\n')
+        return data
+
+    def filter_blob(self, sha1):
+        result = self._blobs.get(sha1)
+        if result is None:
+            data = GitHgHelper.cat_file(b'blob', sha1)
+            filtered = self.filter_data(data, sha1)
+            if filtered == data:
+                result = sha1
+            else:
+                result = GitHgHelper.put_blob(filtered)
+            self._blobs[sha1] = result
+        return result
+
+    def filter_tree(self, sha1, path=b''):
+        '''Returns the filtered version of the given git tree.'''
+        # Without exclusion patterns, the result doesn't depend on the path.
+        key = (path, sha1) if self.exclude else sha1
+        result = self._trees.get(key)
+        if result is not None:
+            return result
+        entries = []
+        changed = False
+        for mode, typ, entry_sha1, name in GitHgHelper.ls_tree(sha1):
+            entry_path = b'%s/%s' % (path, name) if path else name
+            new_sha1 = entry_sha1
+            if typ == b'tree':
+                new_sha1 = self.filter_tree(entry_sha1, entry_path)
+                if new_sha1 == EMPTY_TREE:
+                    new_sha1 = None
+            elif typ == b'blob' and mode != b'120000':
+                if self.excluded(entry_path):
+                    new_sha1 = None
+                else:
+                    new_sha1 = self.filter_blob(entry_sha1)
+            if new_sha1 != entry_sha1:
+                changed = True
+            if new_sha1:
+                entries.append((mode, typ, new_sha1, name))
+        if not changed:
+            result = sha1
+        elif not entries:
+            result = EMPTY_TREE
+        else:
+            result = self._write_tree(entries)
+        self._trees[key] = result
+        return result
+
+    def _write_tree(self, entries):
+        if self._mktree is None:
+            # The blobs may only be in the pack being written by the
+            # helper, hence --missing.
+            self._mktree = GitProcess('mktree', '--missing', '-z', '--batch',
+                                      stdin=subprocess.PIPE)
+        for entry in entries:
+            self._mktree.stdin.write(b'%s %s %s\t%s\0' % entry)
+        self._mktree.stdin.write(b'\0')
+        self._mktree.stdin.flush()
+        return self._mktree.stdout.readline().strip()
+
+    def close(self):
+        if self._mktree:
+            self._mktree.wait()
+            self._mktree = None
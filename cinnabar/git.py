@@ -116,8 +116,10 @@ class Git(object):
         self.update_ref(ref, NULL_NODE_ID)
 
     @classmethod
-    def config(self, name, remote=None, values={}, multiple=False):
-        assert not (values and multiple)
+    def config_lookup(self, name, remote=None):
+        '''Returns the variable the given configuration is taken from, its
+        raw value, and whether it comes from the environment or the git
+        configuration.'''
         if self._config is None:
             proc = GitProcess('config', '-l', '-z')
             data = proc.stdout.read()
@@ -132,10 +134,13 @@ class Git(object):
                         self._config[k] = v
         var = name.encode('ascii')
         value = None
+        origin = 'config'
         if name.startswith('cinnabar.'):
             var = ('GIT_%s' % name.replace('.', '_').upper()).encode('ascii')
             value = environ(var)
-            if value is None and remote:
+            if value is not None:
+                origin = 'environment'
+            elif remote:
                 var = b'remote.%s.%s' % (
                     remote, name.replace('.', '-').encode('ascii'))
                 value = self._config.get(var.lower())
@@ -145,6 +150,12 @@ class Git(object):
         if value is None:
             var = name.encode('ascii')
             value = self._config.get(var.lower())
+        return var, value, origin
+
+    @classmethod
+    def config(self, name, remote=None, values={}, multiple=False):
+        assert not (values and multiple)
+        var, value, _ = self.config_lookup(name, remote)
         if value:
             value = value.split(b'\0')
             if not multiple:
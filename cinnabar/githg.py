@@ -9,6 +9,7 @@ import os
 import shutil
 import subprocess
 import sys
+import time
 try:
     from urllib.parse import quote_from_bytes, unquote_to_bytes
 except ImportError:
@@ -42,6 +43,7 @@ from .util import (
     Seekable,
     byte_diff,
     check_enabled,
+    memory_limit,
     one,
     VersionedDict,
 )
@@ -50,7 +52,9 @@ from .git import (
     EMPTY_TREE,
     Git,
     GitProcess,
+    InvalidConfig,
     NULL_NODE_ID,
+    split_ls_tree,
 )
 from .hg.changegroup import (
     RawRevChunk,
@@ -63,6 +67,12 @@ from .hg.objects import (
     Manifest,
 )
 from .helper import GitHgHelper
+from .authors import AuthorMap
+from .filters import ContentFilter
+from .subrepos import (
+    SubrepoConverter,
+    subrepos_mode,
+)
 from .util import progress_iter
 from cinnabar import util
 from cinnabar.util import fsdecode
@@ -148,6 +158,21 @@ class FileFindParents(object):
         return file.node == file.sha1
 
 
+def write_commit_graph(expire_time='1.hour.ago'):
+    '''Writes or refreshes a split commit-graph for all the commits
+    reachable from refs. Layers are merged when a new one is at least half
+    the size of the one below, and the files of the layers merged that way
+    are removed once they are older than `expire_time`, so that processes
+    still using them are not disrupted.'''
+    args = ['commit-graph', 'write', '--reachable', '--split',
+            '--size-multiple=2', '--expire-time=%s' % expire_time]
+    if not util.progress:
+        args.append('--no-progress')
+    # Our stdout may be the remote helper protocol stream.
+    with open(os.devnull, 'wb') as devnull:
+        return GitProcess(*args, stdout=devnull).wait()
+
+
 class ChangesetPatcher(bytes):
     class ChangesetPatch(RawRevChunk):
         __slots__ = ('patch', '_changeset')
@@ -210,6 +235,8 @@ class ChangesetPatcher(bytes):
                     changeset.extra.update(
                         (k, v) for k, v in extra.items()
                         if k not in changeset.extra)
+            elif k == b'extra-raw':
+                changeset.extra = v
             elif k == b'files':
                 changeset.files = v.split(b'\0')
             elif k == b'patch':
@@ -225,6 +252,10 @@ class ChangesetPatcher(bytes):
 
         return changeset
 
+    @property
+    def has_extra_raw(self):
+        return any(l.startswith(b'extra-raw ') for l in self.splitlines())
+
     @classmethod
     def from_diff(cls, changeset1, changeset2):
         items = []
@@ -234,7 +265,12 @@ class ChangesetPatcher(bytes):
             items.append(b'manifest %s' % changeset2.manifest)
         if changeset1.author != changeset2.author:
             items.append(b'author %s' % changeset2.author)
-        if changeset1.extra != changeset2.extra:
+        if changeset2.extra is not None and \
+                changeset2.extra.to_str() != changeset2.extra.normalized():
+            # Extras that can't be reproduced from their values (unsorted,
+            # duplicate keys, etc.) are stored verbatim.
+            items.append(b'extra-raw %s' % changeset2.extra.to_str())
+        elif changeset1.extra != changeset2.extra:
             if changeset2.extra is not None:
                 items.append(b'extra %s' % Changeset.ExtraData({
                     k: v
@@ -364,9 +400,12 @@ def autohexlify(h):
 
 
 class BranchMap(object):
-    __slots__ = "_heads", "_all_heads", "_tips", "_git_sha1s", "_unknown_heads"
+    __slots__ = ("_heads", "_all_heads", "_tips", "_git_sha1s",
+                 "_unknown_heads", "_store", "_closed")
 
     def __init__(self, store, remote_branchmap, remote_heads):
+        self._store = store
+        self._closed = {}
         self._heads = {}
         self._all_heads = tuple(autohexlify(h) for h in remote_heads)
         self._tips = {}
@@ -390,10 +429,8 @@ class BranchMap(object):
             if branch and heads and sequenced:
                 for head in reversed(branch_heads):
                     self._tips[branch] = head
-                    if head in self._git_sha1s:
-                        changeset = store.changeset(head)
-                        if changeset.close:
-                            continue
+                    if self.closed(head):
+                        continue
                     break
             if branch:
                 self._heads[branch] = tuple(branch_heads)
@@ -415,15 +452,68 @@ class BranchMap(object):
     def tip(self, branch):
         return self._tips.get(branch, None)
 
+    def closed(self, head):
+        if head not in self._git_sha1s:
+            return False
+        if head not in self._closed:
+            self._closed[head] = bool(self._store.changeset(head).close)
+        return self._closed[head]
+
+
+# Email addresses conversion tools use for mercurial authors without one.
+PLACEHOLDER_EMAILS = (b'', b'none@none', b'devnull@localhost', b'unknown')
+
+
+def same_date(authorship, changeset):
+    '''Returns whether the git authorship has the same date as the
+    changeset, allowing for the timezone mishandling of some conversion
+    tools, which shifts the timestamp by the UTC offset.'''
+    timestamp = int(changeset.timestamp)
+    utcoffset = int(changeset.utcoffset)
+    return authorship.timestamp in (
+        timestamp, timestamp + utcoffset, timestamp - utcoffset)
+
+
+def same_author(authorship, changeset):
+    '''Returns whether the git authorship has the same author as the
+    changeset, ignoring the differences in how conversion tools deal with
+    missing email addresses.'''
+    author = Authorship.from_hg(changeset.author, changeset.timestamp,
+                                changeset.utcoffset)
+    emails = (authorship.email.lower(), author.email.lower())
+    if not any(e in PLACEHOLDER_EMAILS for e in emails):
+        return emails[0] == emails[1]
+    return authorship.name.strip() == author.name.strip()
+
+
+def diff_id(rev1, rev2):
+    '''Returns an identifier for the changes between the given trees or
+    commits, such that commits making the same changes on top of different
+    trees get the same identifier. Renames and copies are detected from the
+    contents, so how they were recorded doesn't matter.'''
+    return tuple(sorted(
+        (status, path, mode_after, sha1_after)
+        for _, mode_after, _, sha1_after, status, path in
+        GitHgHelper.diff_tree(rev1, rev2, detect_copy=True)))
+
 
 class Grafter(object):
-    __slots__ = "_store", "_early_history", "_graft_trees", "_grafted"
+    __slots__ = ("_store", "_early_history", "_graft_trees", "_grafted",
+                 "_mode", "_children", "_trees", "_diff_ids", "_ungrafted")
 
-    def __init__(self, store):
+    def __init__(self, store, mode=True):
         self._store = store
         self._early_history = set()
         self._graft_trees = defaultdict(list)
         self._grafted = False
+        # True for exact matches, 'fuzzy' to allow for the differences
+        # introduced by other conversion tools, and 'patch-id' to also
+        # match commits making the same changes, when the trees differ.
+        self._mode = mode
+        self._children = defaultdict(list)
+        self._trees = {}
+        self._diff_ids = {}
+        self._ungrafted = []
         refs = [
             b'--exclude=refs/cinnabar/*',
             b'--exclude=refs/notes/cinnabar',
@@ -436,26 +526,43 @@ class Grafter(object):
                 'Reading {} graft candidates',
                 GitHgHelper.rev_list(b'--full-history', *refs)):
             self._graft_trees[tree].append(node)
+            if mode == 'patch-id':
+                self._trees[node] = tree, tuple(parents)
+                self._children[tuple(parents)].append(node)
 
     def _is_cinnabar_commit(self, commit):
         data = self._store.read_changeset_data(commit)
         return b'\npatch' not in data if data else False
 
+    def _take(self, node, tree):
+        '''Removes the given commit from the graft candidates.'''
+        self._graft_trees[tree].remove(node)
+        if node in self._trees:
+            self._children[self._trees[node][1]].remove(node)
+
     def _graft(self, changeset, parents):
         store = self._store
         tree = store.git_tree(changeset.manifest, *changeset.parents[:1])
         do_graft = tree and tree in self._graft_trees
         if not do_graft:
+            if self._mode == 'patch-id':
+                return self._graft_by_diff(changeset, parents)
             return None
 
         commits = {}
+        subject = changeset.body.split(b'\n', 1)[0]
 
         def graftable(c):
             commit = commits.get(c)
             if not commit:
                 commit = commits[c] = GitCommit(c)
-            if (Authorship.from_git_str(commit.author).timestamp !=
-                    int(changeset.timestamp)):
+            author = Authorship.from_git_str(commit.author)
+            if self._mode is True:
+                if author.timestamp != int(changeset.timestamp):
+                    return False
+            elif not same_date(author, changeset) and not (
+                    same_author(author, changeset) and
+                    commit.body.split(b'\n', 1)[0] == subject):
                 return False
 
             if all(store._replace.get(p1, p1) == store._replace.get(p2, p2)
@@ -473,7 +580,6 @@ class Grafter(object):
             # following is enough to graft github.com/mozilla/gecko-dev
             # to mozilla-central and related repositories.
             # Try with commits with the same subject line
-            subject = changeset.body.split(b'\n', 1)[0]
             possible_nodes = tuple(
                 n for n in nodes
                 if commits[n].body.split(b'\n', 1)[0] == subject
@@ -512,8 +618,55 @@ class Grafter(object):
 
         if nodes:
             node = nodes[0]
-            self._graft_trees[tree].remove(node)
+            self._take(node, tree)
             return commits[node]
+        if self._mode == 'patch-id':
+            return self._graft_by_diff(changeset, parents)
+        return None
+
+    def _graft_by_diff(self, changeset, parents):
+        '''Finds a commit with the given parents making the same changes as
+        the changeset, for when the trees don't match, e.g. because the other
+        conversion tool dropped or altered some files.'''
+        store = self._store
+        replaced = dict((v, k) for k, v in util.iteritems(store._replace))
+        candidates = set(self._children.get(tuple(parents), ()))
+        candidates.update(self._children.get(
+            tuple(replaced.get(p, p) for p in parents), ()))
+        if not candidates:
+            return None
+
+        if changeset.parent1 == NULL_NODE_ID:
+            parent_tree = EMPTY_TREE
+        else:
+            parent = store.changeset(changeset.parent1)
+            parent_tree = store.git_tree(parent.manifest,
+                                         *parent.parents[:1])
+        tree = store.git_tree(changeset.manifest, *changeset.parents[:1])
+        changes = diff_id(parent_tree, tree)
+
+        def commit_diff_id(node):
+            if node not in self._diff_ids:
+                commit = GitCommit(node)
+                self._diff_ids[node] = diff_id(
+                    commit.parents[0] if commit.parents else EMPTY_TREE,
+                    node)
+            return self._diff_ids[node]
+
+        nodes = [n for n in sorted(candidates) if commit_diff_id(n) == changes]
+        if len(nodes) > 1:
+            subject = changeset.body.split(b'\n', 1)[0]
+            nodes = [n for n in nodes
+                     if GitCommit(n).body.split(b'\n', 1)[0] == subject]
+        if len(nodes) > 1:
+            raise AmbiguousGraftAbort(
+                'Cannot graft changeset %s. Candidates: %s'
+                % (changeset.node.decode('ascii'),
+                   ', '.join(n.decode('ascii') for n in nodes)))
+        if nodes:
+            node = nodes[0]
+            self._take(node, self._trees[node][0])
+            return GitCommit(node)
         return None
 
     def graft(self, changeset):
@@ -529,6 +682,7 @@ class Grafter(object):
         else:
             is_early_history = not result
         if not (is_early_history or result):
+            self._ungrafted.append((changeset.node, changeset.parents))
             raise NothingToGraftException()
         if is_early_history or not result:
             commit = store.changeset_ref(changeset.node)
@@ -550,17 +704,179 @@ class Grafter(object):
         if result:
             self._grafted = True
 
+    def _report_ungrafted(self):
+        # Group the changesets that couldn't be grafted in ranges of
+        # changesets descending from each other.
+        ranges = []
+        for node, parents in self._ungrafted:
+            if ranges and ranges[-1][1] in parents:
+                ranges[-1][1] = node
+                ranges[-1][2] += 1
+            else:
+                ranges.append([node, node, 1])
+        message = ['Could not graft %d changeset%s:' % (
+            len(self._ungrafted), 's' if len(self._ungrafted) > 1 else '')]
+        for first, last, count in ranges[:20]:
+            if count == 1:
+                message.append('  %s' % first.decode('ascii'))
+            else:
+                message.append('  %s..%s (%d changesets)' % (
+                    first.decode('ascii'), last.decode('ascii'), count))
+        if len(ranges) > 20:
+            message.append('  and %d more ranges' % (len(ranges) - 20))
+        logging.warning('\n'.join(message))
+
     def close(self):
+        if self._ungrafted:
+            self._report_ungrafted()
         if not self._grafted and self._early_history:
             raise NothingToGraftException()
 
 
+# The metadata generations are recorded in a blob, with a line for each,
+# giving its sha1, the time it was created, and the command that created it.
+METADATA_LOG_REF = b'refs/cinnabar/metadata-log'
+METADATA_LOG_SIZE = 1000
+
+
+def metadata_log():
+    '''Returns a {metadata sha1: (timestamp, command)} dict for the
+    recorded metadata generations.'''
+    sha1 = Git.resolve_ref(METADATA_LOG_REF)
+    if not sha1:
+        return {}
+    result = {}
+    for line in Git.iter('cat-file', 'blob', sha1):
+        metadata, timestamp, command = line.split(b' ', 2)
+        result[metadata] = (int(timestamp), command)
+    return result
+
+
+def metadata_log_lines(log):
+    '''Returns the lines of the metadata log for the given
+    {metadata sha1: (timestamp, command)} dict, keeping the most recent
+    entries.'''
+    entries = sorted(util.iteritems(log), key=lambda x: x[1][0])
+    return [b'%s %d %s' % (metadata, timestamp, command.replace(b'\n', b' '))
+            for metadata, (timestamp, command)
+            in entries[-METADATA_LOG_SIZE:]]
+
+
+def write_metadata_log(log):
+    '''Stores the given {metadata sha1: (timestamp, command)} dict as the
+    metadata log, keeping the most recent entries.'''
+    data = b''.join(b'%s\n' % line for line in metadata_log_lines(log))
+    # Go through the helper, so that the log is only updated along the
+    # metadata itself.
+    Git.update_ref(METADATA_LOG_REF, GitHgHelper.put_blob(data))
+
+
+# With the cinnabar.shared-metadata configuration, the metadata from a
+# reference repository, whose objects are available through git alternates,
+# is used as a base. This ref records the last metadata adopted from there.
+SHARED_METADATA_REF = b'refs/cinnabar/shared'
+
+
+def shared_metadata(path):
+    '''Returns the metadata commit of the repository with the given git
+    directory.'''
+    return one(Git.iter('--git-dir', path, 'rev-parse', '--revs-only',
+                        'refs/cinnabar/metadata',
+                        stderr=open(os.devnull, 'wb')))
+
+
+def sharers():
+    '''Returns a {git dir: metadata} dict of the repositories registered in
+    the cinnabar.shared-by configuration as sharing this one's metadata,
+    with, for those that have metadata of their own, the metadata last
+    adopted from here.'''
+    result = {}
+    for path in Git.iter('config', '--get-all', 'cinnabar.shared-by'):
+        if not os.path.isdir(path):
+            continue
+        refs = dict((ref, sha1) for sha1, ref in (
+            l.split(b' ', 1) for l in Git.iter(
+                '--git-dir', path, 'for-each-ref', '--format',
+                '%(objectname) %(refname)', 'refs/cinnabar/metadata',
+                SHARED_METADATA_REF, stderr=open(os.devnull, 'wb'))))
+        adopted = refs.get(SHARED_METADATA_REF)
+        if adopted and refs.get(b'refs/cinnabar/metadata') != adopted:
+            result[path] = adopted
+    return result
+
+
+def adopt_shared_metadata():
+    '''Switches to the metadata of the reference repository given in the
+    cinnabar.shared-metadata configuration, unless the local metadata has
+    changesets of its own that the reference repository doesn't have.'''
+    path = Git.config('cinnabar.shared-metadata')
+    if not path:
+        return
+    shared = shared_metadata(path)
+    if not shared:
+        return
+    metadata = Git.resolve_ref('refs/cinnabar/metadata')
+    if metadata == shared:
+        return
+    if GitProcess('cat-file', '-e', shared).wait():
+        logging.warning('The metadata from %s is not available. Please '
+                        'check the objects/info/alternates file.',
+                        fsdecode(path))
+        return
+    adopted = Git.resolve_ref(SHARED_METADATA_REF)
+    if metadata and metadata != adopted and tuple(Git.iter(
+            'rev-list', '-1', b'%s^^@' % metadata, '--not',
+            b'%s^' % shared)):
+        logging.getLogger('shared').info(
+            'Local metadata diverged from %s', fsdecode(path))
+        return
+    # The store reads the refs when it is created, and the helper when it
+    # starts, so the refs are updated before either, in a single git
+    # transaction rather than in the helper's.
+    updates = [
+        b'update refs/cinnabar/metadata %s %s'
+        % (shared, metadata or NULL_NODE_ID),
+        b'update %s %s %s' % (SHARED_METADATA_REF, shared,
+                              adopted or NULL_NODE_ID),
+    ]
+    replace = dict((ref, sha1) for sha1, ref in Git.for_each_ref(
+        'refs/cinnabar/replace'))
+    for line in Git.iter('ls-tree', shared):
+        mode, typ, sha1, name = split_ls_tree(line)
+        ref = b'refs/cinnabar/replace/%s' % name
+        if replace.pop(ref, None) != sha1:
+            updates.append(b'update %s %s' % (ref, sha1))
+    updates.extend(b'delete %s %s' % (ref, sha1)
+                   for ref, sha1 in replace.items())
+    with open(os.devnull, 'wb') as devnull:
+        if GitProcess('update-ref', '--stdin', stdin=updates,
+                      stdout=devnull).wait():
+            logging.warning('Could not update the metadata from %s.',
+                            fsdecode(path))
+
+
 class GitHgStore(object):
     FLAGS = [
         b'files-meta',
         b'unified-manifests-v2',
     ]
 
+    # Set when the conversion was done with content filters, which makes
+    # the git trees not match the mercurial manifests.
+    FILTERED_FLAG = b'filtered'
+
+    # Set when some changeset metadata uses the `extra-raw` key, which older
+    # versions would silently ignore, creating changesets with the wrong
+    # extras. A `patch` could reproduce the same changesets, but it is what
+    # tells grafting that a changeset was not created by git-cinnabar, and
+    # it is a diff of the whole changeset, instead of something readable
+    # from the extras.
+    EXTRA_RAW_FLAG = b'extra-raw'
+
+    # Flags that are only set when necessary. Older versions don't know
+    # about them, and refuse the metadata when they are set.
+    OPTIONAL_FLAGS = (FILTERED_FLAG, EXTRA_RAW_FLAG)
+
     METADATA_REFS = (
         b'refs/cinnabar/changesets',
         b'refs/cinnabar/manifests',
@@ -582,21 +898,33 @@ class GitHgStore(object):
     def metadata(self):
         metadata = self._metadata()
         if metadata:
-            if len(self._flags) > len(self.FLAGS):
+            flags = self._flags - set(self.OPTIONAL_FLAGS)
+            if len(flags) > len(self.FLAGS):
                 raise UpgradeAbort(
                     'It looks like this repository was used with a newer '
                     'version of git-cinnabar. Cannot use this version.')
-            if set(self._flags) != set(self.FLAGS):
+            if flags != set(self.FLAGS):
                 raise UpgradeAbort()
         return metadata
 
     def __init__(self):
+        # The helper reads the metadata when it starts.
+        if GitHgHelper._helper is False:
+            adopt_shared_metadata()
         self._flags = set()
+        self._extra_raw = False
         self._closed = False
         self._graft = None
+        # Description of what is being done, for the metadata log.
+        self.action = None
 
         self._hgheads = VersionedDict()
         self._branches = {}
+        self._author_map = None
+        self._content_filter = None
+        self._subrepos = None
+        # The content filters recorded in the metadata, if any.
+        self._filters = None
 
         self._replace = Git._replace
         self._tagcache_ref = None
@@ -624,6 +952,7 @@ class GitHgStore(object):
         self._tagfiles = {}
         self._tags = {NULL_NODE_ID: {}}
         self._cached_changeset_ref = {}
+        memory_limit.add_shrinker(self._cached_changeset_ref.clear)
         self._tagcache_items = set()
         if self._tagcache_ref:
             for line in Git.ls_tree(self._tagcache_ref):
@@ -645,6 +974,7 @@ class GitHgStore(object):
             metadata, refs = metadata
         self._has_metadata = bool(metadata)
         self._metadata_refs = refs if metadata else {}
+        self._extra_raw = self.EXTRA_RAW_FLAG in self._flags
         self._manifest_heads_orig = set()
         self._generation = 0
         if metadata:
@@ -656,6 +986,7 @@ class GitHgStore(object):
                     hghead, branch = head.split(b' ', 1)
                     self._hgheads._previous[hghead] = (branch, n)
                     self._generation = n + 1
+                self._filters = self._read_filters(changesets_ref)
 
             self._manifest_heads_orig = set(GitHgHelper.heads(b'manifests'))
 
@@ -668,8 +999,29 @@ class GitHgStore(object):
 
         self._replace = VersionedDict(self._replace)
 
-    def prepare_graft(self):
-        self._graft = Grafter(self)
+    @staticmethod
+    def _read_filters(changesets_ref):
+        for mode, typ, sha1, path in Git.ls_tree(changesets_ref, b'filters'):
+            return GitHgHelper.cat_file(b'blob', sha1)
+
+    def content_filter(self):
+        if self._content_filter is None:
+            self._content_filter = ContentFilter.for_store(
+                self._filters, self._has_metadata)
+            if self._content_filter:
+                self._filters = self._content_filter.spec
+        return self._content_filter
+
+    def subrepos(self):
+        '''Returns the converter between mercurial subrepositories and git
+        submodules, or False when they are not to be converted.'''
+        if self._subrepos is None:
+            self._subrepos = subrepos_mode() == b'submodules' and \
+                SubrepoConverter(self)
+        return self._subrepos
+
+    def prepare_graft(self, mode=True):
+        self._graft = Grafter(self, mode)
 
     @staticmethod
     def _try_merge_branches(repo_url):
@@ -833,6 +1185,7 @@ class GitHgStore(object):
                 hghead, branch = head.split(b' ', 1)
                 self._hgheads._previous[hghead] = (branch, 1)
                 self._generation = n + 1
+            self._filters = self._read_filters(changesets_ref)
 
         self._manifest_heads_orig = set(GitHgHelper.heads(b'manifests'))
 
@@ -896,6 +1249,14 @@ class GitHgStore(object):
         return set(h for h, (b, _) in util.iteritems(self._hgheads)
                    if not branches or b in branches)
 
+    def branch_heads(self):
+        '''Returns the heads of each branch, most recent last.'''
+        result = defaultdict(list)
+        for _, branch, head in sorted(
+                (g, b, h) for h, (b, g) in util.iteritems(self._hgheads)):
+            result[branch].append(head)
+        return result
+
     def _head_branch(self, head):
         if head in self._hgheads:
             return self._hgheads[head][0], head
@@ -1054,7 +1415,12 @@ class GitHgStore(object):
             return self._graft.graft(instance)
 
         if not commit:
-            author = Authorship.from_hg(instance.author, instance.timestamp,
+            if self._author_map is None:
+                self._author_map = AuthorMap()
+            # When the author is mapped, the original one is kept in the
+            # changeset metadata, through ChangesetPatcher.
+            author = Authorship.from_hg(self._author_map(instance.author),
+                                        instance.timestamp,
                                         instance.utcoffset)
             extra = instance.extra
             if extra and extra.get(b'committer'):
@@ -1086,6 +1452,11 @@ class GitHgStore(object):
             # changeset.
             committer = committer.to_git_str()
             author = author.to_git_str()
+            tree = self.git_tree(instance.manifest, *instance.parents[:1])
+            if self.content_filter():
+                tree = self._content_filter.filter_tree(tree)
+            submodules = self.subrepos() and \
+                self._subrepos.to_git(tree, instance)
             with GitHgHelper.commit(
                 ref=b'refs/cinnabar/tip',
                 message=body,
@@ -1094,9 +1465,14 @@ class GitHgStore(object):
                 parents=parents,
                 pseudo_mark=b':h%s' % instance.node,
             ) as c:
-                c.filemodify(b'', self.git_tree(instance.manifest,
-                                                *instance.parents[:1]),
-                             typ=b'tree')
+                c.filemodify(b'', tree, typ=b'tree')
+                if submodules:
+                    gitmodules, gitlinks = submodules
+                    c.filedelete(b'.hgsub')
+                    c.filedelete(b'.hgsubstate')
+                    c.filemodify(b'.gitmodules', content=gitmodules)
+                    for path, sha1 in gitlinks:
+                        c.filemodify(path, sha1, typ=b'commit')
 
             commit = PseudoGitCommit(b':1')
             commit.author = author
@@ -1105,8 +1481,10 @@ class GitHgStore(object):
 
         GitHgHelper.set(b'changeset', instance.node, commit.sha1)
         changeset = Changeset.from_git_commit(commit)
-        GitHgHelper.put_blob(
-            ChangesetPatcher.from_diff(changeset, instance), want_sha1=False)
+        patcher = ChangesetPatcher.from_diff(changeset, instance)
+        if patcher.has_extra_raw:
+            self._extra_raw = True
+        GitHgHelper.put_blob(patcher, want_sha1=False)
         GitHgHelper.set(b'changeset-metadata', instance.node, b':1')
 
         self._branches[instance.node] = instance.branch or b'default'
@@ -1157,11 +1535,14 @@ class GitHgStore(object):
                      instance.delta_node.decode('ascii'))
                 )
 
+    @util.stats.timed('metadata update')
     def close(self, refresh=()):
         if self._closed:
             return
         if self._graft:
             self._graft.close()
+        if self._content_filter:
+            self._content_filter.close()
         self._closed = True
         # If the helper is not running, we don't have anything to update.
         if not GitHgHelper._helper:
@@ -1193,6 +1574,9 @@ class GitHgStore(object):
         bundle_blob = getattr(self, "bundle_blob", None)
         if (any(self._hgheads.iterchanges()) or
                 b'refs/cinnabar/changesets' in refresh or bundle_blob):
+            filters_blob = None
+            if self._filters:
+                filters_blob = GitHgHelper.put_blob(self._filters)
             heads = sorted((self._hgheads[h][1], self._hgheads[h][0], h, g)
                            for h, g in zip(hg_changeset_heads,
                                            changeset_heads))
@@ -1203,6 +1587,8 @@ class GitHgStore(object):
             ) as commit:
                 if bundle_blob:
                     commit.filemodify(b'bundle', bundle_blob)
+                if filters_blob:
+                    commit.filemodify(b'filters', filters_blob)
             update_metadata[b'refs/cinnabar/changesets'] = commit.sha1
 
         changeset_heads = set(changeset_heads)
@@ -1244,14 +1630,24 @@ class GitHgStore(object):
                              self._metadata_sha1)
             if metadata_sha1:
                 parents.append(metadata_sha1)
+            flags = list(self.FLAGS)
+            if self._filters:
+                flags.append(self.FILTERED_FLAG)
+            if self._extra_raw:
+                flags.append(self.EXTRA_RAW_FLAG)
             with GitHgHelper.commit(
                 ref=b'refs/cinnabar/metadata',
                 parents=parents,
-                message=b' '.join(sorted(self.FLAGS)),
+                message=b' '.join(sorted(flags)),
             ) as commit:
                 for sha1, target in util.iteritems(self._replace):
                     commit.filemodify(sha1, target, b'commit')
             self._metadata_sha1 = commit.sha1
+            log = metadata_log()
+            log[commit.sha1] = (int(time.time()), self.action or b' '.join(
+                util.fsencode(a) for a in
+                [os.path.basename(sys.argv[0])] + sys.argv[1:]))
+            write_metadata_log(log)
 
         for c in self._tagcache:
             if c not in changeset_heads:
@@ -1342,3 +1738,25 @@ class GitHgStore(object):
                 "attempt succeeded.\n" + extra + "\n"
                 "Please read all the above and keep a copy of this repository."
             )
+
+        if b'refs/cinnabar/changesets' in update_metadata:
+            self._update_commit_graph()
+
+    def _update_commit_graph(self):
+        # Git and the helper use the commit-graph generation numbers to
+        # cut ancestry walks short (e.g. during push discovery or fsck)
+        # whenever the commit-graph file exists. Optionally refresh it
+        # after imports, so that it covers newly imported commits as well
+        # as the metadata commits under refs/cinnabar.
+        try:
+            enabled = Git.config('cinnabar.commit-graph', values={
+                None: False,
+                b'false': False,
+                b'true': True,
+            })
+        except InvalidConfig as e:
+            logging.warning(str(e))
+            return
+        if not enabled:
+            return
+        write_commit_graph()
@@ -25,6 +25,7 @@ from .util import (
     IOLogger,
     lrucache,
     Process,
+    stats,
 )
 from contextlib import contextmanager
 
@@ -101,6 +102,15 @@ class ReadWriter(object):
 
 class BaseHelper(object):
     _helper_hash = None
+    _env = None
+
+    @classmethod
+    def set_env(self, name, value):
+        '''Sets an environment variable for the helper process only, rather
+        than for all the processes we spawn.'''
+        if self._env is None:
+            self._env = {}
+        self._env[name] = value
 
     @classmethod
     def close(self):
@@ -118,6 +128,7 @@ class BaseHelper(object):
             for k, v in iteritems(environ()):
                 if k.startswith(b'GIT_CINNABAR_'):
                     env[k] = v
+            env.update(self._env or {})
             if helper_path:
                 helper_path = fsdecode(helper_path)
             if helper_path and os.path.exists(helper_path):
@@ -187,7 +198,8 @@ class BaseHelper(object):
             return
 
         helper = self._helper
-        logger = logging.getLogger(name.decode('ascii'))
+        logger = logging.getLogger(
+            '%s.%s' % (self.MODE, name.decode('ascii')))
         if logger.isEnabledFor(logging.INFO):
             wrapper = IOLogger(logger, helper.stdout, helper.stdin,
                                prefix='[%d]' % helper.pid)
@@ -244,7 +256,7 @@ class BaseHelper(object):
 
 
 class GitHgHelper(BaseHelper):
-    VERSION = 3003
+    VERSION = 3004
     MODE = 'import'
     _helper = False
 
@@ -311,6 +323,31 @@ class GitHgHelper(BaseHelper):
         with self.query(b'check-file', hg_sha1, *parents) as stdout:
             return stdout.readline().strip() == b'ok'
 
+    @classmethod
+    def check_manifests(self, hg_sha1s):
+        '''Like check_manifest, for several manifests at once.'''
+        return self._check_many(b'check-manifest',
+                                [(hg_sha1,) for hg_sha1 in hg_sha1s])
+
+    @classmethod
+    def check_files(self, files):
+        '''Like check_file, for several (hg_sha1, parent1, parent2) at
+        once.'''
+        return self._check_many(b'check-file', list(files))
+
+    @classmethod
+    def _check_many(self, command, items):
+        # All the commands are sent before reading their results, which
+        # avoids a round-trip each. Their results being short, the batches
+        # need to be quite large for the helper to block on its output.
+        if not items:
+            return []
+        with self.query(command, *items[0]) as stdout:
+            for args in items[1:]:
+                stdout.write(b'%s %s\n' % (command, b' '.join(args)))
+            stdout.flush()
+            return [stdout.readline().strip() == b'ok' for _ in items]
+
     @classmethod
     def ls_tree(self, sha1, recursive=False):
         extra = () if not recursive else (b'-r',)
@@ -562,7 +599,7 @@ class CommitHelper(object):
 
 
 class HgRepoHelper(BaseHelper):
-    VERSION = 3003
+    VERSION = 3004
     MODE = 'wire'
     _helper = False
 
@@ -570,6 +607,9 @@ class HgRepoHelper(BaseHelper):
     def connect(self, url):
         with self.query(b'connect', url) as stdout:
             resp = stdout.readline().rstrip()
+            # The helper sends the decompressed contents of bundles it
+            # can't handle on its own, e.g. when their changesets are
+            # deltas against changesets that are not in the bundle.
             if resp == b'bundle':
                 return stdout
             if resp != b'ok':
@@ -600,15 +640,19 @@ class HgRepoHelper(BaseHelper):
             return self._read_data(stdout)
 
     @classmethod
-    def getbundle(self, heads, common, bundle2caps=False):
-        with self.query(b'getbundle', b','.join(heads), b','.join(common),
-                        bundle2caps) as stdout:
+    def getbundle(self, heads, common, bundle2caps=False, includepats=(),
+                  excludepats=()):
+        args = [b','.join(heads), b','.join(common), bundle2caps or b'']
+        if includepats or excludepats:
+            args.extend((b','.join(includepats), b','.join(excludepats)))
+        with self.query(b'getbundle', *args) as stdout:
             return stdout
 
     @classmethod
     def unbundle(self, input_iterator, heads):
         with self.query(b'unbundle', *heads) as stdout:
             for data in input_iterator:
+                stats.add_size('sent', len(data))
                 self._helper.stdin.write(data)
             self._helper.stdin.flush()
             ret = self._read_data(stdout)
@@ -632,6 +676,11 @@ class HgRepoHelper(BaseHelper):
             success, data = self._read_data(stdout).rstrip().split(b' ', 1)
             return data if int(success) else None
 
+    @classmethod
+    def between(self, pairs):
+        with self.query(b'between', *(b'%s-%s' % p for p in pairs)) as stdout:
+            return self._read_data(stdout)
+
     @classmethod
     def clonebundles(self):
         with self.query(b'clonebundles') as stdout:
@@ -17,12 +17,14 @@ from cinnabar.git import (
     EMPTY_BLOB,
     Git,
     NULL_NODE_ID,
+    split_ls_tree,
 )
 from cinnabar.util import (
     check_enabled,
     chunkbuffer,
     experiment,
     iteritems,
+    one,
     progress_enum,
     progress_iter,
     sorted_merge,
@@ -39,8 +41,12 @@ from collections import (
     OrderedDict,
     defaultdict,
 )
+import bz2
+import hashlib
 import logging
+from binascii import unhexlify
 import struct
+import zlib
 
 
 # We used to have a pseudo string class that didn't derive from str, and
@@ -122,27 +128,147 @@ def get_changes(tree, parents, all=False):
             yield path, node, parents
 
 
+# Mercurial manifests created when pushing are remembered, with the list of
+# files changed in the corresponding changesets, keyed by the git tree and the
+# parent manifests they were created from. As the mercurial manifest only
+# depends on those, pushing again commits with the same trees on top of the
+# same parents (e.g. after amending commit messages in a stack, or a rebase
+# that only touched the top of the stack) doesn't require computing them
+# again.
+# The ref points to a tree of blobs, each containing the entries for the keys
+# starting with the same two hex digits, so that a push only needs to write
+# the blobs for the entries it adds.
+MANIFEST_CACHE_REF = b'refs/cinnabar/manifest-cache'
+# Maximum number of entries in each blob, for about 10000 entries overall.
+MANIFEST_CACHE_BUCKET_SIZE = 40
+# Configuration changing the file nodes in the created manifests, through
+# the copy detection between git trees.
+MANIFEST_CACHE_CONFIG = ('diff.renamelimit',)
+
+
+def manifest_cache_key(tree, parent_manifests, config=b''):
+    return hashlib.sha1(b''.join((config, tree) + tuple(parent_manifests))) \
+        .hexdigest().encode('ascii')
+
+
+class ManifestCache(object):
+    '''Associates keys from `manifest_cache_key` with a (manifest node,
+    changeset files) tuple.'''
+    def __init__(self):
+        self._blobs = {}
+        self._buckets = {}
+        self._dirty = set()
+        sha1 = Git.resolve_ref(MANIFEST_CACHE_REF)
+        if sha1:
+            for line in Git.iter('ls-tree', sha1):
+                mode, typ, blob, path = split_ls_tree(line)
+                self._blobs[path] = blob
+
+    def _bucket(self, key):
+        prefix = key[:2]
+        bucket = self._buckets.get(prefix)
+        if bucket is None:
+            bucket = self._buckets[prefix] = OrderedDict()
+            blob = self._blobs.get(prefix)
+            for line in Git.iter('cat-file', 'blob', blob) if blob else ():
+                key, _, rest = line.partition(b' ')
+                node, _, files = rest.partition(b'\t')
+                if key and node:
+                    bucket[key] = (node, files.split(b'\0') if files else [])
+        return bucket
+
+    def get(self, key):
+        return self._bucket(key).get(key)
+
+    def set(self, key, node, changeset_files):
+        bucket = self._bucket(key)
+        bucket.pop(key, None)
+        bucket[key] = (node, changeset_files)
+        while len(bucket) > MANIFEST_CACHE_BUCKET_SIZE:
+            bucket.popitem(last=False)
+        self._dirty.add(key[:2])
+
+    def write(self):
+        if not self._dirty:
+            return
+        for prefix in self._dirty:
+            self._blobs[prefix] = one(Git.iter(
+                'hash-object', '-w', '--stdin', stdin=(
+                    b'%s %s\t%s' % (key, node, b'\0'.join(files))
+                    for key, (node, files) in iteritems(
+                        self._buckets[prefix]))))
+        self._dirty.clear()
+        tree = one(Git.iter('mktree', stdin=(
+            b'100644 blob %s\t%s' % (blob, prefix)
+            for prefix, blob in sorted(iteritems(self._blobs)))))
+        Git.run('update-ref', MANIFEST_CACHE_REF, tree)
+
+
+def merge_file_parents(dag, node1, node2):
+    '''Returns the parents for a file revision merging the given file nodes,
+    following the same rules as mercurial: when one of them is an ancestor
+    of the other, according to the given file dag, only the other is a
+    parent.'''
+    if dag:
+        dag.tag_nodes_and_parents((node1,), 'a')
+        if dag._tags.get(node2) == 'a':
+            return (node1,)
+        dag._tags.clear()
+        dag.tag_nodes_and_parents((node2,), 'b')
+        if dag._tags.get(node1) == 'b':
+            return (node2,)
+    return (node1, node2)
+
+
 class PushStore(GitHgStore):
     @classmethod
-    def adopt(cls, store, graft):
+    def adopt(cls, store, graft, topic=None):
         assert isinstance(store, GitHgStore)
         store.__class__ = cls
-        store._init(graft)
+        store._init(graft, topic)
 
     def __init__(self, *args, **kwargs):
-        graft = kwargs.get('graft', False)
-        if 'graft' in kwargs:
-            del kwargs['graft']
+        graft = kwargs.pop('graft', False)
+        topic = kwargs.pop('topic', None)
         super(PushStore, self).__init__(*args, **kwargs)
-        self._init(graft)
+        self._init(graft, topic)
 
-    def _init(self, graft=False):
+    def _init(self, graft=False, topic=None):
         self._pushed = set()
         self._manifest_git_tree = {}
         self._graft = bool(graft)
+        self._topic = topic
         self._merge_warn = 0
+        self._manifest_cache = None
+        self._manifest_cache_config = None
+        self._new_manifests = OrderedDict()
+        self._merge_file_parents = {}
 
     def create_hg_manifest(self, commit, parents):
+        '''Returns the mercurial manifest for the given commit, the files
+        changed in the corresponding changeset, and the key to remember the
+        manifest with in the manifest cache, if it's not already there.'''
+        if check_enabled('bundle') or (len(parents) > 1 and
+                                       not experiment('merge')):
+            return self._create_hg_manifest(commit, parents) + (None,)
+        if self._manifest_cache is None:
+            self._manifest_cache = ManifestCache()
+            self._manifest_cache_config = b'\0'.join(
+                Git.config(c) or b'' for c in MANIFEST_CACHE_CONFIG)
+        tree = GitCommit(commit).tree
+        key = manifest_cache_key(tree, (
+            self.changeset(self.hg_changeset(p)).manifest for p in parents),
+            self._manifest_cache_config)
+        cached = self._manifest_cache.get(key)
+        if cached:
+            node, changeset_files = cached
+            # Only use the cached manifest when it is still in the metadata.
+            # It may not be when the push it was created for failed.
+            if self.manifest_ref(node):
+                return self.manifest(node), list(changeset_files), None
+        return self._create_hg_manifest(commit, parents) + (key,)
+
+    def _create_hg_manifest(self, commit, parents):
         manifest = GeneratedManifestInfo(NULL_NODE_ID)
         changeset_files = []
 
@@ -158,8 +284,13 @@ class PushStore(GitHgStore):
             if parent_node == parent2_node:
                 parents = parents[:1]
 
+        subrepos = self.subrepos()
+
         if not parents:
-            for line in Git.ls_tree(commit, recursive=True):
+            files = Git.ls_tree(commit, recursive=True)
+            if subrepos:
+                files = subrepos.translate_files(commit, files)
+            for line in files:
                 mode, typ, sha1, path = line
                 node = self.create_file(sha1)
                 manifest.add(path, node, self.ATTR[mode], modified=True)
@@ -180,24 +311,47 @@ class PushStore(GitHgStore):
             git_manifests = (self.manifest_ref(parent_node),
                              self.manifest_ref(parent2_node))
 
-            # TODO: this would benefit from less git queries
+            # Files that exist with a different node on both sides need to
+            # know how those nodes relate to each other to get the right
+            # file parents. That requires the history of those files
+            # between the two parent manifests, which is only gathered for
+            # the files where it's not already known from a previous merge.
+            pending = set()
+            for path, sha1_after, sha1_before in manifest_diff(
+                    *git_manifests):
+                if NULL_NODE_ID in (sha1_after, sha1_before):
+                    continue
+                path = GitHgStore.manifest_path(path)
+                if (path, sha1_before, sha1_after) not in \
+                        self._merge_file_parents:
+                    pending.add(path)
+
             file_dags = {}
-            for m, tree, mparents in GitHgHelper.rev_list(
-                    b'--parents', b'--topo-order',
-                    b'--full-history', b'--reverse',
-                    b'%s...%s' % git_manifests):
-                for p in mparents:
-                    for path, sha1_after, sha1_before in manifest_diff(p, m):
-                        path = GitHgStore.manifest_path(path)
-                        if path not in file_dags:
-                            file_dags[path] = gitdag()
-                        dag = file_dags[path]
-                        if sha1_before == NULL_NODE_ID:
-                            dag.add(sha1_after, ())
-                        else:
-                            dag.add(sha1_after, (sha1_before,))
-            files = [(p, mode, sha1) for mode, _, sha1, p in
-                     Git.ls_tree(commit, recursive=True)]
+            if pending:
+                if self._merge_warn == 1:
+                    logging.warning('This may take a while...')
+                    self._merge_warn = 2
+                for m, tree, mparents in GitHgHelper.rev_list(
+                        b'--parents', b'--topo-order',
+                        b'--full-history', b'--reverse',
+                        b'%s...%s' % git_manifests):
+                    for p in mparents:
+                        for path, sha1_after, sha1_before in manifest_diff(
+                                p, m):
+                            path = GitHgStore.manifest_path(path)
+                            if path not in pending:
+                                continue
+                            if path not in file_dags:
+                                file_dags[path] = gitdag()
+                            dag = file_dags[path]
+                            if sha1_before == NULL_NODE_ID:
+                                dag.add(sha1_after, ())
+                            else:
+                                dag.add(sha1_after, (sha1_before,))
+            files = Git.ls_tree(commit, recursive=True)
+            if subrepos:
+                files = subrepos.translate_files(commit, files)
+            files = [(p, mode, sha1) for mode, _, sha1, p in files]
             manifests = sorted_merge(parent_manifest, parent2_manifest,
                                      key=lambda i: i.path, non_key=lambda i: i)
             for line in sorted_merge(files, manifests):
@@ -222,25 +376,13 @@ class PushStore(GitHgStore):
                 elif manifest_line_p1.sha1 == manifest_line_p2.sha1:
                     file_parents = (manifest_line_p1.sha1,)
                 else:
-                    if self._merge_warn == 1:
-                        logging.warning('This may take a while...')
-                        self._merge_warn = 2
-                    file_parents = ()
-                    dag = file_dags.pop(path)
-                    if dag:
-                        dag.tag_nodes_and_parents(
-                            (manifest_line_p1.sha1,), 'a')
-                        if dag._tags.get(manifest_line_p2.sha1) == 'a':
-                            file_parents = (manifest_line_p1.sha1,)
-                        else:
-                            dag._tags.clear()
-                            dag.tag_nodes_and_parents(
-                                (manifest_line_p2.sha1,), 'b')
-                            if dag._tags.get(manifest_line_p1.sha1) == 'b':
-                                file_parents = (manifest_line_p2.sha1,)
-                    if not file_parents:
-                        file_parents = (manifest_line_p1.sha1,
-                                        manifest_line_p2.sha1)
+                    key = (path, manifest_line_p1.sha1, manifest_line_p2.sha1)
+                    file_parents = self._merge_file_parents.get(key)
+                    if file_parents is None:
+                        file_parents = merge_file_parents(
+                            file_dags.pop(path, None), manifest_line_p1.sha1,
+                            manifest_line_p2.sha1)
+                        self._merge_file_parents[key] = file_parents
 
                 assert file_parents is not None
                 f = self._create_file_internal(sha1, *file_parents)
@@ -275,14 +417,14 @@ class PushStore(GitHgStore):
                         b'000000', sha1_before, NULL_NODE_ID, b'D')
                 yield path, (mode_after, sha1_before, sha1_after,
                              status)
-        git_diff = sorted(
-            l for l in process_diff(GitHgHelper.diff_tree(
-                parents[0], commit, detect_copy=True))
-        )
+        parent_lines = OrderedDict((l.path, l) for l in parent_manifest)
+        git_diff = GitHgHelper.diff_tree(parents[0], commit, detect_copy=True)
+        if subrepos:
+            git_diff = subrepos.translate_diff(commit, git_diff, parent_lines)
+        git_diff = sorted(l for l in process_diff(git_diff))
         if not git_diff:
             return parent_manifest, []
 
-        parent_lines = OrderedDict((l.path, l) for l in parent_manifest)
         items = manifest.items
         for line in sorted_merge(iteritems(parent_lines), git_diff,
                                  non_key=lambda i: i[1]):
@@ -322,7 +464,8 @@ class PushStore(GitHgStore):
     def create_hg_metadata(self, commit, parents):
         if check_enabled('bundle'):
             real_changeset = self.changeset(self.hg_changeset(commit))
-        manifest, changeset_files = self.create_hg_manifest(commit, parents)
+        manifest, changeset_files, cache_key = self.create_hg_manifest(
+            commit, parents)
         commit_data = GitCommit(commit)
 
         if manifest.node == NULL_NODE_ID:
@@ -339,6 +482,8 @@ class PushStore(GitHgStore):
             self._pushed.add(manifest.node)
             self.store_manifest(manifest)
             self._manifest_git_tree[manifest.node] = commit_data.tree
+        if cache_key:
+            self._new_manifests[cache_key] = (manifest.node, changeset_files)
 
         changeset = Changeset.from_git_commit(commit_data)
         changeset.parents = tuple(self.hg_changeset(p) for p in parents)
@@ -349,6 +494,8 @@ class PushStore(GitHgStore):
             parent_changeset = self.changeset(changeset.parent1)
             if parent_changeset.branch:
                 changeset.branch = parent_changeset.branch
+        if self._topic:
+            changeset.topic = self._topic
 
         if self._graft is True and parents and changeset.body[-1:] == b'\n':
             parent_commit = GitCommit(parents[0])
@@ -441,6 +588,11 @@ class PushStore(GitHgStore):
         if self._closed:
             return
         super(PushStore, self).close()
+        if self._new_manifests:
+            for key, (node, changeset_files) in iteritems(
+                    self._new_manifests):
+                self._manifest_cache.set(key, node, changeset_files)
+            self._manifest_cache.write()
 
 
 def bundle_data(store, commits):
@@ -539,7 +691,25 @@ def bundlepart(name, advisoryparams=(), data=None):
     yield b'\0' * 4  # Empty chunk ending the part
 
 
-def create_bundle(store, commits, bundle2caps={}):
+def compress(chunks, compression):
+    '''Compresses the given chunks with the given mercurial bundle
+    compression (GZ or BZ).'''
+    if compression == b'GZ':
+        compressor = zlib.compressobj()
+    elif compression == b'BZ':
+        compressor = bz2.BZ2Compressor()
+    else:
+        raise Exception('Unsupported bundle compression: %s'
+                        % compression.decode('ascii', 'replace'))
+    for chunk in chunks:
+        data = compressor.compress(chunk)
+        if data:
+            yield data
+    yield compressor.flush()
+
+
+def create_bundle(store, commits, bundle2caps={}, compression=None,
+                  check_heads=None, pushvars=(), bookmarks=()):
     version = b'01'
     chunk_type = RawRevChunk01
     if bundle2caps:
@@ -551,22 +721,69 @@ def create_bundle(store, commits, bundle2caps={}):
     cg = create_changegroup(store, bundle_data(store, commits), chunk_type)
     if bundle2caps:
         yield b'HG20'
-        yield b'\0' * 4  # bundle parameters length: no params
-        replycaps = bundle2caps.get(b'replycaps')
-        if replycaps:
-            for chunk in bundlepart(b'REPLYCAPS',
-                                    data=chunkbuffer([replycaps])):
-                yield chunk
-        for chunk in bundlepart(b'CHANGEGROUP',
-                                advisoryparams=((b'version', version),),
-                                data=chunkbuffer(cg)):
+        params = b'Compression=%s' % compression if compression else b''
+        yield struct.pack('>i', len(params))
+        if params:
+            yield params
+        parts = create_bundle2_parts(store, cg, version, bundle2caps,
+                                     check_heads, pushvars, bookmarks)
+        if compression:
+            parts = compress(parts, compression)
+        for chunk in parts:
             yield chunk
-        yield b'\0' * 4  # End of bundle
     else:
         for chunk in cg:
             yield chunk
 
 
+def bookmarks_data(bookmarks):
+    '''Encodes the given (name, binary node) for bookmarks parts.'''
+    return b''.join(struct.pack('>20sH', node, len(name)) + name
+                    for name, node in bookmarks)
+
+
+def create_bundle2_parts(store, cg, version, bundle2caps, check_heads=None,
+                         pushvars=(), bookmarks=()):
+    replycaps = bundle2caps.get(b'replycaps')
+    if replycaps:
+        for chunk in bundlepart(b'REPLYCAPS',
+                                data=chunkbuffer([replycaps])):
+            yield chunk
+    # pushvars is a list of (key, value) for the server hooks, which are
+    # advisory, like the part itself.
+    if pushvars:
+        for chunk in bundlepart(b'pushvars', advisoryparams=pushvars):
+            yield chunk
+    # check_heads is a (part name, binary heads) tuple, for the server to
+    # check the heads didn't change before applying the changegroup.
+    if check_heads:
+        name, heads = check_heads
+        for chunk in bundlepart(name, data=chunkbuffer([b''.join(heads)])):
+            yield chunk
+    # bookmarks is a list of (name, old hg node, new git commit) for the
+    # bookmarks to update along the changesets. The server checks the
+    # bookmarks still have their old values before applying anything.
+    if bookmarks:
+        data = bookmarks_data((name, unhexlify(old or NULL_NODE_ID))
+                              for name, old, _ in bookmarks)
+        for chunk in bundlepart(b'CHECK:BOOKMARKS',
+                                data=chunkbuffer([data])):
+            yield chunk
+    for chunk in bundlepart(b'CHANGEGROUP',
+                            advisoryparams=((b'version', version),),
+                            data=chunkbuffer(cg)):
+        yield chunk
+    if bookmarks:
+        # The changegroup was entirely generated at this point, so the
+        # new changesets are known.
+        data = bookmarks_data(
+            (name, unhexlify(store.hg_changeset(commit)))
+            for name, _, commit in bookmarks)
+        for chunk in bundlepart(b'BOOKMARKS', data=chunkbuffer([data])):
+            yield chunk
+    yield b'\0' * 4  # End of bundle
+
+
 def get_previous(store, sha1, type):
     if issubclass(type, Changeset):
         return store.changeset(sha1)
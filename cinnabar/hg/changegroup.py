@@ -128,3 +128,27 @@ class RawRevChunk02(RawRevChunk):
     changeset = RawRevChunk._field(80, 20, hexlify)
     data = RawRevChunk._field(100)
     patch = RawRevChunk._field(100, filter=RevDiff)
+
+
+# Revlog flag for revisions whose content is stored out of the repository by
+# the mercurial lfs extension. The data sent for such revisions is the lfs
+# pointer, which follows the git-lfs format, and which is what is stored.
+REVIDX_EXTSTORED = 1 << 13
+
+# Nodes of the file revisions received with the REVIDX_EXTSTORED flag. Their
+# sha1 is that of the content stored out of the repository, not that of the
+# pointer.
+extstored_nodes = set()
+
+
+def rev_chunk_03(data):
+    '''Changegroup v3 chunks are v2 chunks with additional revlog flags.
+    Revisions with flags other than REVIDX_EXTSTORED (e.g. censored) are not
+    supported, so v3 chunks are converted to v2 chunks.'''
+    flags = struct.unpack('>H', bytes(data[100:102]))[0]
+    if flags & ~REVIDX_EXTSTORED:
+        raise Exception('Unsupported revision flags (0x%x) for node %s'
+                        % (flags, hexlify(data[:20]).decode('ascii')))
+    if flags:
+        extstored_nodes.add(hexlify(data[:20]))
+    return RawRevChunk02(data[:100] + data[102:])
@@ -280,24 +280,68 @@ class Changeset(HgObject):
     files = TypedProperty(list)
 
     class ExtraData(dict):
+        # The extras, as they appeared in the changeset. Mercurial normally
+        # writes them sorted, but some changesets have them in a different
+        # order, with duplicate keys, or without a value, and reproducing
+        # them verbatim is the only way to keep the node the same. Any
+        # modification resets them.
+        _raw = None
+
         @classmethod
         def from_str(cls, s):
-            return cls(i.split(b':', 1) for i in s.split(b'\0') if i)
+            this = cls(i.partition(b':')[::2] for i in s.split(b'\0') if i)
+            this._raw = s
+            return this
 
         @classmethod
         def from_obj(cls, obj):
             if obj is None:
                 return None
             if isinstance(obj, dict):
-                return cls(obj)
+                this = cls(obj)
+                this._raw = getattr(obj, '_raw', None)
+                return this
             return cls.from_str(obj)
 
         def __str__(self):
             raise RuntimeError('Use to_str()')
 
         def to_str(self):
+            if self._raw is not None:
+                return self._raw
+            return self.normalized()
+
+        def normalized(self):
             return b'\0'.join(b':'.join(i) for i in sorted(self.items()))
 
+        def __setitem__(self, key, value):
+            self._raw = None
+            super(Changeset.ExtraData, self).__setitem__(key, value)
+
+        def __delitem__(self, key):
+            self._raw = None
+            super(Changeset.ExtraData, self).__delitem__(key)
+
+        def clear(self):
+            self._raw = None
+            super(Changeset.ExtraData, self).clear()
+
+        def pop(self, *args):
+            self._raw = None
+            return super(Changeset.ExtraData, self).pop(*args)
+
+        def popitem(self):
+            self._raw = None
+            return super(Changeset.ExtraData, self).popitem()
+
+        def setdefault(self, key, default=None):
+            self._raw = None
+            return super(Changeset.ExtraData, self).setdefault(key, default)
+
+        def update(self, *args, **kwargs):
+            self._raw = None
+            super(Changeset.ExtraData, self).update(*args, **kwargs)
+
     extra = TypedProperty(ExtraData)
 
     def _data_iter(self):
@@ -351,6 +395,7 @@ class Changeset(HgObject):
     branch = ExtraProperty('branch')
     committer = ExtraProperty('committer')
     close = ExtraProperty('close')
+    topic = ExtraProperty('topic')
 
 
 class Manifest(HgObject):
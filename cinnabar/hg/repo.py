@@ -12,7 +12,12 @@ try:
     from urllib2 import HTTPError
 except ImportError:
     from urllib.error import HTTPError
-from cinnabar.exceptions import NothingToGraftException
+from cinnabar.exceptions import (
+    Abort,
+    HelperClosedError,
+    NothingToGraftException,
+    PushRejectedAbort,
+)
 from cinnabar.githg import Changeset
 from cinnabar.helper import (
     GitHgHelper,
@@ -23,7 +28,10 @@ from binascii import (
     hexlify,
     unhexlify,
 )
-from itertools import chain
+from itertools import (
+    chain,
+    islice,
+)
 try:
     from itertools import izip as zip
 except ImportError:
@@ -44,21 +52,33 @@ except ImportError:
 import logging
 import struct
 import random
+import tempfile
 from cinnabar.dag import gitdag
+from cinnabar.remotes import stored_remote_heads
 from cinnabar.git import (
     Git,
+    GitProcess,
     InvalidConfig,
     NULL_NODE_ID,
 )
 from cinnabar.util import (
     HTTPReader,
+    ask,
     check_enabled,
     chunkbuffer,
+    DigestReader,
     environ,
     experiment,
     fsdecode,
+    iteritems,
+    itervalues,
+    memory_limit,
+    one,
     progress_enum,
     progress_iter,
+    ProgressReader,
+    stats,
+    ThrottledReader,
 )
 from collections import (
     defaultdict,
@@ -73,7 +93,10 @@ from .bundle import (
 from .changegroup import (
     RawRevChunk01,
     RawRevChunk02,
+    rev_chunk_03,
 )
+from . import streamclone
+from .revlog import RevlogRepo
 
 
 try:
@@ -109,6 +132,21 @@ try:
 except ImportError:
     changegroup = unbundle20 = False
 
+
+def git_credential(action, lines):
+    '''Runs `git credential <action>` with the given input lines, and
+    returns the lines it outputs. The output must not go to our own stdout,
+    which is the remote helper protocol stream.'''
+    return tuple(Git.iter('credential', action, stdin=lines))
+
+
+def credentials_from_lines(lines):
+    '''Returns the username and password from `git credential fill`
+    output.'''
+    credentials = dict(line.split(b'=', 1) for line in lines if b'=' in line)
+    return credentials.get(b'username'), credentials.get(b'password')
+
+
 if changegroup:
     try:
         from mercurial.changegroup import cg1unpacker
@@ -128,6 +166,10 @@ if changegroup:
     url_passwordmgr = url.passwordmgr
 
     class passwordmgr(url_passwordmgr):
+        # Credentials obtained from git credential, until they are approved
+        # or rejected.
+        git_credentials = {}
+
         def find_user_password(self, realm, authuri):
             try:
                 return url_passwordmgr.find_user_password(self, realm,
@@ -137,23 +179,34 @@ if changegroup:
                 # find_user_password itself, which reflects that authentication
                 # information is missing and mercurial would want to get it
                 # from user input, but can't because the ui isn't interactive.
-                credentials = dict(
-                    line.split(b'=', 1)
-                    for line in Git.iter('credential', 'fill',
-                                         stdin=b'url=%s' % authuri)
-                )
-                username = credentials.get(b'username')
-                password = credentials.get(b'password')
+                previous = self.git_credentials.pop(authuri, None)
+                if previous:
+                    # Being asked again means the server rejected the
+                    # credentials we gave.
+                    git_credential('reject', previous)
+                lines = git_credential('fill', [b'url=%s' % authuri])
+                username, password = credentials_from_lines(lines)
                 if not username or not password:
                     raise
+                self.git_credentials[authuri] = lines
                 return username, password
 
     url.passwordmgr = passwordmgr
+
+    def approve_credentials():
+        '''Let git credential helpers store the credentials that were
+        used successfully.'''
+        for credentials in itervalues(passwordmgr.git_credentials):
+            git_credential('approve', credentials)
+        passwordmgr.git_credentials.clear()
 else:
     def cg1unpacker(fh, alg):
         assert alg == b'UN'
         return fh
 
+    def approve_credentials():
+        pass
+
 
 if not changegroup and not unbundle20 and not check_enabled('no-bundle2'):
     class unbundle20(object):
@@ -316,18 +369,52 @@ def iter_initialized(get_missing, iterable, init=None):
 
 
 class ChunksCollection(object):
+    '''Collects the chunks from the given iterator, to iterate over them
+    later. When memory usage gets close to cinnabar.memory-limit, the
+    chunks are moved to a temporary file.'''
     def __init__(self, iterator):
         self._chunks = deque()
+        self._spill = None
+        self._cls = None
+
+        memory_limit.add_shrinker(self.spill)
+        try:
+            for chunk in iterator:
+                if self._spill:
+                    self._write(chunk)
+                else:
+                    self._chunks.append(chunk)
+        finally:
+            memory_limit.remove_shrinker(self.spill)
 
-        for chunk in iterator:
-            self._chunks.append(chunk)
+    def _write(self, chunk):
+        self._cls = type(chunk)
+        self._spill.write(struct.pack('>l', len(chunk)))
+        self._spill.write(chunk)
+
+    def spill(self):
+        if not self._spill:
+            self._spill = tempfile.TemporaryFile()
+        while self._chunks:
+            self._write(self._chunks.popleft())
 
     def __iter__(self):
         while True:
             try:
                 yield self._chunks.popleft()
             except IndexError:
-                return
+                break
+        # Once spilling started, all the remaining chunks are in the file.
+        if self._spill:
+            self._spill.seek(0)
+            while True:
+                length = self._spill.read(4)
+                if not length:
+                    break
+                length = struct.unpack('>l', length)[0]
+                yield self._cls(self._spill.read(length))
+            self._spill.close()
+            self._spill = None
 
     def iter_initialized(self, cls, get_missing, init=None):
         return iter_initialized(get_missing, iter_chunks(self, cls),
@@ -340,26 +427,55 @@ def _sample(l, size):
     return random.sample(l, size)
 
 
-# TODO: this algorithm is not very smart and might as well be completely wrong
+def _discovery_sample(dag, size):
+    '''Returns a sample of the undecided nodes in the dag, made of the nodes
+    at exponentially growing distances from its heads and roots, like
+    mercurial's set discovery, filled with random nodes if that's not
+    enough.'''
+    sample = set(_sample(list(chain(dag.sample(dag.heads()),
+                                    dag.sample(dag.roots(), children=True))),
+                         size))
+    if len(sample) < size:
+        sample |= set(_sample(list(set(dag.iternodes()) - sample),
+                              size - len(sample)))
+    return list(sample)
+
+
+@stats.timed('negotiation')
 def findcommon(repo, store, hgheads):
+    '''Returns the heads of the changesets that are both in the local store
+    and on the remote, starting from the given local heads. This follows
+    mercurial's set discovery: the remote heads we already have are known
+    to be common without asking, and each round asks the remote about a
+    sample of the undecided changesets at exponentially growing distances
+    from the undecided heads and roots, so that the number and size of
+    requests stay small even with many heads.'''
     logger = logging.getLogger('findcommon')
     logger.debug(hgheads)
     if not hgheads:
         logger.info('no requests')
         return set()
 
-    sample_size = 100
+    sample_size = 200
+    hgheads = list(hgheads)
 
-    sample = _sample(hgheads, sample_size)
-    requests = 1
-    known = repo.known(unhexlify(h) for h in sample)
-    known = set(h for h, k in zip(sample, known) if k)
+    remote_heads = set(hexlify(h) for h in repo.heads())
+    remote_heads.discard(NULL_NODE_ID)
+    known = set(h for h in remote_heads if store.changeset_ref(h))
+    logger.debug('known remote heads: %d', len(known))
+
+    requests = 0
+    sample = _sample([h for h in hgheads if h not in known], sample_size)
+    if sample:
+        requests += 1
+        known |= set(h for h, k in zip(sample, repo.known(
+            unhexlify(h) for h in sample)) if k)
 
     logger.debug('initial sample size: %d', len(sample))
 
-    if len(known) == len(hgheads):
+    if all(h in known for h in hgheads):
         logger.debug('all heads known')
-        logger.info('1 request')
+        logger.info('%d request(s)', requests)
         return hgheads
 
     git_heads = set(store.changeset_ref(h) for h in hgheads)
@@ -396,16 +512,10 @@ def findcommon(repo, store, hgheads):
     log_dag('known')
 
     while True:
-        unknown = set(chain(dag.heads(), dag.roots()))
-        if not unknown:
+        sample = _discovery_sample(dag, sample_size)
+        if not sample:
             break
 
-        sample = set(_sample(unknown, sample_size))
-        if len(sample) < sample_size:
-            sample |= set(_sample(set(dag.iternodes()),
-                                  sample_size - len(sample)))
-
-        sample = list(sample)
         hg_sample = [store.hg_changeset(h) for h in sample]
         requests += 1
         known = repo.known(unhexlify(h) for h in hg_sample)
@@ -478,9 +588,10 @@ class HelperRepo(object):
         if capability == b'bundle2':
             return quote_from_bytes(
                 HgRepoHelper.capable(b'bundle2') or b'').encode('ascii')
-        if capability in (b'clonebundles', b'cinnabarclone'):
+        if capability in (b'clonebundles', b'cinnabarclone', b'largefiles',
+                          b'lfs', NARROW_CAP, b'unbundle'):
             return HgRepoHelper.capable(capability) is not None
-        return capability in (b'getbundle', b'unbundle', b'lookup')
+        return capability in (b'getbundle', b'lookup')
 
     def batch(self):
         raise NotImplementedError()
@@ -515,7 +626,9 @@ class HelperRepo(object):
         getbundle_params["common"] = [
             c.decode('ascii', 'replace') for c in common]
         getbundle_params["bundlecaps"] = bundlecaps.decode('utf-8', 'replace')
-        data = HgRepoHelper.getbundle(heads, common, bundlecaps)
+        data = ProgressReader(ThrottledReader(HgRepoHelper.getbundle(
+            heads, common, bundlecaps, kwargs.get('includepats', ()),
+            kwargs.get('excludepats', ()))))
         header = readexactly(data, 4)
         if header == b'HG20':
             return unbundle20(self.ui, data)
@@ -542,6 +655,9 @@ class HelperRepo(object):
     def unbundle(self, cg, heads, *args, **kwargs):
         data = HgRepoHelper.unbundle(cg, (hexlify(h) if h != b'force' else h
                                           for h in heads))
+        if isinstance(data, bytes) and data.startswith(b'error\n'):
+            raise PushRejectedAbort(
+                fsdecode(data[6:]).strip() or 'unknown error')
         if isinstance(data, str) and data.startswith(b'HG20'):
             data = unbundle20(self.ui, BytesIO(data[4:]))
         return data
@@ -549,6 +665,12 @@ class HelperRepo(object):
     def local(self):
         return None
 
+    def between(self, pairs):
+        data = HgRepoHelper.between((hexlify(top), hexlify(bottom))
+                                    for top, bottom in pairs)
+        return [[unhexlify(n) for n in line.split()]
+                for line in data.splitlines()]
+
     def lookup(self, key):
         data = HgRepoHelper.lookup(key)
         if data:
@@ -556,16 +678,51 @@ class HelperRepo(object):
         raise Exception('Unknown revision %s' % fsdecode(key))
 
 
-def unbundle_fh(fh, path):
+BUNDLESPEC_COMPRESSIONS = {
+    b'none': b'UN',
+    b'gzip': b'GZ',
+    b'bzip2': b'BZ',
+    b'zstd': b'ZS',
+}
+
+
+def check_bundlespec(path, spec, version, compression):
+    '''Checks that the version and compression found in the bundle match
+    its BUNDLESPEC.'''
+    typ = spec.partition(b';')[0]
+    expected_compression, _, expected_version = typ.partition(b'-')
+    if (version != expected_version or compression !=
+            BUNDLESPEC_COMPRESSIONS.get(expected_compression)):
+        raise Exception(
+            '%s: bundle does not match its BUNDLESPEC (expected %s, found '
+            '%s-%s)' % (fsdecode(path), typ.decode('ascii'),
+                        next((k for k, v in BUNDLESPEC_COMPRESSIONS.items()
+                              if v == compression),
+                             compression).decode('ascii'),
+                        version.decode('ascii')))
+
+
+def unbundle_fh(fh, path, spec=None):
     header = readexactly(fh, 4)
     magic, version = header[0:2], header[2:4]
     if magic != b'HG':
         raise Exception('%s: not a Mercurial bundle' % fsdecode(path))
+    if version == b'S1':
+        if spec:
+            check_bundlespec(path, spec, b'packed1', b'UN')
+        return streamclone.packed1(fh)
     if version == b'10':
         alg = readexactly(fh, 2)
+        if spec:
+            check_bundlespec(path, spec, b'v1', alg)
         return cg1unpacker(fh, alg)
     elif unbundle20 and version.startswith(b'2'):
-        return unbundle20(get_ui(), fh)
+        bundle = unbundle20(get_ui(), fh)
+        if spec:
+            params = getattr(bundle, 'params', None) or {}
+            check_bundlespec(path, spec, b'v2',
+                             params.get(b'Compression', b'UN'))
+        return bundle
     else:
         raise Exception('%s: unsupported bundle version %s' % (fsdecode(path),
                         version.decode('ascii')))
@@ -626,10 +783,8 @@ class bundlerepo(object):
 
         def repo_unbundler():
             yield iter(chunks)
-            yield next(raw_unbundler, None)
-            yield next(raw_unbundler, None)
-            if next(raw_unbundler, None) is not None:
-                assert False
+            for item in raw_unbundler:
+                yield item
 
         self._unbundler = repo_unbundler()
 
@@ -652,10 +807,52 @@ class bundlerepo(object):
         return [h in self._dag for h in heads]
 
 
+def read_rev_branch_cache(part):
+    '''Returns a dict associating the changesets listed in a
+    cache:rev-branch-cache bundle2 part with their branch.'''
+    branches = {}
+    while True:
+        header = part.read(12)
+        if not header:
+            break
+        branch_len, nodes, closed = struct.unpack('>III', header)
+        branch = readexactly(part, branch_len)
+        for _ in range(nodes + closed):
+            branches[hexlify(readexactly(part, 20))] = branch
+    return branches
+
+
+def verify_digest(bundle):
+    '''Verifies the digest of bundles from get_clonebundle, once all their
+    data has been read, and before their changesets are stored.'''
+    verify = getattr(bundle, 'verify_digest', None)
+    if verify:
+        verify()
+
+
 def unbundler(bundle):
+    '''Yields the changesets, manifests and files sections of the given
+    bundle, followed, when the bundle contains a cache:rev-branch-cache
+    part, by a dict associating changesets with their branch.'''
+    if isinstance(bundle, streamclone.StreamBundle):
+        for item in bundle.unbundler():
+            yield item
+        verify_digest(bundle)
+        return
+    branches = {}
     if unbundle20 and isinstance(bundle, unbundle20):
         parts = iter(bundle.iterparts())
         for part in parts:
+            if part.type == b'stream2':
+                logging.getLogger('bundle2').debug('part: %s', part.type)
+                for item in streamclone.stream2(part).unbundler():
+                    yield item
+                verify_digest(bundle)
+                return
+            if part.type == b'cache:rev-branch-cache':
+                logging.getLogger('bundle2').debug('part: %s', part.type)
+                branches.update(read_rev_branch_cache(part))
+                continue
             if part.type != b'changegroup':
                 logging.getLogger('bundle2').warning(
                     'ignoring bundle2 part: %s', part.type)
@@ -667,6 +864,8 @@ def unbundler(bundle):
                 chunk_type = RawRevChunk01
             elif version == b'02':
                 chunk_type = RawRevChunk02
+            elif version == b'03':
+                chunk_type = rev_chunk_03
             else:
                 raise Exception('Unknown changegroup version %s'
                                 % version.decode('ascii'))
@@ -675,6 +874,7 @@ def unbundler(bundle):
         else:
             raise Exception('No changegroups in the bundle')
     else:
+        version = b'01'
         chunk_type = RawRevChunk01
         cg = bundle
 
@@ -686,7 +886,7 @@ def unbundler(bundle):
                 self.out.write(b'HG20\0\0\0\0')
                 self.out.write(b'\0\0\0\x1d\x0bCHANGEGROUP\0\0\0\0')
                 self.out.write(b'\x01\x00\x07\x02version')
-                self.out.write(b'01' if chunk_type is RawRevChunk01 else b'02')
+                self.out.write(version)
 
             def read(self, length=None):
                 data = self.cg.read(length)
@@ -701,6 +901,10 @@ def unbundler(bundle):
 
     yield chunks_in_changegroup(chunk_type, cg, 'changeset')
     yield chunks_in_changegroup(chunk_type, cg, 'manifest')
+    if version == b'03' and getchunk(cg):
+        # Changegroup v3 has a section for tree manifests, which we expect
+        # to be empty.
+        raise Exception('Tree manifests are not supported')
     yield iterate_files(chunk_type, cg)
 
     if hasattr(cg, "end_bundle"):
@@ -709,14 +913,23 @@ def unbundler(bundle):
 
     if unbundle20 and isinstance(bundle, unbundle20):
         for part in parts:
+            if part.type == b'cache:rev-branch-cache':
+                logging.getLogger('bundle2').debug('part: %s', part.type)
+                branches.update(read_rev_branch_cache(part))
+                continue
             logging.getLogger('bundle2').warning(
                 'ignoring bundle2 part: %s', part.type)
 
+    verify_digest(bundle)
 
-def get_clonebundle_url(repo):
+    if branches:
+        yield branches
+
+
+def get_clonebundle_urls(repo):
     bundles = repo._call(b'clonebundles')
 
-    supported_bundles = (b'v1', b'v2')
+    supported_bundles = (b'v1', b'v2', b'packed1')
     supported_compressions = tuple(
         k for k, v in (
             (b'none', b'UN'),
@@ -730,6 +943,7 @@ def get_clonebundle_url(repo):
 
     logger = logging.getLogger('clonebundle')
 
+    candidates = []
     for line in bundles.splitlines():
         attrs = line.split()
         if not attrs:
@@ -753,6 +967,10 @@ def get_clonebundle_url(repo):
         typ, _, params = spec.partition(b';')
         compression, _, version = typ.partition(b'-')
 
+        if version == b'packed1' and compression != b'none':
+            logger.debug('Skip because of invalid packed1 compression (%s)',
+                         compression)
+            continue
         if compression not in supported_compressions:
             logger.debug('Skip because unsupported compression (%s)',
                          compression)
@@ -763,42 +981,179 @@ def get_clonebundle_url(repo):
             continue
 
         params_dict = {}
-        for p in params.split(b':'):
+        for p in params.split(b';'):
             k, _, v = p.partition(b'=')
-            params_dict[k] = v
+            params_dict[unquote_to_bytes(k)] = unquote_to_bytes(v)
+
+        stream = version == b'packed1' or b'stream' in params_dict
+        if stream:
+            if params_dict.get(b'stream', b'v2') != b'v2':
+                logger.debug('Skip because of unsupported stream version '
+                             '(%s)', params_dict[b'stream'])
+                continue
+            unsupported = streamclone.unsupported_requirements(
+                streamclone.parse_requirements(
+                    params_dict.get(b'requirements', b'')))
+            if unsupported:
+                logger.debug('Skip because of unsupported requirements (%s)',
+                             b', '.join(sorted(unsupported)))
+                continue
 
-        if b'stream' in params_dict:
-            logger.debug('Skip because stream bundles are not supported')
+        # Like mercurial, expose the parts of the BUNDLESPEC as attributes
+        # that can be used for preferences.
+        attrs.setdefault(b'COMPRESSION', compression)
+        attrs.setdefault(b'VERSION', version)
+        candidates.append((url, stream, attrs))
+
+    # cinnabar.clonebundle-prefers works like mercurial's
+    # ui.clonebundleprefers: a list of KEY=VALUE attributes, in order of
+    # preference. Entries matching none of them keep the server order.
+    prefers = Git.config('cinnabar.clonebundle-prefers', remote=repo.remote)
+    prefers = [
+        p.partition(b'=')[::2] for p in (prefers or b'').replace(
+            b',', b' ').split()
+    ]
+
+    def preference(candidate):
+        attrs = candidate[-1]
+        return tuple(attrs.get(k) != v for k, v in prefers)
+
+    for url, stream, attrs in sorted(candidates, key=preference):
+        yield url, stream, attrs
+
+
+def parse_digest(value):
+    '''Returns the (algorithm, hex digest) from a clone bundle DIGEST
+    attribute, of the form <algorithm>:<hex digest>[,...], for the first
+    algorithm we support.'''
+    if not value:
+        return None
+    for digest in value.split(b','):
+        algorithm, _, expected = digest.partition(b':')
+        algorithm = algorithm.lower().decode('ascii', 'replace')
+        if algorithm in ('sha256', 'sha512') and expected:
+            return algorithm, expected
+    logging.getLogger('clonebundle').warning(
+        'Ignoring unsupported clone bundle digest: %s', fsdecode(value))
+    return None
+
+
+def get_clonebundles(repo):
+    '''Yields (url, bundle) for the clone bundles that could be opened, in
+    order of preference.'''
+    url = Git.config('cinnabar.clonebundle', remote=repo.remote)
+    limit_schemes = False
+    if url == b'':
+        return
+    if url:
+        urls = [(url, False, {})]
+    else:
+        urls = get_clonebundle_urls(repo)
+        limit_schemes = True
+
+    for url, stream, attrs in urls:
+        parsed_url = urlparse(url)
+        if limit_schemes and parsed_url.scheme not in (b'http', b'https'):
+            logging.warn('Server advertizes clone bundle but provided a non '
+                         'http/https url. Skipping.')
             continue
 
-        return url
+        digest = parse_digest(attrs.get(b'DIGEST'))
+        sys.stderr.write('Getting clone bundle from %s\n' % fsdecode(url))
+        try:
+            if stream or digest:
+                # Stream clone bundles are not compressed, and can't be
+                # handled by the helper. Neither can bundles whose digest
+                # we need to compute.
+                reader = ProgressReader(HTTPReader(url))
+                if digest:
+                    reader = DigestReader(reader, *digest, name=url)
+                bundle = unbundle_fh(reader, url, attrs.get(b'BUNDLESPEC'))
+                if digest:
+                    bundle.verify_digest = reader.verify
+            else:
+                bundle = get_bundle(url)
+        except Exception as e:
+            logging.warn('Failed to get clone bundle from %s: %s',
+                         fsdecode(url), e)
+            continue
+        yield url, bundle
 
 
 def get_clonebundle(repo):
-    url = Git.config('cinnabar.clonebundle', remote=repo.remote)
-    limit_schemes = False
-    if not url:
-        url = get_clonebundle_url(repo)
-        limit_schemes = True
+    return next((bundle for _, bundle in get_clonebundles(repo)), None)
 
-    if not url:
-        return None
 
-    parsed_url = urlparse(url)
-    if limit_schemes and parsed_url.scheme not in (b'http', b'https'):
-        logging.warn('Server advertizes clone bundle but provided a non '
-                     'http/https url. Skipping.')
+def apply_clonebundle(repo, store):
+    '''Applies the first clone bundle that can be fully downloaded, in
+    order of preference. Returns whether one was applied.'''
+    for url, bundle in get_clonebundles(repo):
+        try:
+            BundleApplier(unbundler(bundle), repo.url())(store)
+        except Abort:
+            raise
+        except Exception as e:
+            # The download can also fail midway, in which case the next
+            # bundle is tried. What was already stored is harmless: the
+            # changesets are only stored once the bundle was fully read.
+            logging.warn('Failed to get clone bundle from %s: %s',
+                         fsdecode(url), e)
+            continue
+        finally:
+            if not changegroup:
+                BundleHelper.close()
+        return True
+    return False
+
+
+def get_stream_clone(repo):
+    # Only the mercurial libraries give access to the raw stream.
+    stream_out = getattr(repo, 'stream_out', None)
+    if not stream_out:
+        return None
+    if repo.capable(b'stream'):
+        requirements = set((b'revlogv1',))
+    else:
+        streamreqs = repo.capable(b'streamreqs')
+        if not streamreqs:
+            return None
+        requirements = streamclone.parse_requirements(streamreqs)
+
+    # Like mercurial, only use stream clones when the server prefers them,
+    # unless explicitly enabled or disabled.
+    enabled = Git.config('cinnabar.stream-clone', remote=repo.remote,
+                         values={
+                             None: None,
+                             b'false': False,
+                             b'true': True,
+                         })
+    if enabled is None:
+        enabled = bool(repo.capable(b'stream-preferred'))
+    if not enabled:
+        return None
+    unsupported = streamclone.unsupported_requirements(requirements)
+    if unsupported:
+        logging.warn('Cannot use stream clone because of unsupported '
+                     'repository requirements: %s',
+                     ', '.join(r.decode('ascii') for r in sorted(unsupported)))
         return None
 
-    sys.stderr.write('Getting clone bundle from %s\n' % fsdecode(url))
-    return get_bundle(url)
+    sys.stderr.write('Getting stream clone from %s\n' % fsdecode(repo.url()))
+    return streamclone.stream_out(stream_out(), requirements)
 
 
 def get_bundle(url):
     reader = None
     if not changegroup:
-        reader = BundleHelper.connect(url)
-        if not reader:
+        try:
+            reader = BundleHelper.connect(url)
+        except HelperClosedError:
+            # The helper can only handle one bundle, and was closed after
+            # a previous one failed.
+            reader = None
+        if reader:
+            reader = ThrottledReader(reader)
+        else:
             BundleHelper.close()
     if not reader:
         reader = HTTPReader(url)
@@ -814,37 +1169,56 @@ def store_changegroup(changegroup):
     if isinstance(first_changeset, RawRevChunk02):
         version = 2
     with GitHgHelper.store_changegroup(version) as fh:
+        # Number of empty chunks the helper still expects before the end
+        # of the changegroup: one for each of the changesets, manifests and
+        # files sections, and one for the file being sent, if any. When
+        # the changegroup is not read entirely, e.g. because its download
+        # failed, they are sent so that the helper doesn't wait for more.
+        ends = [3]
+
+        def end():
+            fh.write(struct.pack('>l', 0))
+            ends[0] -= 1
+
         def iter_chunks(iter):
             for chunk in iter:
                 fh.write(struct.pack('>l', len(chunk) + 4))
                 fh.write(chunk)
                 yield chunk
-            fh.write(struct.pack('>l', 0))
-
-        yield iter_chunks(chain((first_changeset,), changesets))
-        yield iter_chunks(next(changegroup, None))
+            end()
 
         def iter_files(iter):
             last_name = None
             for name, chunk in iter:
                 if name != last_name:
                     if last_name is not None:
-                        fh.write(struct.pack('>l', 0))
+                        end()
                     fh.write(struct.pack('>l', len(name) + 4))
                     fh.write(name)
+                    ends[0] += 1
                 last_name = name
                 fh.write(struct.pack('>l', len(chunk) + 4))
                 fh.write(chunk)
                 yield name, chunk
             if last_name is not None:
-                fh.write(struct.pack('>l', 0))
-            fh.write(struct.pack('>l', 0))
+                end()
+            end()
 
-        yield iter_files(next(changegroup, None))
+        try:
+            yield iter_chunks(chain((first_changeset,), changesets))
+            yield iter_chunks(next(changegroup, None))
+            yield iter_files(next(changegroup, None))
+        finally:
+            while ends[0]:
+                end()
 
         if check_enabled('unbundler') and "GIT_DIR" in os.environ:
             yield next(changegroup)
 
+        branches = next(changegroup, None)
+        if branches is not None:
+            yield branches
+
         if next(changegroup, None) is not None:
             assert False
 
@@ -852,18 +1226,84 @@ def store_changegroup(changegroup):
 stored_files = OrderedDict()
 
 
+def quarantine(kind, node, data):
+    '''Saves the data for the given node, which doesn't match its node
+    hash, in the cinnabar-quarantine directory of the git repository, and
+    returns the path of the file.'''
+    git_dir = one(Git.iter('rev-parse', '--git-dir'))
+    directory = os.path.join(fsdecode(git_dir), 'cinnabar-quarantine')
+    if not os.path.isdir(directory):
+        os.makedirs(directory)
+    path = os.path.join(directory, '%s-%s' % (kind, node.decode('ascii')))
+    with open(path, 'wb') as fh:
+        fh.write(data)
+    return path
+
+
 class BundleApplier(object):
-    def __init__(self, bundle):
+    # Number of manifests or files checked per helper round-trip with
+    # cinnabar.check=nodes.
+    VERIFY_BATCH = 1000
+
+    def __init__(self, bundle, source=None):
         self._bundle = store_changegroup(bundle)
+        self._source = source
+        self.heads = []
 
     def __call__(self, store):
+        try:
+            with stats.timer('changegroup application'):
+                self._apply(store)
+        finally:
+            # When the changegroup was not entirely read, let the helper
+            # know it ended.
+            bundle = getattr(self, '_bundle', None)
+            if bundle:
+                bundle.close()
+            ProgressReader.reset()
+
+    def _mismatch(self, kind, node, data):
+        path = quarantine(kind, node, data)
+        # Manifests are stored as git trees, so what can be saved is what
+        # git-cinnabar reconstructs from them, not what the server sent.
+        logging.error('sha1 mismatch for %s %s%s. Its data%s was saved in %s.',
+                      kind, node.decode('ascii'),
+                      ' from %s' % fsdecode(self._source)
+                      if self._source else '',
+                      ', as reconstructed by git-cinnabar,'
+                      if kind == 'manifest' else '', path)
+
+    def _verify(self, kind, items, check):
+        '''Yields the given items that `check` reports as not matching
+        their node, checking them in batches.'''
+        items = progress_iter('Verifying {} %s' % kind, items)
+        while True:
+            batch = list(islice(items, self.VERIFY_BATCH))
+            if not batch:
+                break
+            for item, ok in zip(batch, check(batch)):
+                if not ok:
+                    yield item
+
+    def _apply(self, store):
+        # With cinnabar.check=nodes, the node hashes of all the changesets,
+        # manifests and files are recomputed, and the import aborted on
+        # mismatch, before any metadata is stored. With cinnabar.check=fast,
+        # even the usual check of file roots and heads is skipped.
+        verify = check_enabled('nodes')
+        fast = check_enabled('fast') and not verify
+        manifests = []
+        files = []
+
         changeset_chunks = ChunksCollection(progress_iter(
             'Reading {} changesets', next(self._bundle, None)))
 
         for rev_chunk in progress_iter(
                 'Reading and importing {} manifests',
                 next(self._bundle, None)):
-            pass
+            stats.count('manifests')
+            if verify:
+                manifests.append(rev_chunk.node)
 
         def enumerate_files(iterator):
             null_parents = (NULL_NODE_ID, NULL_NODE_ID)
@@ -872,8 +1312,15 @@ class BundleApplier(object):
             for count_chunks, (name, chunk) in enumerate(iterator, start=1):
                 if name != last_name:
                     count_names += 1
+                    stats.count('files')
                 last_name = name
+                stats.count('file revisions')
                 parents = (chunk.parent1, chunk.parent2)
+                if verify:
+                    files.append((chunk.node,) + parents)
+                if fast:
+                    yield (count_chunks, count_names), chunk
+                    continue
                 # Try to detect issue #207 as early as possible.
                 # Keep track of file roots of files with metadata and at least
                 # one head that can be traced back to each of those roots.
@@ -901,18 +1348,52 @@ class BundleApplier(object):
         if check_enabled('unbundler') and "GIT_DIR" in os.environ:
             store.bundle_blob = next(self._bundle)
 
+        # When the server sent a rev-branch-cache, use it instead of the
+        # changeset extras to know the branch of the heads.
+        branches = next(self._bundle, None) or {}
+
         if next(self._bundle, None) is not None:
             assert False
         del self._bundle
 
+        if verify:
+            # The helper can only be asked to recompute the hashes of the
+            # manifests and files it stored once it is done with the
+            # changegroup.
+            mismatches = False
+            for node in self._verify('manifests', manifests,
+                                     GitHgHelper.check_manifests):
+                self._mismatch('manifest', node, GitHgHelper.manifest(node))
+                mismatches = True
+            for node, parent1, parent2 in self._verify(
+                    'file revisions', files, GitHgHelper.check_files):
+                self._mismatch('file', node, store.file(node).raw_data)
+                mismatches = True
+            if mismatches:
+                raise Abort('The bundle contains corrupted data. '
+                            'No metadata was stored.')
+
+        heads = OrderedDict()
         for cs in progress_iter(
                 'Importing {} changesets',
                 changeset_chunks.iter_initialized(lambda x: x, store.changeset,
                                                   Changeset.from_chunk)):
+            if verify and cs.node != cs.sha1:
+                self._mismatch('changeset', cs.node, cs.raw_data)
+                raise Abort('The bundle contains corrupted data. '
+                            'No metadata was stored.')
             try:
                 store.store_changeset(cs)
             except NothingToGraftException:
                 logging.debug('Cannot graft %s, not importing.', cs.node)
+                continue
+            stats.count('changesets')
+            for p in cs.parents:
+                heads.pop(p, None)
+            heads[cs.node] = branches.get(cs.node) or cs.branch or \
+                b'default'
+        # The (node, branch) of the heads of the imported changesets.
+        self.heads = list(heads.items())
 
 
 SHA1_RE = re.compile(b'[0-9a-fA-F]{1,40}$')
@@ -923,6 +1404,8 @@ def do_cinnabarclone(repo, manifest, store, limit_schemes=True):
         None: None,
         b'false': False,
         b'true': True,
+        b'fuzzy': 'fuzzy',
+        b'patch-id': 'patch-id',
     }
     try:
         enable_graft = Git.config(
@@ -1004,15 +1487,66 @@ def do_cinnabarclone(repo, manifest, store, limit_schemes=True):
     return store.merge(url, repo.url(), branch)
 
 
+# Capability of servers supporting the narrow extension.
+NARROW_CAP = b'exp-narrow-1'
+
+
+def narrow_spec():
+    '''Returns the lists of directories to include and exclude when the
+    repository is a narrow clone. Both are empty otherwise.'''
+    spec = []
+    for kind in ('include', 'exclude'):
+        value = Git.config('cinnabar.narrow-%s' % kind) or b''
+        paths = [p.strip().strip(b'/') for p in value.split(b',')]
+        paths = [p or b'.' for p in paths if p]
+        for p in paths:
+            if b' ' in p:
+                raise Exception('Narrow clones of paths containing spaces '
+                                'are not supported.')
+        spec.append(paths)
+    include, exclude = spec
+    if exclude and not include:
+        include = [b'.']
+    return include, exclude
+
+
+def narrow_match(include, exclude, path):
+    '''Returns whether the given path is part of the narrow clone with the
+    given spec.'''
+    def match(patterns):
+        return any(p == b'.' or path == p or path.startswith(p + b'/')
+                   for p in patterns)
+    return match(include) and not match(exclude)
+
+
+def discovery_heads(repo, store, branch_names):
+    '''Returns the local heads to start the discovery of common changesets
+    with the given remote from. The heads last seen on that remote at the
+    end of the last pull from it are preferred to all the local heads, which
+    may include heads from other remotes. When nothing changed on the remote
+    since, its heads are all already known, and discovery doesn't need to
+    ask it anything else.'''
+    heads = set(h for h in stored_remote_heads(repo.remote)
+                if store.changeset_ref(h))
+    return heads or store.heads(branch_names)
+
+
 def getbundle(repo, store, heads, branch_names):
+    include, exclude = narrow_spec()
+    narrow = bool(include or exclude)
     if isinstance(repo, bundlerepo):
+        if narrow:
+            raise Exception('Narrow clones can not be created from bundles.')
         bundle = repo._unbundler
     else:
-        common = findcommon(repo, store, store.heads(branch_names))
+        common = findcommon(
+            repo, store, discovery_heads(repo, store, branch_names))
         logging.info('common: %s', common)
         bundle = None
         got_partial = False
-        if not common:
+        # Clone bundles and stream clones contain everything, which is not
+        # what narrow clones want.
+        if not common and not narrow:
             if not store._has_metadata:
                 manifest = Git.config('cinnabar.clone', remote=repo.remote)
                 limit_schemes = False
@@ -1033,14 +1567,16 @@ def getbundle(repo, store, heads, branch_names):
                             raise Exception('cinnabarclone failed.')
                         logging.warn('Falling back to normal clone.')
             if not got_partial and repo.capable(b'clonebundles'):
-                bundle = get_clonebundle(repo)
-                got_partial = bool(bundle)
+                got_partial = apply_clonebundle(repo, store)
                 if not got_partial and check_enabled('clonebundles'):
                     raise Exception('clonebundles failed.')
+            if not got_partial and not store._has_metadata:
+                bundle = get_stream_clone(repo)
+                got_partial = bool(bundle)
         if bundle:
             bundle = unbundler(bundle)
             # Manual move semantics
-            apply_bundle = BundleApplier(bundle)
+            apply_bundle = BundleApplier(bundle, repo.url())
             del bundle
             apply_bundle(store)
             if not changegroup:
@@ -1051,19 +1587,34 @@ def getbundle(repo, store, heads, branch_names):
             heads = [h for h in heads if not store.changeset_ref(h)]
             if not heads:
                 return
-            common = findcommon(repo, store, store.heads(branch_names))
+            common = findcommon(
+                repo, store, discovery_heads(repo, store, branch_names))
             logging.info('common: %s', common)
 
         kwargs = {}
         if unbundle20 and repo.capable(b'bundle2'):
+            # The rev-branch-cache part is not requested: when pulling, the
+            # branches of the changesets are read from the changesets
+            # themselves, and those of the remote heads come from the
+            # branchmap. It's only used in bundles that already contain it.
             bundle2caps = {
                 b'HG20': (),
                 b'changegroup': (b'01', b'02'),
             }
+            # Servers with the lfs extension only send changegroups with
+            # revlog flags.
+            if repo.capable(b'lfs'):
+                bundle2caps[b'changegroup'] += (b'03',)
             kwargs['bundlecaps'] = set((
                 b'HG20',
                 b'bundle2=%s' % quote_from_bytes(
                     encodecaps(bundle2caps)).encode('ascii')))
+        if narrow:
+            if not repo.capable(NARROW_CAP):
+                raise Exception('Remote does not support narrow clones.')
+            kwargs['narrow'] = True
+            kwargs['includepats'] = [b'path:%s' % p for p in include]
+            kwargs['excludepats'] = [b'path:%s' % p for p in exclude]
 
         bundle = repo.getbundle(b'bundle', heads=[unhexlify(h) for h in heads],
                                 common=[unhexlify(h) for h in common],
@@ -1072,12 +1623,15 @@ def getbundle(repo, store, heads, branch_names):
         bundle = unbundler(bundle)
 
     # Manual move semantics
-    apply_bundle = BundleApplier(bundle)
+    apply_bundle = BundleApplier(bundle, repo.url())
     del bundle
     apply_bundle(store)
 
 
-def push(repo, store, what, repo_heads, repo_branches, dry_run=False):
+def outgoing(repo, store, what, repo_heads, repo_branches):
+    '''Returns the list of (commit, parents) that pushing the given
+    (source, dest, force) to the given repository would create changesets
+    for, in topological order.'''
     def heads():
         for sha1 in store.heads(repo_branches):
             yield b'^%s' % store.changeset_ref(sha1)
@@ -1123,10 +1677,179 @@ def push(repo, store, what, repo_heads, repo_branches, dry_run=False):
             yield b'^%s' % store.changeset_ref(sha1)
 
     revs = chain(revs(), (w for w, _, _ in what if w))
-    push_commits = list((c, p) for c, t, p in GitHgHelper.rev_list(
+    return list((c, p) for c, t, p in GitHgHelper.rev_list(
         b'--topo-order', b'--full-history', b'--parents', b'--reverse', *revs))
 
+
+def is_ancestor(commit, descendant):
+    return GitProcess('merge-base', '--is-ancestor', commit, descendant,
+                      stdout=open(os.devnull, 'wb')).wait() == 0
+
+
+def commit_branches(store, commits):
+    '''Returns a dict associating the given (commit, parents) with the
+    branch of their changeset. Commits without mercurial metadata get the
+    branch of their first parent, like when they are pushed.'''
+    branches = {}
+    for commit, parents in commits:
+        node = store.hg_changeset(commit)
+        if node:
+            branch = store.changeset(node).branch
+        elif parents and parents[0] in branches:
+            branch = branches[parents[0]]
+        elif parents:
+            node = store.hg_changeset(parents[0])
+            branch = node and store.changeset(node).branch
+        else:
+            branch = None
+        branches[commit] = branch or b'default'
+    return branches
+
+
+def new_heads(store, push_commits, repo_heads):
+    '''Returns the (commit, branch, new_branch) for the heads of the given
+    (commit, parents) that would add a head to the remote, which has the
+    given heads. new_branch tells whether the branch is new on the remote.
+    Remote heads that are not known locally may be on any branch, so no
+    branch is considered new when there are some.
+    '''
+    branches = commit_branches(store, push_commits)
+    remote_heads = defaultdict(list)
+    unknown_heads = False
+    for head in repo_heads:
+        if head == NULL_NODE_ID:
+            continue
+        commit = store.changeset_ref(head)
+        if commit:
+            branch = store.changeset(head).branch or b'default'
+            remote_heads[branch].append(commit)
+        else:
+            unknown_heads = True
+    children = set(p for _, parents in push_commits for p in parents)
+    result = []
+    for commit, _ in push_commits:
+        if commit in children:
+            continue
+        branch = branches[commit]
+        heads = remote_heads[branch]
+        if any(is_ancestor(head, commit) for head in heads):
+            continue
+        result.append((commit, branch, not heads and not unknown_heads))
+        # Other heads pushed to the same branch are new heads.
+        heads.append(commit)
+    return result
+
+
+def report_push(store, push_commits, repo_heads):
+    '''Reports the changesets created for the given (commit, parents), and
+    whether they would add heads to the remote, which has the given heads.
+    '''
+    for commit, parents in push_commits:
+        node = store.hg_changeset(commit)
+        branch = store.changeset(node).branch or b'default'
+        sys.stderr.write('Would push changeset %s (git commit %s) on branch '
+                         '%s\n' % (fsdecode(node), fsdecode(commit),
+                                    fsdecode(branch)))
+    for commit, branch, new_branch in new_heads(store, push_commits,
+                                                 repo_heads):
+        sys.stderr.write('Would create a new %s %s: %s\n' % (
+            'branch' if new_branch else 'head on branch', fsdecode(branch),
+            fsdecode(store.hg_changeset(commit))))
+
+
+PUSH_POLICY = {
+    None: b'allow',
+    b'allow': b'allow',
+    b'ask': b'ask',
+    b'deny': b'deny',
+}
+
+
+def check_push_policy(repo, store, push_commits, repo_heads, dry_run=False):
+    '''Enforces the cinnabar.push.allow-new-branch and
+    cinnabar.push.allow-new-head configurations.'''
+    try:
+        policies = {
+            kind: Git.config('cinnabar.push.allow-new-%s' % kind,
+                             remote=repo.remote, values=PUSH_POLICY)
+            for kind in ('branch', 'head')
+        }
+    except InvalidConfig as e:
+        raise Abort(str(e))
+    if all(p == b'allow' for p in policies.values()):
+        return
+    for commit, branch, new_branch in new_heads(store, push_commits,
+                                                 repo_heads):
+        kind = 'branch' if new_branch else 'head'
+        message = 'Pushing %s would create a new %s %s' % (
+            fsdecode(commit), 'branch' if new_branch else 'head on branch',
+            fsdecode(branch))
+        policy = policies[kind]
+        if policy == b'ask' and not dry_run:
+            allowed = ask('%s. Continue?' % message)
+        else:
+            allowed = policy != b'deny'
+        if not allowed:
+            raise Exception(
+                '%s.\nSet cinnabar.push.allow-new-%s to allow it, or push '
+                'with --force.' % (message, kind))
+
+
+def affected_heads(store, push_commits, repo_heads):
+    '''Returns the given (binary) remote heads that are on the branches
+    the changesets for the given (commit, parents) would be on. Heads that
+    are not known locally are assumed to be on those branches. The null
+    head of empty repositories is not a branch head, and is excluded.'''
+    branches = set(itervalues(commit_branches(store, push_commits)))
+    result = []
+    for head in repo_heads:
+        node = hexlify(head)
+        if node == NULL_NODE_ID:
+            continue
+        if not store.changeset_ref(node) or \
+                (store.changeset(node).branch or b'default') in branches:
+            result.append(head)
+    return result
+
+
+def get_pushvars(repo, push_options=()):
+    '''Returns the (key, value) pushvars from the cinnabar.pushvars
+    configuration, a whitespace-separated list of KEY=VALUE, followed by
+    those from the given push options.'''
+    config = Git.config('cinnabar.pushvars', remote=repo.remote) or b''
+    pushvars = []
+    for var in chain(config.split(), push_options):
+        if b'=' not in var:
+            raise Exception(
+                "Unable to parse pushvar '%s'. It should follow the "
+                "'KEY=VALUE' or 'KEY=' format." % fsdecode(var))
+        pushvars.append(tuple(var.split(b'=', 1)))
+    return pushvars
+
+
+def push(repo, store, what, repo_heads, repo_branches, dry_run=False,
+         push_options=(), bookmarks=()):
+    '''Pushes the given (source, dest, force) to the given repository.
+    The given (name, old hg node, new git commit) bookmarks are updated
+    along the changesets when the server allows it. Returns the pushed
+    commits and the names of the bookmarks that were updated.'''
+    logger = logging.getLogger('push')
+    push_commits = outgoing(repo, store, what, repo_heads, repo_branches)
+    logger.info('%d outgoing changesets', len(push_commits))
+    pushvars = get_pushvars(repo, push_options)
+    force = all(v for _, _, v in what)
+    if push_commits and not force:
+        check_push_policy(repo, store, push_commits, repo_heads, dry_run)
+    if push_commits and dry_run:
+        # Go through the whole conversion, without sending the result, so
+        # that the changesets that would be pushed can be reported. The
+        # caller is expected to roll the store back.
+        for _ in create_bundle(store, push_commits):
+            pass
+        report_push(store, push_commits, repo_heads)
+
     pushed = False
+    bundle_bookmarks = ()
     if push_commits:
         has_root = any(not p for (c, p) in push_commits)
         if has_root and repo_heads:
@@ -1150,14 +1873,38 @@ def push(repo, store, what, repo_heads, repo_branches, dry_run=False):
         if b2caps:
             b2caps = decodecaps(unquote_to_bytes(b2caps))
         logging.getLogger('bundle2').debug('%r', b2caps)
+        check_heads = None
         if b2caps:
-            b2caps[b'replycaps'] = encodecaps({b'error': [b'abort']})
-        cg = create_bundle(store, push_commits, b2caps)
-        if not isinstance(repo, HelperRepo):
+            b2caps[b'replycaps'] = encodecaps(
+                {b'error': [b'abort', b'pushraced']})
+            # Like mercurial, let the server check the heads atomically
+            # with bundle2 parts instead of the unbundle command argument.
+            if not force:
+                if b'related' in b2caps.get(b'checkheads', ()):
+                    check_heads = (b'CHECK:UPDATED-HEADS', affected_heads(
+                        store, push_commits, repo_heads))
+                else:
+                    check_heads = (b'CHECK:HEADS', repo_heads)
+                logger.debug('checking heads with %s', check_heads[0])
+                repo_heads = [b'force']
+        elif pushvars:
+            raise Exception('Pushvars can only be sent to servers '
+                            'supporting bundle2.')
+        # Otherwise, the bookmarks are updated with pushkey afterwards.
+        if b'bookmarks' in b2caps:
+            bundle_bookmarks = bookmarks
+        cg = create_bundle(store, push_commits, b2caps,
+                           check_heads=check_heads, pushvars=pushvars,
+                           bookmarks=bundle_bookmarks)
+        if not isinstance(repo, (HelperRepo, RevlogRepo)):
             cg = chunkbuffer(cg)
             if not b2caps:
                 cg = cg1unpacker(cg, b'UN')
-        reply = repo.unbundle(cg, repo_heads, b'')
+        logger.info('sending %s with %d pushvars and %d bookmarks',
+                    'bundle2' if b2caps else 'changegroup', len(pushvars),
+                    len(bundle_bookmarks))
+        with stats.timer('bundle creation and upload'):
+            reply = repo.unbundle(cg, repo_heads, b'')
         if unbundle20 and isinstance(reply, unbundle20):
             parts = iter(reply.iterparts())
             for part in parts:
@@ -1168,6 +1915,9 @@ def push(repo, store, what, repo_heads, repo_branches, dry_run=False):
                 elif part.type == b'reply:changegroup':
                     # TODO: should check params['in-reply-to']
                     reply = int(part.params[b'return'])
+                elif part.type == b'error:pushraced':
+                    raise PushRejectedAbort(
+                        part.params[b'message'].decode('utf-8'))
                 elif part.type == b'error:abort':
                     message = part.params[b'message'].decode('utf-8')
                     hint = part.params.get(b'hint')
@@ -1178,7 +1928,14 @@ def push(repo, store, what, repo_heads, repo_branches, dry_run=False):
                     logging.getLogger('bundle2').warning(
                         'ignoring bundle2 part: %s', part.type)
         pushed = reply != 0
-    return gitdag(push_commits) if pushed or dry_run else ()
+        logger.info('server replied %r', reply)
+        if pushed:
+            stats.count('pushed changesets', len(push_commits))
+        approve_credentials()
+    if not pushed:
+        bundle_bookmarks = ()
+    return (gitdag(push_commits) if pushed or dry_run else (),
+            set(name for name, _, _ in bundle_bookmarks))
 
 
 def get_ui():
@@ -1249,6 +2006,17 @@ class Remote(object):
         self.url = urlunparse(self.parsed_url)
         self.git_url = url if url.startswith(b'hg://') else b'hg::%s' % url
 
+    def local_path(self):
+        '''Returns the local path for file urls, or None.'''
+        if self.parsed_url.scheme != b'file':
+            return None
+        # Make file://c:/... paths work by taking the netloc
+        path = self.parsed_url.netloc + self.parsed_url.path
+        if sys.platform == 'win32':
+            # TODO: This probably needs more thought.
+            path = path.lstrip(b'/')
+        return path
+
 
 if changegroup:
     def localpeer(ui, path):
@@ -1308,8 +2076,221 @@ def get_repo(remote):
     return repo
 
 
+def remote_heads(repo):
+    '''Returns the branchmap, heads and bookmarks of the given repository,
+    in one round-trip when the server supports batching.'''
+    if repo.capable(b'batch'):
+        if hasattr(repo, 'commandexecutor'):
+            with repo.commandexecutor() as e:
+                branchmap = e.callcommand(b'branchmap', {})
+                heads = e.callcommand(b'heads', {})
+                bookmarks = e.callcommand(b'listkeys', {
+                    b'namespace': b'bookmarks'
+                })
+            branchmap = branchmap.result()
+            heads = heads.result()
+            bookmarks = bookmarks.result()
+        elif hasattr(repo, b'iterbatch'):
+            batch = repo.iterbatch()
+            batch.branchmap()
+            batch.heads()
+            batch.listkeys(b'bookmarks')
+            batch.submit()
+            branchmap, heads, bookmarks = batch.results()
+        else:
+            batch = repo.batch()
+            branchmap = batch.branchmap()
+            heads = batch.heads()
+            bookmarks = batch.listkeys(b'bookmarks')
+            batch.submit()
+            branchmap = branchmap.value
+            heads = heads.value
+            bookmarks = bookmarks.value
+        if heads == [b'\0' * 20]:
+            heads = []
+    else:
+        while True:
+            branchmap = repo.branchmap()
+            heads = repo.heads()
+            if heads == [b'\0' * 20]:
+                heads = []
+            # Some branch heads can be non-heads topologically, but if
+            # some heads don't appear in the branchmap, then something
+            # was pushed to the repo between branchmap() and heads()
+            if set(heads).issubset(
+                    set(chain(*(v for _, v in iteritems(branchmap))))):
+                break
+        bookmarks = repo.listkeys(b'bookmarks')
+    return branchmap, heads, bookmarks
+
+
+def revset_heads(remote, repo, revset):
+    '''Returns the heads of the changesets matching the given revset in the
+    repository for the given remote. Only mercurial can evaluate revsets,
+    and only on local repositories. Otherwise, the revset can only be what
+    the `lookup` command knows: a changeset id, bookmark, branch or tag.'''
+    path = remote.local_path()
+    if changegroup and path and os.path.isdir(path):
+        local = hg.repository(get_ui(), path).filtered(b'served')
+        return [local.changelog.node(r)
+                for r in local.revs(b'heads(%r)', revset)]
+    if not repo.capable(b'lookup'):
+        raise Exception(
+            'Remote repository does not support the "lookup" command. '
+            'Please use a non-abbreviated mercurial revision.')
+    try:
+        return [repo.lookup(revset)]
+    except Exception:
+        raise Exception(
+            'Cannot resolve "%s" on the remote repository. Revsets are only '
+            'supported for local repositories, when mercurial is installed.'
+            % fsdecode(revset))
+
+
+def remote_tags(remote, repo):
+    '''Returns a dict associating the names of the tags of the repository
+    for the given remote with their changeset id (hex). Mercurial tags are
+    stored in .hgtags files, which the wire protocol doesn't give access to
+    without getting the changesets. So the tags come, in order of
+    preference, from:
+    - the `tags` pushkey namespace, for servers with an extension providing
+      it,
+    - the repository itself, for local repositories, with mercurial,
+    - the raw tags page of hgweb, for http remotes.'''
+    tags = repo.listkeys(b'tags')
+    if tags:
+        return dict(tags)
+    path = remote.local_path()
+    if changegroup and path and os.path.isdir(path):
+        local = hg.repository(get_ui(), path).filtered(b'served')
+        return dict((name, hexlify(node))
+                    for name, node in local.tags().items()
+                    if name != b'tip')
+    if remote.parsed_url.scheme in (b'http', b'https'):
+        url = urlunparse(remote.parsed_url._replace(query=b''))
+        reader = HTTPReader(b'%s/tags?style=raw' % url.rstrip(b'/'))
+        data = b''
+        while True:
+            chunk = reader.read(65536)
+            if not chunk:
+                break
+            data += chunk
+        tags = {}
+        for line in data.splitlines():
+            name, _, node = line.rpartition(b'\t')
+            if name and name != b'tip' and SHA1_RE.match(node):
+                tags[name] = node
+        return tags
+    raise Exception('Cannot get the list of tags from this remote.')
+
+
+def ssh_variant(ssh_command):
+    variant = environ(b'GIT_SSH_VARIANT') or Git.config('ssh.variant')
+    if variant and variant != b'auto':
+        return variant
+    # Like git, guess the variant from the name of the ssh program.
+    program = os.path.basename(ssh_command.split()[0]) if ssh_command \
+        else b'ssh'
+    program = program.lower()
+    if program.endswith(b'.exe'):
+        program = program[:-4]
+    if program in (b'plink', b'tortoiseplink', b'putty'):
+        return program
+    return b'ssh'
+
+
+def get_http_config(name, url, path=False):
+    '''Returns the value of the given http.* configuration that applies to
+    the given url, including from http.<url>.* configurations.'''
+    args = ('--path',) if path else ()
+    # Git.config doesn't know about url matching, so ask git config itself,
+    # capturing its output.
+    value = tuple(Git.iter('config', *(args + ('--get-urlmatch', name, url))))
+    return value[-1] if value else None
+
+
+def get_proxy(remote):
+    '''Returns the proxy git would use for the given remote, if any. Proxies
+    set in the environment are not considered, as both curl and Mercurial
+    already handle them.'''
+    if remote.name:
+        proxy = Git.config('remote.%s.proxy' % remote.name.decode('utf-8'))
+        if proxy:
+            return proxy, True
+    return get_http_config('http.proxy', remote.url), False
+
+
 def _get_repo(remote):
-    if not changegroup or experiment('wire'):
+    ssh_command = None
+    if remote.parsed_url.scheme == b'ssh':
+        ssh_command = Git.config('cinnabar.ssh', remote=remote.name)
+        if ssh_command:
+            # Make the remote-specific value available to the helper, which
+            # uses git's ssh handling, and to get_ui.
+            os.environ['GIT_SSH_COMMAND'] = fsdecode(ssh_command)
+        else:
+            ssh_command = environ(b'GIT_SSH_COMMAND') or \
+                environ(b'GIT_SSH') or Git.config('core.sshCommand')
+    # Mercurial only knows how to pass arguments to OpenSSH, while git
+    # also handles plink and tortoiseplink.
+    native_ssh = ssh_command is not None and \
+        ssh_variant(ssh_command) != b'ssh'
+    ssh_timeout = None
+    if remote.parsed_url.scheme == b'ssh':
+        ssh_timeout = Git.config('cinnabar.ssh-timeout', remote=remote.name)
+    if ssh_timeout:
+        # Timeouts are only handled by the helper.
+        os.environ['GIT_CINNABAR_SSH-TIMEOUT'] = fsdecode(ssh_timeout)
+
+    auth_command = None
+    if remote.parsed_url.scheme in (b'http', b'https'):
+        auth_command = Git.config('cinnabar.auth-command', remote=remote.name)
+    if auth_command:
+        # Tokens from the auth command are only handled by the helper.
+        # Make the remote-specific value available to it, and only to it.
+        HgRepoHelper.set_env(b'GIT_CINNABAR_AUTH_COMMAND', auth_command)
+
+    proxy = None
+    if remote.parsed_url.scheme in (b'http', b'https'):
+        proxy, remote_proxy = get_proxy(remote)
+        if remote_proxy:
+            # The helper doesn't know about the remote, so pass the
+            # remote-specific value as if it were given with `git -c`.
+            config = environ(b'GIT_CONFIG_PARAMETERS')
+            param = b"'http.proxy=%s'" % proxy.replace(b"'", b"'\\''")
+            os.environ['GIT_CONFIG_PARAMETERS'] = fsdecode(
+                b'%s %s' % (config, param) if config else param)
+    # Mercurial doesn't support SOCKS proxies.
+    native_proxy = proxy is not None and proxy.startswith(b'socks')
+
+    # Wire logs are recorded and replayed by the helper.
+    wire_log = environ(b'GIT_CINNABAR_WIRE_LOG') or \
+        environ(b'GIT_CINNABAR_WIRE_REPLAY')
+
+    path = remote.local_path()
+    if path:
+        if os.path.isfile(path):
+            return bundlerepo(path)
+        if os.path.isdir(path) and not wire_log and \
+                (not changegroup or experiment('revlog')):
+            repo = RevlogRepo.open(path, remote.url)
+            if repo:
+                return repo
+
+    # Some settings are only supported by the native wire protocol
+    # implementation, which is then used instead of the mercurial libraries.
+    native_reasons = [reason for reason, needed in (
+        ('cinnabar.auth-command', auth_command),
+        ('a non-OpenSSH ssh command', native_ssh),
+        ('cinnabar.ssh-timeout', ssh_timeout),
+        ('a SOCKS proxy', native_proxy),
+    ) if needed]
+    if changegroup and native_reasons and not experiment('wire'):
+        logging.getLogger('wire').info(
+            'Using the native wire protocol implementation because of %s.',
+            ', '.join(native_reasons))
+
+    if not changegroup or experiment('wire') or native_reasons or wire_log:
         if not changegroup and not check_enabled('no-mercurial'):
             logging.warning('Mercurial libraries not found. Falling back to '
                             'experimental native access.')
@@ -1319,15 +2300,34 @@ def _get_repo(remote):
             return bundlerepo(remote.url, stream)
         return HelperRepo(remote.url)
 
-    if remote.parsed_url.scheme == b'file':
-        # Make file://c:/... paths work by taking the netloc
-        path = remote.parsed_url.netloc + remote.parsed_url.path
-        if sys.platform == 'win32':
-            # TODO: This probably needs more thought.
-            path = path.lstrip(b'/')
-        if os.path.isfile(path):
-            return bundlerepo(path)
     ui = get_ui()
+    if proxy:
+        parsed_proxy = urlparse(proxy if b'://' in proxy
+                                else b'http://' + proxy)
+        ui.setconfig(b'http_proxy', b'host', b'%s%s' % (
+            parsed_proxy.hostname,
+            b':%d' % parsed_proxy.port if parsed_proxy.port else b''))
+        if parsed_proxy.username:
+            ui.setconfig(b'http_proxy', b'user',
+                         unquote_to_bytes(parsed_proxy.username))
+        if parsed_proxy.password:
+            ui.setconfig(b'http_proxy', b'passwd',
+                         unquote_to_bytes(parsed_proxy.password))
+    if remote.parsed_url.scheme == b'https':
+        # Use the same TLS settings as git would.
+        cainfo = environ(b'GIT_SSL_CAINFO') or \
+            get_http_config('http.sslCAInfo', remote.url, path=True)
+        if cainfo:
+            ui.setconfig(b'web', b'cacerts', cainfo)
+        cert = environ(b'GIT_SSL_CERT') or \
+            get_http_config('http.sslCert', remote.url, path=True)
+        if cert:
+            ui.setconfig(b'auth', b'cinnabar.prefix', b'https://%s' % (
+                remote.parsed_url.netloc.rpartition(b'@')[2]))
+            ui.setconfig(b'auth', b'cinnabar.cert', cert)
+            key = environ(b'GIT_SSL_KEY') or \
+                get_http_config('http.sslKey', remote.url, path=True)
+            ui.setconfig(b'auth', b'cinnabar.key', key or cert)
     if changegroup and remote.parsed_url.scheme == b'file':
         repo = localpeer(ui, path)
     else:
@@ -1337,6 +2337,7 @@ def _get_repo(remote):
             if remote.parsed_url.scheme in ('http', 'https'):
                 return bundlerepo(remote.url, HTTPReader(remote.url))
             raise
+        approve_credentials()
 
     assert repo.capable(b'getbundle')
 
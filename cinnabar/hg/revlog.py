@@ -0,0 +1,677 @@
+'''Direct access to the revlogs of local mercurial repositories.
+
+This allows to pull from local repositories without mercurial, whether its
+libraries or `hg serve`. Only the common repository formats are supported:
+RevlogNG revlogs with zlib compression, in a store using the fncache and
+dotencode encodings. Other repositories still go through mercurial.
+'''
+from __future__ import absolute_import, unicode_literals
+import hashlib
+import os
+import re
+import struct
+import zlib
+from binascii import (
+    hexlify,
+    unhexlify,
+)
+from collections import (
+    OrderedDict,
+    defaultdict,
+)
+from cinnabar.hg.changegroup import RevDiff
+
+NULL_REV = -1
+NULL_ID = b'\0' * 20
+
+INDEX_ENTRY = struct.Struct('>Qiiiiii20s12x')
+FLAG_INLINE_DATA = 1 << 16
+FLAG_GENERALDELTA = 1 << 17
+REVLOGV1 = 1
+# Revisions with this flag have copy information in their sidedata, which
+# doesn't change their contents.
+REVIDX_HASCOPIESINFO = 1 << 12
+
+SUPPORTED_REQUIREMENTS = set((
+    b'bookmarksinstore',
+    b'dirstate-v2',
+    b'dotencode',
+    b'fncache',
+    b'generaldelta',
+    b'internal-phase',
+    b'persistent-nodemap',
+    b'relshared',
+    b'revlogv1',
+    b'share-safe',
+    b'shared',
+    b'sparserevlog',
+    b'store',
+))
+
+# Changesets in these phases (secret, archived, internal) are not exchanged.
+HIDDEN_PHASE = 2
+
+
+def _reserved():
+    for x in range(32):
+        yield x
+    for x in range(126, 256):
+        yield x
+    for x in bytearray(b'\\:*?"<>|'):
+        yield x
+
+
+def _build_encode_maps():
+    encode = dict((bytes(bytearray((x,))), bytes(bytearray((x,))))
+                  for x in range(256))
+    lower = dict(encode)
+    for x in _reserved():
+        encode[bytes(bytearray((x,)))] = b'~%02x' % x
+        lower[bytes(bytearray((x,)))] = b'~%02x' % x
+    for x in bytearray(b'ABCDEFGHIJKLMNOPQRSTUVWXYZ_'):
+        encode[bytes(bytearray((x,)))] = b'_' + bytes(bytearray((x,))).lower()
+    for x in bytearray(b'ABCDEFGHIJKLMNOPQRSTUVWXYZ'):
+        lower[bytes(bytearray((x,)))] = bytes(bytearray((x,))).lower()
+    return encode, lower
+
+
+_ENCODE_MAP, _LOWER_ENCODE_MAP = _build_encode_maps()
+
+_WINDOWS_RESERVED3 = (b'aux', b'con', b'prn', b'nul')
+_WINDOWS_RESERVED4 = (b'com', b'lpt')
+MAX_STORE_PATH_LEN = 120
+DIR_PREFIX_LEN = 8
+MAX_SHORT_DIRS_LEN = 8 * (DIR_PREFIX_LEN + 1) - 4
+
+
+def encode_dir(path):
+    return path.replace(b'.hg/', b'.hg.hg/').replace(b'.i/', b'.i.hg/') \
+        .replace(b'.d/', b'.d.hg/')
+
+
+def encode_filename(path):
+    return b''.join(_ENCODE_MAP[path[i:i + 1]] for i in range(len(path)))
+
+
+def lower_encode(path):
+    return b''.join(_LOWER_ENCODE_MAP[path[i:i + 1]]
+                    for i in range(len(path)))
+
+
+def _aux_encode(parts, dotencode):
+    for i, n in enumerate(parts):
+        if not n:
+            continue
+        if dotencode and n[:1] in (b'.', b' '):
+            n = b'~%02x' % ord(n[:1]) + n[1:]
+            parts[i] = n
+        else:
+            l = n.find(b'.')
+            if l == -1:
+                l = len(n)
+            if (l == 3 and n[:3] in _WINDOWS_RESERVED3) or \
+                    (l == 4 and b'1' <= n[3:4] <= b'9' and
+                     n[:3] in _WINDOWS_RESERVED4):
+                n = n[:2] + b'~%02x' % ord(n[2:3]) + n[3:]
+                parts[i] = n
+        if n[-1:] in (b'.', b' '):
+            parts[i] = n[:-1] + b'~%02x' % ord(n[-1:])
+    return parts
+
+
+def _hash_encode(path, dotencode):
+    digest = hexlify(hashlib.sha1(path).digest())
+    parts = _aux_encode(lower_encode(path[5:]).split(b'/'), dotencode)
+    basename = parts[-1]
+    ext = os.path.splitext(basename)[1]
+    short_dirs = []
+    short_dirs_len = 0
+    for p in parts[:-1]:
+        d = p[:DIR_PREFIX_LEN]
+        if d[-1:] in (b'.', b' '):
+            d = d[:-1] + b'_'
+        if short_dirs_len == 0:
+            t = len(d)
+        else:
+            t = short_dirs_len + 1 + len(d)
+            if t > MAX_SHORT_DIRS_LEN:
+                break
+        short_dirs.append(d)
+        short_dirs_len = t
+    dirs = b'/'.join(short_dirs)
+    if dirs:
+        dirs += b'/'
+    result = b'dh/' + dirs + digest + ext
+    space_left = MAX_STORE_PATH_LEN - len(result)
+    if space_left > 0:
+        result = b'dh/' + dirs + basename[:space_left] + digest + ext
+    return result
+
+
+def store_path(path, fncache=True, dotencode=True):
+    '''Returns the path of the given file (e.g. `data/foo.i`) in a
+    mercurial store, as per the encoding the repository uses.'''
+    path = encode_dir(path)
+    if not fncache:
+        return encode_filename(path)
+    result = b'/'.join(_aux_encode(encode_filename(path).split(b'/'),
+                                   dotencode))
+    if len(result) > MAX_STORE_PATH_LEN:
+        result = _hash_encode(path, dotencode)
+    return result
+
+
+def decompress(data):
+    if not data:
+        return data
+    kind = data[:1]
+    if kind == b'x':
+        return zlib.decompress(data)
+    if kind == b'u':
+        return data[1:]
+    if kind == b'\0':
+        return data
+    raise Exception('Unsupported revlog compression')
+
+
+class Revlog(object):
+    '''Read-only access to a RevlogNG revlog.'''
+    CACHE_SIZE = 8
+
+    def __init__(self, index_path, data_path=None):
+        self._path = index_path
+        self._data_path = data_path or index_path[:-2] + b'.d'
+        self._data = None
+        try:
+            with open(index_path, 'rb') as fh:
+                index = fh.read()
+        except (IOError, OSError):
+            index = b''
+        self._entries = []
+        self._inline = False
+        self._generaldelta = False
+        if index:
+            header = struct.unpack('>I', index[:4])[0]
+            if header & 0xffff != REVLOGV1:
+                raise Exception('Unsupported revlog version in %s'
+                                % index_path.decode('utf-8', 'replace'))
+            self._inline = bool(header & FLAG_INLINE_DATA)
+            self._generaldelta = bool(header & FLAG_GENERALDELTA)
+        pos = 0
+        while pos < len(index):
+            entry = list(INDEX_ENTRY.unpack_from(index, pos))
+            if not self._entries:
+                # The first entry's offset is overlapped by the header.
+                entry[0] &= 0xffff
+            self._entries.append(entry)
+            pos += INDEX_ENTRY.size
+            if self._inline:
+                pos += entry[1]
+        self._index = index if self._inline else None
+        self._nodemap = None
+        self._cache = OrderedDict()
+
+    def __len__(self):
+        return len(self._entries)
+
+    def node(self, rev):
+        if rev == NULL_REV:
+            return NULL_ID
+        return self._entries[rev][7]
+
+    def rev(self, node):
+        if self._nodemap is None:
+            self._nodemap = dict(
+                (e[7], r) for r, e in enumerate(self._entries))
+            self._nodemap[NULL_ID] = NULL_REV
+        return self._nodemap.get(node)
+
+    def flags(self, rev):
+        return self._entries[rev][0] & 0xffff
+
+    def rawsize(self, rev):
+        if rev == NULL_REV:
+            return 0
+        return self._entries[rev][2]
+
+    def linkrev(self, rev):
+        return self._entries[rev][4]
+
+    def parentrevs(self, rev):
+        return self._entries[rev][5:7]
+
+    def parents(self, rev):
+        return tuple(self.node(p) for p in self.parentrevs(rev))
+
+    def _chunk(self, rev):
+        entry = self._entries[rev]
+        offset = entry[0] >> 16
+        length = entry[1]
+        if self._inline:
+            start = offset + (rev + 1) * INDEX_ENTRY.size
+            return decompress(self._index[start:start + length])
+        if self._data is None:
+            self._data = open(self._data_path, 'rb')
+        self._data.seek(offset)
+        return decompress(self._data.read(length))
+
+    def revision(self, rev):
+        '''Returns the full text of the given revision.'''
+        if rev == NULL_REV:
+            return b''
+        flags = self.flags(rev) & ~REVIDX_HASCOPIESINFO
+        if flags:
+            raise Exception('Unsupported revision flags (0x%x) in %s'
+                            % (flags, self._path.decode('utf-8', 'replace')))
+        chain = []
+        r = rev
+        text = None
+        while True:
+            if r in self._cache:
+                text = self._cache[r]
+                break
+            chain.append(r)
+            base = self._entries[r][3]
+            if base == r:
+                break
+            r = base if self._generaldelta else r - 1
+        if text is None:
+            text = self._chunk(chain.pop())
+        for r in reversed(chain):
+            text = RevDiff(self._chunk(r)).apply(text)
+
+        p1, p2 = sorted(self.parents(rev))
+        if hashlib.sha1(p1 + p2 + text).digest() != self.node(rev):
+            raise Exception('Integrity check failed for revision %d of %s'
+                            % (rev, self._path.decode('utf-8', 'replace')))
+        self._cache[rev] = text
+        if len(self._cache) > self.CACHE_SIZE:
+            self._cache.popitem(last=False)
+        return text
+
+
+UNESCAPE_RE = re.compile(br'\\(.)')
+UNESCAPE_MAP = {b'0': b'\0', b'n': b'\n', b'r': b'\r', b'\\': b'\\'}
+
+
+def changeset_info(text):
+    '''Returns the branch and the list of files of a changeset text.'''
+    header = text.split(b'\n\n', 1)[0].split(b'\n')
+    branch = b'default'
+    date = header[2].split(b' ', 2)
+    if len(date) == 3:
+        for item in date[2].split(b'\0'):
+            key, _, value = UNESCAPE_RE.sub(
+                lambda m: UNESCAPE_MAP.get(m.group(1), m.group(1)),
+                item).partition(b':')
+            if key == b'branch' and value:
+                branch = value
+    return branch, header[3:]
+
+
+def _chunk(data):
+    return struct.pack('>l', len(data) + 4) + data
+
+
+CLOSING_CHUNK = struct.pack('>l', 0)
+
+
+class GeneratorReader(object):
+    def __init__(self, iterator):
+        self._iterator = iterator
+        self._buf = b''
+
+    def read(self, length):
+        while len(self._buf) < length:
+            data = next(self._iterator, None)
+            if data is None:
+                break
+            self._buf += data
+        result = self._buf[:length]
+        self._buf = self._buf[length:]
+        return result
+
+
+class RevlogRepo(object):
+    '''A mercurial repository, read directly from its revlogs.'''
+    def __init__(self, path, store, url, requirements):
+        self._path = path
+        self._store = store
+        self._url = url
+        self._fncache = b'fncache' in requirements
+        self._dotencode = b'dotencode' in requirements
+        self._bookmarks_path = os.path.join(
+            store if b'bookmarksinstore' in requirements else path,
+            b'bookmarks')
+        self._changelog = Revlog(os.path.join(store, b'00changelog.i'))
+        self._manifest = Revlog(os.path.join(store, b'00manifest.i'))
+        self._hidden = self._hidden_revs()
+        self._branches = None
+        self._connection = None
+        self.remote = None
+
+    @classmethod
+    def open(cls, path, url):
+        '''Returns a RevlogRepo for the mercurial repository at the given
+        path, or None if it can't be read directly.'''
+        hg_path = os.path.join(path, b'.hg')
+        requirements = cls._read_requirements(hg_path)
+        if requirements is None:
+            return None
+        store = os.path.join(hg_path, b'store')
+        if b'shared' in requirements or b'relshared' in requirements:
+            with open(os.path.join(hg_path, b'sharedpath'), 'rb') as fh:
+                shared = fh.read().rstrip(b'\n')
+            if b'relshared' in requirements:
+                shared = os.path.join(hg_path, shared)
+            store = os.path.join(shared, b'store')
+            if b'share-safe' in requirements:
+                requirements |= cls._read_requirements(shared) or set()
+        if b'share-safe' in requirements:
+            store_requirements = cls._read_requirements(store)
+            if store_requirements is None:
+                return None
+            requirements |= store_requirements
+        if not requirements <= SUPPORTED_REQUIREMENTS or \
+                b'store' not in requirements or \
+                b'revlogv1' not in requirements:
+            return None
+        # Obsolescence markers would hide changesets, and reading them is
+        # not supported.
+        obsstore = os.path.join(store, b'obsstore')
+        if os.path.exists(obsstore) and os.path.getsize(obsstore):
+            return None
+        return cls(path, store, url, requirements)
+
+    @staticmethod
+    def _read_requirements(path):
+        try:
+            with open(os.path.join(path, b'requires'), 'rb') as fh:
+                return set(l for l in fh.read().splitlines() if l)
+        except (IOError, OSError):
+            return None
+
+    def _phase_roots(self):
+        roots = defaultdict(list)
+        try:
+            with open(os.path.join(self._store, b'phaseroots'), 'rb') as fh:
+                for line in fh:
+                    phase, _, node = line.strip().partition(b' ')
+                    rev = self._changelog.rev(unhexlify(node))
+                    if rev is not None:
+                        roots[int(phase)].append(rev)
+        except (IOError, OSError):
+            pass
+        return roots
+
+    def _hidden_revs(self):
+        roots = [r for phase, revs in self._phase_roots().items()
+                 if phase >= HIDDEN_PHASE for r in revs]
+        hidden = set(roots)
+        if not roots:
+            return hidden
+        for rev in range(min(roots), len(self._changelog)):
+            if any(p in hidden for p in self._changelog.parentrevs(rev)):
+                hidden.add(rev)
+        return hidden
+
+    def _publishing(self):
+        # Like mercurial, only look at the repository configuration, and
+        # consider repositories publishing unless told otherwise.
+        publishing = True
+        section = None
+        try:
+            with open(os.path.join(self._path, b'.hg', b'hgrc'), 'rb') as fh:
+                for line in fh:
+                    line = line.strip()
+                    if line.startswith(b'[') and line.endswith(b']'):
+                        section = line[1:-1].strip()
+                    elif section == b'phases' and b'=' in line:
+                        key, _, value = line.partition(b'=')
+                        if key.strip() == b'publish':
+                            publishing = value.strip().lower() not in (
+                                b'0', b'no', b'false', b'off', b'never')
+        except (IOError, OSError):
+            pass
+        return publishing
+
+    def _revs(self):
+        return (r for r in range(len(self._changelog))
+                if r not in self._hidden)
+
+    def url(self):
+        return self._url
+
+    def local(self):
+        return None
+
+    def capable(self, capability):
+        return capability in (b'getbundle', b'unbundle', b'lookup',
+                              b'known', b'branchmap')
+
+    def heads(self):
+        parents = set()
+        revs = list(self._revs())
+        for rev in revs:
+            parents.update(self._changelog.parentrevs(rev))
+        heads = [self._changelog.node(r) for r in revs if r not in parents]
+        return heads or [NULL_ID]
+
+    def _branch_revs(self):
+        if self._branches is None:
+            self._branches = {}
+            for rev in self._revs():
+                self._branches[rev] = changeset_info(
+                    self._changelog.revision(rev))[0]
+        return self._branches
+
+    def branchmap(self):
+        branches = self._branch_revs()
+        not_heads = set()
+        for rev, branch in branches.items():
+            for p in self._changelog.parentrevs(rev):
+                if p != NULL_REV and branches.get(p) == branch:
+                    not_heads.add(p)
+        result = defaultdict(list)
+        for rev in sorted(branches):
+            if rev not in not_heads:
+                result[branches[rev]].append(self._changelog.node(rev))
+        return dict(result)
+
+    def _bookmarks(self):
+        bookmarks = {}
+        try:
+            with open(self._bookmarks_path, 'rb') as fh:
+                for line in fh:
+                    node, _, name = line.strip().partition(b' ')
+                    rev = self._changelog.rev(unhexlify(node))
+                    if name and rev is not None and rev not in self._hidden:
+                        bookmarks[name] = node
+        except (IOError, OSError):
+            pass
+        return bookmarks
+
+    def listkeys(self, namespace):
+        if namespace == b'bookmarks':
+            return self._bookmarks()
+        if namespace == b'phases':
+            if self._publishing():
+                return {b'publishing': b'True'}
+            return dict((hexlify(self._changelog.node(r)), b'1')
+                        for r in self._phase_roots().get(1, ())
+                        if r not in self._hidden)
+        return {}
+
+    def known(self, nodes):
+        result = []
+        for node in nodes:
+            rev = self._changelog.rev(node)
+            result.append(rev is not None and rev not in self._hidden)
+        return result
+
+    def lookup(self, key):
+        bookmarks = self._bookmarks()
+        if key in bookmarks:
+            return unhexlify(bookmarks[key])
+        heads = self.branchmap().get(key)
+        if heads:
+            return heads[-1]
+        if key == b'tip':
+            return self._changelog.node(max(self._revs()))
+        if re.match(b'[0-9a-fA-F]{1,40}$', key):
+            key = key.lower()
+            matches = [r for r in self._revs()
+                       if hexlify(self._changelog.node(r)).startswith(key)]
+            if len(matches) == 1:
+                return self._changelog.node(matches[0])
+        raise Exception('Unknown revision %s'
+                        % key.decode('utf-8', 'replace'))
+
+    def between(self, pairs):
+        result = []
+        for top, bottom in pairs:
+            nodes = []
+            rev = self._changelog.rev(top)
+            i, f = 0, 1
+            while rev not in (None, NULL_REV) and \
+                    self._changelog.node(rev) != bottom:
+                if i == f:
+                    nodes.append(self._changelog.node(rev))
+                    f *= 2
+                rev = self._changelog.parentrevs(rev)[0]
+                i += 1
+            result.append(nodes)
+        return result
+
+    def _ancestors(self, nodes):
+        revs = set()
+        stack = [self._changelog.rev(n) for n in nodes]
+        while stack:
+            rev = stack.pop()
+            if rev in (None, NULL_REV) or rev in revs:
+                continue
+            revs.add(rev)
+            stack.extend(self._changelog.parentrevs(rev))
+        return revs
+
+    def getbundle(self, name, heads, common, *args, **kwargs):
+        if kwargs.get('narrow'):
+            raise Exception('Narrow clones are not supported with direct '
+                            'access to mercurial repositories.')
+        common = self._ancestors(common)
+        revs = sorted((self._ancestors(heads) - common) - self._hidden)
+        return GeneratorReader(self._changegroup(revs, common))
+
+    def _revlog_chunks(self, revlog, revs, linknode):
+        previous = None
+        for rev in revs:
+            p1, p2 = revlog.parentrevs(rev)
+            base = previous if previous is not None else p1
+            text = revlog.revision(rev)
+            # Each revision is sent in full, as a replacement of the whole
+            # text of the revision it is a delta against.
+            yield _chunk(b''.join((
+                revlog.node(rev), revlog.node(p1), revlog.node(p2),
+                linknode(revlog, rev),
+                struct.pack('>lll', 0, revlog.rawsize(base), len(text)),
+                text)))
+            previous = rev
+
+    def _manifest_files(self, node, cache):
+        if node not in cache:
+            text = self._manifest.revision(self._manifest.rev(node))
+            cache[node] = dict(
+                (path, file_node[:40])
+                for path, _, file_node in (l.partition(b'\0')
+                                           for l in text.splitlines()))
+            if len(cache) > Revlog.CACHE_SIZE:
+                cache.popitem(last=False)
+        return cache[node]
+
+    def _changegroup(self, revs, common):
+        changelog = self._changelog
+
+        def manifest_node(rev):
+            return unhexlify(changelog.revision(rev).split(b'\n', 1)[0])
+
+        # The linkrevs stored in the manifest and file revlogs point to the
+        # first changeset that introduced a given node, which may be hidden
+        # or not part of what is requested. So, like mercurial, select the
+        # manifests and files from the outgoing changesets themselves, and
+        # link each node to the first of them that introduces it. Nodes
+        # whose linkrev is a common changeset are known to the other end.
+        linknodes = {}
+        manifest_revs = {}
+        file_revs = defaultdict(dict)
+        manifests = OrderedDict()
+        for rev in revs:
+            node = changelog.node(rev)
+            mnode = manifest_node(rev)
+            mrev = self._manifest.rev(mnode)
+            if mrev in (None, NULL_REV):
+                continue
+            if mrev not in manifest_revs and \
+                    self._manifest.linkrev(mrev) not in common:
+                manifest_revs[mrev] = node
+            files = self._manifest_files(mnode, manifests)
+            parents = [self._manifest_files(manifest_node(p), manifests)
+                       for p in changelog.parentrevs(rev) if p != NULL_REV]
+            for path, file_node in files.items():
+                if any(p.get(path) == file_node for p in parents):
+                    continue
+                file_revs[path].setdefault(file_node, node)
+
+        def linknode(revlog, rev):
+            return linknodes[revlog][rev]
+
+        linknodes[changelog] = dict((r, changelog.node(r)) for r in revs)
+        for chunk in self._revlog_chunks(changelog, revs, linknode):
+            yield chunk
+        yield CLOSING_CHUNK
+
+        linknodes[self._manifest] = manifest_revs
+        for chunk in self._revlog_chunks(self._manifest,
+                                         sorted(manifest_revs), linknode):
+            yield chunk
+        yield CLOSING_CHUNK
+
+        for path in sorted(file_revs):
+            filelog = Revlog(*(
+                os.path.join(self._store, store_path(
+                    b'data/%s.%s' % (path, ext), self._fncache,
+                    self._dotencode))
+                for ext in (b'i', b'd')))
+            links = {}
+            for file_node, node in file_revs[path].items():
+                rev = filelog.rev(unhexlify(file_node))
+                if rev is None:
+                    raise Exception(
+                        'Missing revision %s of %s'
+                        % (file_node.decode('ascii'),
+                           path.decode('utf-8', 'replace')))
+                if filelog.linkrev(rev) not in common:
+                    links[rev] = node
+            if not links:
+                continue
+            linknodes[filelog] = links
+            yield _chunk(path)
+            for chunk in self._revlog_chunks(filelog, sorted(links),
+                                             linknode):
+                yield chunk
+            yield CLOSING_CHUNK
+        yield CLOSING_CHUNK
+
+    def _connect(self):
+        # Pushes go through the native helper, which uses `hg serve`.
+        if self._connection is None:
+            from cinnabar.helper import HgRepoHelper
+            from cinnabar.hg.repo import HelperRepo
+            HgRepoHelper.connect(self._url)
+            self._connection = HelperRepo(self._url)
+        return self._connection
+
+    def unbundle(self, cg, heads, *args, **kwargs):
+        return self._connect().unbundle(cg, heads, *args, **kwargs)
+
+    def pushkey(self, namespace, key, old, new):
+        return self._connect().pushkey(namespace, key, old, new)
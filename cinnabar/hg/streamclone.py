@@ -0,0 +1,373 @@
+from __future__ import absolute_import, division, unicode_literals
+import logging
+import os
+import shutil
+import struct
+import tempfile
+import zlib
+try:
+    from urllib.parse import unquote_to_bytes
+except ImportError:
+    from urllib import unquote as unquote_to_bytes
+from cinnabar.util import (
+    lrucache,
+    progress_iter,
+    stats,
+)
+from .changegroup import (
+    RawRevChunk02,
+    RevDiff,
+)
+try:
+    import zstandard
+except ImportError:
+    zstandard = None
+
+
+# Streaming clones send the raw revlog files from the server's store. We can
+# only make sense of the revlog formats listed here.
+SUPPORTED_REQUIREMENTS = set((
+    b'dotencode',
+    b'fncache',
+    b'generaldelta',
+    b'persistent-nodemap',
+    b'revlogv1',
+    b'share-safe',
+    b'sparserevlog',
+    b'store',
+))
+if zstandard:
+    SUPPORTED_REQUIREMENTS.add(b'revlog-compression-zstd')
+
+NULL_NODE = b'\0' * 20
+
+REVLOG_INLINE_DATA = 1 << 16
+REVLOG_GENERALDELTA = 1 << 17
+
+logger = logging.getLogger('streamclone')
+
+
+def unsupported_requirements(requirements):
+    return set(requirements) - SUPPORTED_REQUIREMENTS
+
+
+def parse_requirements(data):
+    return set(r for r in unquote_to_bytes(data).split(b',') if r)
+
+
+class BufferedReader(object):
+    def __init__(self, fh):
+        self._fh = fh
+        self._buf = b''
+
+    def _fill(self):
+        data = self._fh.read(32768)
+        self._buf += data
+        return bool(data)
+
+    def read(self, size):
+        while len(self._buf) < size and self._fill():
+            pass
+        result, self._buf = self._buf[:size], self._buf[size:]
+        if len(result) < size:
+            raise Exception('stream ended unexpectedly (got %d bytes, '
+                            'expected %d)' % (len(result), size))
+        return result
+
+    def readline(self):
+        while b'\n' not in self._buf and self._fill():
+            pass
+        line, nl, self._buf = self._buf.partition(b'\n')
+        return line + nl
+
+    def read_uvarint(self):
+        result = 0
+        shift = 0
+        while True:
+            byte = ord(self.read(1))
+            result |= (byte & 0x7f) << shift
+            if not byte & 0x80:
+                return result
+            shift += 7
+
+
+def decodedir(path):
+    if b'.hg/' not in path:
+        return path
+    return path.replace(b'.d.hg/', b'.d/').replace(b'.i.hg/', b'.i/') \
+        .replace(b'.hg.hg/', b'.hg/')
+
+
+@stats.timed('stream clone decompression')
+def decompress(data):
+    if not data:
+        return data
+    header = data[:1]
+    if header == b'\0':
+        return data
+    if header == b'x':
+        return zlib.decompress(data)
+    if header == b'u':
+        return data[1:]
+    if header == b'\x28' and zstandard:
+        return zstandard.ZstdDecompressor().decompressobj().decompress(data)
+    raise Exception('Unknown revlog compression: %r' % header)
+
+
+class Revlog(object):
+    INDEX_ENTRY = struct.Struct('>Qiiiiii20s12x')
+
+    def __init__(self, index_path, data_path=None):
+        with open(index_path, 'rb') as fh:
+            index = fh.read()
+        self._entries = []
+        self._nodes = []
+        if not index:
+            self._inline = False
+            self._generaldelta = False
+            self._data = None
+            return
+        header = struct.unpack('>I', index[:4])[0]
+        if header & 0xffff != 1:
+            raise Exception('Unsupported revlog version %d'
+                            % (header & 0xffff))
+        self._inline = bool(header & REVLOG_INLINE_DATA)
+        self._generaldelta = bool(header & REVLOG_GENERALDELTA)
+
+        pos = 0
+        while pos < len(index):
+            entry = list(self.INDEX_ENTRY.unpack_from(index, pos))
+            if not self._entries:
+                # The first entry holds the revlog header instead of its
+                # offset.
+                entry[0] &= 0xffff
+            if entry[0] & 0xffff:
+                raise Exception('Unsupported revlog flags for %s'
+                                % os.path.basename(index_path))
+            entry[0] >>= 16
+            pos += self.INDEX_ENTRY.size
+            if self._inline:
+                entry[0] = pos
+                pos += entry[1]
+            self._entries.append(entry)
+            self._nodes.append(entry[7])
+        if self._inline:
+            self._data = BytesData(index)
+        elif data_path:
+            self._data = open(data_path, 'rb')
+        else:
+            self._data = None
+
+    def close(self):
+        if self._data:
+            self._data.close()
+
+    def __len__(self):
+        return len(self._entries)
+
+    def node(self, rev):
+        if rev < 0:
+            return NULL_NODE
+        return self._nodes[rev]
+
+    def delta_base(self, rev):
+        base = self._entries[rev][3]
+        if base == rev or base < 0:
+            return None
+        if self._generaldelta:
+            return base
+        return rev - 1
+
+    def chunk(self, rev):
+        offset, length = self._entries[rev][:2]
+        self._data.seek(offset)
+        return decompress(self._data.read(length))
+
+    def chunks(self, linknode):
+        for rev, entry in enumerate(self._entries):
+            base = self.delta_base(rev)
+            data = self.chunk(rev)
+            if base is None:
+                data = struct.pack('>lll', 0, 0, len(data)) + data
+            yield RawRevChunk02(b''.join((
+                entry[7], self.node(entry[5]), self.node(entry[6]),
+                self.node(base if base is not None else -1),
+                linknode(entry[4]), data)))
+
+
+class BytesData(object):
+    def __init__(self, data):
+        self._data = data
+        self._pos = 0
+
+    def seek(self, pos):
+        self._pos = pos
+
+    def read(self, length):
+        result = self._data[self._pos:self._pos + length]
+        self._pos += length
+        return result
+
+    def close(self):
+        self._data = None
+
+
+class Changelog(Revlog):
+    def __init__(self, *args):
+        super(Changelog, self).__init__(*args)
+        self._revisions = lrucache(1000)
+
+    def revision(self, rev):
+        chain = []
+        text = None
+        while True:
+            try:
+                text = self._revisions[rev]
+                break
+            except KeyError:
+                pass
+            chain.append(rev)
+            rev = self.delta_base(rev)
+            if rev is None:
+                break
+        for rev in reversed(chain):
+            data = self.chunk(rev)
+            text = data if text is None else RevDiff(data).apply(text)
+            self._revisions[rev] = text
+        return text
+
+    def chunks(self, linknode):
+        # Changesets are sent as full texts, so that the changeset import
+        # doesn't need to go back to already stored changesets.
+        for rev, entry in enumerate(self._entries):
+            data = self.revision(rev)
+            yield RawRevChunk02(b''.join((
+                entry[7], self.node(entry[5]), self.node(entry[6]),
+                NULL_NODE, entry[7],
+                struct.pack('>lll', 0, 0, len(data)), data)))
+
+
+class StreamBundle(object):
+    '''A streaming clone, presented to the unbundler like a changegroup.'''
+    def __init__(self, reader, requirements, entries, filecount=None):
+        unsupported = unsupported_requirements(requirements)
+        if unsupported:
+            raise Exception(
+                'Unsupported repository requirements for stream clone: %s'
+                % ', '.join(r.decode('ascii') for r in sorted(unsupported)))
+        self._reader = reader
+        self._entries = entries
+        self._filecount = filecount
+
+    def _spool(self, directory):
+        files = {}
+        for src, name, size in progress_iter(
+                'Receiving {} files', self._entries, self._filecount):
+            if src != b's' or not name.endswith((b'.i', b'.d')):
+                logger.debug('Skipping %s', name)
+                while size:
+                    size -= len(self._reader.read(min(size, 32768)))
+                continue
+            path = os.path.join(directory, str(len(files)))
+            with open(path, 'wb') as fh:
+                while size:
+                    data = self._reader.read(min(size, 32768))
+                    fh.write(data)
+                    size -= len(data)
+            files[name] = path
+        return files
+
+    def unbundler(self):
+        directory = tempfile.mkdtemp(prefix='hg-stream-')
+        try:
+            files = self._spool(directory)
+
+            def revlog(name, cls=Revlog):
+                if name + b'.i' not in files:
+                    raise Exception('Missing %s in stream clone'
+                                    % (name + b'.i').decode('ascii'))
+                return cls(files[name + b'.i'], files.get(name + b'.d'))
+
+            changelog = revlog(b'00changelog', Changelog)
+            linknode = changelog.node
+            yield changelog.chunks(linknode)
+            # The changesets have all been consumed by now. Only their node
+            # is needed from now on.
+            changelog._revisions.invalidate()
+            changelog.close()
+            manifest = revlog(b'00manifest')
+            yield manifest.chunks(linknode)
+            manifest.close()
+
+            def iter_files():
+                for name in sorted(files):
+                    if not name.startswith(b'data/') or \
+                            not name.endswith(b'.i'):
+                        continue
+                    filelog = revlog(name[:-2])
+                    path = name[5:-2]
+                    for chunk in filelog.chunks(linknode):
+                        yield path, chunk
+                    filelog.close()
+
+            yield iter_files()
+        finally:
+            shutil.rmtree(directory, ignore_errors=True)
+
+
+def _v1_entries(reader, filecount):
+    for _ in range(filecount):
+        line = reader.readline()
+        name, sep, size = line.rstrip(b'\n').partition(b'\0')
+        if not sep:
+            raise Exception('Unexpected response from remote server')
+        yield b's', decodedir(name), int(size)
+
+
+def stream_out(fh, requirements):
+    '''Reads the response of the stream_out command.'''
+    reader = BufferedReader(fh)
+    status = reader.readline().strip()
+    if status == b'1':
+        raise Exception('Stream clone forbidden by the server')
+    elif status == b'2':
+        raise Exception('Locking the remote repository failed')
+    elif status != b'0':
+        raise Exception('The server sent an unknown error code')
+    filecount, bytecount = (int(x) for x in reader.readline().split())
+    logger.info('%d files, %d bytes', filecount, bytecount)
+    return StreamBundle(reader, requirements,
+                        _v1_entries(reader, filecount), filecount)
+
+
+def packed1(fh):
+    '''Reads a packed1 bundle, after its HGS1 header.'''
+    reader = BufferedReader(fh)
+    compression = reader.read(2)
+    if compression != b'UN':
+        raise Exception('Unsupported packed1 compression: %s'
+                        % compression.decode('ascii'))
+    filecount, bytecount, length = struct.unpack('>QQH', reader.read(18))
+    requirements = reader.read(length).rstrip(b'\0')
+    logger.info('%d files, %d bytes', filecount, bytecount)
+    return StreamBundle(reader, parse_requirements(requirements),
+                        _v1_entries(reader, filecount), filecount)
+
+
+def _v2_entries(reader, filecount):
+    for _ in range(filecount):
+        src = reader.read(1)
+        name_length = reader.read_uvarint()
+        size = reader.read_uvarint()
+        yield src, reader.read(name_length), size
+
+
+def stream2(part):
+    '''Reads a stream2 bundle2 part.'''
+    reader = BufferedReader(part)
+    filecount = int(part.params[b'filecount'])
+    logger.info('%d files, %s bytes', filecount,
+                part.params.get(b'bytecount', b'?').decode('ascii'))
+    return StreamBundle(
+        reader, parse_requirements(part.params.get(b'requirements', b'')),
+        _v2_entries(reader, filecount), filecount)
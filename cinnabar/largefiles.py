@@ -0,0 +1,151 @@
+from __future__ import absolute_import, unicode_literals
+import hashlib
+import subprocess
+from cinnabar.git import (
+    Git,
+    GitProcess,
+    split_ls_tree,
+)
+from cinnabar.helper import GitHgHelper
+from cinnabar.hg.repo import HelperRepo
+from cinnabar.util import (
+    HTTPReader,
+    iteritems,
+    one,
+)
+
+# Mercurial tracks largefiles through standins: small files under .hglf/
+# containing the sha1 of the actual content, which is stored outside the
+# repository. The converted git trees contain the standins, like the
+# mercurial manifests do. The contents fetched from remotes are stored as
+# git blobs, in a tree under this ref where each entry is named after the
+# sha1 of the content.
+LARGEFILES_REF = b'refs/cinnabar/largefiles'
+
+STANDIN_DIR = b'.hglf/'
+
+
+def standin_path(path):
+    return STANDIN_DIR + path
+
+
+def standins(commit):
+    '''Returns a dict mapping the largefiles paths in the given commit to
+    the sha1 of their content.'''
+    result = {}
+    for line in Git.iter('ls-tree', '-r', commit, '--', STANDIN_DIR):
+        mode, typ, sha1, path = split_ls_tree(line)
+        content = GitHgHelper.cat_file(b'blob', sha1)
+        result[path[len(STANDIN_DIR):]] = content.strip()
+    return result
+
+
+def stored_largefiles():
+    '''Returns a dict mapping the sha1 of largefiles contents to the
+    corresponding git blobs.'''
+    tree = Git.resolve_ref(LARGEFILES_REF)
+    if not tree:
+        return {}
+    return dict((path, sha1) for mode, typ, sha1, path in (
+        split_ls_tree(line) for line in Git.iter('ls-tree', tree)))
+
+
+def store_largefiles(largefiles):
+    tree = one(Git.iter('mktree', stdin=(
+        b'100644 blob %s\t%s' % (blob, sha)
+        for sha, blob in sorted(iteritems(largefiles)))))
+    Git.run('update-ref', LARGEFILES_REF, tree)
+
+
+def _wire_url(repo, command, sha):
+    url = repo.url()
+    return b'%s%scmd=%s&sha=%s' % (url, b'&' if b'?' in url else b'?',
+                                   command, sha)
+
+
+def _readline(fh):
+    line = b''
+    while not line.endswith(b'\n'):
+        c = fh.read(1)
+        if not c:
+            break
+        line += c
+    return line
+
+
+def _call_largefiles(repo, command, sha):
+    '''Sends a command of the largefiles extension to the remote, and
+    returns a file-like object for the response.'''
+    if not isinstance(repo, HelperRepo):
+        return repo._callstream(command, sha=sha)
+    # The native helper doesn't know about these commands, but for http
+    # remotes, they are simple requests.
+    if repo.url().startswith((b'http:', b'https:')):
+        return HTTPReader(_wire_url(repo, command, sha))
+    raise Exception('Accessing largefiles on this remote requires the '
+                    'mercurial libraries.')
+
+
+def open_largefile(repo, sha, store_url=None):
+    '''Returns a file-like object for the content of the given largefile,
+    and its length, from the given store url or remote repository.'''
+    if store_url:
+        fh = HTTPReader(b'%s/%s' % (store_url.rstrip(b'/'), sha))
+        return fh, fh.length
+    fh = _call_largefiles(repo, b'getlfile', sha)
+    length = _readline(fh)
+    try:
+        return fh, int(length)
+    except ValueError:
+        raise Exception('Unexpected response from remote for largefile %s'
+                        % sha.decode('ascii'))
+
+
+def has_largefile(repo, sha):
+    '''Returns whether the remote repository has the given largefile.'''
+    fh = _call_largefiles(repo, b'statlfile', sha)
+    return fh.read(1) == b'0'
+
+
+def fetch_largefile(repo, sha, store_url=None):
+    '''Fetches the given largefile and stores it as a git blob, whose sha1
+    is returned.'''
+    fh, length = open_largefile(repo, sha, store_url)
+    proc = GitProcess('hash-object', '-w', '--stdin', stdin=subprocess.PIPE)
+    h = hashlib.sha1()
+    while length is None or length > 0:
+        data = fh.read(32768 if length is None else min(length, 32768))
+        if not data:
+            break
+        h.update(data)
+        proc.stdin.write(data)
+        if length is not None:
+            length -= len(data)
+    proc.stdin.close()
+    blob = proc.stdout.read().strip()
+    proc.wait()
+    if length:
+        raise Exception('Incomplete largefile %s' % sha.decode('ascii'))
+    if h.hexdigest().encode('ascii') != sha:
+        raise Exception('Corrupted largefile %s' % sha.decode('ascii'))
+    return blob
+
+
+def added_largefiles(commits, exclude):
+    '''Returns the largefiles added or modified in the history of the given
+    commits, excluding the history of the commits in `exclude`, as a dict
+    mapping their sha1 to their path.'''
+    args = ['log', '--format=', '--raw', '--no-abbrev', '--diff-filter=AMR']
+    args.extend(commits)
+    args.append('--not')
+    args.extend(exclude)
+    args.extend(('--', STANDIN_DIR))
+    result = {}
+    for line in Git.iter(*args):
+        if not line.startswith(b':'):
+            continue
+        info, path = line.split(b'\t', 1)
+        sha1 = info.split(b' ')[3]
+        content = GitHgHelper.cat_file(b'blob', sha1).strip()
+        result[content] = path.split(b'\t')[-1][len(STANDIN_DIR):]
+    return result
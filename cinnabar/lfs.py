@@ -0,0 +1,213 @@
+from __future__ import absolute_import, unicode_literals
+import hashlib
+import json
+import logging
+import os
+from cinnabar.git import Git
+from cinnabar.helper import GitHgHelper
+from cinnabar.util import (
+    fsdecode,
+    fsencode,
+    HTTPReader,
+    one,
+)
+try:
+    from urllib2 import (
+        Request,
+        urlopen,
+    )
+except ImportError:
+    from urllib.request import (
+        Request,
+        urlopen,
+    )
+
+# The mercurial lfs extension stores the contents of files out of the
+# repository, and the file revisions contain pointers in the git-lfs format
+# instead. Those pointers are what the converted git trees contain, such
+# that git-lfs can handle them. The mercurial sha1s of the file revisions
+# that were received as such pointers are stored, as a blob with one sha1
+# per line, in this ref.
+LFS_REF = b'refs/cinnabar/lfs'
+
+POINTER_VERSION = b'https://git-lfs.github.com/spec/v1'
+
+# Pointers are small. Don't bother looking at larger blobs.
+MAX_POINTER_SIZE = 1024
+
+LFS_MEDIA_TYPE = 'application/vnd.git-lfs+json'
+
+
+def parse_pointer(data):
+    '''Returns a dict with the keys and values of the given lfs pointer, or
+    None if the data is not a pointer.'''
+    if len(data) > MAX_POINTER_SIZE or not data.endswith(b'\n'):
+        return None
+    pointer = {}
+    for line in data.splitlines():
+        key, sep, value = line.partition(b' ')
+        if not sep or key in pointer:
+            return None
+        pointer[key] = value
+    if pointer.get(b'version') != POINTER_VERSION:
+        return None
+    oid = pointer.get(b'oid', b'')
+    size = pointer.get(b'size', b'')
+    if not oid.startswith(b'sha256:') or len(oid) != 71 or \
+            not size.isdigit():
+        return None
+    return pointer
+
+
+def is_git_lfs_pointer(pointer):
+    '''Returns whether git-lfs can use the given pointer. Mercurial may add
+    keys of its own (x-is-binary, x-hg-copy, x-hg-copyrev), which git-lfs
+    doesn't accept.'''
+    return set(pointer) == set((b'version', b'oid', b'size'))
+
+
+def stored_lfs_nodes():
+    sha1 = Git.resolve_ref(LFS_REF)
+    if not sha1:
+        return set()
+    return set(line for line in Git.iter('cat-file', 'blob', sha1) if line)
+
+
+def store_lfs_nodes(nodes):
+    known = stored_lfs_nodes()
+    if nodes <= known:
+        return
+    sha1 = one(Git.iter('hash-object', '-w', '--stdin',
+                        stdin=sorted(nodes | known)))
+    Git.run('update-ref', LFS_REF, sha1)
+
+
+def _pointers(blobs):
+    '''Returns a dict mapping the given blobs that contain lfs pointers to
+    the parsed pointers.'''
+    result = {}
+    blobs = set(blobs)
+    if not blobs:
+        return result
+    for line in Git.iter('cat-file', '--batch-check', stdin=sorted(blobs)):
+        sha1, typ, size = line.split(b' ')
+        if typ != b'blob' or int(size) > MAX_POINTER_SIZE:
+            continue
+        pointer = parse_pointer(GitHgHelper.cat_file(b'blob', sha1))
+        if pointer:
+            result[sha1] = pointer
+    return result
+
+
+def lfs_pointers(commit):
+    '''Returns a dict mapping the paths of the files in the given commit
+    that git-lfs can handle to their pointer.'''
+    files = dict((path, sha1)
+                 for mode, typ, sha1, path in Git.ls_tree(commit,
+                                                          recursive=True)
+                 if typ == b'blob' and mode != b'120000')
+    pointers = _pointers(files.values())
+    return dict((path, pointers[sha1]) for path, sha1 in files.items()
+                if sha1 in pointers and is_git_lfs_pointer(pointers[sha1]))
+
+
+def added_lfs_pointers(commits, exclude):
+    '''Returns the paths of the lfs pointers added or modified in the
+    history of the given commits, excluding the history of the commits in
+    `exclude`.'''
+    args = ['log', '--format=', '--raw', '--no-abbrev', '--diff-filter=AMR']
+    args.extend(commits)
+    args.append('--not')
+    args.extend(exclude)
+    files = {}
+    for line in Git.iter(*args):
+        if not line.startswith(b':'):
+            continue
+        info, path = line.split(b'\t', 1)
+        info = info.split(b' ')
+        if info[1] != b'120000':
+            files[path.split(b'\t')[-1]] = info[3]
+    pointers = _pointers(files.values())
+    return set(path for path, sha1 in files.items() if sha1 in pointers)
+
+
+def objects_dir():
+    '''Returns the path of the local git-lfs store.'''
+    common_dir = fsdecode(one(Git.iter('rev-parse', '--git-common-dir')))
+    return os.path.join(common_dir, 'lfs', 'objects')
+
+
+def object_path(directory, oid):
+    return os.path.join(directory, oid[:2], oid[2:4], oid)
+
+
+def lfs_endpoint(remote):
+    '''Returns the url of the git-lfs API of the given remote. Mercurial
+    serves it as part of the repository url, under the same path git-lfs
+    would expect it from a git server.'''
+    url = Git.config('cinnabar.lfs-url', remote=remote.name)
+    if url:
+        return url.rstrip(b'/')
+    if not remote.url.startswith((b'http:', b'https:')):
+        raise Exception('Cannot determine the lfs server for this remote. '
+                        'Please set cinnabar.lfs-url.')
+    return b'%s/.git/info/lfs' % remote.url.rstrip(b'/')
+
+
+def _batch(endpoint, objects):
+    data = json.dumps({
+        'operation': 'download',
+        'transfers': ['basic'],
+        'objects': [{'oid': oid, 'size': size} for oid, size in objects],
+    }).encode('utf-8')
+    request = Request(fsdecode(endpoint) + '/objects/batch', data, {
+        'Accept': LFS_MEDIA_TYPE,
+        'Content-Type': LFS_MEDIA_TYPE,
+    })
+    fh = urlopen(request, timeout=HTTPReader.TIMEOUT)
+    return json.loads(fh.read().decode('utf-8')).get('objects', [])
+
+
+def fetch_lfs_objects(endpoint, pointers):
+    '''Downloads the objects for the given pointers, and stores them in the
+    local git-lfs store. Returns the number of objects that couldn't be
+    downloaded.'''
+    directory = objects_dir()
+    objects = {}
+    for pointer in pointers:
+        oid = fsdecode(pointer[b'oid'][len(b'sha256:'):])
+        if not os.path.exists(object_path(directory, oid)):
+            objects[oid] = int(pointer[b'size'])
+    if not objects:
+        return 0
+    for obj in _batch(endpoint, sorted(objects.items())):
+        oid = obj.get('oid')
+        download = obj.get('actions', {}).get('download')
+        if oid in objects and download:
+            fetch_lfs_object(object_path(directory, oid), oid,
+                             objects.pop(oid), download)
+        else:
+            logging.error('Cannot get lfs object %s: %s', oid,
+                          obj.get('error', {}).get('message', 'not found'))
+    return len(objects)
+
+
+def fetch_lfs_object(path, oid, size, download):
+    reader = HTTPReader(fsencode(download['href']),
+                        headers=download.get('header', {}))
+    directory = os.path.dirname(path)
+    if not os.path.isdir(directory):
+        os.makedirs(directory)
+    tmp = path + '.tmp'
+    h = hashlib.sha256()
+    with open(tmp, 'wb') as fh:
+        while True:
+            data = reader.read(32768)
+            if not data:
+                break
+            h.update(data)
+            fh.write(data)
+    if h.hexdigest() != oid or os.path.getsize(tmp) != size:
+        os.unlink(tmp)
+        raise Exception('Corrupted lfs object %s' % oid)
+    os.rename(tmp, path)
@@ -0,0 +1,114 @@
+from __future__ import absolute_import, unicode_literals
+import struct
+from binascii import hexlify
+from cinnabar.git import Git
+from cinnabar.util import (
+    iteritems,
+    one,
+)
+
+# Obsolescence markers fetched from remotes are stored, as a blob with one
+# line per marker listing the mercurial sha1 of the obsoleted changeset
+# followed by those of its successors, in this ref.
+OBSOLETE_REF = b'refs/cinnabar/obsolete'
+
+B85CHARS = (b'0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz'
+            b'!#$%&()*+-;<=>?@^_`{|}~')
+B85DEC = dict((c, i) for i, c in enumerate(bytearray(B85CHARS)))
+
+# Version 0 of the obsolescence markers format, which is what servers send
+# for `listkeys obsolete`: number of successors, metadata size, flags and
+# obsoleted node.
+FM0_HEADER = struct.Struct('>BIB20s')
+
+
+def b85decode(data):
+    '''Decodes base85 data, as encoded by mercurial without padding.'''
+    data = bytearray(data)
+    padding = -len(data) % 5
+    data += b'~' * padding
+    result = bytearray()
+    for i in range(0, len(data), 5):
+        acc = 0
+        for c in data[i:i + 5]:
+            try:
+                acc = acc * 85 + B85DEC[c]
+            except KeyError:
+                raise Exception('Invalid base85 data')
+        if acc > 0xffffffff:
+            raise Exception('Invalid base85 data')
+        result += struct.pack('>I', acc)
+    if padding:
+        del result[-padding:]
+    return bytes(result)
+
+
+def parse_markers(data):
+    '''Returns (obsoleted, successors) tuples for the version 0 markers in
+    the given data.'''
+    version = bytearray(data[:1])
+    if not version:
+        return
+    if version[0] != 0:
+        raise Exception('Unsupported obsolescence markers version %d'
+                        % version[0])
+    pos = 1
+    while pos < len(data):
+        numsuc, mdsize, flags, prec = FM0_HEADER.unpack_from(data, pos)
+        pos += FM0_HEADER.size
+        successors = tuple(hexlify(data[pos + 20 * i:pos + 20 * (i + 1)])
+                           for i in range(numsuc))
+        pos += 20 * numsuc + mdsize
+        if pos > len(data):
+            raise Exception('Truncated obsolescence markers')
+        yield hexlify(prec), successors
+
+
+def obsolescence_markers(keys):
+    '''Returns the markers from the result of `listkeys obsolete`.'''
+    markers = set()
+    for key, value in iteritems(keys):
+        if key.startswith(b'dump'):
+            markers.update(parse_markers(b85decode(value)))
+    return markers
+
+
+def remote_markers(repo):
+    '''Returns the obsolescence markers from the given repository, when it
+    exposes them.'''
+    if b'obsolete' not in repo.listkeys(b'namespaces'):
+        return set()
+    return obsolescence_markers(repo.listkeys(b'obsolete'))
+
+
+def stored_markers():
+    '''Returns the markers fetched from all remotes so far.'''
+    sha1 = Git.resolve_ref(OBSOLETE_REF)
+    markers = set()
+    if sha1:
+        for line in Git.iter('cat-file', 'blob', sha1):
+            if line:
+                nodes = line.split(b' ')
+                markers.add((nodes[0], tuple(nodes[1:])))
+    return markers
+
+
+def store_markers(markers):
+    known = stored_markers()
+    if markers <= known:
+        return
+    markers = markers | known
+    sha1 = one(Git.iter('hash-object', '-w', '--stdin', stdin=(
+        b' '.join((prec,) + successors)
+        for prec, successors in sorted(markers))))
+    Git.run('update-ref', OBSOLETE_REF, sha1)
+
+
+def successors(markers, node):
+    '''Returns the successors of the given changeset, or None if it is not
+    obsolete. An empty tuple means the changeset was pruned.'''
+    result = None
+    for prec, succ in markers:
+        if prec == node:
+            result = (result or ()) + succ
+    return result
@@ -0,0 +1,60 @@
+from __future__ import absolute_import, unicode_literals
+import os
+from cinnabar.git import Git
+from cinnabar.helper import GitHgHelper
+from cinnabar.util import (
+    iteritems,
+    one,
+)
+
+# The draft roots last seen on each remote are stored, as a blob listing
+# their mercurial sha1s, in a ref named after the remote under this prefix.
+PHASES_REF_PREFIX = b'refs/cinnabar/phases/'
+
+
+def draft_roots(phases):
+    '''Returns the draft roots from the result of `listkeys phases`.'''
+    return set(node for node, phase in iteritems(phases)
+               if node != b'publishing' and int(phase))
+
+
+def store_draft_roots(remote, roots):
+    ref = PHASES_REF_PREFIX + remote
+    if not roots:
+        Git.run('update-ref', '-d', ref, stderr=open(os.devnull, 'wb'))
+        return
+    sha1 = one(Git.iter('hash-object', '-w', '--stdin', stdin=sorted(roots)))
+    Git.run('update-ref', ref, sha1)
+
+
+def stored_draft_roots():
+    '''Returns the draft roots recorded for all remotes.'''
+    roots = set()
+    for sha1, ref in Git.for_each_ref(PHASES_REF_PREFIX):
+        roots.update(r for r in Git.iter('cat-file', 'blob', sha1) if r)
+    return roots
+
+
+def draft_commits(store, commits, roots):
+    '''Returns the git commits among the given commits and their ancestors
+    that descend from one of the given draft roots.'''
+    commits = list(commits)
+    result = set()
+    if not commits:
+        return result
+    tips = set(c for c, t, p in GitHgHelper.rev_list(
+        b'--no-walk=unsorted', *commits))
+    # `^root^@` excludes nothing for a parentless root, leaving the ancestry
+    # path unbounded. So each root gets its own `root..commits` walk, to
+    # which the root is added when it is among the commits or their
+    # ancestors.
+    for root in roots:
+        commit = store.changeset_ref(root)
+        if not commit:
+            continue
+        descendants = set(c for c, t, p in GitHgHelper.rev_list(
+            b'--ancestry-path', b'--topo-order', b'^%s' % commit, *commits))
+        if descendants or commit in tips:
+            descendants.add(commit)
+        result |= descendants
+    return result
@@ -1,25 +1,35 @@
 from __future__ import absolute_import, division, unicode_literals
-from binascii import unhexlify
+from binascii import (
+    hexlify,
+    unhexlify,
+)
+import codecs
+import os
 import sys
 
 from cinnabar.exceptions import Abort
 from cinnabar.githg import (
     BranchMap,
+    GitHgStore,
+    write_commit_graph,
 )
 from cinnabar.helper import GitHgHelper
 from cinnabar.hg.repo import (
     bundlerepo,
     getbundle,
     get_repo,
+    narrow_spec,
     push,
+    remote_heads,
 )
 from cinnabar.hg.bundle import (
     PushStore,
 )
-from itertools import chain
+from cinnabar.hg.changegroup import extstored_nodes
 import logging
 from cinnabar.git import (
     Git,
+    GitProcess,
     InvalidConfig,
     NULL_NODE_ID,
 )
@@ -30,10 +40,38 @@ from cinnabar.util import (
     fsdecode,
     IOLogger,
     iteritems,
+    one,
     strip_suffix,
     VersionedDict,
 )
 import cinnabar.util
+from cinnabar.largefiles import (
+    added_largefiles,
+    has_largefile,
+)
+from cinnabar.lfs import (
+    added_lfs_pointers,
+    store_lfs_nodes,
+)
+from cinnabar.obsolete import (
+    remote_markers,
+    store_markers,
+)
+from cinnabar.phases import (
+    draft_commits,
+    draft_roots,
+    store_draft_roots,
+    stored_draft_roots,
+)
+from cinnabar.remotes import (
+    remote_ref_prefix,
+    store_remote_heads,
+)
+from cinnabar.tags import (
+    AUTO_TAGS_REF,
+    create_tag_commit,
+    update_git_tags,
+)
 try:
     from urllib.parse import unquote_to_bytes
 except ImportError:
@@ -50,9 +88,47 @@ def sanitize_branch_name(name):
     return name.replace(b'%', b'%25').replace(b' ', b'%20')
 
 
+def branch_mapping(remote=None):
+    '''Returns a function giving the name under which a mercurial branch is
+    exposed in git refs, or None if it is not exposed at all, as per the
+    cinnabar.branch-map configuration. The configuration is a comma-separated
+    list of `hg-branch:git-name` rules, where an empty `git-name` filters
+    the branch out.'''
+    mapping = {}
+    config = Git.config('cinnabar.branch-map', remote) or b''
+    for rule in config.split(b','):
+        rule = rule.strip()
+        if not rule:
+            continue
+        branch, sep, name = rule.partition(b':')
+        if not sep or not branch.strip():
+            logging.warning('Ignoring invalid cinnabar.branch-map rule: %s',
+                            rule.decode('utf-8', 'replace'))
+            continue
+        mapping[branch.strip()] = name.strip() or None
+    return lambda branch: mapping.get(branch, branch)
+
+
+def optimize_object_storage():
+    '''Rearrange the object storage after an initial clone, which leaves
+    many packs behind, so that the first operations on the resulting
+    repository are fast.'''
+    progress = [] if cinnabar.util.progress else ['--no-progress']
+    sys.stderr.write('Optimizing object storage...\n')
+    # This happens before the import is complete, when our stdout is the
+    # fast-import stream, so it must not be inherited.
+    with open(os.devnull, 'wb') as devnull:
+        GitProcess('repack', '--geometric=2', '-d', '--write-midx',
+                   *progress, stdout=devnull).wait()
+    write_commit_graph()
+
+
 class BaseRemoteHelper(object):
     def __init__(self, stdin=bytes_stdin, stdout=bytes_stdout):
         self._dry_run = False
+        self._push_options = []
+        self._object_format = False
+        self._shallow = False
         self._helper = IOLogger(logging.getLogger('remote-helper'),
                                 stdin, stdout)
 
@@ -108,10 +184,29 @@ class BaseRemoteHelper(object):
         elif name == b'dry-run' and value in (b'true', b'false'):
             self._dry_run = value == b'true'
             self._helper.write(b'ok\n')
+        elif name == b'push-option':
+            # Git quotes values containing special characters.
+            if value.startswith(b'"') and value.endswith(b'"'):
+                value = codecs.escape_decode(value[1:-1])[0]
+            self._push_options.append(value)
+            self._helper.write(b'ok\n')
+        elif name == b'object-format' and value in (b'true', b'sha1'):
+            # Only sha1 repositories are supported (see util.run).
+            self._object_format = True
+            self._helper.write(b'ok\n')
+        elif name in (b'depth', b'deepen-since', b'deepen-not'):
+            # git clone ignores options the helper doesn't support, so
+            # remember the request to refuse the fetch later.
+            self._shallow = True
+            self._helper.write(b'unsupported\n')
         else:
             self._helper.write(b'unsupported\n')
         self._helper.flush()
 
+    def _list_object_format(self):
+        if self._object_format:
+            self._helper.write(b':object-format sha1\n')
+
 
 class TagsRemoteHelper(BaseRemoteHelper):
     def __init__(self, store, stdin=bytes_stdin, stdout=bytes_stdout):
@@ -122,6 +217,7 @@ class TagsRemoteHelper(BaseRemoteHelper):
         self._helper.write(
             b'option\n'
             b'import\n'
+            b'object-format\n'
             b'refspec HEAD:refs/cinnabar/HEAD\n'
             b'\n'
         )
@@ -137,6 +233,7 @@ class TagsRemoteHelper(BaseRemoteHelper):
         for tag, ref in tags:
             Git.update_ref(b'refs/cinnabar/refs/tags/' + tag, ref)
         GitHgHelper.reload()
+        self._list_object_format()
         for tag, ref in tags:
             self._helper.write(b'%s refs/tags/%s\n' % (ref, tag))
         self._helper.write(b'\n')
@@ -157,10 +254,14 @@ class GitRemoteHelper(BaseRemoteHelper):
         if isinstance(self._repo, bundlerepo):
             self._repo.init(self._store)
         self._remote = remote
+        self._store.action = b'fetch %s' % (remote.name or remote.url)
+        # Where git is told to expect the imported refs.
+        self._ref_prefix = remote_ref_prefix(remote.name)
 
         self._head_template = None
         self._tip_template = None
         self._bookmark_template = None
+        self._topic_template = None
 
         self._branchmap = None
         self._bookmarks = {}
@@ -170,6 +271,8 @@ class GitRemoteHelper(BaseRemoteHelper):
             None: False,
             b'false': False,
             b'true': True,
+            b'fuzzy': 'fuzzy',
+            b'patch-id': 'patch-id',
         }
         try:
             self._graft = Git.config('cinnabar.graft', remote=remote.name,
@@ -184,14 +287,18 @@ class GitRemoteHelper(BaseRemoteHelper):
             )
 
     def capabilities(self):
+        # Other modern capabilities don't apply to mercurial remotes:
+        # stateless-connect and check-connectivity only make sense for
+        # helpers talking the git protocol with the remote.
         self._helper.write(
             b'option\n'
             b'import\n'
             b'push\n'
-            b'refspec refs/heads/*:refs/cinnabar/refs/heads/*\n'
-            b'refspec hg/*:refs/cinnabar/hg/*\n'
-            b'refspec HEAD:refs/cinnabar/HEAD\n'
-            b'\n'
+            b'object-format\n'
+            b'refspec refs/heads/*:%(prefix)srefs/heads/*\n'
+            b'refspec hg/*:%(prefix)shg/*\n'
+            b'refspec HEAD:%(prefix)sHEAD\n'
+            b'\n' % {b'prefix': self._ref_prefix}
         )
         self._helper.flush()
 
@@ -203,56 +310,21 @@ class GitRemoteHelper(BaseRemoteHelper):
             heads = [unhexlify(f) for f in fetch]
             branchmap = {None: heads}
             bookmarks = {}
-
-        elif self._repo.capable(b'batch'):
-            if hasattr(self._repo, 'commandexecutor'):
-                with self._repo.commandexecutor() as e:
-                    branchmap = e.callcommand(b'branchmap', {})
-                    heads = e.callcommand(b'heads', {})
-                    bookmarks = e.callcommand(b'listkeys', {
-                        b'namespace': b'bookmarks'
-                    })
-                branchmap = branchmap.result()
-                heads = heads.result()
-                bookmarks = bookmarks.result()
-            elif hasattr(self._repo, b'iterbatch'):
-                batch = self._repo.iterbatch()
-                batch.branchmap()
-                batch.heads()
-                batch.listkeys(b'bookmarks')
-                batch.submit()
-                branchmap, heads, bookmarks = batch.results()
-            else:
-                batch = self._repo.batch()
-                branchmap = batch.branchmap()
-                heads = batch.heads()
-                bookmarks = batch.listkeys(b'bookmarks')
-                batch.submit()
-                branchmap = branchmap.value
-                heads = heads.value
-                bookmarks = bookmarks.value
-            if heads == [b'\0' * 20]:
-                heads = []
         else:
-            while True:
-                branchmap = self._repo.branchmap()
-                heads = self._repo.heads()
-                if heads == [b'\0' * 20]:
-                    heads = []
-                # Some branch heads can be non-heads topologically, but if
-                # some heads don't appear in the branchmap, then something
-                # was pushed to the repo between branchmap() and heads()
-                if set(heads).issubset(
-                        set(chain(*(v for _, v in iteritems(branchmap))))):
-                    break
-            bookmarks = self._repo.listkeys(b'bookmarks')
+            branchmap, heads, bookmarks = remote_heads(self._repo)
+
+        if any(len(h) != 20 for h in heads):
+            # The metadata, both here and in the helper, only has room for
+            # 20-bytes mercurial nodes.
+            raise Abort('The remote repository does not use SHA-1 node ids, '
+                        'which git-cinnabar does not support.')
 
         self._bookmarks = bookmarks
         branchmap = self._branchmap = BranchMap(self._store, branchmap,
                                                 heads)
         self._has_unknown_heads = bool(self._branchmap.unknown_heads())
         if self._graft and self._has_unknown_heads and not arg:
-            self._store.prepare_graft()
+            self._store.prepare_graft(self._graft)
             get_heads = set(branchmap.heads()) & branchmap.unknown_heads()
             getbundle(self._repo, self._store, get_heads, branchmap.names())
             # We may have failed to graft all changesets, in which case we
@@ -305,12 +377,38 @@ class GitRemoteHelper(BaseRemoteHelper):
                     refs_config = 'cinnabar.pushrefs'
 
             refs_style = ConfigSetFunc(refs_config, refs_styles,
+                                       extra_values=('topics',),
                                        remote=self._remote.name,
                                        default='all')
 
         refs_style = refs_style or (lambda x: True)
         self._refs_style = refs_style
 
+        try:
+            hide_closed = Git.config('cinnabar.hide-closed',
+                                     self._remote.name, values={
+                                         None: False,
+                                         b'false': False,
+                                         b'true': True,
+                                     })
+        except InvalidConfig as e:
+            logging.error(str(e))
+            hide_closed = False
+
+        try:
+            collapse = Git.config('cinnabar.collapse-branches',
+                                  self._remote.name, values={
+                                      None: False,
+                                      b'false': False,
+                                      b'true': True,
+                                  })
+        except InvalidConfig as e:
+            logging.error(str(e))
+            collapse = False
+
+        branch_name = branch_mapping(self._remote.name)
+        # Where the tip of each branch is exposed, mapped by branch name.
+        tip_refs = {}
         refs = {}
         if refs_style('heads') or refs_style('tips'):
             if refs_style('heads') and refs_style('tips'):
@@ -325,15 +423,54 @@ class GitRemoteHelper(BaseRemoteHelper):
             elif refs_style('tips'):
                 self._tip_template = b'refs/heads/%s'
 
+            # Collapsing branches to refs/heads/$branch is only possible
+            # when the other refs don't live there.
+            collapse = collapse and (
+                self._head_template or self._tip_template).startswith(
+                    b'refs/heads/branches/')
+            names = {}
             for branch in sorted(branchmap.names()):
+                name = branch_name(branch)
+                if name is None:
+                    continue
+                if name in names:
+                    logging.warning(
+                        'Branches %s and %s are both mapped to %s. Ignoring '
+                        'the latter.', names[name].decode('utf-8', 'replace'),
+                        branch.decode('utf-8', 'replace'),
+                        name.decode('utf-8', 'replace'))
+                    continue
+                names[name] = branch
                 branch_tip = branchmap.tip(branch)
+                heads = [h for h in sorted(branchmap.heads(branch))
+                         if not (hide_closed and branchmap.closed(h))]
+                if collapse and len(heads) == 1 and name.split(b'/')[0] \
+                        not in (b'bookmarks', b'branches', b'topics'):
+                    tip_refs[branch] = b'refs/heads/%s' % name
+                    refs[tip_refs[branch]] = heads[0]
+                    continue
                 if refs_style('heads'):
-                    for head in sorted(branchmap.heads(branch)):
+                    for head in heads:
                         if head == branch_tip and refs_style('tips'):
                             continue
-                        refs[self._head_template % (branch, head)] = head
-                if branch_tip and refs_style('tips'):
-                    refs[self._tip_template % branch] = branch_tip
+                        refs[self._head_template % (name, head)] = head
+                        if head == branch_tip:
+                            tip_refs[branch] = self._head_template % (
+                                name, head)
+                if branch_tip in heads and refs_style('tips'):
+                    tip_refs[branch] = self._tip_template % name
+                    refs[tip_refs[branch]] = branch_tip
+
+            if refs_style('topics'):
+                self._topic_template = b'refs/heads/topics/%s/%s'
+                for (branch, topic), heads in iteritems(
+                        self._topics(branchmap)):
+                    name = branch_name(branch)
+                    # Topics with several heads are only available through
+                    # the branch heads.
+                    if len(heads) == 1 and names.get(name) == branch:
+                        refs[self._topic_template % (name, topic)] = \
+                            heads[0]
 
         if refs_style('bookmarks'):
             if refs_style('heads') or refs_style('tips'):
@@ -354,11 +491,8 @@ class GitRemoteHelper(BaseRemoteHelper):
         head_ref = None
         if refs_style('bookmarks') and b'@' in bookmarks:
             head_ref = self._bookmark_template % b'@'
-        elif refs_style('tips'):
-            head_ref = self._tip_template % b'default'
-        elif refs_style('heads'):
-            head_ref = self._head_template % (
-                b'default', branchmap.tip(b'default'))
+        else:
+            head_ref = tip_refs.get(b'default')
 
         if head_ref:
             head = refs.get(head_ref)
@@ -370,6 +504,7 @@ class GitRemoteHelper(BaseRemoteHelper):
         self._refs = {sanitize_branch_name(k): v
                       for k, v in iteritems(refs)}
 
+        self._list_object_format()
         head_prefix = strip_suffix((self._head_template or b''), b'%s/%s')
         for k, v in sorted(iteritems(self._refs)):
             if head_prefix and k.startswith(head_prefix):
@@ -387,11 +522,16 @@ class GitRemoteHelper(BaseRemoteHelper):
             raise Abort('Cannot fetch with broken metadata. '
                         'Please fix your clone first.\n')
 
+        if self._shallow:
+            raise Abort('Shallow clones and fetches are not supported. '
+                        'Please try again without --depth, --shallow-since '
+                        'or --shallow-exclude.\n')
+
         # If anything wrong happens at any time, we risk git picking
         # the existing refs/cinnabar refs, so remove them preventively.
-        for sha1, ref in Git.for_each_ref('refs/cinnabar/refs/heads',
-                                          'refs/cinnabar/hg',
-                                          'refs/cinnabar/HEAD'):
+        for sha1, ref in Git.for_each_ref(self._ref_prefix + b'refs/heads',
+                                          self._ref_prefix + b'hg',
+                                          self._ref_prefix + b'HEAD'):
             Git.delete_ref(ref)
 
         def resolve_head(head):
@@ -409,6 +549,8 @@ class GitRemoteHelper(BaseRemoteHelper):
         if not heads:
             heads = self._branchmap.heads()
 
+        initial_clone = not self._store._has_metadata
+
         try:
             # Mercurial can be an order of magnitude slower when creating
             # a bundle when not giving topological heads, which some of
@@ -423,6 +565,8 @@ class GitRemoteHelper(BaseRemoteHelper):
                 unknown_heads = self._branchmap.unknown_heads()
                 if set(heads).issuperset(unknown_heads):
                     heads = set(self._branchmap.heads()) & unknown_heads
+                if initial_clone and not self._store._graft:
+                    self._checkpointed_clone(heads)
                 getbundle(self._repo, self._store, heads,
                           self._branchmap.names())
         except:  # noqa: E722
@@ -430,11 +574,39 @@ class GitRemoteHelper(BaseRemoteHelper):
             raise
         finally:
             for ref, value in iteritems(wanted_refs):
-                ref = b'refs/cinnabar/' + ref
+                ref = self._ref_prefix + ref
                 Git.update_ref(ref, self._store.changeset_ref(value))
 
         self._store.close()
 
+        if self._remote.name:
+            store_draft_roots(self._remote.name,
+                              draft_roots(self._repo.listkeys(b'phases')))
+
+        if self._remote.name:
+            store_remote_heads(self._remote.name, [
+                h for h in self._branchmap.heads()
+                if self._store.changeset_ref(h)])
+
+        store_markers(remote_markers(self._repo))
+
+        store_lfs_nodes(extstored_nodes)
+
+        if initial_clone and self._store._metadata_sha1:
+            try:
+                optimize = Git.config(
+                    'cinnabar.optimize-on-clone', remote=self._remote.name,
+                    values={
+                        None: False,
+                        b'false': False,
+                        b'true': True,
+                    })
+            except InvalidConfig as e:
+                logging.warning(str(e))
+                optimize = False
+            if optimize:
+                optimize_object_storage()
+
         self._helper.write(b'done\n')
         self._helper.flush()
 
@@ -450,12 +622,278 @@ class GitRemoteHelper(BaseRemoteHelper):
                     % {'conf': prune}
                 )
 
-        if self._store.tag_changes:
+        try:
+            auto_tags = Git.config('cinnabar.auto-tags', self._remote.name,
+                                   values={
+                                       None: False,
+                                       b'false': False,
+                                       b'true': True,
+                                   })
+        except InvalidConfig as e:
+            logging.error(str(e))
+            auto_tags = False
+        if auto_tags:
+            if self._store.tag_changes or not Git.resolve_ref(AUTO_TAGS_REF):
+                update_git_tags(self._store)
+        elif self._store.tag_changes:
             sys.stderr.write(
                 '\nRun the following command to update tags:\n')
             sys.stderr.write('  git fetch --tags hg::tags: tag "*"\n')
 
+        if initial_clone and self._repo.capable(b'largefiles'):
+            sys.stderr.write(
+                '\nThis repository may use largefiles. Run the following '
+                'commands to\nget their contents after checkout:\n')
+            sys.stderr.write('  git cinnabar largefiles fetch\n')
+            sys.stderr.write('  git cinnabar largefiles checkout\n')
+
+        if initial_clone and extstored_nodes:
+            sys.stderr.write(
+                '\nThis repository uses lfs. Run the following command to '
+                'get the lfs files\ncontents after checkout:\n')
+            sys.stderr.write('  git cinnabar lfs fetch\n')
+
+        if not initial_clone:
+            try:
+                fsck = Git.config(
+                    'cinnabar.fsck-after-fetch', remote=self._remote.name,
+                    values={
+                        None: False,
+                        b'false': False,
+                        b'true': True,
+                    })
+            except InvalidConfig as e:
+                logging.warning(str(e))
+                fsck = False
+            if fsck:
+                from cinnabar.cmd.fsck import (
+                    FsckStatus,
+                    fsck_quick,
+                    last_checked_metadata,
+                )
+                if last_checked_metadata():
+                    # Stdout is where git expects the remote helper output.
+                    fsck_quick(incremental=True,
+                               status=FsckStatus(out=sys.stderr))
+                else:
+                    logging.getLogger('fsck').debug(
+                        'Skipping fsck after fetch: the metadata was never '
+                        'checked. Run `git cinnabar fsck` once to enable it.')
+
+    def _checkpoints(self, heads):
+        '''Returns changesets on the first parent line of one of the given
+        heads, about every `cinnabar.clone-checkpoint` changesets, oldest
+        first.'''
+        try:
+            step = Git.config('cinnabar.clone-checkpoint', self._remote.name)
+            step = int(step or 0)
+        except ValueError:
+            logging.error('Invalid value for cinnabar.clone-checkpoint')
+            return []
+        if step <= 0 or isinstance(self._repo, bundlerepo):
+            return []
+        # The `between` command returns the changesets at distance 1, 2, 4,
+        # 8, etc. of the top changeset, so walk down by the largest power
+        # of two not over the step.
+        index = step.bit_length() - 1
+        tip = self._branchmap.tip(b'default')
+        top = unhexlify(tip if tip in heads else sorted(heads)[0])
+        points = []
+        while True:
+            samples = (self._repo.between(
+                [(top, unhexlify(NULL_NODE_ID))]) or [[]])[0]
+            if len(samples) <= index:
+                break
+            top = samples[index]
+            points.append(hexlify(top))
+        return points[::-1]
+
+    def _checkpointed_clone(self, heads):
+        '''Fetches the history leading to the given heads in steps, storing
+        the metadata after each, such that an interrupted clone can be
+        resumed from the last step with `git fetch`.'''
+        points = self._checkpoints(heads)
+        for n, point in enumerate(points, start=1):
+            logging.info('Fetching checkpoint %d/%d: %s', n, len(points),
+                         point.decode('ascii'))
+            getbundle(self._repo, self._store, [point],
+                      self._branchmap.names())
+            self._store.close()
+            self._store = self._branchmap._store = GitHgStore()
+
+    def _check_publish(self, pushes):
+        '''Checks whether the push would publish changesets that are draft
+        on some remote, and what to do about it. Returns whether the push
+        can go ahead.'''
+        if self._dry_run or not self._repo.listkeys(b'phases').get(
+                b'publishing'):
+            return True
+        sources = [source for source, dest, force in pushes if source]
+        drafts = [c for c in draft_commits(self._store, sources,
+                                           stored_draft_roots())
+                  if self._store.hg_changeset(c)]
+        if not drafts:
+            return True
+        try:
+            mode = Git.config('cinnabar.auto-publish', self._remote.name,
+                              values={
+                                  None: b'warn',
+                                  b'publish': b'publish',
+                                  b'warn': b'warn',
+                                  b'abort': b'abort',
+                              })
+        except InvalidConfig as e:
+            logging.error(str(e))
+            return False
+        if mode == b'abort':
+            logging.error('Push would publish %d draft changesets. Set '
+                          'cinnabar.auto-publish to "publish" to push '
+                          'anyways.', len(drafts))
+            return False
+        if mode == b'warn':
+            logging.warning('Pushing %d draft changesets to a publishing '
+                            'repository. They will become public.',
+                            len(drafts))
+        return True
+
+    def _check_largefiles(self, pushes):
+        '''Checks that the remote has the contents of the largefiles used
+        by the pushed commits, since they can't be uploaded.'''
+        if self._dry_run or not self._repo.capable(b'largefiles'):
+            return True
+        sources = [source for source, dest, force in pushes if source]
+        known = [c for c in (self._store.changeset_ref(h)
+                             for h in self._branchmap.heads()) if c]
+        try:
+            missing = [path for sha, path in sorted(iteritems(
+                added_largefiles(sources, known)))
+                if not has_largefile(self._repo, sha)]
+        except Exception as e:
+            logging.error(str(e))
+            return False
+        if missing:
+            logging.error('The remote repository is missing the contents of '
+                          'the following largefiles, and uploading them is '
+                          'not supported: %s',
+                          ', '.join(fsdecode(p) for p in missing))
+            return False
+        return True
+
+    def _check_lfs(self, pushes):
+        '''Checks that the pushed commits don't add lfs pointers, since
+        sending them as files stored out of the repository is not
+        supported.'''
+        if self._dry_run or not self._repo.capable(b'lfs'):
+            return True
+        sources = [source for source, dest, force in pushes if source]
+        known = [c for c in (self._store.changeset_ref(h)
+                             for h in self._branchmap.heads()) if c]
+        added = added_lfs_pointers(sources, known)
+        if added:
+            logging.error('Pushing the following lfs files is not '
+                          'supported: %s',
+                          ', '.join(fsdecode(p) for p in sorted(added)))
+            return False
+        return True
+
+    def _topics(self, branchmap):
+        '''Returns the heads of each (branch, topic) pair, as per the topic
+        extension. Only heads that were already fetched are considered.'''
+        topics = {}
+        for branch in branchmap.names():
+            for head in branchmap.heads(branch):
+                if not self._store.changeset_ref(head):
+                    continue
+                topic = self._store.changeset(head).topic
+                if topic:
+                    topics.setdefault((branch, topic), []).append(head)
+        return topics
+
+    def _topic_name(self, dest):
+        topic_prefix = strip_suffix((self._topic_template or b''), b'%s/%s')
+        if topic_prefix and dest.startswith(topic_prefix):
+            return unquote_to_bytes(dest.rpartition(b'/')[2])
+
+    def _bookmark_name(self, dest):
+        bookmark_prefix = strip_suffix(
+            (self._bookmark_template or b''), b'%s')
+        if bookmark_prefix and dest.startswith(bookmark_prefix):
+            return unquote_to_bytes(dest[len(bookmark_prefix):])
+
+    def _check_bookmark(self, source, dest, force):
+        '''Checks whether updating the bookmark the given dest corresponds
+        to is a fast-forward. Returns the reason for rejecting the update
+        if it is not.'''
+        name = self._bookmark_name(dest)
+        if not name or force or not source:
+            return None
+        current = self._bookmarks.get(name)
+        if not current or current == NULL_NODE_ID:
+            return None
+        commit = self._store.changeset_ref(current)
+        if not commit:
+            return b'fetch first'
+        proc = GitProcess('merge-base', '--is-ancestor', commit, source,
+                          stdout=open(os.devnull, 'wb'))
+        if proc.wait() != 0:
+            return b'non-fast-forward'
+        return None
+
+    def _tag_commits(self, pushes, status):
+        '''Creates the commits updating the .hgtags file for the pushed
+        tags, and returns them, by destination. Sets the status of the tags
+        that can't, or don't need to, be pushed.'''
+        tags = [(source, dest, force) for source, dest, force in pushes
+                if source and dest.startswith(b'refs/tags/') and
+                dest not in status]
+        if not tags:
+            return {}
+        existing = dict(self._store.tags())
+        sources = [source for source, dest, _ in pushes
+                   if source and not dest.startswith(b'refs/tags/')]
+        target_branch = Git.config('cinnabar.tag-branch', self._remote.name)
+        # The commit to put the next tag commit on top of, for each branch.
+        parents = {}
+        tag_commits = {}
+        for source, dest, force in tags:
+            tag = dest[len(b'refs/tags/'):]
+            commit = one(Git.iter('rev-parse', b'%s^{commit}' % source))
+            node = self._store.hg_changeset(commit)
+            if not node:
+                status[dest] = b'The tagged commit must be pushed first'
+                continue
+            current = existing.get(tag)
+            if current == commit:
+                status[dest] = True
+                continue
+            if current and not force:
+                status[dest] = b'already exists'
+                continue
+            branch = (target_branch or self._store.changeset(node).branch or
+                      b'default')
+            parent = parents.get(branch)
+            if not parent:
+                tip = self._branchmap.tip(branch)
+                parent = tip and self._store.changeset_ref(tip)
+                if not parent:
+                    status[dest] = (b'Cannot find the head of branch %s. '
+                                    b'Please fetch first.' % branch)
+                    continue
+                # Put the tag on top of what is being pushed to the same
+                # branch, if anything, so as not to create a new head.
+                for s in sources:
+                    proc = GitProcess('merge-base', '--is-ancestor', parent,
+                                      s, stdout=open(os.devnull, 'wb'))
+                    if proc.wait() == 0:
+                        parent = s
+                        break
+            parents[branch] = tag_commits[dest] = create_tag_commit(
+                self._store, tag, commit, parent, current)
+        return tag_commits
+
     def push(self, *refspecs):
+        self._store.action = b'push %s' % (self._remote.name or
+                                           self._remote.url)
         try:
             default = b'never' if self._graft else b'phase'
             values = {
@@ -486,33 +924,105 @@ class GitRemoteHelper(BaseRemoteHelper):
                         b'Please fix your clone first.\n' % dest)
             self._helper.write(b'\n')
             self._helper.flush()
+        elif any(narrow_spec()):
+            # Mercurial manifests are created from git trees, which don't
+            # contain the files outside the narrow clone.
+            for source, dest, force in pushes:
+                self._helper.write(
+                    b'error %s Pushing from a narrow clone is not '
+                    b'supported\n' % dest)
+            self._helper.write(b'\n')
+            self._helper.flush()
+        elif self._store._filters:
+            # Mercurial manifests are created from git trees, which don't
+            # match them when content filters were used.
+            for source, dest, force in pushes:
+                self._helper.write(
+                    b'error %s Pushing from a repository converted with '
+                    b'content filters is not supported\n' % dest)
+            self._helper.write(b'\n')
+            self._helper.flush()
+        elif not self._check_publish(pushes):
+            for source, dest, force in pushes:
+                self._helper.write(
+                    b'error %s Pushing would publish draft changesets\n'
+                    % dest)
+            self._helper.write(b'\n')
+            self._helper.flush()
+        elif not self._check_largefiles(pushes):
+            for source, dest, force in pushes:
+                self._helper.write(
+                    b'error %s Remote is missing largefiles\n' % dest)
+            self._helper.write(b'\n')
+            self._helper.flush()
+        elif not self._check_lfs(pushes):
+            for source, dest, force in pushes:
+                self._helper.write(
+                    b'error %s Cannot push lfs files\n' % dest)
+            self._helper.write(b'\n')
+            self._helper.flush()
         else:
-            repo_heads = self._branchmap.heads()
-            PushStore.adopt(self._store, self._graft)
-            pushed = push(self._repo, self._store, pushes, repo_heads,
-                          self._branchmap.names(), self._dry_run)
-
             status = {}
+            for source, dest, force in pushes:
+                rejected = self._check_bookmark(source, dest, force)
+                if rejected:
+                    status[dest] = rejected
+            topics = set(self._topic_name(dest) for source, dest, _ in pushes
+                         if source)
+            topic = None
+            if len(topics) > 1:
+                for source, dest, _ in pushes:
+                    if self._topic_name(dest):
+                        status[dest] = (b'Cannot push to a topic along with '
+                                        b'other topics or branches')
+            elif topics:
+                topic = topics.pop()
+            tag_commits = self._tag_commits(pushes, status)
+            remaining = [(tag_commits.get(dest, source), dest, force)
+                         for source, dest, force in pushes
+                         if dest not in status]
+            PushStore.adopt(self._store, self._graft, topic)
+            bookmarks = []
+            for source, dest, _ in remaining:
+                name = self._bookmark_name(dest)
+                if name and source:
+                    bookmarks.append(
+                        (name, self._bookmarks.get(name, b''), source))
+            if remaining:
+                pushed, pushed_bookmarks = push(
+                    self._repo, self._store, remaining,
+                    self._branchmap.heads(), self._branchmap.names(),
+                    self._dry_run, self._push_options, bookmarks)
+            else:
+                pushed = ()
+                pushed_bookmarks = set()
+
             for source, dest, _ in pushes:
+                if dest in status:
+                    continue
                 if dest.startswith(b'refs/tags/'):
                     if source:
-                        status[dest] = b'Pushing tags is unsupported'
+                        status[dest] = bool(len(pushed))
                     else:
                         status[dest] = \
                             b'Deleting remote tags is unsupported'
                     continue
-                bookmark_prefix = strip_suffix(
-                    (self._bookmark_template or b''), b'%s')
-                if not bookmark_prefix or not dest.startswith(bookmark_prefix):
+                name = self._bookmark_name(dest)
+                if not name:
                     if source:
                         status[dest] = bool(len(pushed))
+                    elif self._topic_name(dest):
+                        status[dest] = \
+                            b'Deleting remote topics is unsupported'
                     else:
                         status[dest] = \
                             b'Deleting remote branches is unsupported'
                     continue
-                name = unquote_to_bytes(dest[len(bookmark_prefix):])
                 if source:
                     source = self._store.hg_changeset(source)
+                if self._dry_run or name in pushed_bookmarks:
+                    status[dest] = True
+                    continue
                 status[dest] = self._repo.pushkey(
                     b'bookmarks', name, self._bookmarks.get(name, b''),
                     source or b'')
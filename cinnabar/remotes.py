@@ -0,0 +1,39 @@
+from __future__ import absolute_import, unicode_literals
+import os
+from cinnabar.git import Git
+from cinnabar.util import one
+
+# Refs specific to a remote are stored under this prefix followed by the
+# remote name, such that several mercurial remotes can be used in the same
+# repository without stepping on each other.
+REMOTES_REF_PREFIX = b'refs/cinnabar/remotes/'
+
+
+def remote_ref_prefix(remote):
+    '''Returns the prefix for the refs specific to the given remote name.
+    Anonymous remotes use the same prefix as before remotes had their own
+    namespace.'''
+    if remote:
+        return b'%s%s/' % (REMOTES_REF_PREFIX, remote)
+    return b'refs/cinnabar/'
+
+
+def store_remote_heads(remote, heads):
+    '''Records the heads last seen on the given remote, as a blob listing
+    their mercurial sha1s.'''
+    ref = remote_ref_prefix(remote) + b'heads'
+    if not heads:
+        Git.run('update-ref', '-d', ref, stderr=open(os.devnull, 'wb'))
+        return
+    sha1 = one(Git.iter('hash-object', '-w', '--stdin', stdin=sorted(heads)))
+    Git.run('update-ref', ref, sha1)
+
+
+def stored_remote_heads(remote):
+    '''Returns the heads last seen on the given remote.'''
+    if not remote:
+        return set()
+    sha1 = Git.resolve_ref(remote_ref_prefix(remote) + b'heads')
+    if not sha1:
+        return set()
+    return set(h for h in Git.iter('cat-file', 'blob', sha1) if h)
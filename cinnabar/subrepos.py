@@ -0,0 +1,301 @@
+from __future__ import absolute_import, unicode_literals
+import logging
+import re
+from collections import OrderedDict
+from cinnabar.git import (
+    Git,
+    GitProcess,
+    InvalidConfig,
+    NULL_NODE_ID,
+)
+from cinnabar.helper import GitHgHelper
+from cinnabar.util import one
+
+GITLINK_MODE = b'160000'
+
+SHA1_RE = re.compile(b'[0-9a-f]{40}$')
+
+SECTION_RE = re.compile(br'^[ \t]*\[.*\][ \t]*$', re.M)
+
+
+def subrepos_mode():
+    '''Returns how mercurial subrepositories are converted, as per the
+    cinnabar.subrepos configuration: `keep` leaves the .hgsub and
+    .hgsubstate files as they are, and `submodules` converts them to git
+    submodules.'''
+    try:
+        return Git.config('cinnabar.subrepos', values={
+            None: b'keep',
+            b'keep': b'keep',
+            b'submodules': b'submodules',
+        })
+    except InvalidConfig as e:
+        logging.warning(str(e))
+        return b'keep'
+
+
+def parse_hgsub(data):
+    '''Returns an ordered dict of subrepository path to source, from the
+    contents of a .hgsub file.'''
+    subs = OrderedDict()
+    for line in data.splitlines():
+        line = line.strip()
+        if not line or line.startswith((b'#', b';')):
+            continue
+        # Sections, like [subpaths], alter sources with the configuration
+        # of the user, which we don't know about.
+        if line.startswith(b'[') and line.endswith(b']'):
+            break
+        path, sep, source = line.partition(b'=')
+        if sep:
+            subs[path.strip()] = source.strip()
+    return subs
+
+
+def parse_hgsubstate(data):
+    '''Returns a dict of subrepository path to revision, from the contents
+    of a .hgsubstate file.'''
+    state = {}
+    for line in data.splitlines():
+        rev, _, path = line.strip().partition(b' ')
+        if path:
+            state[path] = rev
+    return state
+
+
+def is_relative(source):
+    return b'://' not in source and not source.startswith(b'/')
+
+
+def source_to_url(source):
+    '''Returns the git submodule url for a mercurial subrepository source,
+    or None when the kind of subrepository is not supported.'''
+    if source.startswith(b'[git]'):
+        return source[5:]
+    if source.startswith(b'['):
+        return None
+    if is_relative(source):
+        # Relative sources are relative to the parent repository, like git
+        # submodule relative urls.
+        if not source.startswith(b'.'):
+            source = b'./' + source
+        return source
+    return b'hg::' + source
+
+
+def url_to_source(url):
+    '''Returns the mercurial subrepository source for a git submodule url,
+    and whether it is a mercurial subrepository.'''
+    if url.startswith(b'hg::'):
+        return url[4:], True
+    if url.startswith(b'./'):
+        return url[2:], True
+    if url.startswith(b'../'):
+        return url, True
+    return b'[git]' + url, False
+
+
+def read_gitmodules(sha1):
+    '''Returns an ordered dict of submodule path to url, from the contents
+    of the .gitmodules blob with the given sha1.'''
+    proc = GitProcess('config', '-z', '--blob', sha1, '--get-regexp',
+                      r'^submodule\..*\.(path|url)$')
+    data = proc.stdout.read()
+    proc.wait()
+    paths = OrderedDict()
+    urls = {}
+    for item in data.split(b'\0'):
+        if not item:
+            continue
+        key, _, value = item.partition(b'\n')
+        name, _, var = key[len(b'submodule.'):].rpartition(b'.')
+        if var == b'path':
+            paths[name] = value
+        else:
+            urls[name] = value
+    return OrderedDict((path, urls.get(name))
+                       for name, path in paths.items())
+
+
+def hgsub_sections(data):
+    '''Returns the part of the contents of a .hgsub file starting at its
+    first section, e.g. [subpaths].'''
+    match = SECTION_RE.search(data)
+    return data[match.start():] if match else b''
+
+
+class SubrepoConverter(object):
+    '''Converts between mercurial subrepositories and git submodules.'''
+    # Number of changesets for which the .hgsub and .hgsubstate entries are
+    # kept around, for their children.
+    CACHE_SIZE = 64
+
+    def __init__(self, store):
+        self._store = store
+        self._cache = {}
+        self._entries = OrderedDict()
+
+    def _ls(self, treeish, path):
+        return one(Git.ls_tree(treeish, path))
+
+    def _hgsub_entries(self, tree, changeset):
+        entries = None
+        if changeset is not None and len(changeset.parents) == 1 and \
+                not any(f in (b'.hgsub', b'.hgsubstate')
+                        for f in changeset.files):
+            # The files are the same as in the parent changeset.
+            entries = self._entries.get(changeset.parents[0])
+        if entries is None:
+            hgsub = self._ls(tree, b'.hgsub')
+            entries = (hgsub, hgsub and self._ls(tree, b'.hgsubstate'))
+        if changeset is not None:
+            self._entries[changeset.node] = entries
+            if len(self._entries) > self.CACHE_SIZE:
+                self._entries.popitem(last=False)
+        return entries
+
+    def to_git(self, tree, changeset=None):
+        '''Returns the changes to apply to the given git tree, created from
+        a mercurial manifest, to replace the .hgsub and .hgsubstate files
+        with git submodules, as a .gitmodules content and a list of
+        (path, commit) gitlinks. Returns None when there is nothing to
+        change or the subrepositories can't be converted. When the
+        changeset the tree is for is given, the lookups of its parent are
+        reused when it doesn't touch the .hgsub and .hgsubstate files.'''
+        hgsub, hgsubstate = self._hgsub_entries(tree, changeset)
+        if not hgsub or not hgsubstate:
+            return None
+        key = (hgsub[2], hgsubstate[2])
+        if key in self._cache:
+            return self._cache[key]
+        subs = parse_hgsub(GitHgHelper.cat_file(b'blob', hgsub[2]))
+        state = parse_hgsubstate(
+            GitHgHelper.cat_file(b'blob', hgsubstate[2]))
+        gitmodules = []
+        gitlinks = []
+        result = None
+        for path, source in subs.items():
+            url = source_to_url(source)
+            rev = state.get(path)
+            if url is None or not rev or not SHA1_RE.match(rev):
+                logging.warning('Cannot convert subrepository %s (%s).',
+                                path.decode('utf-8', 'replace'),
+                                source.decode('utf-8', 'replace'))
+                break
+            if not source.startswith(b'[git]'):
+                # Mercurial subrepositories can only be converted when they
+                # were also converted in this repository.
+                rev = self._store.changeset_ref(rev)
+                if not rev:
+                    logging.warning(
+                        'Cannot convert subrepository %s: changeset %s is '
+                        'unknown.', path.decode('utf-8', 'replace'),
+                        state[path].decode('ascii'))
+                    break
+            gitmodules.append(b'[submodule "%s"]\n\tpath = %s\n\turl = %s\n'
+                              % (path, path, url))
+            gitlinks.append((path, rev))
+        else:
+            if gitlinks:
+                result = (b''.join(gitmodules), gitlinks)
+        self._cache[key] = result
+        return result
+
+    def to_hg(self, commit, parent=(None, None)):
+        '''Returns the contents of the .hgsub and .hgsubstate files for the
+        submodules in the given git commit, or None for both when there are
+        none. When given, the contents of the files in the parent changeset
+        are kept when the subrepositories they list are unchanged, so that
+        comments and sections, like [subpaths], are preserved.'''
+        gitmodules = self._ls(commit, b'.gitmodules')
+        if not gitmodules:
+            return None, None
+        hgsub = []
+        hgsubstate = []
+        for path, url in read_gitmodules(gitmodules[2]).items():
+            gitlink = self._ls(commit, path)
+            if not gitlink or gitlink[0] != GITLINK_MODE or not url:
+                continue
+            source, is_hg = url_to_source(url)
+            rev = gitlink[2]
+            if is_hg:
+                rev = self._store.hg_changeset(rev)
+                if not rev:
+                    raise Exception(
+                        'Cannot push submodule %s: commit %s is not known '
+                        'to correspond to a mercurial changeset.'
+                        % (path.decode('utf-8', 'replace'),
+                           gitlink[2].decode('ascii')))
+            hgsub.append(b'%s = %s\n' % (path, source))
+            hgsubstate.append(b'%s %s\n' % (rev, path))
+        if not hgsub:
+            return None, None
+        hgsub = b''.join(hgsub)
+        hgsubstate = b''.join(sorted(
+            hgsubstate, key=lambda l: l.split(b' ', 1)[1]))
+        parent_hgsub, parent_hgsubstate = parent
+        if parent_hgsub is not None:
+            if dict(parse_hgsub(parent_hgsub)) == dict(parse_hgsub(hgsub)):
+                hgsub = parent_hgsub
+            else:
+                sections = hgsub_sections(parent_hgsub)
+                if sections:
+                    hgsub = b'%s\n%s' % (hgsub, sections)
+        if parent_hgsubstate is not None and \
+                parse_hgsubstate(parent_hgsubstate) == \
+                parse_hgsubstate(hgsubstate):
+            hgsubstate = parent_hgsubstate
+        return hgsub, hgsubstate
+
+    def translate_diff(self, commit, diff, parent_lines):
+        '''Replaces the changes to git submodules in the given diff_tree
+        output with changes to the .hgsub and .hgsubstate files.'''
+        diff = list(diff)
+        if not any(path == b'.gitmodules' or
+                   GITLINK_MODE in (mode_before, mode_after)
+                   for mode_before, mode_after, _, _, _, path in diff):
+            return diff
+        result = [d for d in diff if d[5] != b'.gitmodules' and
+                  GITLINK_MODE not in d[:2]]
+        paths = (b'.hgsub', b'.hgsubstate')
+        befores = []
+        for path in paths:
+            line = parent_lines.get(path)
+            befores.append(self._store.git_file_ref(line.sha1)
+                           if line else None)
+        parent = tuple(GitHgHelper.cat_file(b'blob', before)
+                       if before else None for before in befores)
+        for path, before, parent_data, data in zip(
+                paths, befores, parent, self.to_hg(commit, parent)):
+            if data is None:
+                if before:
+                    result.append((b'100644', b'000000', before,
+                                   NULL_NODE_ID, b'D', path))
+                continue
+            if data == parent_data:
+                continue
+            sha1 = GitHgHelper.put_blob(data)
+            if before:
+                result.append((b'100644', b'100644', before, sha1, b'M',
+                               path))
+            else:
+                result.append((b'000000', b'100644', NULL_NODE_ID, sha1,
+                               b'A', path))
+        return result
+
+    def translate_files(self, commit, files):
+        '''Replaces the git submodules in the given list of (mode, type,
+        sha1, path) for a whole tree with the .hgsub and .hgsubstate
+        files.'''
+        files = list(files)
+        if not any(path == b'.gitmodules' or mode == GITLINK_MODE
+                   for mode, _, _, path in files):
+            return files
+        result = [f for f in files
+                  if f[3] != b'.gitmodules' and f[0] != GITLINK_MODE]
+        for path, data in zip((b'.hgsub', b'.hgsubstate'),
+                              self.to_hg(commit)):
+            if data is not None:
+                result.append((b'100644', b'blob',
+                               GitHgHelper.put_blob(data), path))
+        return sorted(result, key=lambda f: f[3])
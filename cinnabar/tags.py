@@ -0,0 +1,109 @@
+from __future__ import absolute_import, unicode_literals
+import logging
+import re
+from cinnabar.git import (
+    Git,
+    NULL_NODE_ID,
+)
+from cinnabar.helper import GitHgHelper
+from cinnabar.util import (
+    iteritems,
+    one,
+)
+
+# The git tags created from mercurial tags are recorded, as a blob with one
+# line per tag listing the commit sha1 followed by the tag name, in this
+# ref. This allows to tell them apart from tags created by other means.
+AUTO_TAGS_REF = b'refs/cinnabar/auto-tags'
+
+INVALID_REF_RE = re.compile(br'[\x00-\x20\x7f~^:?*\[\\]|\.\.|@\{|//|'
+                            br'^[-./]|/\.|[./]$|\.lock(/|$)|^@$')
+
+
+DEFAULT_TAG_MESSAGE = b'Added tag {tag} for changeset {node}'
+
+
+def valid_tag_name(tag):
+    return not INVALID_REF_RE.search(tag)
+
+
+def recorded_tags():
+    sha1 = Git.resolve_ref(AUTO_TAGS_REF)
+    tags = {}
+    if sha1:
+        for line in Git.iter('cat-file', 'blob', sha1):
+            if line:
+                commit, tag = line.split(b' ', 1)
+                tags[tag] = commit
+    return tags
+
+
+def update_git_tags(store):
+    '''Creates, updates or removes git tags according to the mercurial
+    tags. Git tags that were not created from mercurial tags are left
+    alone.'''
+    recorded = recorded_tags()
+    existing = dict((ref[len(b'refs/tags/'):], sha1)
+                    for sha1, ref in Git.for_each_ref('refs/tags'))
+    record = {}
+    updates = []
+    for tag, commit in store.tags():
+        if not valid_tag_name(tag):
+            logging.warning('Ignoring mercurial tag with a name invalid for '
+                            'git: %s', tag.decode('utf-8', 'replace'))
+            continue
+        current = existing.get(tag)
+        if current != commit:
+            if current and current != recorded.get(tag):
+                logging.warning(
+                    'Not updating tag %s, which does not match the '
+                    'mercurial tag', tag.decode('utf-8', 'replace'))
+                continue
+            updates.append(b'update refs/tags/%s %s %s'
+                           % (tag, commit, current or NULL_NODE_ID))
+        record[tag] = commit
+    for tag, commit in iteritems(recorded):
+        if tag not in record and existing.get(tag) == commit:
+            updates.append(b'delete refs/tags/%s %s' % (tag, commit))
+
+    if updates:
+        Git.run('update-ref', '--stdin', stdin=updates)
+    if record != recorded:
+        if record:
+            sha1 = one(Git.iter('hash-object', '-w', '--stdin', stdin=(
+                b'%s %s' % (commit, tag)
+                for tag, commit in sorted(iteritems(record)))))
+            Git.run('update-ref', AUTO_TAGS_REF, sha1)
+        else:
+            Git.run('update-ref', '-d', AUTO_TAGS_REF)
+
+
+def create_tag_commit(store, tag, commit, parent, previous=None):
+    '''Creates a git commit on top of the given parent, adding the given tag
+    for the given commit to the .hgtags file, like `hg tag` does. When the
+    tag was previously pointing to another commit, that is recorded too, so
+    that mercurial keeps track of the tag history. Returns the new commit.
+    The commit message can be configured with cinnabar.tag-message, where
+    {tag} and {node} are replaced with the tag name and the short changeset
+    id.'''
+    node = store.hg_changeset(commit)
+    ls = one(Git.ls_tree(parent, b'.hgtags'))
+    data = GitHgHelper.cat_file(b'blob', ls[2]) if ls else b''
+    if data and not data.endswith(b'\n'):
+        data += b'\n'
+    if previous:
+        data += b'%s %s\n' % (store.hg_changeset(previous), tag)
+    data += b'%s %s\n' % (node, tag)
+    message = Git.config('cinnabar.tag-message') or DEFAULT_TAG_MESSAGE
+    message = message.replace(b'{tag}', tag).replace(b'{node}', node[:12])
+    ident = one(Git.iter('var', 'GIT_COMMITTER_IDENT'))
+    with GitHgHelper.commit(
+        ref=b'refs/cinnabar/tip',
+        author=ident,
+        committer=ident,
+        message=message,
+        from_commit=parent,
+        parents=(parent,),
+    ) as c:
+        c.filemodify(b'.hgtags', content=data)
+    return c.sha1
@@ -1,17 +1,28 @@
 from __future__ import absolute_import, unicode_literals
+import errno
+import hashlib
 import logging
 import os
 import socket
 import subprocess
 import sys
+import tempfile
 import time
 import traceback
 try:
+    from httplib import HTTPException
     from urllib2 import (
+        HTTPError,
         Request,
+        URLError,
         urlopen,
     )
 except ImportError:
+    from http.client import HTTPException
+    from urllib.error import (
+        HTTPError,
+        URLError,
+    )
     from urllib.request import (
         Request,
         urlopen,
@@ -20,6 +31,7 @@ from collections import (
     deque,
     OrderedDict,
 )
+from contextlib import contextmanager
 try:
     from collections.abc import Iterable
 except ImportError:
@@ -79,16 +91,59 @@ class Formatter(logging.Formatter):
         return super(Formatter, self).format(record)
 
 
+# Names that can be used in the logging configuration to refer to several
+# loggers at once. The commands sent to the helpers are logged under
+# `wire.<command>` for the mercurial connections and `import.<command>` for
+# the metadata store, so `wire` and `import` cover them.
+LOG_GROUPS = {
+    'bundle': ('bundle2', 'chunks', 'revchunks', 'streamclone',
+               'clonebundle'),
+    'push': ('push', 'findcommon'),
+}
+
+LOG_LEVELS = {
+    'debug': logging.DEBUG,
+    'info': logging.INFO,
+    'warning': logging.WARNING,
+    'error': logging.ERROR,
+    'critical': logging.CRITICAL,
+}
+
+
+def log_level(value):
+    '''Returns the logging level for the given level name or number. Numbers
+    are verbosity levels, where higher numbers are more verbose.'''
+    value = value.decode('ascii').lower()
+    if value in LOG_LEVELS:
+        return LOG_LEVELS[value]
+    return max(logging.DEBUG, logging.FATAL - int(value) * 10)
+
+
+def log_names(name):
+    '''Returns the names of the loggers the given name in the logging
+    configuration refers to.'''
+    if name in LOG_GROUPS:
+        return LOG_GROUPS[name]
+    if name and '.' not in name and name not in ('wire', 'import'):
+        # Helper commands used to be logged without a prefix.
+        return (name, 'wire.%s' % name, 'import.%s' % name)
+    return (name,)
+
+
 def init_logging():
     # Initialize logging from the GIT_CINNABAR_LOG environment variable
     # or the cinnabar.log configuration, the former taking precedence.
+    # The configuration is a comma-separated list of `name=level`, where
+    # level is a level name or a verbosity number (`name:number` is also
+    # accepted), optionally followed by `>path` to send the corresponding
+    # logs to a file instead of stderr.
     # Still read the configuration to force the git config cache being
     # filled before logging is setup, so that the output of
     # `git config -l` is never logged.
     from .git import Git
     logger = logging.getLogger()
     start_time = time.time()
-    handler = StreamHandler(start_time)
+    handler = StreamHandler(start_time, WindowsConsoleWriter.wrap(sys.stderr))
     formatter = Formatter()
     handler.setFormatter(formatter)
     logger.addHandler(handler)
@@ -100,21 +155,25 @@ def init_logging():
     for assignment in log_conf.split(b','):
         try:
             assignment, _, path = assignment.partition(b'>')
-            name, _, value = assignment.partition(b':')
-            name = name.decode('ascii')
+            if b'=' in assignment:
+                name, _, value = assignment.partition(b'=')
+            else:
+                name, _, value = assignment.partition(b':')
+            name = name.strip().decode('ascii')
             if name == '*':
                 name = ''
             if path and path not in file_handlers:
                 file_handlers[path] = FileHandler(start_time, path)
                 file_handlers[path].setFormatter(formatter)
             if path or value:
-                logger = logging.getLogger(name)
-                if value:
-                    logger.setLevel(
-                        max(logging.DEBUG, logging.FATAL - int(value) * 10))
-                if path:
-                    logger.propagate = False
-                    logger.addHandler(file_handlers[path])
+                level = log_level(value) if value else None
+                for n in log_names(name):
+                    logger = logging.getLogger(n)
+                    if level is not None:
+                        logger.setLevel(level)
+                    if path:
+                        logger.propagate = False
+                        logger.addHandler(file_handlers[path])
         except Exception:
             pass
 
@@ -163,16 +222,47 @@ check_enabled = ConfigSetFunc(
     ('nodeid', 'manifests', 'helper'),
     ('bundle', 'files', 'memory', 'cpu', 'time', 'traceback', 'no-mercurial',
      'no-bundle2', 'cinnabarclone', 'clonebundles', 'no-version-check',
-     'unbundler'),
+     'unbundler', 'nodes', 'fast'),
 )
 
 experiment = ConfigSetFunc(
     'cinnabar.experiments',
-    ('wire', 'merge', 'store'),
+    ('wire', 'merge', 'store', 'revlog', 'serve'),
     (),
 )
 
 
+def _config_dir(config_key, default):
+    from .git import Git
+    path = Git.config(config_key)
+    path = fsdecode(path) if path else default
+    path = os.path.expanduser(path)
+    if not os.path.isdir(path):
+        os.makedirs(path)
+    return path
+
+
+_tmpdir = None
+
+
+def tmpdir():
+    '''Directory where large temporary files are written, set with
+    cinnabar.tmpdir. Defaults to the system temporary directory.'''
+    global _tmpdir
+    if _tmpdir is None:
+        _tmpdir = _config_dir('cinnabar.tmpdir', tempfile.gettempdir())
+    return _tmpdir
+
+
+def cachedir():
+    '''Directory where persistent caches are stored, set with
+    cinnabar.cachedir. Defaults to $XDG_CACHE_HOME/git-cinnabar.'''
+    default = os.environ.get('XDG_CACHE_HOME') or \
+        os.path.join('~', '.cache')
+    return _config_dir('cinnabar.cachedir',
+                       os.path.join(default, 'git-cinnabar'))
+
+
 def interval_expired(config_key, interval, globl=False):
     from .git import Git
     config_key = 'cinnabar.{}'.format(config_key)
@@ -193,14 +283,54 @@ def interval_expired(config_key, interval, globl=False):
     return bool(last)
 
 
-progress = True
+def parse_size(value):
+    '''Parse a size in bytes, with an optional k, m or g suffix, the same
+    way git does for e.g. core.packedGitLimit.'''
+    if isinstance(value, bytes):
+        value = value.decode('ascii')
+    value = value.strip().lower()
+    factor = 1
+    for suffix, f in (('k', 1024), ('m', 1024 * 1024),
+                      ('g', 1024 * 1024 * 1024)):
+        if value.endswith(suffix):
+            value = value[:-1]
+            factor = f
+            break
+    size = int(value)
+    if size < 0:
+        raise ValueError('negative size')
+    return size * factor
+
+
+def format_size(size):
+    for unit in ('B', 'KiB', 'MiB'):
+        if size < 1024:
+            return '%d%s' % (size, unit)
+        size /= 1024.0
+    return '%.1fGiB' % size
+
+
+def format_duration(seconds):
+    seconds = int(seconds)
+    if seconds >= 3600:
+        return '%dh%02dm' % (seconds // 3600, seconds % 3600 // 60)
+    if seconds >= 60:
+        return '%dm%02ds' % (seconds // 60, seconds % 60)
+    return '%ds' % seconds
+
+
+# Like git, only display progress when stderr is a terminal by default.
+progress = sys.stderr.isatty()
 
 
 class Progress(object):
-    def __init__(self, fmt):
+    def __init__(self, fmt, total=None, rate=True):
         self._count = 0
         self._start = self._t0 = time.time()
         self._fmt = fmt
+        self._total = total
+        self._rate = rate
+        self._width = 0
 
     def progress(self, count=None):
         if not progress:
@@ -215,12 +345,26 @@ class Progress(object):
     def _print_count(self, count, t1=None):
         if not isinstance(count, tuple):
             count = (count,)
-        timed = ''
+        t1 = t1 or time.time()
+        elapsed = t1 - self._start
+        info = []
+        # Rates are meaningless over too short periods.
+        if self._rate and elapsed >= 1 and count[0]:
+            info.append('%d/s' % (count[0] / elapsed))
+            if self._total and self._total > count[0]:
+                info.append('ETA %s' % format_duration(
+                    (self._total - count[0]) * elapsed / count[0]))
+        if ProgressReader.current:
+            info.append(ProgressReader.current.status(t1))
+        line = self._fmt.format(*count)
+        if info:
+            line += ' (%s)' % ', '.join(info)
         if check_enabled('time'):
-            t1 = t1 or time.time()
-            timed = ' in %.1fs' % (t1 - self._start)
-        sys.stderr.write('\r' + self._fmt.format(*count) + timed)
+            line += ' in %.1fs' % elapsed
+        # Pad to cover the remainder of a previous, longer, line.
+        sys.stderr.write('\r' + line.ljust(self._width))
         sys.stderr.flush()
+        self._width = len(line)
         self._t0 = t1
 
     def finish(self, count=None):
@@ -231,16 +375,66 @@ class Progress(object):
         sys.stderr.flush()
 
 
-def progress_iter(fmt, iter):
-    return progress_enum(fmt, enumerate(iter, start=1))
+class ProgressReader(object):
+    '''Wraps a file-like object to keep track of the amount of data read
+    from it. While it is current, progress lines report that amount and the
+    transfer rate.'''
+    current = None
+
+    def __init__(self, fh, length=None):
+        self._fh = fh
+        self._start = time.time()
+        self.length = getattr(fh, 'length', None) if length is None \
+            else length
+        self.count = 0
+        ProgressReader.current = self
+
+    def read(self, length=-1):
+        with stats.timer('download'):
+            data = self._fh.read(length)
+        self.count += len(data)
+        stats.add_size('received', len(data))
+        return data
+
+    def readline(self):
+        with stats.timer('download'):
+            data = self._fh.readline()
+        self.count += len(data)
+        stats.add_size('received', len(data))
+        return data
+
+    def __getattr__(self, name):
+        return getattr(self._fh, name)
+
+    def status(self, t1=None):
+        elapsed = (t1 or time.time()) - self._start
+        result = format_size(self.count)
+        if self.length:
+            result = '%d%% of %s' % (self.count * 100 // self.length,
+                                     format_size(self.length))
+        if elapsed >= 1:
+            result += ' | %s/s' % format_size(self.count / elapsed)
+            if self.length and self.count and self.length > self.count:
+                result += ', ETA %s' % format_duration(
+                    (self.length - self.count) * elapsed / self.count)
+        return result
+
+    @staticmethod
+    def reset():
+        ProgressReader.current = None
+
 
+def progress_iter(fmt, iter, total=None):
+    return progress_enum(fmt, enumerate(iter, start=1), total)
 
-def progress_enum(fmt, enum_iter):
+
+def progress_enum(fmt, enum_iter, total=None):
     count = 0
-    progress = Progress(fmt)
+    progress = Progress(fmt, total)
     try:
         for count, item in enum_iter:
             progress.progress(count)
+            memory_limit.check()
             yield item
     finally:
         if count:
@@ -293,6 +487,28 @@ def one(iterable):
     return None
 
 
+def prompt(question):
+    '''Asks the given question on the terminal, and returns the answer.
+    Returns None when there is no terminal to ask on.'''
+    try:
+        tty = open('/dev/tty', 'r+')
+    except (IOError, OSError):
+        return None
+    with tty:
+        tty.write('%s ' % question)
+        tty.flush()
+        return tty.readline().strip()
+
+
+def ask(question):
+    '''Asks the given yes/no question on the terminal, and returns whether
+    the answer was yes. Returns None when there is no terminal to ask on.'''
+    answer = prompt('%s [y/N]' % question)
+    if answer is None:
+        return None
+    return answer.lower() in ('y', 'yes')
+
+
 def strip_suffix(s, suffix):
     if s.endswith(suffix):
         return s[:-len(suffix)]
@@ -525,6 +741,7 @@ class lrucache(object):
                 self[args] = result
                 return result
         wrapper.invalidate = self.invalidate
+        memory_limit.add_shrinker(self.invalidate)
         return wrapper
 
     def invalidate(self, *args):
@@ -659,9 +876,18 @@ class chunkbuffer(object):
 
 
 class HTTPReader(object):
-    def __init__(self, url):
+    # Network errors are retried after waiting for an exponentially growing
+    # period, up to this many times in a row.
+    MAX_RETRIES = 6
+    # Give up on connections that stall for that many seconds.
+    TIMEOUT = 120
+
+    def __init__(self, url, headers={}):
         url = fsdecode(url)
-        self.fh = urlopen(url)
+        self.headers = headers
+        self.retries = 0
+        self.fh = self._retry(lambda: urlopen(Request(url, headers=headers),
+                                              timeout=self.TIMEOUT))
         # If the url was redirected, get the final url for possible future
         # range requests.
         self.url = self.fh.geturl()
@@ -672,35 +898,66 @@ class HTTPReader(object):
             self.length = None
         self.can_recover = \
             self.fh.headers.get('Accept-Ranges') == 'bytes'
-        self.backoff_period = 0
         self.offset = 0
         self.closed = False
 
+    @staticmethod
+    def _is_transient(e):
+        if isinstance(e, HTTPError):
+            return e.code >= 500
+        if isinstance(e, URLError):
+            e = e.reason
+            if not isinstance(e, EnvironmentError):
+                return True
+        if isinstance(e, (socket.timeout, socket.gaierror)):
+            return True
+        if isinstance(e, EnvironmentError):
+            # Local errors, such as missing files for file:// urls, are not
+            # going to go away by waiting.
+            return e.errno in (errno.ECONNABORTED, errno.ECONNREFUSED,
+                               errno.ECONNRESET, errno.EHOSTUNREACH,
+                               errno.ENETUNREACH, errno.EPIPE,
+                               errno.ETIMEDOUT)
+        return isinstance(e, HTTPException)
+
+    def _backoff(self, reason):
+        self.retries += 1
+        delay = 2 ** (self.retries - 1)
+        logging.getLogger('httpreader').warning(
+            '%s. Retrying in %d seconds.', reason, delay)
+        time.sleep(delay)
+
+    def _retry(self, func):
+        while True:
+            try:
+                return func()
+            except Exception as e:
+                if not self._is_transient(e) or \
+                        self.retries >= self.MAX_RETRIES:
+                    raise
+                self._backoff('Network error (%s)' % e)
+
     def read(self, size):
         result = []
         length = 0
         while length < size:
             try:
-                buf = self.fh.read(size - length)
-            except socket.error:
+                buf = self.fh.read(bandwidth_limit.chunk_size(size - length))
+            except (socket.error, HTTPException):
                 buf = b''
             if not buf:
-                # When self.length is None, self.offset < self.length is always
-                # false.
-                if self.can_recover and self.offset < self.length:
-                    while True:
-                        # Linear backoff.
-                        self.backoff_period += 1
-                        time.sleep(self.backoff_period)
-                        try:
-                            self.fh = self._reopen()
-                            break
-                        except Exception:
-                            if self.backoff_period >= 10:
-                                raise
+                if self.can_recover and self.length is not None and \
+                        self.offset < self.length and \
+                        self.retries < self.MAX_RETRIES:
+                    self._backoff('Download interrupted at %d bytes'
+                                  % self.offset)
+                    self.fh = self._retry(self._reopen)
                     if self.fh:
                         continue
                 break
+            # Only give up after consecutive failures.
+            self.retries = 0
+            bandwidth_limit.throttle(len(buf))
             length += len(buf)
             self.offset += len(buf)
             result.append(buf)
@@ -709,9 +966,9 @@ class HTTPReader(object):
     def _reopen(self):
         # This reopens the network connection with a HTTP Range request
         # starting from self.offset.
-        req = Request(self.url)
+        req = Request(self.url, headers=self.headers)
         req.add_header('Range', 'bytes=%d-' % self.offset)
-        fh = urlopen(req)
+        fh = urlopen(req, timeout=self.TIMEOUT)
         if fh.getcode() != 206:
             return None
         range = fh.headers.get('Content-Range') or ''
@@ -932,6 +1189,338 @@ class MemoryCPUReporter(Thread):
         self.join()
 
 
+def _resident_memory(pid):
+    with open('/proc/%d/statm' % pid) as fh:
+        return int(fh.read().split()[1]) * os.sysconf(str('SC_PAGE_SIZE'))
+
+
+def _child_processes():
+    '''Return a dict associating process ids to the list of their children
+    process ids, from /proc.'''
+    children = {}
+    for entry in os.listdir('/proc'):
+        if not entry.isdigit():
+            continue
+        try:
+            with open('/proc/%s/stat' % entry) as fh:
+                stat = fh.read()
+        except (IOError, OSError):
+            continue
+        # The parent process id is the second field after the command
+        # name, which is between parens and can contain spaces.
+        ppid = int(stat.rpartition(')')[2].split()[1])
+        children.setdefault(ppid, []).append(int(entry))
+    return children
+
+
+def memory_usage():
+    '''Return the resident memory used by this process and its children
+    (most notably, the helper), or None if it can't be determined.'''
+    try:
+        import psutil
+    except ImportError:
+        psutil = None
+    if psutil:
+        proc = psutil.Process()
+        return sum(p.memory_info().rss
+                   for p in chain((proc,), proc.children(recursive=True)))
+    try:
+        usage = _resident_memory(os.getpid())
+        children = _child_processes()
+    except (IOError, OSError, TypeError, ValueError, IndexError):
+        return None
+    pids = list(children.get(os.getpid(), ()))
+    while pids:
+        pid = pids.pop()
+        pids.extend(children.get(pid, ()))
+        try:
+            usage += _resident_memory(pid)
+        except (IOError, OSError, ValueError, IndexError):
+            # The process may have exited in the meanwhile.
+            pass
+    return usage
+
+
+class MemoryLimit(object):
+    '''Enforce the cinnabar.memory-limit configuration.
+
+    When memory usage gets close to the limit, the registered shrinkers
+    are called to release caches or move data to disk. If that's not
+    enough to get back under the limit, the operation is aborted.'''
+    # Fraction of the limit at which caches start being released.
+    SHRINK_THRESHOLD = 0.9
+    # Minimum interval between two measurements, in seconds.
+    INTERVAL = 0.5
+
+    def __init__(self):
+        self._limit = None
+        self._last = 0
+        self._shrinkers = []
+
+    @property
+    def limit(self):
+        if self._limit is None:
+            from .git import Git
+            value = Git.config('cinnabar.memory-limit')
+            self._limit = 0
+            if value:
+                try:
+                    self._limit = parse_size(value)
+                except ValueError:
+                    logging.getLogger('config').warn(
+                        'Invalid value for cinnabar.memory-limit: %s',
+                        fsdecode(value))
+        return self._limit
+
+    def add_shrinker(self, func):
+        self._shrinkers.append(func)
+
+    def remove_shrinker(self, func):
+        self._shrinkers.remove(func)
+
+    def shrink(self):
+        for func in self._shrinkers:
+            func()
+        import gc
+        gc.collect()
+
+    def check(self, force=False):
+        if not self.limit:
+            return
+        now = time.time()
+        if not force and now - self._last < self.INTERVAL:
+            return
+        self._last = now
+        usage = memory_usage()
+        if usage is None or usage < self.limit * self.SHRINK_THRESHOLD:
+            return
+        logging.getLogger('memory').info(
+            'Memory usage (%s) close to the limit (%s). Releasing caches '
+            'and spilling to disk.',
+            format_size(usage), format_size(self.limit))
+        self.shrink()
+        usage = memory_usage()
+        if usage is not None and usage > self.limit:
+            raise Abort(
+                'Memory usage (%s) exceeds the limit set with '
+                'cinnabar.memory-limit (%s).\n'
+                'Increase the limit or retry on a machine with more memory.'
+                % (format_size(usage), format_size(self.limit)))
+
+
+memory_limit = MemoryLimit()
+
+
+class BandwidthLimit(object):
+    '''Enforce the cinnabar.maxbandwidth configuration.
+
+    The limit applies to all downloads at once, whether they happen over
+    HTTP in this process, or through the native helper, which is held back
+    by not reading its output faster than the limit.'''
+    # Size of the reads when the bandwidth is limited, such that the
+    # throttling is smooth.
+    CHUNK_SIZE = 16384
+
+    def __init__(self):
+        self._limit = None
+        self._last = None
+        self._allowance = 0
+
+    @property
+    def limit(self):
+        if self._limit is None:
+            from .git import Git
+            value = Git.config('cinnabar.maxbandwidth')
+            self._limit = 0
+            if value:
+                try:
+                    self._limit = parse_size(value)
+                except ValueError:
+                    logging.getLogger('config').warn(
+                        'Invalid value for cinnabar.maxbandwidth: %s',
+                        fsdecode(value))
+        return self._limit
+
+    def chunk_size(self, size):
+        if self.limit:
+            return min(size, self.CHUNK_SIZE)
+        return size
+
+    def throttle(self, size):
+        '''Account for size bytes having been received, and wait as long as
+        necessary to stay under the limit. Up to one second worth of data
+        can be received in a burst.'''
+        if not self.limit or not size:
+            return
+        now = time.time()
+        if self._last is None:
+            self._allowance = self.limit
+        else:
+            self._allowance = min(
+                self.limit,
+                self._allowance + (now - self._last) * self.limit)
+        self._last = now
+        self._allowance -= size
+        if self._allowance < 0:
+            time.sleep(-self._allowance / float(self.limit))
+            self._last = time.time()
+            self._allowance = 0
+
+
+bandwidth_limit = BandwidthLimit()
+
+
+class ThrottledReader(object):
+    '''Wraps a file-like object such that it's not read faster than the
+    cinnabar.maxbandwidth configuration allows.'''
+    def __init__(self, fh):
+        self._fh = fh
+
+    def read(self, length=-1):
+        if length < 0:
+            data = self._fh.read()
+            bandwidth_limit.throttle(len(data))
+            return data
+        result = []
+        while length > 0:
+            data = self._fh.read(bandwidth_limit.chunk_size(length))
+            if not data:
+                break
+            bandwidth_limit.throttle(len(data))
+            result.append(data)
+            length -= len(data)
+        return b''.join(result)
+
+    def readline(self):
+        data = self._fh.readline()
+        bandwidth_limit.throttle(len(data))
+        return data
+
+    def __getattr__(self, name):
+        return getattr(self._fh, name)
+
+
+class DigestReader(object):
+    '''Wraps a file-like object to compute the digest of the data read from
+    it. verify() reads whatever data is left and aborts when the digest
+    doesn't match the expected one.'''
+    def __init__(self, fh, algorithm, expected, name=None):
+        self._fh = fh
+        self._hash = hashlib.new(algorithm)
+        self._algorithm = algorithm
+        self._expected = expected.lower()
+        self._name = name
+
+    def read(self, length=-1):
+        data = self._fh.read(length)
+        self._hash.update(data)
+        return data
+
+    def readline(self):
+        data = self._fh.readline()
+        self._hash.update(data)
+        return data
+
+    def verify(self):
+        while self.read(32768):
+            pass
+        digest = self._hash.hexdigest().encode('ascii')
+        if digest != self._expected:
+            raise Abort(
+                '%s digest mismatch%s: expected %s, got %s. The data is '
+                'likely corrupted.' % (
+                    self._algorithm,
+                    ' for %s' % fsdecode(self._name) if self._name else '',
+                    self._expected.decode('ascii'), digest.decode('ascii')))
+
+    def __getattr__(self, name):
+        return getattr(self._fh, name)
+
+
+class Stats(object):
+    '''Collects the time spent in the various phases of a command, the
+    amount of data transferred and the number of objects handled, for the
+    report displayed at the end of the command when cinnabar.showstats is
+    set. Timings are exclusive: the time spent in a phase nested in
+    another is not counted in the latter.'''
+
+    def __init__(self):
+        self.timings = OrderedDict()
+        self.sizes = OrderedDict()
+        self.counts = OrderedDict()
+        self._nested = []
+        self._enabled = None
+
+    @property
+    def enabled(self):
+        if self._enabled is None:
+            from .git import Git, InvalidConfig
+            try:
+                self._enabled = Git.config('cinnabar.showstats', values={
+                    None: False,
+                    b'false': False,
+                    b'true': True,
+                })
+            except InvalidConfig as e:
+                logging.warning(str(e))
+                self._enabled = False
+        return self._enabled
+
+    @contextmanager
+    def timer(self, name):
+        if not self.enabled:
+            yield
+            return
+        start = time.time()
+        self._nested.append(0)
+        try:
+            yield
+        finally:
+            elapsed = time.time() - start
+            self.timings[name] = self.timings.get(name, 0) + elapsed - \
+                self._nested.pop()
+            if self._nested:
+                self._nested[-1] += elapsed
+
+    def timed(self, name):
+        def decorator(func):
+            @wraps(func)
+            def wrapper(*args, **kwargs):
+                with self.timer(name):
+                    return func(*args, **kwargs)
+            return wrapper
+        return decorator
+
+    def add_size(self, name, size):
+        self.sizes[name] = self.sizes.get(name, 0) + size
+
+    def count(self, name, value=1):
+        self.counts[name] = self.counts.get(name, 0) + value
+
+    def report(self, total):
+        if not (self.timings or self.counts) or not self.enabled:
+            return
+        lines = []
+        for name, duration in self.timings.items():
+            lines.append((name, '%.1fs' % duration))
+        other = total - sum(self.timings.values())
+        if self.timings and other > 0:
+            lines.append(('other', '%.1fs' % other))
+        lines.append(('total', '%.1fs' % total))
+        for name, size in self.sizes.items():
+            lines.append((name, format_size(size)))
+        for name, count in self.counts.items():
+            lines.append((name, str(count)))
+        width = max(len(name) for name, _ in lines)
+        sys.stderr.write('Statistics:\n')
+        for name, value in lines:
+            sys.stderr.write('  %s %s\n' % (('%s:' % name).ljust(width + 1),
+                                             value))
+
+
+stats = Stats()
+
+
 class VersionCheck(Thread):
     def __init__(self):
         super(VersionCheck, self).__init__()
@@ -993,6 +1582,7 @@ class VersionCheck(Thread):
 
 
 def run(func, args):
+    start_time = time.time()
     reexec = None
     if os.environ.pop('GIT_CINNABAR_COVERAGE', None):
         if not reexec:
@@ -1010,6 +1600,9 @@ def run(func, args):
 
     version_check = VersionCheck()
     try:
+        # Make all temporary files (including those created by mercurial)
+        # go to cinnabar.tmpdir.
+        tempfile.tempdir = tmpdir()
         from cinnabar.git import Git
         objectformat = Git.config('extensions.objectformat') or 'sha1'
         if objectformat != 'sha1':
@@ -1043,6 +1636,7 @@ def run(func, args):
         if check_enabled('memory') or check_enabled('cpu'):
             reporter.shutdown()
         version_check.join()
+        stats.report(time.time() - start_time)
     if check_enabled('no-mercurial'):
         if any(k.startswith('mercurial.') or k == 'mercurial'
                for k in sys.modules):
@@ -1095,9 +1689,58 @@ else:
             return os.environ
         return os.environ.get(k)
 
+
+class WindowsConsoleWriter(object):
+    '''File-like object writing to a Windows console with WriteConsoleW.
+
+    Writing bytes to a console with the C runtime interprets them in the
+    console code page, which garbles non-ASCII author names and messages.
+    Text written through this class is decoded from UTF-8 (as stored in
+    Mercurial and git metadata) and written as UTF-16.'''
+    def __init__(self, stream, handle):
+        self._stream = stream
+        self._handle = handle
+
+    @classmethod
+    def wrap(cls, stream):
+        # Python >= 3.6 already uses WriteConsoleW for console streams.
+        if sys.platform != 'win32' or sys.version_info >= (3, 6):
+            return stream
+        try:
+            if not stream.isatty():
+                return stream
+            import ctypes
+            import msvcrt
+            handle = msvcrt.get_osfhandle(stream.fileno())
+            mode = ctypes.c_ulong()
+            if not ctypes.windll.kernel32.GetConsoleMode(
+                    handle, ctypes.byref(mode)):
+                return stream
+        except Exception:
+            return stream
+        return cls(stream, handle)
+
+    def write(self, data):
+        import ctypes
+        if isinstance(data, bytes):
+            data = data.decode('utf-8', 'replace')
+        self._stream.flush()
+        written = ctypes.c_ulong()
+        while data:
+            chunk = data[:8192]
+            if not ctypes.windll.kernel32.WriteConsoleW(
+                    self._handle, chunk, len(chunk), ctypes.byref(written),
+                    None):
+                raise ctypes.WinError()
+            data = data[written.value or len(chunk):]
+
+    def __getattr__(self, name):
+        return getattr(self._stream, name)
+
+
 if hasattr(sys.stdout, 'buffer'):
     bytes_stdout = sys.stdout.buffer
     bytes_stdin = sys.stdin.buffer
 else:
-    bytes_stdout = sys.stdout
+    bytes_stdout = WindowsConsoleWriter.wrap(sys.stdout)
     bytes_stdin = sys.stdin
@@ -30,18 +30,25 @@
  *     - listkeys <namespace>
  *     	 Calls the "listkeys" command on the repository and returns the
  *     	 corresponding result.
- *     - getbundle <heads> <common> <bundle2caps>
+ *     - getbundle <heads> <common> <bundle2caps> [<include> <exclude>]
  *       Calls the "getbundle" command on the repository and streams a
  *       changegroup in result. `heads` and `common` are comma separated
- *       lists of changesets.
+ *       lists of changesets. `include` and `exclude` are comma separated
+ *       lists of path patterns for servers supporting narrow clones.
  *     - unbundle <head>+
- *       Calls the "unbundle command on the repository.
+ *       Calls the "unbundle command on the repository. When the server
+ *       rejects the push before receiving the bundle (typically, after a
+ *       lost push race), the result is "error" followed by a line feed and
+ *       the message from the server.
  *     - pushkey <namespace> <key> <old> <new>
  *     	 Calls the "pushkey" command on the repository and returns the
  *     	 corresponding result.
  *     - lookup <key>
  *       Calls the "lookup" command on the repository and returns the
  *     	 corresponding result.
+ *     - between <top>-<bottom>+
+ *       Calls the "between" command on the repository and returns the
+ *       corresponding result.
  */
 
 #include <stdio.h>
@@ -85,7 +92,7 @@
 #define HELPER_HASH unknown
 #endif
 
-#define CMD_VERSION 3003
+#define CMD_VERSION 3004
 
 #define MIN_CMD_VERSION 3003
 
@@ -104,8 +111,11 @@ struct oidset hg2git_seen = OIDSET_INIT;
 int metadata_flags = 0;
 int cinnabar_check = 0;
 int cinnabar_experiments = 0;
+// Whether the repository is a narrow clone, in which case the files outside
+// the narrow spec are not available.
+static int narrow = 0;
 
-static int config(const char *name, struct strbuf *result)
+int config(const char *name, struct strbuf *result)
 {
 	struct strbuf key = STRBUF_INIT;
 	char *p, *end;
@@ -1278,8 +1288,10 @@ static void do_getbundle(struct hg_connection *conn, struct string_list *args)
 	struct oid_array heads = OID_ARRAY_INIT;
 	struct oid_array common = OID_ARRAY_INIT;
 	const char *bundle2caps = NULL;
+	const char *includepats = NULL;
+	const char *excludepats = NULL;
 
-	if (args->nr > 3)
+	if (args->nr > 5)
 		exit(1);
 
 	if (args->nr > 0)
@@ -1288,8 +1300,13 @@ static void do_getbundle(struct hg_connection *conn, struct string_list *args)
 		arg_as_oid_array(args->items[1].string, &common);
 	if (args->nr > 2)
 		bundle2caps = args->items[2].string;
+	if (args->nr > 3)
+		includepats = args->items[3].string;
+	if (args->nr > 4)
+		excludepats = args->items[4].string;
 
-	hg_getbundle(conn, stdout, &heads, &common, bundle2caps);
+	hg_getbundle(conn, stdout, &heads, &common, bundle2caps, includepats,
+	             excludepats);
 
 	oid_array_clear(&common);
 	oid_array_clear(&heads);
@@ -1369,6 +1386,25 @@ static void do_lookup(struct hg_connection *conn, struct string_list *args)
 	strbuf_release(&result);
 }
 
+static void do_between(struct hg_connection *conn, struct string_list *args)
+{
+	struct strbuf result = STRBUF_INIT;
+	struct strbuf pairs = STRBUF_INIT;
+	struct string_list_item *item;
+	if (args->nr == 0)
+		exit(1);
+
+	for_each_string_list_item(item, args) {
+		if (pairs.len)
+			strbuf_addch(&pairs, ' ');
+		strbuf_addstr(&pairs, item->string);
+	}
+	hg_between(conn, &result, pairs.buf);
+	send_buffer(&result);
+	strbuf_release(&pairs);
+	strbuf_release(&result);
+}
+
 static void do_clonebundles(struct hg_connection *conn, struct string_list *args)
 {
 	struct strbuf result = STRBUF_INIT;
@@ -1421,6 +1457,8 @@ static void connected_loop(struct hg_connection *conn)
 			do_state(conn, &args);
 		else if (!strcmp("lookup", command))
 			do_lookup(conn, &args);
+		else if (!strcmp("between", command))
+			do_between(conn, &args);
 		else if (!strcmp("clonebundles", command))
 			do_clonebundles(conn, &args);
 		else if (!strcmp("cinnabarclone", command))
@@ -2067,8 +2105,11 @@ static void recurse_create_git_tree(const struct object_id *tree_id,
 					file_oid = ensure_empty_blob();
 				else
 					file_oid = resolve_hg2git(&hg_oid, 40);
-				if (!file_oid)
+				if (!file_oid) {
+					if (narrow)
+						continue;
 					goto corrupted;
+				}
 				oidcpy(&oid, file_oid);
 				mode &= 0777;
 				if (!mode)
@@ -2290,6 +2331,11 @@ static void init_config()
 		strbuf_list_free(check);
 	}
 	strbuf_release(&conf);
+
+	if ((!config("narrow-include", &conf) && conf.len) ||
+	    (!config("narrow-exclude", &conf) && conf.len))
+		narrow = 1;
+	strbuf_release(&conf);
 }
 
 static void reset_replace_map()
@@ -26,6 +26,10 @@ extern int metadata_flags;
 extern int cinnabar_check;
 extern int cinnabar_experiments;
 
+/* Reads the value of the GIT_CINNABAR_<NAME> environment variable, or of
+ * cinnabar.<name> from the git configuration. Returns 1 if neither is set. */
+int config(const char *name, struct strbuf *result);
+
 extern struct oid_array changeset_heads, manifest_heads;
 
 void ensure_heads(struct oid_array *heads);
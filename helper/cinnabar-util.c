@@ -1,8 +1,11 @@
 #include "cache.h"
 #include "run-command.h"
 #include "strslice.h"
+#include "tempfile.h"
 #include "thread-utils.h"
+#include "cinnabar-helper.h"
 #include "cinnabar-util.h"
+#include "hg-connect-internal.h"
 
 #ifdef NO_CURL
 size_t fwrite_buffer(char *ptr, size_t size, size_t nmemb, void *buffer_)
@@ -31,15 +34,30 @@ size_t copy_to(FILE *in, size_t len, struct writer *writer)
 
 #define BUFFER_SIZE (1024 * 1024)
 
+/* Default amount of data kept in memory by a buffered writer before the
+ * rest goes to a temporary file, in bytes. */
+#define DEFAULT_BUFFER_LIMIT (64 * 1024 * 1024)
+
 struct buffered_context {
 	/* List of buffers of fixed size. */
 	char **buffers;
 	size_t nr_buffers;
 	size_t alloc_buffers;
+	/* Maximum number of buffers, or 0 for no limit. */
+	size_t max_buffers;
 	/* Location where to append data to. */
 	struct strslice append_buf;
 	/* (Unwritten out) buffered data in buffers[0] */
 	struct strslice buffered;
+	/* Temporary file where data goes once max_buffers are full, until
+	 * the background thread caught up with it. */
+	struct tempfile *spill;
+	/* Whether new data goes to the spill file. */
+	int spilling;
+	/* Amount of data in the spill file, and how much of it was already
+	 * written out by the background thread. */
+	off_t spill_len;
+	off_t spill_read;
 	/* Whether the buffered writer was closed by the caller, meaning
 	 * nothing more will be written (and thus the background thread
 	 * doesn't have to wait for more data anymore). */
@@ -57,14 +75,32 @@ static void create_buffer(struct buffered_context *context) {
 	context->append_buf.len = BUFFER_SIZE;
 }
 
+/* Appends data to the spill file. Must be called with the mutex held. */
+static void buffered_spill(struct buffered_context *context,
+                           struct strslice in)
+{
+	if (!context->spill) {
+		context->spill = hg_mks_tempfile("cinnabar-buffer-XXXXXX", 0);
+		if (!context->spill)
+			die_errno("cannot create temporary file");
+	}
+	if (write_in_full(get_tempfile_fd(context->spill), in.buf, in.len) < 0)
+		die_errno("cannot write to temporary file");
+	context->spill_len += in.len;
+}
+
 static size_t buffered_write(char *ptr, size_t size, size_t nmemb, void *context_)
 {
 	struct buffered_context *context = context_;
 	struct strslice in = { size * nmemb, ptr };
-	do {
+	pthread_mutex_lock(&context->mutex);
+	while (in.len && !context->spilling) {
 		struct strslice in_slice =
 			strslice_slice(in, 0, context->append_buf.len);
 		in = strslice_slice(in, in_slice.len, SIZE_MAX);
+		/* The background thread only touches append_buf while
+		 * spilling, so the copy doesn't need the lock. */
+		pthread_mutex_unlock(&context->mutex);
 		memcpy((void *)context->append_buf.buf, in_slice.buf, in_slice.len);
 		pthread_mutex_lock(&context->mutex);
 		/* strslice_slice would set buf to strbuf_slop */
@@ -73,10 +109,19 @@ static size_t buffered_write(char *ptr, size_t size, size_t nmemb, void *context
 		if (context->nr_buffers == 1)
 			context->buffered.len += in_slice.len;
 		pthread_cond_signal(&context->cond);
-		if (context->append_buf.len == 0)
-			create_buffer(context);
-		pthread_mutex_unlock(&context->mutex);
-	} while (in.len);
+		if (context->append_buf.len == 0) {
+			if (context->max_buffers &&
+			    context->nr_buffers >= context->max_buffers)
+				context->spilling = 1;
+			else
+				create_buffer(context);
+		}
+	}
+	if (in.len) {
+		buffered_spill(context, in);
+		pthread_cond_signal(&context->cond);
+	}
+	pthread_mutex_unlock(&context->mutex);
 	return nmemb;
 }
 
@@ -92,6 +137,8 @@ static int buffered_close(void *context_)
 	ret = writer_close(&context->out);
 	pthread_cond_destroy(&context->cond);
 	pthread_mutex_destroy(&context->mutex);
+	if (context->spill)
+		delete_tempfile(&context->spill);
 	for (i = 0; i < context->nr_buffers; i++)
 		free(context->buffers[i]);
 	free(context->buffers);
@@ -99,11 +146,49 @@ static int buffered_close(void *context_)
 	return ret;
 }
 
+/* Writes out the next chunk of the spill file, going through buffers[0],
+ * which is unused at this point. Once the spill file is entirely written
+ * out, go back to buffering in memory. Must be called with the mutex held,
+ * when all the buffers in memory were written out. */
+static void buffered_unspill(struct buffered_context *context)
+{
+	int fd = get_tempfile_fd(context->spill);
+	off_t offset = context->spill_read;
+	size_t len = context->spill_len - offset;
+	char *buf = context->buffers[0];
+
+	if (!len) {
+		if (ftruncate(fd, 0) || lseek(fd, 0, SEEK_SET))
+			die_errno("cannot truncate temporary file");
+		context->spilling = 0;
+		context->spill_len = context->spill_read = 0;
+		context->append_buf.buf = context->buffered.buf = buf;
+		context->append_buf.len = BUFFER_SIZE;
+		context->buffered.len = 0;
+		return;
+	}
+	if (len > BUFFER_SIZE)
+		len = BUFFER_SIZE;
+	/* The producer only appends to the spill file, past what we read. */
+	pthread_mutex_unlock(&context->mutex);
+	if (pread_in_full(fd, buf, len, offset) != len)
+		die_errno("cannot read temporary file");
+	write_to(buf, 1, len, &context->out);
+	pthread_mutex_lock(&context->mutex);
+	context->spill_read += len;
+}
+
 void *buffered_thread(void *context_)
 {
 	struct buffered_context *context = context_;
 	pthread_mutex_lock(&context->mutex);
-	while (!context->closed || context->buffered.len || context->nr_buffers > 1) {
+	while (!context->closed || context->buffered.len ||
+	       context->nr_buffers > 1 || context->spilling) {
+		if (!context->buffered.len && context->nr_buffers == 1 &&
+		    context->spilling) {
+			buffered_unspill(context);
+			continue;
+		}
 		if (!context->buffered.len)
 			pthread_cond_wait(&context->cond, &context->mutex);
 		struct strslice out_slice = context->buffered;
@@ -134,12 +219,29 @@ void *buffered_thread(void *context_)
 	return NULL;
 }
 
+/* Returns the maximum number of buffers a buffered writer keeps in memory,
+ * from the cinnabar.buffer-limit configuration. */
+static size_t buffer_limit()
+{
+	struct strbuf conf = STRBUF_INIT;
+	unsigned long limit = DEFAULT_BUFFER_LIMIT;
+
+	if (!config("buffer-limit", &conf) && conf.len &&
+	    !git_parse_ulong(conf.buf, &limit))
+		die("invalid value for cinnabar.buffer-limit: %s", conf.buf);
+	strbuf_release(&conf);
+	if (!limit)
+		return 0;
+	return limit < BUFFER_SIZE ? 1 : limit / BUFFER_SIZE;
+}
+
 void bufferize_writer(struct writer *writer)
 {
 	if (HAVE_THREADS) {
 		struct buffered_context *context = xcalloc(1, sizeof(struct buffered_context));
 		create_buffer(context);
 		context->buffered.buf = context->buffers[0];
+		context->max_buffers = buffer_limit();
 		pthread_mutex_init(&context->mutex, NULL);
 		pthread_cond_init(&context->cond, NULL);
 		context->out = *writer;
@@ -242,25 +344,68 @@ struct prefix_context {
 	struct writer out;
 	size_t prefix_len;
 	struct strbuf buf;
+	/* Whether the output goes to a terminal. */
+	int isatty;
+	/* Whether the last write ended with a carriage return, which may be
+	 * followed by a line feed in the next write. */
+	int pending_cr;
 };
 
+/* Like git's sideband demultiplexer, clear the end of the terminal line
+ * after each line, such that a line overwriting a longer one doesn't leave
+ * garbage behind. */
+#define ANSI_SUFFIX "\033[K"
+
+static void prefix_end_line(struct prefix_context *context, char eol)
+{
+	/* Lines ending with a carriage return are meant to be overwritten
+	 * by the next one, which is typically used for progress bars. That
+	 * only works on a terminal, so when not writing to one, skip them. */
+	if (eol == '\r' && !context->isatty) {
+		strbuf_setlen(&context->buf, context->prefix_len);
+		return;
+	}
+	if (context->isatty)
+		strbuf_addstr(&context->buf, ANSI_SUFFIX);
+	strbuf_addch(&context->buf, eol);
+	write_to(context->buf.buf, 1, context->buf.len, &context->out);
+	strbuf_setlen(&context->buf, context->prefix_len);
+}
+
 static size_t prefix_write(char *ptr, size_t size, size_t nmemb, void *data)
 {
 	struct prefix_context *context = data;
-	size_t len = size * nmemb;
-	struct strslice slice = { len, ptr };
+	struct strslice slice = { size * nmemb, ptr };
+	if (context->pending_cr && slice.len) {
+		context->pending_cr = 0;
+		if (slice.buf[0] == '\n') {
+			slice = strslice_slice(slice, 1, SIZE_MAX);
+			prefix_end_line(context, '\n');
+		} else
+			prefix_end_line(context, '\r');
+	}
 	for (;;) {
-		struct strslice line = strslice_split_once(&slice, '\n');
-		strbuf_addslice(&context->buf, line);
-		if (slice.len != len) {
-			strbuf_addch(&context->buf, '\n');
-			write_to(context->buf.buf, 1, context->buf.len,
-			         &context->out);
-			strbuf_setlen(&context->buf, context->prefix_len);
-			len = slice.len;
-		} else {
+		size_t len = 0;
+		char eol;
+		while (len < slice.len && slice.buf[len] != '\n' &&
+		       slice.buf[len] != '\r')
+			len++;
+		if (len == slice.len)
 			break;
+		eol = slice.buf[len];
+		strbuf_add(&context->buf, slice.buf, len);
+		/* A carriage return followed by a line feed ends a normal
+		 * line. The line feed may only come with the next write. */
+		if (eol == '\r' && len + 1 == slice.len) {
+			context->pending_cr = 1;
+			return size * nmemb;
+		}
+		if (eol == '\r' && slice.buf[len + 1] == '\n') {
+			eol = '\n';
+			len++;
 		}
+		slice = strslice_slice(slice, len + 1, SIZE_MAX);
+		prefix_end_line(context, eol);
 	}
 	strbuf_addslice(&context->buf, slice);
 	return size * nmemb;
@@ -270,6 +415,8 @@ static int prefix_close(void *data)
 {
 	struct prefix_context *context = data;
 	int ret;
+	if (context->pending_cr)
+		prefix_end_line(context, '\r');
 	if (context->buf.len > context->prefix_len)
 		write_to(context->buf.buf, 1, context->buf.len, &context->out);
 	strbuf_release(&context->buf);
@@ -285,6 +432,8 @@ void prefix_writer(struct writer *writer, const char *prefix)
 	strbuf_init(&context->buf, 0);
 	strbuf_addstr(&context->buf, prefix);
 	context->prefix_len = context->buf.len;
+	/* The prefixed output always ends up on stderr. */
+	context->isatty = isatty(2);
 	writer->write = prefix_write;
 	writer->close = prefix_close;
 	writer->context = context;
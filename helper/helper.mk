@@ -5,27 +5,58 @@ SHELL_SCRIPTS := \
 PYTHON_LIBS := \
 	cinnabar/__init__.py \
 	cinnabar/githg.py \
+	cinnabar/api.py \
+	cinnabar/authors.py \
 	cinnabar/bdiff.py \
+	cinnabar/config.py \
 	cinnabar/dag.py \
 	cinnabar/exceptions.py \
+	cinnabar/filters.py \
 	cinnabar/helper.py \
+	cinnabar/largefiles.py \
+	cinnabar/lfs.py \
+	cinnabar/obsolete.py \
+	cinnabar/phases.py \
 	cinnabar/remote_helper.py \
+	cinnabar/remotes.py \
+	cinnabar/subrepos.py \
+	cinnabar/tags.py \
 	cinnabar/git.py \
 	cinnabar/hg/__init__.py \
 	cinnabar/hg/bundle.py \
 	cinnabar/hg/changegroup.py \
 	cinnabar/hg/objects.py \
 	cinnabar/hg/repo.py \
+	cinnabar/hg/revlog.py \
+	cinnabar/hg/streamclone.py \
 	cinnabar/cmd/__init__.py \
+	cinnabar/cmd/annotate.py \
+	cinnabar/cmd/branches.py \
 	cinnabar/cmd/bundle.py \
+	cinnabar/cmd/clone.py \
+	cinnabar/cmd/config.py \
 	cinnabar/cmd/convert.py \
+	cinnabar/cmd/daemon.py \
 	cinnabar/cmd/data.py \
 	cinnabar/cmd/download.py \
 	cinnabar/cmd/fetch.py \
 	cinnabar/cmd/fsck.py \
+	cinnabar/cmd/gc.py \
+	cinnabar/cmd/incoming.py \
+	cinnabar/cmd/largefiles.py \
+	cinnabar/cmd/lfs.py \
+	cinnabar/cmd/metadata.py \
+	cinnabar/cmd/obsolete.py \
+	cinnabar/cmd/phase.py \
+	cinnabar/cmd/prompt.py \
+	cinnabar/cmd/prune.py \
+	cinnabar/cmd/push.py \
 	cinnabar/cmd/python.py \
 	cinnabar/cmd/reclone.py \
 	cinnabar/cmd/rollback.py \
+	cinnabar/cmd/self_update.py \
+	cinnabar/cmd/serve.py \
+	cinnabar/cmd/status.py \
 	cinnabar/cmd/upgrade.py \
 	cinnabar/cmd/util.py \
 	cinnabar/util.py
@@ -82,9 +113,11 @@ CINNABAR_OBJECTS += cinnabar-notes.o
 CINNABAR_OBJECTS += cinnabar-util.o
 CINNABAR_OBJECTS += hg-bundle.o
 CINNABAR_OBJECTS += hg-connect.o
+CINNABAR_OBJECTS += hg-connect-bundle.o
 ifndef NO_CURL
 CINNABAR_OBJECTS += hg-connect-http.o
 endif
+CINNABAR_OBJECTS += hg-connect-log.o
 CINNABAR_OBJECTS += hg-connect-stdio.o
 CINNABAR_OBJECTS += hg-data.o
 CINNABAR_OBJECTS += which.o
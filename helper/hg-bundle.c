@@ -11,16 +11,16 @@ static size_t copy_chunk(int adjust, FILE *in, struct writer *out)
 	const char *p = buf;
 	uint32_t len;
 	size_t ret = 0;
-	//TODO: Check for errors, etc.
 	if (fread(buf, 1, 4, in) < 4)
-		return 0;
+		die("unexpected end of bundle");
 	write_to(buf, 1, 4, out);
 	len = get_be32(p);
 	if (len <= adjust)
 		//TODO: len != 0 is actually invalid
 		return 0;
 	ret = len -= adjust;
-	copy_to(in, len, out);
+	if (copy_to(in, len, out) != len)
+		die("unexpected end of bundle");
 	return ret;
 }
 
@@ -50,11 +50,14 @@ void copy_bundle(FILE *in, struct writer *out)
 {
 	char buf[4];
 	const char *p = buf;
-	//TODO: Check for errors, etc.
-	fread(buf, 1, 4, in);
+	if (fread(buf, 1, 4, in) != 4)
+		die("unexpected end of bundle");
 	write_to(buf, 1, 4, out);
 	if (memcmp(buf, "HG20", 4)) {
-		copy_to(in, get_be32(p) - 4, out);
+		if (get_be32(p) < 4)
+			die("invalid bundle");
+		if (copy_to(in, get_be32(p) - 4, out) != get_be32(p) - 4)
+			die("unexpected end of bundle");
 		copy_changegroup(in, out);
 		return;
 	}
@@ -0,0 +1,653 @@
+#include "git-compat-util.h"
+#include "cinnabar-util.h"
+#include "hg-connect-internal.h"
+#include "hg-bundle.h"
+#include "hg-data.h"
+#include "strbuf.h"
+#include "tempfile.h"
+#include <stdint.h>
+
+/* A bundle "connection" gives access to a mercurial bundle as if it were
+ * a mercurial server containing the changesets from the bundle.
+ * The bundle is first decompressed to a temporary file, and its changesets
+ * are read to find the repository heads and branches. The "getbundle"
+ * command then sends the whole (decompressed) bundle. */
+
+struct bundle_changeset {
+	struct hg_object_id node;
+	struct hg_object_id parent1;
+	struct hg_object_id parent2;
+	char *branch;
+	unsigned is_head:1;
+	unsigned is_branch_head:1;
+};
+
+typedef size_t (*bundle_read_cb)(void *ctx, void *buf, size_t len);
+
+static size_t file_read(void *ctx, void *buf, size_t len)
+{
+	return fread(buf, 1, len, (FILE *)ctx);
+}
+
+static uint32_t read_be32(FILE *in)
+{
+	unsigned char buf[4];
+	if (fread(buf, 1, 4, in) != 4)
+		die("Truncated bundle");
+	return get_be32(buf);
+}
+
+/* Reads the payload of a bundle2 part, which is split in chunks. */
+struct bundle2_part_reader {
+	FILE *in;
+	uint32_t chunk_remaining;
+	int done;
+};
+
+static size_t part_read(void *ctx, void *buf, size_t len)
+{
+	struct bundle2_part_reader *reader = ctx;
+	size_t total = 0;
+
+	while (len && !reader->done) {
+		size_t read;
+		if (!reader->chunk_remaining) {
+			int32_t size = (int32_t)read_be32(reader->in);
+			if (size == 0) {
+				reader->done = 1;
+				break;
+			}
+			if (size < 0)
+				die("Unsupported bundle2 part interruption");
+			reader->chunk_remaining = size;
+		}
+		read = len < reader->chunk_remaining ? len
+		                                     : reader->chunk_remaining;
+		read = fread((char *)buf + total, 1, read, reader->in);
+		if (!read)
+			die("Truncated bundle");
+		total += read;
+		len -= read;
+		reader->chunk_remaining -= read;
+	}
+	return total;
+}
+
+static void part_reader_init(struct bundle2_part_reader *reader, FILE *in)
+{
+	reader->in = in;
+	reader->chunk_remaining = 0;
+	reader->done = 0;
+}
+
+/* Finds the changegroup part in a bundle2, and sets up the reader to read
+ * its payload. Returns the changegroup version. */
+static char *find_changegroup_part(FILE *in,
+                                   struct bundle2_part_reader *reader)
+{
+	uint32_t len = read_be32(in);
+
+	/* Skip the bundle parameters. */
+	if (fseek(in, len, SEEK_CUR))
+		die_errno("Cannot read bundle");
+
+	while ((len = read_be32(in))) {
+		struct strbuf header = STRBUF_INIT;
+		const unsigned char *p, *sizes, *end;
+		const char *type;
+		size_t type_len;
+		int params, i;
+		char *version = NULL;
+
+		if (strbuf_fread(&header, len, in) != len)
+			die("Truncated bundle");
+		if (!header.len)
+			die("Invalid bundle2 part header");
+		p = (const unsigned char *)header.buf;
+		end = p + header.len;
+		type_len = *p++;
+		type = (const char *)p;
+		p += type_len + 4; /* Skip the part type and id */
+		if (p + 2 > end)
+			die("Invalid bundle2 part header");
+		params = p[0] + p[1];
+		sizes = p + 2;
+		p = sizes + 2 * params;
+		if (p > end)
+			die("Invalid bundle2 part header");
+		for (i = 0; i < params; i++) {
+			const char *key = (const char *)p;
+			size_t key_len = sizes[2 * i];
+			size_t value_len = sizes[2 * i + 1];
+			if (p + key_len + value_len > end)
+				die("Invalid bundle2 part header");
+			p += key_len;
+			if (key_len == 7 && !memcmp(key, "version", 7))
+				version = xstrndup((const char *)p, value_len);
+			p += value_len;
+		}
+
+		part_reader_init(reader, in);
+		if (type_len == 11 && !strncasecmp(type, "changegroup", 11)) {
+			strbuf_release(&header);
+			return version ? version : xstrdup("01");
+		}
+		free(version);
+		strbuf_release(&header);
+
+		/* Skip the part payload. */
+		while (!reader->done) {
+			char buf[4096];
+			part_read(reader, buf, sizeof(buf));
+		}
+	}
+	return NULL;
+}
+
+static int read_changegroup_chunk(bundle_read_cb read, void *ctx,
+                                  struct strbuf *out)
+{
+	unsigned char buf[4];
+	uint32_t len;
+
+	strbuf_reset(out);
+	if (read(ctx, buf, 4) != 4)
+		die("Truncated bundle");
+	len = get_be32(buf);
+	if (len <= 4)
+		return 0;
+	len -= 4;
+	strbuf_grow(out, len);
+	if (read(ctx, out->buf, len) != len)
+		die("Truncated bundle");
+	strbuf_setlen(out, len);
+	return 1;
+}
+
+static void apply_rev_chunk(struct strbuf *result, const struct strbuf *base,
+                            struct rev_chunk *chunk)
+{
+	struct rev_diff_part diff;
+	size_t last_end = 0;
+
+	strbuf_reset(result);
+	rev_diff_start_iter(&diff, chunk);
+	while (rev_diff_iter_next(&diff)) {
+		if (diff.start < last_end || diff.end > base->len)
+			die("Invalid changeset delta in bundle");
+		strbuf_add(result, base->buf + last_end, diff.start - last_end);
+		strbuf_add(result, diff.data.buf, diff.data.len);
+		last_end = diff.end;
+	}
+	strbuf_add(result, base->buf + last_end, base->len - last_end);
+}
+
+/* Unescapes a changeset extra, as escaped by mercurial's _string_escape. */
+static void unescape_extra(struct strbuf *out, const char *s, size_t len)
+{
+	const char *end = s + len;
+	for (; s < end; s++) {
+		if (*s != '\\' || s + 1 == end) {
+			strbuf_addch(out, *s);
+			continue;
+		}
+		switch (*++s) {
+		case 'n':
+			strbuf_addch(out, '\n');
+			break;
+		case 'r':
+			strbuf_addch(out, '\r');
+			break;
+		case 't':
+			strbuf_addch(out, '\t');
+			break;
+		case '0':
+			strbuf_addch(out, '\0');
+			break;
+		case 'x':
+			if (s + 2 < end && isxdigit(s[1]) && isxdigit(s[2])) {
+				strbuf_addch(out, (hexval(s[1]) << 4) |
+				                  hexval(s[2]));
+				s += 2;
+				break;
+			}
+			/* fallthrough */
+		default:
+			strbuf_addch(out, *s);
+		}
+	}
+}
+
+/* Returns the branch of the changeset with the given raw text. The branch
+ * is stored in the extras, at the end of the third line. */
+static char *changeset_branch(const char *text, size_t len)
+{
+	const char *end = text + len;
+	const char *line = text;
+	const char *eol, *extra;
+	int i;
+
+	for (i = 0; i < 2; i++) {
+		eol = memchr(line, '\n', end - line);
+		if (!eol)
+			return xstrdup("default");
+		line = eol + 1;
+	}
+	eol = memchr(line, '\n', end - line);
+	if (!eol)
+		eol = end;
+	/* Skip the timestamp and the timezone. */
+	extra = memchr(line, ' ', eol - line);
+	if (extra)
+		extra = memchr(extra + 1, ' ', eol - extra - 1);
+	while (extra && extra < eol) {
+		const char *item_end;
+		extra++;
+		item_end = memchr(extra, '\0', eol - extra);
+		if (!item_end)
+			item_end = eol;
+		if (item_end - extra > 7 && !memcmp(extra, "branch:", 7)) {
+			struct strbuf branch = STRBUF_INIT;
+			unescape_extra(&branch, extra + 7, item_end - extra - 7);
+			return strbuf_detach(&branch, NULL);
+		}
+		extra = item_end;
+	}
+	return xstrdup("default");
+}
+
+static int hg_oid_sort_cmp(const void *a, const void *b)
+{
+	return hg_oidcmp(a, b);
+}
+
+static void add_branch_name(struct strbuf *out, const char *branch)
+{
+	/* Branch names are url-quoted in the branchmap. */
+	for (; *branch; branch++) {
+		if (isalnum(*branch) || strchr("-._~", *branch))
+			strbuf_addch(out, *branch);
+		else
+			strbuf_addf(out, "%%%02X", (unsigned char)*branch);
+	}
+}
+
+/* Reads the changesets from the bundle to gather the heads and branches.
+ * Returns -1 if the bundle has no changegroup, or if a changeset is a delta
+ * against a changeset that is not in the bundle, in which case its branch
+ * can't be known without a local copy of that changeset. */
+static int bundle_read_changesets(struct hg_connection *conn, FILE *in)
+{
+	char magic[4];
+	bundle_read_cb read = file_read;
+	void *ctx = in;
+	struct bundle2_part_reader reader;
+	int cg2 = 0, cg3 = 0;
+	struct strbuf buf = STRBUF_INIT;
+	struct strbuf text = STRBUF_INIT;
+	struct strbuf prev_text = STRBUF_INIT;
+	struct hg_object_id prev_node;
+	struct bundle_changeset *changesets = NULL;
+	struct string_list branches = STRING_LIST_INIT_DUP;
+	struct string_list_item *item;
+	size_t nr = 0, alloc = 0, i;
+	int ret = 0;
+
+	if (fread(magic, 1, 4, in) != 4)
+		die("Truncated bundle");
+	if (!memcmp(magic, "HG20", 4)) {
+		char *version = find_changegroup_part(in, &reader);
+		/* Leave bundles without a changegroup (e.g. stream clone
+		 * bundles) to the python side. */
+		if (!version)
+			return -1;
+		if (!strcmp(version, "02"))
+			cg2 = 1;
+		else if (!strcmp(version, "03"))
+			cg2 = cg3 = 1;
+		else if (strcmp(version, "01"))
+			die("Unsupported changegroup version %s", version);
+		free(version);
+		read = part_read;
+		ctx = &reader;
+	} else {
+		/* Skip the "UN" compression of the decompressed HG10 bundle. */
+		if (fseek(in, 2, SEEK_CUR))
+			die_errno("Cannot read bundle");
+	}
+
+	hg_oidclr(&prev_node);
+	while (read_changegroup_chunk(read, ctx, &buf)) {
+		struct rev_chunk chunk = { STRBUF_INIT, };
+		struct hg_object_id delta_node;
+		struct bundle_changeset *cs;
+
+		if (cg3) {
+			/* Changegroup v3 chunks are v2 chunks with two bytes
+			 * of flags before the delta data. */
+			if (buf.len < 102)
+				die("Invalid revchunk");
+			strbuf_remove(&buf, 100, 2);
+		} else if (!cg2) {
+			/* In changegroup v1, the first changeset is a delta
+			 * against its first parent, and the following ones
+			 * against the previous changeset. */
+			if (buf.len < 80)
+				die("Invalid revchunk");
+			hg_oidcpy(&delta_node, nr ? &prev_node
+			          : (const struct hg_object_id *)(buf.buf + 20));
+		}
+		rev_chunk_from_memory(&chunk, &buf, cg2 ? NULL : &delta_node);
+		if (!is_null_hg_oid(chunk.delta_node) &&
+		    (!nr || !hg_oideq(chunk.delta_node, &prev_node))) {
+			rev_chunk_release(&chunk);
+			ret = -1;
+			break;
+		}
+
+		if (is_null_hg_oid(chunk.delta_node))
+			strbuf_reset(&prev_text);
+		apply_rev_chunk(&text, &prev_text, &chunk);
+
+		ALLOC_GROW(changesets, nr + 1, alloc);
+		cs = &changesets[nr++];
+		hg_oidcpy(&cs->node, chunk.node);
+		hg_oidcpy(&cs->parent1, chunk.parent1);
+		hg_oidcpy(&cs->parent2, chunk.parent2);
+		cs->branch = changeset_branch(text.buf, text.len);
+		cs->is_head = cs->is_branch_head = 1;
+
+		hg_oidcpy(&prev_node, chunk.node);
+		strbuf_swap(&text, &prev_text);
+		rev_chunk_release(&chunk);
+	}
+	strbuf_release(&buf);
+	strbuf_release(&text);
+	strbuf_release(&prev_text);
+
+	if (ret) {
+		for (i = 0; i < nr; i++)
+			free(changesets[i].branch);
+		free(changesets);
+		return ret;
+	}
+
+	conn->bundle.nodes_nr = nr;
+	ALLOC_ARRAY(conn->bundle.nodes, nr);
+	for (i = 0; i < nr; i++)
+		hg_oidcpy(&conn->bundle.nodes[i], &changesets[i].node);
+	QSORT(conn->bundle.nodes, nr, hg_oid_sort_cmp);
+
+	/* Changesets in a changegroup come in topological order, so parents
+	 * always come before their children. */
+	for (i = 0; i < nr; i++) {
+		struct bundle_changeset *cs = &changesets[i];
+		const struct hg_object_id *parents[] = {
+			&cs->parent1, &cs->parent2 };
+		int p;
+		size_t j;
+		for (p = 0; p < 2; p++) {
+			if (is_null_hg_oid(parents[p]))
+				continue;
+			for (j = i; j-- > 0;) {
+				if (!hg_oideq(&changesets[j].node, parents[p]))
+					continue;
+				changesets[j].is_head = 0;
+				if (!strcmp(changesets[j].branch, cs->branch))
+					changesets[j].is_branch_head = 0;
+				break;
+			}
+		}
+	}
+
+	for (i = 0; i < nr; i++) {
+		struct bundle_changeset *cs = &changesets[i];
+		if (cs->is_head) {
+			if (conn->bundle.heads.len)
+				strbuf_addch(&conn->bundle.heads, ' ');
+			strbuf_addstr(&conn->bundle.heads,
+			              hg_oid_to_hex(&cs->node));
+		}
+		if (cs->is_branch_head) {
+			struct strbuf *heads;
+			item = string_list_insert(&branches, cs->branch);
+			if (!item->util) {
+				item->util = xmalloc(sizeof(struct strbuf));
+				strbuf_init(item->util, 0);
+			}
+			heads = item->util;
+			strbuf_addch(heads, ' ');
+			strbuf_addstr(heads, hg_oid_to_hex(&cs->node));
+		}
+		free(cs->branch);
+	}
+	free(changesets);
+
+	if (!conn->bundle.heads.len)
+		strbuf_addstr(&conn->bundle.heads, hg_oid_to_hex(&hg_null_oid));
+	strbuf_addch(&conn->bundle.heads, '\n');
+
+	for_each_string_list_item(item, &branches) {
+		struct strbuf *heads = item->util;
+		add_branch_name(&conn->bundle.branchmap, item->string);
+		strbuf_addbuf(&conn->bundle.branchmap, heads);
+		strbuf_addch(&conn->bundle.branchmap, '\n');
+		strbuf_release(heads);
+	}
+	string_list_clear(&branches, 1);
+	return 0;
+}
+
+static int bundle_has_node(struct hg_connection *conn,
+                           const struct hg_object_id *oid)
+{
+	return !!bsearch(oid, conn->bundle.nodes, conn->bundle.nodes_nr,
+	                 sizeof(*conn->bundle.nodes), hg_oid_sort_cmp);
+}
+
+static void bundle_known(struct hg_connection *conn, struct strbuf *response,
+                         const char *nodes)
+{
+	while (nodes && *nodes) {
+		struct hg_object_id oid;
+		if (get_sha1_hex(nodes, oid.hash))
+			die("Invalid node in known command");
+		strbuf_addch(response, bundle_has_node(conn, &oid) ? '1' : '0');
+		nodes += 40;
+		if (*nodes == ' ')
+			nodes++;
+	}
+}
+
+static void bundle_lookup(struct hg_connection *conn, struct strbuf *response,
+                          const char *key)
+{
+	size_t len = strlen(key);
+	const struct hg_object_id *found = NULL;
+	size_t i;
+
+	for (i = 0; len <= 40 && i < conn->bundle.nodes_nr; i++) {
+		const char *hex = hg_oid_to_hex(&conn->bundle.nodes[i]);
+		if (strncasecmp(hex, key, len))
+			continue;
+		if (found) {
+			strbuf_addf(response, "0 ambiguous identifier\n");
+			return;
+		}
+		found = &conn->bundle.nodes[i];
+	}
+	if (found)
+		strbuf_addf(response, "1 %s\n", hg_oid_to_hex(found));
+	else
+		strbuf_addf(response, "0 unknown revision '%s'\n", key);
+}
+
+static void bundle_simple_command(struct hg_connection *conn,
+                                  struct strbuf *response,
+                                  const char *command, va_list ap)
+{
+	const char *name;
+	const char *value = NULL;
+
+	/* The commands we handle take at most one parameter we care about. */
+	while ((name = va_arg(ap, const char *))) {
+		if (strcmp(name, "*"))
+			value = va_arg(ap, const char *);
+		else
+			va_arg(ap, const struct string_list *);
+	}
+
+	if (!strcmp(command, "branchmap"))
+		strbuf_addbuf(response, &conn->bundle.branchmap);
+	else if (!strcmp(command, "heads"))
+		strbuf_addbuf(response, &conn->bundle.heads);
+	else if (!strcmp(command, "known"))
+		bundle_known(conn, response, value);
+	else if (!strcmp(command, "lookup"))
+		bundle_lookup(conn, response, value);
+	else if (!strcmp(command, "listkeys") ||
+	         !strcmp(command, "clonebundles") ||
+	         !strcmp(command, "cinnabarclone"))
+		; /* Bundles have no bookmarks, and nothing to clone from. */
+	else
+		die("Mercurial bundles don't support the \"%s\" command",
+		    command);
+}
+
+static void bundle_changegroup_command(struct hg_connection *conn,
+                                       struct writer *out,
+                                       const char *command, va_list ap)
+{
+	FILE *in = conn->bundle.file;
+	struct stat st;
+	off_t start = 0;
+
+	/* A bundle contains everything there is to get, so send it all,
+	 * whatever the requested heads and common nodes. */
+	if (strcmp(command, "getbundle"))
+		die("Mercurial bundles don't support the \"%s\" command",
+		    command);
+
+	if (fstat(fileno(in), &st))
+		die_errno("Cannot read bundle");
+	rewind(in);
+	/* Changegroup v1 bundles are sent as a raw changegroup, as a server
+	 * would. */
+	if (st.st_size >= 6 && conn->bundle.is_changegroup)
+		start = 6;
+	if (fseek(in, start, SEEK_SET))
+		die_errno("Cannot read bundle");
+	copy_to(in, st.st_size - start, out);
+}
+
+static void bundle_push_command(struct hg_connection *conn,
+                                struct strbuf *response, FILE *in,
+                                off_t len, const char *command,
+                                va_list ap)
+{
+	die("Cannot push to a mercurial bundle");
+}
+
+static int bundle_finish(struct hg_connection *conn)
+{
+	fclose(conn->bundle.file);
+	delete_tempfile(&conn->bundle.spool);
+	free(conn->bundle.nodes);
+	strbuf_release(&conn->bundle.branchmap);
+	strbuf_release(&conn->bundle.heads);
+	return 0;
+}
+
+FILE *bundle_spool_writer(struct writer *writer, struct tempfile **spool)
+{
+	FILE *file;
+
+	*spool = hg_mks_tempfile("hg-bundle-XXXXXX.hg", 3);
+	if (!*spool)
+		die_errno("Cannot create temporary file");
+	file = fdopen_tempfile(*spool, "w");
+	if (!file)
+		die_errno("Cannot create temporary file");
+	writer->write = (write_callback)fwrite;
+	writer->close = (close_callback)fflush;
+	writer->context = file;
+	decompress_bundle_writer(writer);
+	return file;
+}
+
+int hg_connection_is_bundle(struct hg_connection *conn)
+{
+	return conn->finish == bundle_finish;
+}
+
+struct hg_connection *hg_connect_bundle_spool(struct tempfile *spool)
+{
+	struct hg_connection *conn = xcalloc(1, sizeof(*conn));
+	char magic[4];
+
+	if (close_tempfile_gently(spool))
+		die_errno("Cannot write temporary file");
+
+	string_list_init_dup(&conn->capabilities);
+	/* Advertize what hg_connect requires. Not advertizing unbundle and
+	 * pushkey makes pushes fail with an explicit error. */
+	string_list_append(&conn->capabilities, "getbundle");
+	string_list_append(&conn->capabilities, "branchmap");
+	string_list_append(&conn->capabilities, "known");
+	string_list_append(&conn->capabilities, "lookup");
+
+	conn->bundle.spool = spool;
+	conn->bundle.file = fopen(get_tempfile_path(spool), "r");
+	if (!conn->bundle.file)
+		die_errno("Cannot read temporary file");
+	strbuf_init(&conn->bundle.branchmap, 0);
+	strbuf_init(&conn->bundle.heads, 0);
+	if (fread(magic, 1, 4, conn->bundle.file) != 4)
+		die("Truncated bundle");
+	conn->bundle.is_changegroup = !!memcmp(magic, "HG20", 4);
+	rewind(conn->bundle.file);
+	if (bundle_read_changesets(conn, conn->bundle.file)) {
+		/* Fall back to sending the decompressed bundle to stdout, for
+		 * the python side to handle with the help of the local
+		 * changesets. */
+		struct writer writer;
+		struct stat st;
+		if (fstat(fileno(conn->bundle.file), &st))
+			die_errno("Cannot read bundle");
+		rewind(conn->bundle.file);
+		fwrite("bundle\n", 1, 7, stdout);
+		writer.write = (write_callback)fwrite;
+		writer.close = (close_callback)fflush;
+		writer.context = stdout;
+		copy_to(conn->bundle.file, st.st_size, &writer);
+		writer_close(&writer);
+		bundle_finish(conn);
+		string_list_clear(&conn->capabilities, 0);
+		free(conn);
+		return NULL;
+	}
+
+	conn->simple_command = bundle_simple_command;
+	conn->changegroup_command = bundle_changegroup_command;
+	conn->push_command = bundle_push_command;
+	conn->finish = bundle_finish;
+	return conn;
+}
+
+struct hg_connection *hg_connect_bundle(const char *path)
+{
+	struct tempfile *spool;
+	struct writer writer;
+	struct stat st;
+	FILE *file = fopen(path, "r");
+
+	if (!file)
+		die_errno("cannot open '%s'", path);
+	if (fstat(fileno(file), &st))
+		die_errno("cannot stat '%s'", path);
+	bundle_spool_writer(&writer, &spool);
+	copy_to(file, st.st_size, &writer);
+	writer_close(&writer);
+	fclose(file);
+	return hg_connect_bundle_spool(spool);
+}
@@ -1,9 +1,11 @@
 #include "git-compat-util.h"
+#include "cinnabar-helper.h"
 #include "cinnabar-util.h"
 #include "hg-connect-internal.h"
 #include "hg-bundle.h"
 #include "credential.h"
 #include "http.h"
+#include "which.h"
 #include "strbuf.h"
 
 typedef void (*prepare_request_cb_t)(CURL *curl, struct curl_slist *headers,
@@ -13,6 +15,7 @@ struct http_request_info {
 	long redirects;
 	char *effective_url;
 	void *data;
+	int refresh_auth;
 };
 
 struct command_request_data {
@@ -23,8 +26,87 @@ struct command_request_data {
 	struct strbuf args;
 };
 
+/* Some services require short-lived tokens obtained from an external
+ * command, set with the cinnabar.auth-command configuration.
+ * The first line the command outputs is either a complete HTTP header
+ * ("Name: value"), or a bare token, which is sent as a bearer token.
+ * An optional second line gives the lifetime of the token, in seconds.
+ * The token is cached until it expires, or the server responds with a
+ * 401. */
+static struct {
+	struct strbuf header;
+	time_t expires;
+	int state; /* -1: not initialized, 0: no command, 1: command */
+} auth_token = { STRBUF_INIT, 0, -1 };
+
+static const char *http_auth_header(int refresh)
+{
+	struct child_process proc = CHILD_PROCESS_INIT;
+	struct strbuf command = STRBUF_INIT;
+	struct strbuf output = STRBUF_INIT;
+	struct string_list lines = STRING_LIST_INIT_NODUP;
+	char *end;
+	long lifetime = 0;
+
+	if (auth_token.state == 0)
+		return NULL;
+	if (!refresh && auth_token.header.len &&
+	    (!auth_token.expires || time(NULL) < auth_token.expires))
+		return auth_token.header.buf;
+
+	/* The remote-specific value is given by git-cinnabar. */
+	if (getenv("GIT_CINNABAR_AUTH_COMMAND"))
+		strbuf_addstr(&command, getenv("GIT_CINNABAR_AUTH_COMMAND"));
+	else if (config("auth-command", &command))
+		strbuf_reset(&command);
+	if (!command.len) {
+		auth_token.state = 0;
+		strbuf_release(&command);
+		return NULL;
+	}
+	auth_token.state = 1;
+
+	strvec_push(&proc.args, command.buf);
+	proc.use_shell = 1;
+	proc.no_stdin = 1;
+	if (capture_command(&proc, &output, 0))
+		die("auth command `%s` failed", command.buf);
+
+	string_list_split_in_place(&lines, output.buf, '\n', 2);
+	if (!lines.nr || !*lines.items[0].string)
+		die("auth command `%s` didn't output a token", command.buf);
+
+	strbuf_reset(&auth_token.header);
+	if (strchr(lines.items[0].string, ':'))
+		strbuf_addstr(&auth_token.header, lines.items[0].string);
+	else
+		strbuf_addf(&auth_token.header, "Authorization: Bearer %s",
+		            lines.items[0].string);
+	strbuf_rtrim(&auth_token.header);
+
+	if (lines.nr > 1 && *lines.items[1].string) {
+		lifetime = strtol(lines.items[1].string, &end, 10);
+		if (lifetime <= 0 || (*end && *end != '\r' && *end != '\n'))
+			die("auth command `%s` output an invalid lifetime: %s",
+			    command.buf, lines.items[1].string);
+	}
+	auth_token.expires = lifetime ? time(NULL) + lifetime : 0;
+
+	string_list_clear(&lines, 0);
+	strbuf_release(&output);
+	strbuf_release(&command);
+	return auth_token.header.buf;
+}
+
+/* Requests go through git's http.c active slots, which keep their curl
+ * handle around between requests, so the TCP and TLS connections are
+ * reused across commands to the same server. The HTTP version is negotiated
+ * by curl (HTTP/2 over TLS when supported) unless forced with git's
+ * http.version configuration. As commands are sent one at a time, there
+ * would be nothing to gain from HTTP/2 multiplexing. */
 static int http_request(prepare_request_cb_t prepare_request_cb, void *data)
 {
+	const char *auth_header;
 	struct active_request_slot *slot;
 	struct slot_results results;
 	struct curl_slist *headers = NULL;
@@ -38,6 +120,9 @@ static int http_request(prepare_request_cb_t prepare_request_cb, void *data)
 
 	headers = curl_slist_append(headers,
 				    "Accept: application/mercurial-0.1");
+	auth_header = http_auth_header(info->refresh_auth);
+	if (auth_header)
+		headers = curl_slist_append(headers, auth_header);
 	prepare_request_cb(slot->curl, headers, info->data);
 
 	curl_easy_setopt(slot->curl, CURLOPT_HTTPHEADER, headers);
@@ -59,7 +144,7 @@ static int http_request(prepare_request_cb_t prepare_request_cb, void *data)
 static int http_request_reauth(prepare_request_cb_t prepare_request_cb,
 			       void *data)
 {
-	struct http_request_info info = { 0, NULL, data };
+	struct http_request_info info = { 0, NULL, data, 0 };
 	int ret = http_request(prepare_request_cb, &info);
 
 	if (ret != HTTP_OK && ret != HTTP_REAUTH)
@@ -82,7 +167,12 @@ static int http_request_reauth(prepare_request_cb_t prepare_request_cb,
 	if (ret != HTTP_REAUTH)
 		return ret;
 
-	credential_fill(&http_auth);
+	/* When using a token from the auth command, get a fresh one instead
+	 * of asking for credentials. */
+	if (auth_token.state == 1)
+		info.refresh_auth = 1;
+	else
+		credential_fill(&http_auth);
 
 	return http_request(prepare_request_cb, &info);
 }
@@ -219,24 +309,118 @@ static void http_command(struct hg_connection *conn,
 
 static void http_simple_command(struct hg_connection *conn,
 				struct strbuf *response,
-				const char *command, ...)
+				const char *command, va_list ap)
 {
-	va_list ap;
-	va_start(ap, command);
 	if (strcmp(command, "pushkey"))
 		http_command(conn, prepare_simple_request, response, command,
 		             ap);
 	else
 		http_command(conn, prepare_pushkey_request, response, command,
 		             ap);
-	va_end(ap);
 }
 
 struct changegroup_response_data {
 	CURL *curl;
 	struct writer *writer;
+	const char *proto_header;
+};
+
+/* Responses with the application/mercurial-0.2 media type start with the
+ * name of the compression engine the server used, preceded by its length
+ * on one byte. */
+struct mediatype_0_2_context {
+	struct writer out;
+	struct strbuf header;
+	int saw_header;
 };
 
+static size_t mediatype_0_2_write(char *ptr, size_t size, size_t nmemb,
+				  void *data)
+{
+	struct mediatype_0_2_context *context = data;
+	size_t len = size * nmemb;
+	size_t header_len;
+
+	if (!context->saw_header) {
+		const char *engine;
+		size_t used;
+		header_len = context->header.len ?
+			(unsigned char)context->header.buf[0] + 1 :
+			(unsigned char)ptr[0] + 1;
+		used = header_len - context->header.len;
+		if (used > len)
+			used = len;
+		strbuf_add(&context->header, ptr, used);
+		ptr += used;
+		if (context->header.len < header_len)
+			return nmemb;
+		context->saw_header = 1;
+		engine = context->header.buf + 1;
+		if (!strcmp(engine, "zlib")) {
+			inflate_writer(&context->out);
+		} else if (!strcmp(engine, "zstd")) {
+			const char *argv[] = { "zstd", "-d", NULL };
+			pipe_writer(&context->out, argv);
+		} else if (strcmp(engine, "none"))
+			die("unsupported compression engine from server: %s",
+			    engine);
+		write_to(ptr, 1, len - used, &context->out);
+		return nmemb;
+	}
+	write_to(ptr, 1, len, &context->out);
+	return nmemb;
+}
+
+static int mediatype_0_2_close(void *data)
+{
+	struct mediatype_0_2_context *context = data;
+	int ret = writer_close(&context->out);
+	strbuf_release(&context->header);
+	free(context);
+	return ret;
+}
+
+static void mediatype_0_2_writer(struct writer *writer)
+{
+	struct mediatype_0_2_context *context = xcalloc(1, sizeof(*context));
+	strbuf_init(&context->header, 0);
+	context->out = *writer;
+	writer->write = mediatype_0_2_write;
+	writer->close = mediatype_0_2_close;
+	writer->context = context;
+}
+
+/* Returns the X-HgProto-1 header to send when the server supports the
+ * application/mercurial-0.2 media type, which allows to negotiate the
+ * compression of responses, or NULL. */
+static char *hgproto_header(struct hg_connection *conn)
+{
+	struct strbuf header = STRBUF_INIT;
+	struct string_list types = STRING_LIST_INIT_DUP;
+	const char *mediatypes = hg_get_capability(conn, "httpmediatype");
+	const char *compression = hg_get_capability(conn, "compression");
+	char *zstd;
+
+	if (mediatypes)
+		string_list_split(&types, mediatypes, ',', -1);
+	if (!compression || !unsorted_string_list_has_string(&types, "0.2tx")) {
+		string_list_clear(&types, 0);
+		return NULL;
+	}
+	string_list_clear(&types, 0);
+
+	strbuf_addstr(&header, "X-HgProto-1: 0.1 0.2 comp=");
+	/* zstd streams are decompressed with the zstd program, like for
+	 * bundles. */
+	zstd = which("zstd");
+	if (zstd) {
+		free(zstd);
+		strbuf_addstr(&header, "zstd,");
+	}
+	strbuf_addstr(&header, "zlib,none");
+	return strbuf_detach(&header, NULL);
+}
+
 static size_t changegroup_write(char *buffer, size_t size, size_t nmemb, void* data)
 {
 	struct changegroup_response_data *response_data =
@@ -248,6 +432,9 @@ static size_t changegroup_write(char *buffer, size_t size, size_t nmemb, void* d
 		                       &content_type) && content_type) {
 			if (strcmp(content_type, "application/mercurial-0.1") == 0) {
 				inflate_writer(response_data->writer);
+			} else if (strcmp(content_type,
+			                  "application/mercurial-0.2") == 0) {
+				mediatype_0_2_writer(response_data->writer);
 			} else if (strcmp(content_type, "application/hg-error") == 0) {
 				write_to("err\n", 1, 4, response_data->writer);
 				response_data->writer->write = (write_callback)fwrite;
@@ -270,26 +457,31 @@ static void prepare_changegroup_request(CURL *curl, struct curl_slist *headers,
 		(struct changegroup_response_data *)data;
 
 	response_data->curl = curl;
+	if (response_data->proto_header)
+		headers = curl_slist_append(headers,
+		                            response_data->proto_header);
 
 	curl_easy_setopt(curl, CURLOPT_FILE, response_data);
 	curl_easy_setopt(curl, CURLOPT_WRITEFUNCTION, changegroup_write);
 }
 
 /* The changegroup, changegroupsubset and getbundle commands return a raw
- * zlib stream when called over HTTP. */
+ * zlib stream when called over HTTP, unless the server supports the
+ * application/mercurial-0.2 media type, in which case we can request other
+ * compression engines, like zstd. */
 static void http_changegroup_command(struct hg_connection *conn,
                                      struct writer *out,
-                                     const char *command, ...)
+                                     const char *command, va_list ap)
 {
-	va_list ap;
 	struct changegroup_response_data response_data;
+	char *proto_header = hgproto_header(conn);
 
 	response_data.curl = NULL;
 	response_data.writer = out;
+	response_data.proto_header = proto_header;
 
-	va_start(ap, command);
 	http_command(conn, prepare_changegroup_request, &response_data, command, ap);
-	va_end(ap);
+	free(proto_header);
 }
 
 struct push_request_info {
@@ -318,19 +510,16 @@ static void prepare_push_request(CURL *curl, struct curl_slist *headers,
 
 static void http_push_command(struct hg_connection *conn,
 			      struct strbuf *response, FILE *in, off_t len,
-			      const char *command, ...)
+			      const char *command, va_list ap)
 {
-	va_list ap;
 	struct push_request_info info;
 	struct strbuf http_response = STRBUF_INIT;
 	struct string_list list = STRING_LIST_INIT_NODUP;
-	va_start(ap, command);
 	info.response = &http_response;
 	info.in = in;
 	info.len = len;
 	//TODO: handle errors
 	http_command(conn, prepare_push_request, &info, command, ap);
-	va_end(ap);
 
 	if (!strncmp(http_response.buf, "HG20", 4)) {
 		strbuf_addbuf(response, &http_response);
@@ -353,27 +542,30 @@ static void http_push_command(struct hg_connection *conn,
  * the repo url with a query string "?cmd=capabilities". If the remote
  * url is not actually a repo, but a bundle, the content will start with
  * 'HG10' or 'HG20', which is not something that would appear as the first
- * four characters for the "capabilities" answer. In that case, we output
- * the stream to stdout.
+ * four characters for the "capabilities" answer. In that case, we spool
+ * the decompressed stream to a temporary file, which is then used for a
+ * bundle connection.
  * (Note this assumes HTTP servers serving bundles don't care about query
  * strings)
  * Ideally, it would be good to pause the curl request, return a
  * hg_connection, and give control back to the caller, but git's http.c
  * doesn't allow pauses.
  */
+struct caps_request_data {
+	struct writer writer;
+	struct tempfile *spool;
+};
+
 static size_t caps_request_write(char *ptr, size_t size, size_t nmemb,
 				 void *data)
 {
-	struct writer *writer = data;
+	struct caps_request_data *request = data;
+	struct writer *writer = &request->writer;
 	size_t len = size * nmemb;
-	if (writer->write == fwrite_buffer && ((struct strbuf *)writer->context)->len == 0) {
+	if (!request->spool && ((struct strbuf *)writer->context)->len == 0) {
 		if (len > 4 && ptr[0] == 'H' && ptr[1] == 'G' &&
 		    (ptr[2] == '1' || ptr[2] == '2') && ptr[3] == '0') {
-			writer->write = (write_callback)fwrite;
-			writer->close = (close_callback)fflush;
-			writer->context = stdout;
-			fwrite("bundle\n", 1, 7, stdout);
-			decompress_bundle_writer(writer);
+			bundle_spool_writer(writer, &request->spool);
 			bufferize_writer(writer);
 		}
 	}
@@ -388,11 +580,11 @@ static void prepare_caps_request(CURL *curl, struct curl_slist *headers,
 }
 
 static void http_capabilities_command(struct hg_connection *conn,
-				      struct writer *writer, ...)
+				      struct caps_request_data *data, ...)
 {
 	va_list ap;
-	va_start(ap, writer);
-	http_command(conn, prepare_caps_request, writer, "capabilities", ap);
+	va_start(ap, data);
+	http_command(conn, prepare_caps_request, data, "capabilities", ap);
 	va_end(ap);
 }
 
@@ -407,7 +599,7 @@ struct hg_connection *hg_connect_http(const char *url, int flags)
 {
 	struct hg_connection *conn = xmalloc(sizeof(*conn));
 	struct strbuf caps = STRBUF_INIT;
-	struct writer writer;
+	struct caps_request_data data;
 	string_list_init_dup(&conn->capabilities);
 
 	conn->http.url = xstrdup(url);
@@ -415,18 +607,25 @@ struct hg_connection *hg_connect_http(const char *url, int flags)
 
 	http_init(NULL, conn->http.url, 0);
 
-	writer.write = fwrite_buffer;
-	writer.close = NULL;
-	writer.context = &caps;
-	http_capabilities_command(conn, &writer, NULL);
-	/* Cf. comment above caps_request_write. If the bundle stream was
-	 * sent to stdout, the writer was switched to fwrite. */
-	if (writer.write != fwrite_buffer) {
-		writer_close(&writer);
-		free(conn->http.url);
+	data.writer.write = fwrite_buffer;
+	data.writer.close = NULL;
+	data.writer.context = &caps;
+	data.spool = NULL;
+	http_capabilities_command(conn, &data, NULL);
+	/* Cf. comment above caps_request_write. If the response was a
+	 * bundle, it was spooled to a temporary file. */
+	if (data.spool) {
+		writer_close(&data.writer);
+		http_finish(conn);
+		string_list_clear(&conn->capabilities, 0);
 		free(conn);
-		return NULL;
+		strbuf_release(&caps);
+		return hg_connect_bundle_spool(data.spool);
 	}
+	/* We only speak version 1 of the wire protocol. Version 2 (advertized
+	 * as "exp-http-v2-*" in the capabilities) never left the experimental
+	 * stage and was removed in Mercurial 6.0, so servers always fall back
+	 * to version 1, which gives the same results. */
 	split_capabilities(&conn->capabilities, caps.buf);
 	strbuf_release(&caps);
 
@@ -3,6 +3,8 @@
 
 #include "hg-connect.h"
 
+struct tempfile;
+
 void split_capabilities(struct string_list *list, const char *buf);
 
 struct hg_connection *hg_connect_stdio(const char *url, int flags);
@@ -10,6 +12,20 @@ struct hg_connection *hg_connect_http(const char *url, int flags);
 
 struct hg_connection *hg_connect_bundle(const char *path);
 
+int hg_connection_is_bundle(struct hg_connection *conn);
+
+/* Connects to a fake server replaying the responses recorded in the given
+ * wire log. */
+struct hg_connection *hg_connect_replay(const char *path);
+
+/* Creates a temporary file, in cinnabar.tmpdir if it is set. */
+struct tempfile *hg_mks_tempfile(const char *template, int suffixlen);
+
+/* Sets up the given writer to decompress a bundle to a new temporary file,
+ * which can then be given to hg_connect_bundle_spool. */
+FILE *bundle_spool_writer(struct writer *writer, struct tempfile **spool);
+struct hg_connection *hg_connect_bundle_spool(struct tempfile *spool);
+
 /* Generic helpers to handle passing parameters through the mercurial
  * wire protocol. Meant for internal use in hg-connect*.c only. */
 union param_value {
@@ -23,4 +39,18 @@ typedef void (*command_add_param_t)(void *data, const char *name,
 void prepare_command(void *data, command_add_param_t command_add_param,
                      va_list ap);
 
+/* Recording of commands and their responses in a wire log, when
+ * GIT_CINNABAR_WIRE_LOG is set. */
+void wire_log_open(struct hg_connection *conn, const char *path);
+
+void wire_log_command(struct hg_connection *conn, const char *command,
+                      va_list ap);
+
+void wire_log_response(struct hg_connection *conn, struct strbuf *response);
+
+/* Sets up the given writer to record the data written to it. */
+void wire_log_writer(struct hg_connection *conn, struct writer *writer);
+
+void wire_log_finish(struct hg_connection *conn);
+
 #endif
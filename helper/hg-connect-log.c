@@ -0,0 +1,243 @@
+#include "git-compat-util.h"
+#include "hg-connect-internal.h"
+#include "strbuf.h"
+
+/* The wire log is a sequence of records of the form
+ *   <type> <length>\n<data>\n
+ * where <type> is one of:
+ * - "capabilities": the server capabilities, separated with NUL
+ *   characters, each followed by '=' and its value, if it has one.
+ * - "command": the command name, followed by its arguments, one per
+ *   line, as <name>=<value>.
+ * - "response": the response to a command.
+ * - "data": a chunk of the bundle returned by a changegroup command.
+ * - "end": the end of that bundle.
+ * The log starts with a "capabilities" record, and each "command" record
+ * is followed by either a "response" record or by "data" records and an
+ * "end" record. Data sent with push commands is not recorded. */
+
+static void wire_log_record(FILE *log, const char *type, const char *buf,
+                            size_t len)
+{
+	fprintf(log, "%s %"PRIuMAX"\n", type, (uintmax_t)len);
+	fwrite(buf, 1, len, log);
+	fputc('\n', log);
+}
+
+static void command_log_add_param(void *data, const char *name,
+                                  union param_value value)
+{
+	struct strbuf *buf = data;
+	/* The parameters from the list following "*" are added
+	 * individually afterwards. */
+	if (strcmp(name, "*"))
+		strbuf_addf(buf, "\n%s=%s", name, value.value);
+}
+
+static void format_command(struct strbuf *buf, const char *command,
+                           va_list ap)
+{
+	strbuf_addstr(buf, command);
+	prepare_command(buf, command_log_add_param, ap);
+}
+
+void wire_log_open(struct hg_connection *conn, const char *path)
+{
+	struct string_list_item *item;
+	struct strbuf caps = STRBUF_INIT;
+
+	conn->wire_log = fopen(path, "w");
+	if (!conn->wire_log)
+		die_errno("cannot open '%s'", path);
+	for_each_string_list_item(item, &conn->capabilities) {
+		strbuf_addstr(&caps, item->string);
+		if (item->util)
+			strbuf_addf(&caps, "=%s", (char *)item->util);
+		strbuf_addch(&caps, '\0');
+	}
+	wire_log_record(conn->wire_log, "capabilities", caps.buf, caps.len);
+	strbuf_release(&caps);
+}
+
+void wire_log_command(struct hg_connection *conn, const char *command,
+                      va_list ap)
+{
+	struct strbuf buf = STRBUF_INIT;
+	format_command(&buf, command, ap);
+	wire_log_record(conn->wire_log, "command", buf.buf, buf.len);
+	strbuf_release(&buf);
+}
+
+void wire_log_response(struct hg_connection *conn, struct strbuf *response)
+{
+	wire_log_record(conn->wire_log, "response", response->buf,
+	                response->len);
+}
+
+struct wire_log_context {
+	struct writer out;
+	FILE *log;
+};
+
+static size_t wire_log_write(char *ptr, size_t size, size_t nmemb,
+                             void *data)
+{
+	struct wire_log_context *context = data;
+	wire_log_record(context->log, "data", ptr, size * nmemb);
+	return write_to(ptr, size, nmemb, &context->out);
+}
+
+static int wire_log_close(void *data)
+{
+	struct wire_log_context *context = data;
+	int ret;
+	wire_log_record(context->log, "end", NULL, 0);
+	ret = writer_close(&context->out);
+	free(context);
+	return ret;
+}
+
+void wire_log_writer(struct hg_connection *conn, struct writer *writer)
+{
+	struct wire_log_context *context = xcalloc(1, sizeof(*context));
+	context->out = *writer;
+	context->log = conn->wire_log;
+	writer->write = wire_log_write;
+	writer->close = wire_log_close;
+	writer->context = context;
+}
+
+void wire_log_finish(struct hg_connection *conn)
+{
+	if (fclose(conn->wire_log))
+		error_errno("failed to write wire log");
+	conn->wire_log = NULL;
+}
+
+/* Reads the next record, appending its data to the given strbuf. */
+static void replay_read_record(FILE *file, struct strbuf *type,
+                               struct strbuf *data)
+{
+	struct strbuf header = STRBUF_INIT;
+	char *len_str, *end;
+	uintmax_t len;
+
+	if (strbuf_getline_lf(&header, file) == EOF)
+		die("wire replay: unexpected end of log");
+	len_str = strchr(header.buf, ' ');
+	if (!len_str)
+		die("wire replay: invalid record: '%s'", header.buf);
+	strbuf_reset(type);
+	strbuf_add(type, header.buf, len_str - header.buf);
+	errno = 0;
+	len = strtoumax(len_str + 1, &end, 10);
+	if (!len_str[1] || *end || errno || len > SIZE_MAX)
+		die("wire replay: invalid record: '%s'", header.buf);
+	strbuf_release(&header);
+	if (strbuf_fread(data, len, file) != len || fgetc(file) != '\n')
+		die("wire replay: truncated log");
+}
+
+static void replay_expect(FILE *file, const char *expected,
+                          struct strbuf *data)
+{
+	struct strbuf type = STRBUF_INIT;
+	replay_read_record(file, &type, data);
+	if (strcmp(type.buf, expected))
+		die("wire replay: expected a \"%s\" record, got \"%s\"",
+		    expected, type.buf);
+	strbuf_release(&type);
+}
+
+/* Checks that the command is the next one in the log. Replaying only
+ * makes sense when the commands are the same as when recording. */
+static void replay_command(struct hg_connection *conn, const char *command,
+                           va_list ap)
+{
+	struct strbuf expected = STRBUF_INIT;
+	struct strbuf recorded = STRBUF_INIT;
+
+	format_command(&expected, command, ap);
+	replay_expect(conn->replay.file, "command", &recorded);
+	if (strbuf_cmp(&expected, &recorded))
+		die("wire replay: unexpected command:\n%s\n"
+		    "The log has instead:\n%s", expected.buf, recorded.buf);
+	strbuf_release(&expected);
+	strbuf_release(&recorded);
+}
+
+static void replay_simple_command(struct hg_connection *conn,
+                                  struct strbuf *response,
+                                  const char *command, va_list ap)
+{
+	replay_command(conn, command, ap);
+	replay_expect(conn->replay.file, "response", response);
+}
+
+static void replay_changegroup_command(struct hg_connection *conn,
+                                       struct writer *out,
+                                       const char *command, va_list ap)
+{
+	struct strbuf type = STRBUF_INIT;
+	struct strbuf data = STRBUF_INIT;
+
+	replay_command(conn, command, ap);
+	for (;;) {
+		strbuf_reset(&data);
+		replay_read_record(conn->replay.file, &type, &data);
+		if (!strcmp(type.buf, "end"))
+			break;
+		if (strcmp(type.buf, "data"))
+			die("wire replay: expected a \"data\" record, got "
+			    "\"%s\"", type.buf);
+		write_to(data.buf, 1, data.len, out);
+	}
+	strbuf_release(&type);
+	strbuf_release(&data);
+}
+
+static void replay_push_command(struct hg_connection *conn,
+                                struct strbuf *response, FILE *in,
+                                off_t len, const char *command,
+                                va_list ap)
+{
+	replay_command(conn, command, ap);
+	replay_expect(conn->replay.file, "response", response);
+}
+
+static int replay_finish(struct hg_connection *conn)
+{
+	fclose(conn->replay.file);
+	return 0;
+}
+
+struct hg_connection *hg_connect_replay(const char *path)
+{
+	struct hg_connection *conn = xcalloc(1, sizeof(*conn));
+	struct strbuf caps = STRBUF_INIT;
+	const char *cap;
+
+	conn->replay.file = fopen(path, "r");
+	if (!conn->replay.file)
+		die_errno("cannot open '%s'", path);
+
+	string_list_init_dup(&conn->capabilities);
+	replay_expect(conn->replay.file, "capabilities", &caps);
+	for (cap = caps.buf; cap < caps.buf + caps.len;
+	     cap += strlen(cap) + 1) {
+		struct string_list_item *item =
+			string_list_append(&conn->capabilities, cap);
+		char *value = strchr(item->string, '=');
+		if (value) {
+			*(value++) = '\0';
+			item->util = value;
+		}
+	}
+	strbuf_release(&caps);
+
+	conn->simple_command = replay_simple_command;
+	conn->changegroup_command = replay_changegroup_command;
+	conn->push_command = replay_push_command;
+	conn->finish = replay_finish;
+	return conn;
+}
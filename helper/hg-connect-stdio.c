@@ -64,8 +64,10 @@ static void stdio_send_command_v(struct hg_connection *conn,
 	strbuf_addch(&cmd, '\n');
 	prepare_command(&cmd, stdio_command_add_param, ap);
 
-	xwrite(conn->stdio.proc.in, cmd.buf, cmd.len);
+	if (write_in_full(conn->stdio.proc.in, cmd.buf, cmd.len) < 0)
+		die_errno("failed to send %s command to remote", command);
 	strbuf_release(&cmd);
+	conn->stdio.waiting = 1;
 }
 
 static void stdio_send_command(struct hg_connection *conn,
@@ -77,37 +79,87 @@ static void stdio_send_command(struct hg_connection *conn,
 	va_end(ap);
 }
 
-static void stdio_read_response(struct hg_connection *conn,
-				struct strbuf *response)
+static NORETURN void die_invalid_response(const char *command,
+                                          struct strbuf *line)
+{
+	struct strbuf quoted = STRBUF_INIT;
+	size_t i;
+
+	/* The line may be anything, including binary data. Only show the
+	 * beginning of it, with non-printable characters escaped. */
+	for (i = 0; i < line->len && i < 80; i++) {
+		unsigned char c = line->buf[i];
+		if (isprint(c))
+			strbuf_addch(&quoted, c);
+		else
+			strbuf_addf(&quoted, "\\x%02x", c);
+	}
+	if (line->len > 80)
+		strbuf_addstr(&quoted, "...");
+	die("invalid response from remote to %s: '%s'\n"
+	    "The remote sent something that is not a mercurial response, "
+	    "like a banner or an error message.", command, quoted.buf);
+}
+
+/* Reads the length line preceding a response. When skip_garbage is set,
+ * lines that are not a length are relayed to stderr instead of being an
+ * error. This is used at the beginning of the connection, because some
+ * servers, or shell startup files, print things on stdout before the
+ * mercurial server starts. */
+static size_t stdio_read_length(struct hg_connection *conn,
+                                const char *command, int skip_garbage)
+{
+	struct strbuf line = STRBUF_INIT;
+	uintmax_t length;
+	char *end;
+
+	for (;;) {
+		if (strbuf_getline_lf(&line, conn->stdio.out) == EOF)
+			die("remote hung up unexpectedly while reading "
+			    "response to %s", command);
+		errno = 0;
+		length = strtoumax(line.buf, &end, 10);
+		if (line.len && isdigit(line.buf[0]) && !*end && !errno &&
+		    length <= SIZE_MAX)
+			break;
+		if (!skip_garbage)
+			die_invalid_response(command, &line);
+		fprintf(stderr, "remote: %s\n", line.buf);
+	}
+	strbuf_release(&line);
+	return length;
+}
+
+static void stdio_read_response_1(struct hg_connection *conn,
+                                  struct strbuf *response,
+                                  const char *command, int skip_garbage)
 {
-	struct strbuf length_str = STRBUF_INIT;
-	size_t length;
+	size_t length = stdio_read_length(conn, command, skip_garbage);
 
-	//TODO: Check for errors, etc.
-	strbuf_getline_lf(&length_str, conn->stdio.out);
-	length = strtol(length_str.buf, NULL, 10);
-	strbuf_release(&length_str);
+	if (strbuf_fread(response, length, conn->stdio.out) != length)
+		die("remote hung up unexpectedly while reading response to %s",
+		    command);
+}
 
-	strbuf_fread(response, length, conn->stdio.out);
+static void stdio_read_response(struct hg_connection *conn,
+				struct strbuf *response, const char *command)
+{
+	stdio_read_response_1(conn, response, command, 0);
 }
 
 static void stdio_simple_command(struct hg_connection *conn,
 				 struct strbuf *response,
-				 const char *command, ...)
+				 const char *command, va_list ap)
 {
-	va_list ap;
-	va_start(ap, command);
 	stdio_send_command_v(conn, command, ap);
-	stdio_read_response(conn, response);
-	va_end(ap);
+	stdio_read_response(conn, response, command);
+	conn->stdio.waiting = 0;
 }
 
 static void stdio_changegroup_command(struct hg_connection *conn,
                                       struct writer *out,
-				      const char *command, ...)
+				      const char *command, va_list ap)
 {
-	va_list ap;
-	va_start(ap, command);
 	stdio_send_command_v(conn, command, ap);
 
 	/* We're going to receive a stream, but we don't know how big it is
@@ -117,29 +169,29 @@ static void stdio_changegroup_command(struct hg_connection *conn,
 	if (conn->stdio.is_remote)
 		bufferize_writer(out);
 	copy_bundle(conn->stdio.out, out);
-	va_end(ap);
+	conn->stdio.waiting = 0;
 }
 
 static void stdio_push_command(struct hg_connection *conn,
 			       struct strbuf *response, FILE *in, off_t len,
-			       const char *command, ...)
+			       const char *command, va_list ap)
 {
 	int is_bundle2 = 0;
-	char buf[4096];
+	char buf[32768];
 	struct strbuf header = STRBUF_INIT;
-	va_list ap;
-	va_start(ap, command);
 	stdio_send_command_v(conn, command, ap);
 	/* The server normally sends an empty response before reading the data
 	 * it's sent if not, it's an error (typically, the remote will
-	 * complain here if there was a lost push race). */
-	//TODO: handle that error.
-	stdio_read_response(conn, &header);
-	va_end(ap);
-
-	//TODO: chunk in smaller pieces.
-	strbuf_addf(&header, "%"PRIdMAX"\n", (intmax_t)len);
-	xwrite(conn->stdio.proc.in, header.buf, header.len);
+	 * complain here if there was a lost push race). In that case, the
+	 * server doesn't expect the data, so don't send it. */
+	stdio_read_response(conn, &header, command);
+	conn->stdio.waiting = 0;
+	if (header.len) {
+		strbuf_addstr(response, "error\n");
+		strbuf_addbuf(response, &header);
+		strbuf_release(&header);
+		return;
+	}
 	strbuf_release(&header);
 
         if (len > 4) {
@@ -149,38 +201,77 @@ static void stdio_push_command(struct hg_connection *conn,
 		is_bundle2 = memcmp(header, "HG20", 4) == 0;
 	}
 
+	/* The data is sent in chunks, each preceded by its length, and
+	 * terminated by an empty chunk. */
 	while (len) {
+		struct strbuf chunk_len = STRBUF_INIT;
 		size_t read = sizeof(buf) > len ? len : sizeof(buf);
 		read = fread(buf, 1, read, in);
+		if (!read)
+			die_errno("failed to read bundle");
 		len -= read;
-		xwrite(conn->stdio.proc.in, buf, read);
+		strbuf_addf(&chunk_len, "%"PRIuMAX"\n", (uintmax_t)read);
+		if (write_in_full(conn->stdio.proc.in, chunk_len.buf,
+		                  chunk_len.len) < 0 ||
+		    write_in_full(conn->stdio.proc.in, buf, read) < 0)
+			die_errno("failed to send %s data to remote", command);
+		strbuf_release(&chunk_len);
 	}
 
-	xwrite(conn->stdio.proc.in, "0\n", 2);
+	if (write_in_full(conn->stdio.proc.in, "0\n", 2) < 0)
+		die_errno("failed to send %s data to remote", command);
+	conn->stdio.waiting = 1;
 	if (is_bundle2) {
 		copy_bundle_to_strbuf(conn->stdio.out, response);
 	} else {
-		/* There are two responses, one for output, one for actual response. */
-		//TODO: actually handle output here
-		stdio_read_response(conn, &header);
+		/* There are two responses, one for output, one for actual
+		 * response. The output is relayed like the remote stderr. */
+		struct writer writer;
+		stdio_read_response(conn, &header, command);
+		writer.write = (write_callback)fwrite;
+		writer.close = (close_callback)fflush;
+		writer.context = stderr;
+		prefix_writer(&writer, "remote: ");
+		write_to(header.buf, 1, header.len, &writer);
+		writer_close(&writer);
 		strbuf_release(&header);
-		stdio_read_response(conn, response);
+		stdio_read_response(conn, response, command);
 	}
+	conn->stdio.waiting = 0;
 }
 
 static int stdio_finish(struct hg_connection *conn)
 {
-	stdio_send_command(conn, "", NULL);
+	void *stderr_errno = NULL;
+	int ret;
+
+	/* Send an empty command to end the session. The remote may already
+	 * be gone, in which case its exit status tells what happened. */
+	write_in_full(conn->stdio.proc.in, "\n", 1);
 	close(conn->stdio.proc.in);
 	fclose(conn->stdio.out);
-	pthread_join(conn->stdio.thread, NULL);
-	return finish_command(&conn->stdio.proc);
+	if (conn->stdio.timeout) {
+		pthread_join(conn->stdio.relay, NULL);
+		close(conn->stdio.proc.out);
+	}
+	pthread_join(conn->stdio.thread, &stderr_errno);
+	/* finish_command doesn't close the pipes start_command created. */
+	close(conn->stdio.proc.err);
+	ret = finish_command(&conn->stdio.proc);
+	if (stderr_errno) {
+		errno = (int)(intptr_t)stderr_errno;
+		ret = error_errno("failed to read from remote stderr");
+	}
+	return ret;
 }
 
+/* Relays the remote stderr, prefixed with "remote: ". Returns the errno
+ * of a failed read, if any, which is reported by stdio_finish. */
 void *prefix_remote_stderr(void *context)
 {
 	struct hg_connection *conn = context;
 	struct writer writer;
+	intptr_t result = 0;
 
 	writer.write = (write_callback)fwrite;
 	writer.close = (close_callback)fflush;
@@ -190,14 +281,79 @@ void *prefix_remote_stderr(void *context)
 	for (;;) {
 		char buf[4096];
 		ssize_t len = xread(conn->stdio.proc.err, buf, 4096);
+		if (len < 0)
+			result = errno;
 		if (len <= 0)
 			break;
 		write_to(buf, 1, len, &writer);
 	}
 	writer_close(&writer);
+	return (void *)result;
+}
+
+/* Relays the remote stdout to the pipe read by the main thread, and gives
+ * up when the remote doesn't send anything for longer than the configured
+ * timeout while a response is expected. The remote is then killed, so that
+ * the main thread sees the connection end instead of hanging forever. */
+static void *relay_remote_stdout(void *context)
+{
+	struct hg_connection *conn = context;
+	struct pollfd pfd;
+	time_t last_activity = time(NULL);
+	sigset_t mask;
+
+	/* Let writes to a closed pipe fail instead of killing the process. */
+	sigemptyset(&mask);
+	sigaddset(&mask, SIGPIPE);
+	pthread_sigmask(SIG_BLOCK, &mask, NULL);
+
+	pfd.fd = conn->stdio.proc.out;
+	pfd.events = POLLIN;
+	for (;;) {
+		char buf[32768];
+		ssize_t len;
+		int ret = poll(&pfd, 1, 1000);
+		if (ret < 0 && errno != EINTR)
+			break;
+		if (ret <= 0) {
+			if (!conn->stdio.waiting)
+				last_activity = time(NULL);
+			else if (time(NULL) - last_activity >=
+			         conn->stdio.timeout) {
+				error("no response from remote for %d seconds, "
+				      "giving up; try again later",
+				      conn->stdio.timeout);
+				kill(conn->stdio.proc.pid, SIGTERM);
+				break;
+			}
+			continue;
+		}
+		len = xread(pfd.fd, buf, sizeof(buf));
+		if (len <= 0 ||
+		    write_in_full(conn->stdio.relay_fd, buf, len) < 0)
+			break;
+		last_activity = time(NULL);
+	}
+	close(conn->stdio.relay_fd);
 	return NULL;
 }
 
+static int get_stdio_timeout(void)
+{
+	struct strbuf value = STRBUF_INIT;
+	long timeout = 0;
+	char *end;
+
+	if (!config("ssh-timeout", &value) && value.len) {
+		timeout = strtol(value.buf, &end, 10);
+		if (*end || timeout < 0 || timeout > INT_MAX)
+			die("invalid value for cinnabar.ssh-timeout: %s",
+			    value.buf);
+	}
+	strbuf_release(&value);
+	return timeout;
+}
+
 struct hg_connection *hg_connect_stdio(const char *url, int flags)
 {
 	char *hostandport, *path;
@@ -236,27 +392,14 @@ struct hg_connection *hg_connect_stdio(const char *url, int flags)
 		fill_ssh_args(proc, ssh_host, port, protocol_v0, flags);
 	} else if (protocol == PROTO_FILE || protocol == PROTO_LOCAL) {
 		struct stat st;
-		stat(path, &st);
-		if (S_ISREG(st.st_mode)) {
-			FILE *file;
-			struct writer writer;
+		if (!stat(path, &st) && S_ISREG(st.st_mode)) {
 			free(hostandport);
 			child_process_clear(proc);
 			string_list_clear(&conn->capabilities, 0);
 			free(conn);
-			// TODO: Eventually we want to have a hg_connection
-			// for bundles, but for now, just send the stream to
-			// stdout and return NULL.
-			file = fopen(path, "r");
+			conn = hg_connect_bundle(path);
 			free(path);
-			fwrite("bundle\n", 1, 7, stdout);
-			writer.write = (write_callback)fwrite;
-			writer.close = (close_callback)fflush;
-			writer.context = stdout;
-			decompress_bundle_writer(&writer);
-			copy_to(file, st.st_size, &writer);
-			writer_close(&writer);
-			return NULL;
+			return conn;
 		}
 		proc->use_shell = 1;
 	} else
@@ -268,10 +411,26 @@ struct hg_connection *hg_connect_stdio(const char *url, int flags)
 	strvec_push(&proc->args, buf.buf);
 	strbuf_release(&buf);
 
-	start_command(proc);
+	/* start_command already reported the error. */
+	if (start_command(proc))
+		exit(128);
 	conn->stdio.is_remote = (protocol == PROTO_SSH);
-	conn->stdio.out = xfdopen(proc->out, "r");
-	pthread_create(&conn->stdio.thread, NULL, prefix_remote_stderr, conn);
+	conn->stdio.timeout = conn->stdio.is_remote ? get_stdio_timeout() : 0;
+	conn->stdio.waiting = 0;
+	if (conn->stdio.timeout) {
+		int fds[2];
+		if (pipe(fds) < 0)
+			die_errno("unable to create pipe");
+		conn->stdio.out = xfdopen(fds[0], "r");
+		conn->stdio.relay_fd = fds[1];
+		if (pthread_create(&conn->stdio.relay, NULL,
+		                   relay_remote_stdout, conn))
+			die("unable to create thread to relay remote output");
+	} else
+		conn->stdio.out = xfdopen(proc->out, "r");
+	if (pthread_create(&conn->stdio.thread, NULL, prefix_remote_stderr,
+	                   conn))
+		die("unable to create thread to relay remote stderr");
 	// TODO: return earlier in case the command fails somehow.
 
 	free(path);
@@ -292,12 +451,13 @@ struct hg_connection *hg_connect_stdio(const char *url, int flags)
 			   "0000000000000000000000000000000000000000-"
 			   "0000000000000000000000000000000000000000", NULL);
 
-	stdio_read_response(conn, &buf);
+	stdio_read_response_1(conn, &buf, "capabilities", 1);
 	if (!(buf.len == 1 && buf.buf[0] == '\n')) {
 		split_capabilities(&conn->capabilities, buf.buf);
 		/* Now read the response for the "between" command. */
-		stdio_read_response(conn, &buf);
+		stdio_read_response(conn, &buf, "between");
 	}
+	conn->stdio.waiting = 0;
 	strbuf_release(&buf);
 
 	conn->simple_command = stdio_simple_command;
@@ -1,5 +1,6 @@
 #include "git-compat-util.h"
 #include "cache.h"
+#include "cinnabar-helper.h"
 #include "hg-connect-internal.h"
 #include "hg-bundle.h"
 #include "strbuf.h"
@@ -97,40 +98,107 @@ struct hg_connection *hg_connect(const char *url, int flags)
 		"getbundle",
 		"branchmap",
 		"known",
+	};
+	/* Bundle connections can't be pushed to, and don't advertize these. */
+	const char *required_push_caps[] = {
 		"pushkey",
 		//TODO: defer to when pushing.
 		"unbundle",
 	};
+
+	const char *wire_log = getenv("GIT_CINNABAR_WIRE_LOG");
+	const char *wire_replay = getenv("GIT_CINNABAR_WIRE_REPLAY");
 	int i;
 
+	if (wire_replay && *wire_replay)
+		conn = hg_connect_replay(wire_replay);
 #ifndef NO_CURL
-	if (!strncmp(url, "http://", sizeof("http://") - 1) ||
-	    !strncmp(url, "https://", sizeof("https://") - 1)) {
+	else if (!strncmp(url, "http://", sizeof("http://") - 1) ||
+	         !strncmp(url, "https://", sizeof("https://") - 1))
 		conn = hg_connect_http(url, flags);
-	} else
 #endif
+	else
 		conn = hg_connect_stdio(url, flags);
 
 	if (!conn)
 		return NULL;
 
+	conn->wire_log = NULL;
+	if (wire_log && *wire_log)
+		wire_log_open(conn, wire_log);
+
+	string_list_init_dup(&conn->prefetched);
+
 	for (i = 0; i < ARRAY_SIZE(required_caps); i++)
 		if (!hg_get_capability(conn, required_caps[i]))
 			die("Mercurial repository doesn't support the required"
 			    " \"%s\" capability.", required_caps[i]);
 
+	if (!hg_connection_is_bundle(conn))
+		for (i = 0; i < ARRAY_SIZE(required_push_caps); i++)
+			if (!hg_get_capability(conn, required_push_caps[i]))
+				die("Mercurial repository doesn't support the "
+				    "required \"%s\" capability.",
+				    required_push_caps[i]);
+
 	return conn;
 }
 
+/* Wrappers around the connection commands, recording them and their
+ * responses in the wire log when there is one. */
+static void simple_command(struct hg_connection *conn,
+                           struct strbuf *response, const char *command, ...)
+{
+	va_list ap;
+	if (conn->wire_log) {
+		va_start(ap, command);
+		wire_log_command(conn, command, ap);
+		va_end(ap);
+	}
+	va_start(ap, command);
+	conn->simple_command(conn, response, command, ap);
+	va_end(ap);
+	if (conn->wire_log)
+		wire_log_response(conn, response);
+}
+
+static void changegroup_command(struct hg_connection *conn,
+                                struct writer *out, const char *command, ...)
+{
+	va_list ap;
+	if (conn->wire_log) {
+		va_start(ap, command);
+		wire_log_command(conn, command, ap);
+		va_end(ap);
+		wire_log_writer(conn, out);
+	}
+	va_start(ap, command);
+	conn->changegroup_command(conn, out, command, ap);
+	va_end(ap);
+}
+
+static void push_command(struct hg_connection *conn,
+                         struct strbuf *response, FILE *in, off_t len,
+                         const char *command, ...)
+{
+	va_list ap;
+	if (conn->wire_log) {
+		va_start(ap, command);
+		wire_log_command(conn, command, ap);
+		va_end(ap);
+	}
+	va_start(ap, command);
+	conn->push_command(conn, response, in, len, command, ap);
+	va_end(ap);
+	if (conn->wire_log)
+		wire_log_response(conn, response);
+}
+
 /* Batched output concatenates all responses, separating them with ';'
  * The output also has four characters escaped: '=', ';', ',' and ':',
  * as, resp., ":e", ":s", ":o", and ":c". */
-static void split_batched_repo_state(struct strbuf *state,
-				     struct strbuf *branchmap,
-				     struct strbuf *heads,
-				     struct strbuf *bookmarks)
+static void split_batched(struct strbuf *state, struct strbuf **all)
 {
-	struct strbuf *all[] = { branchmap, heads, bookmarks, NULL };
 	struct strbuf **current;
 	const char *buf, *state_end;
 
@@ -162,24 +230,77 @@ static void split_batched_repo_state(struct strbuf *state,
 	}
 }
 
+/* Sends the given commands in a single "batch" command, and fills the
+ * NULL-terminated results array with their respective responses.
+ * Commands are separated with ';', and each is given as
+ * "<name> <arg>=<value>,<arg>=<value>", with escaped values. Note the space
+ * after the command name is required even without arguments. */
+static void hg_batch(struct hg_connection *conn, const char *cmds,
+		     struct strbuf **results)
+{
+	struct strbuf out = STRBUF_INIT;
+
+	simple_command(conn, &out, "batch", "cmds", cmds,
+		       "*", NULL, NULL);
+	split_batched(&out, results);
+	strbuf_release(&out);
+}
+
+/* Commands whose response is fetched along the repository state when
+ * the server advertizes them, saving a roundtrip each when they are
+ * used later (which they are on the initial clone). */
+static const char *prefetch_commands[] = {
+	"clonebundles",
+	"cinnabarclone",
+};
+
+static int get_prefetched(struct hg_connection *conn, const char *command,
+			  struct strbuf *result)
+{
+	struct string_list_item *item;
+	struct strbuf *prefetched;
+
+	item = unsorted_string_list_lookup(&conn->prefetched, command);
+	if (!item || !item->util)
+		return 0;
+	prefetched = item->util;
+	strbuf_addbuf(result, prefetched);
+	strbuf_release(prefetched);
+	FREE_AND_NULL(item->util);
+	return 1;
+}
+
 void hg_get_repo_state(struct hg_connection *conn, struct strbuf *branchmap,
 		       struct strbuf *heads, struct strbuf *bookmarks)
 {
 	if (hg_get_capability(conn, "batch")) {
-		struct strbuf out = STRBUF_INIT;
-		conn->simple_command(
-			conn, &out, "batch", "cmds",
-			"branchmap ;heads ;listkeys namespace=bookmarks",
-			"*", NULL, NULL);
-		if (!out.buf)
-			return;
-		split_batched_repo_state(&out, branchmap, heads, bookmarks);
-		strbuf_release(&out);
+		struct strbuf cmds = STRBUF_INIT;
+		struct strbuf *results[3 + ARRAY_SIZE(prefetch_commands) + 1] = {
+			branchmap, heads, bookmarks,
+		};
+		int i, nr = 3;
+
+		strbuf_addstr(&cmds,
+		              "branchmap ;heads ;listkeys namespace=bookmarks");
+		for (i = 0; i < ARRAY_SIZE(prefetch_commands); i++) {
+			const char *command = prefetch_commands[i];
+			struct strbuf *result;
+			if (!hg_get_capability(conn, command))
+				continue;
+			strbuf_addf(&cmds, ";%s ", command);
+			result = xmalloc(sizeof(*result));
+			strbuf_init(result, 0);
+			string_list_append(&conn->prefetched, command)->util =
+				result;
+			results[nr++] = result;
+		}
+		hg_batch(conn, cmds.buf, results);
+		strbuf_release(&cmds);
 	} else {
 		// TODO: when not batching, check for coherency
 		// (see the cinnabar.remote_helper python module)
-		conn->simple_command(conn, branchmap, "branchmap", NULL);
-		conn->simple_command(conn, heads, "heads", NULL);
+		simple_command(conn, branchmap, "branchmap", NULL);
+		simple_command(conn, heads, "heads", NULL);
 		hg_listkeys(conn, bookmarks, "bookmarks");
 	}
 }
@@ -188,22 +309,23 @@ void hg_known(struct hg_connection *conn, struct strbuf *result,
 	      struct oid_array *nodes)
 {
 	char *nodes_str = join_oid_array_hex(nodes, ' ');
-	conn->simple_command(conn, result, "known",
-			     "nodes", nodes_str,
-			     "*", NULL, NULL);
+	simple_command(conn, result, "known",
+		       "nodes", nodes_str,
+		       "*", NULL, NULL);
 	free(nodes_str);
 }
 
 void hg_listkeys(struct hg_connection *conn, struct strbuf *result,
 		 const char *namespace)
 {
-	conn->simple_command(conn, result, "listkeys",
-			     "namespace", namespace, NULL);
+	simple_command(conn, result, "listkeys",
+		       "namespace", namespace, NULL);
 }
 
 void hg_getbundle(struct hg_connection *conn, FILE *out,
 		  struct oid_array *heads, struct oid_array *common,
-		  const char *bundle2caps)
+		  const char *bundle2caps, const char *includepats,
+		  const char *excludepats)
 {
 	struct string_list args = STRING_LIST_INIT_NODUP;
 	struct string_list_item *item;
@@ -221,10 +343,22 @@ void hg_getbundle(struct hg_connection *conn, FILE *out,
 		item = string_list_append(&args, "bundlecaps");
 		item->util = strdup(bundle2caps);
 	}
+	if ((includepats && *includepats) || (excludepats && *excludepats)) {
+		item = string_list_append(&args, "narrow");
+		item->util = strdup("1");
+	}
+	if (includepats && *includepats) {
+		item = string_list_append(&args, "includepats");
+		item->util = strdup(includepats);
+	}
+	if (excludepats && *excludepats) {
+		item = string_list_append(&args, "excludepats");
+		item->util = strdup(excludepats);
+	}
 	writer.write = (write_callback)fwrite;
 	writer.close = (close_callback)fflush;
 	writer.context = out;
-	conn->changegroup_command(conn, &writer, "getbundle", "*", &args, NULL);
+	changegroup_command(conn, &writer, "getbundle", "*", &args, NULL);
 	string_list_clear(&args, 1);
 
 	writer_close(&writer);
@@ -239,6 +373,27 @@ static int unbundlehash(const struct object_id *oid, void *data)
 	return 0;
 }
 
+struct tempfile *hg_mks_tempfile(const char *template, int suffixlen)
+{
+	struct tempfile *tmpfile;
+	struct strbuf tmpdir = STRBUF_INIT;
+
+	if (!config("tmpdir", &tmpdir) && tmpdir.len) {
+		char *path = interpolate_path(tmpdir.buf, 0);
+		if (path) {
+			strbuf_reset(&tmpdir);
+			strbuf_addstr(&tmpdir, path);
+			free(path);
+		}
+		strbuf_complete(&tmpdir, '/');
+		strbuf_addstr(&tmpdir, template);
+		tmpfile = mks_tempfile_s(tmpdir.buf, suffixlen);
+	} else
+		tmpfile = mks_tempfile_ts(template, suffixlen);
+	strbuf_release(&tmpdir);
+	return tmpfile;
+}
+
 void hg_unbundle(struct hg_connection *conn, struct strbuf *response, FILE *in,
 		 struct oid_array *heads)
 {
@@ -248,6 +403,12 @@ void hg_unbundle(struct hg_connection *conn, struct strbuf *response, FILE *in,
 	/* When the heads list is empty, we send "force", which needs to be
 	 * sent as hex. */
 	char *heads_str;
+
+	/* Bundle connections don't advertize it. */
+	if (!hg_get_capability(conn, "unbundle"))
+		die("Mercurial repository doesn't support the required"
+		    " \"unbundle\" capability.");
+
 	if (heads->nr) {
 		if (hg_get_capability(conn, "unbundlehash")) {
 			git_SHA_CTX ctx;
@@ -273,7 +434,7 @@ void hg_unbundle(struct hg_connection *conn, struct strbuf *response, FILE *in,
 	/* Neither the stdio nor the HTTP protocols can handle a stream for
 	 * push commands, so store the data as a temporary file. */
 	//TODO: error checking
-	tmpfile = mks_tempfile_ts("hg-bundle-XXXXXX.hg", 3);
+	tmpfile = hg_mks_tempfile("hg-bundle-XXXXXX.hg", 3);
 	file = fdopen_tempfile(tmpfile, "w");
 	copy_bundle_to_file(in, file);
 	fflush(file);
@@ -281,8 +442,8 @@ void hg_unbundle(struct hg_connection *conn, struct strbuf *response, FILE *in,
 
 	file = fopen(tmpfile->filename.buf, "r");
 	fstat(fileno(file), &st);
-	conn->push_command(conn, response, file, st.st_size, "unbundle",
-			   "heads", heads_str, NULL);
+	push_command(conn, response, file, st.st_size, "unbundle",
+		     "heads", heads_str, NULL);
 	fclose(file);
 
 	delete_tempfile(&tmpfile);
@@ -295,33 +456,53 @@ void hg_pushkey(struct hg_connection *conn, struct strbuf *response,
 		const char *new)
 {
 	//TODO: handle the response being a mix of return code and output
-	conn->simple_command(conn, response, "pushkey",
-			     "namespace", namespace,
-			     "key", key,
-			     "old", old,
-			     "new", new,
-			     NULL);
+	if (!hg_get_capability(conn, "pushkey"))
+		die("Mercurial repository doesn't support the required"
+		    " \"pushkey\" capability.");
+	simple_command(conn, response, "pushkey",
+		       "namespace", namespace,
+		       "key", key,
+		       "old", old,
+		       "new", new,
+		       NULL);
 }
 
 void hg_lookup(struct hg_connection *conn, struct strbuf *result,
 	       const char *key)
 {
-	conn->simple_command(conn, result, "lookup", "key", key, NULL);
+	simple_command(conn, result, "lookup", "key", key, NULL);
+}
+
+void hg_between(struct hg_connection *conn, struct strbuf *result,
+		const char *pairs)
+{
+	simple_command(conn, result, "between", "pairs", pairs, NULL);
 }
 
 void hg_clonebundles(struct hg_connection *conn, struct strbuf *result)
 {
-	conn->simple_command(conn, result, "clonebundles", NULL);
+	if (!get_prefetched(conn, "clonebundles", result))
+		simple_command(conn, result, "clonebundles", NULL);
 }
 
 void hg_cinnabarclone(struct hg_connection *conn, struct strbuf *result)
 {
-	conn->simple_command(conn, result, "cinnabarclone", NULL);
+	if (!get_prefetched(conn, "cinnabarclone", result))
+		simple_command(conn, result, "cinnabarclone", NULL);
 }
 
 int hg_finish_connect(struct hg_connection *conn)
 {
 	int code = conn->finish(conn);
+	struct string_list_item *item;
+
+	if (conn->wire_log)
+		wire_log_finish(conn);
+
+	for_each_string_list_item(item, &conn->prefetched)
+		if (item->util)
+			strbuf_release(item->util);
+	string_list_clear(&conn->prefetched, 1);
 	string_list_clear(&conn->capabilities, 0);
 	free(conn);
 	return code;
@@ -6,31 +6,64 @@
 #include "oid-array.h"
 #include "string-list.h"
 
+struct hg_object_id;
+struct tempfile;
+
 struct hg_connection {
 	struct string_list capabilities;
 
+	/* Responses to commands sent ahead of time as part of a batch,
+	 * with the command name in item->string and a strbuf in item->util.
+	 * Each is used at most once. */
+	struct string_list prefetched;
+
+	/* The command arguments are given in ap, as name and value pairs,
+	 * terminated by NULL. See prepare_command. */
 	void (*simple_command)(struct hg_connection *, struct strbuf *response,
-			       const char *command, ...);
+			       const char *command, va_list ap);
 
 	void (*changegroup_command)(struct hg_connection *, struct writer *out,
-				    const char *command, ...);
+				    const char *command, va_list ap);
 
 	void (*push_command)(struct hg_connection *, struct strbuf *response,
-			     FILE *in, off_t len, const char *command, ...);
+			     FILE *in, off_t len, const char *command,
+			     va_list ap);
 
 	int (*finish)(struct hg_connection *);
 
+	/* When GIT_CINNABAR_WIRE_LOG is set, where commands and their
+	 * responses are recorded. */
+	FILE *wire_log;
+
 	union {
 		struct {
 			struct child_process proc;
 			FILE *out;
 			pthread_t thread;
 			int is_remote;
+			/* When a timeout is set, the remote output goes
+			 * through a relay thread that watches for it. */
+			int timeout;
+			pthread_t relay;
+			int relay_fd;
+			volatile int waiting;
 		} stdio;
 		struct {
 			char *url;
 			int initial_request;
 		} http;
+		struct {
+			struct tempfile *spool;
+			FILE *file;
+			struct hg_object_id *nodes;
+			size_t nodes_nr;
+			struct strbuf branchmap;
+			struct strbuf heads;
+			int is_changegroup;
+		} bundle;
+		struct {
+			FILE *file;
+		} replay;
 	};
 };
 
@@ -50,7 +83,8 @@ void hg_known(struct hg_connection *conn, struct strbuf *result,
 
 void hg_getbundle(struct hg_connection *conn, FILE *out,
                   struct oid_array *heads, struct oid_array *common,
-                  const char *bundle2caps);
+                  const char *bundle2caps, const char *includepats,
+                  const char *excludepats);
 
 void hg_unbundle(struct hg_connection *conn, struct strbuf *response,
                  FILE *in, struct oid_array *heads);
@@ -65,6 +99,9 @@ void hg_listkeys(struct hg_connection *conn, struct strbuf *result,
 void hg_lookup(struct hg_connection *conn, struct strbuf *result,
                const char *key);
 
+void hg_between(struct hg_connection *conn, struct strbuf *result,
+                const char *pairs);
+
 void hg_clonebundles(struct hg_connection *conn, struct strbuf *result);
 
 void hg_cinnabarclone(struct hg_connection *conn, struct strbuf *result);
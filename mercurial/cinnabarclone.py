@@ -39,9 +39,10 @@ To create a git repository or bundle for use with this extension, first
 clone the mercurial repository with git-cinnabar. For a git repository,
 push the `refs/cinnabar/metadata` ref to the git repository, renaming it
 as necessary to match the optional `<branch>` name configured in the
-`cinnabar.manifest` file. For a bundle, use a command like `git bundle
-create <bundle-file> refs/cinnabar/metadata`, and upload the resulting
-bundle-file to a HTTP/HTTPS server.
+`cinnabar.manifest` file. For a bundle, use a command like `git cinnabar
+bundle --cinnabarclone <bundle-file>`, and upload the resulting bundle-file
+to a HTTP/HTTPS server. Running the command again only recreates the bundle
+when the metadata changed.
 """
 
 from __future__ import absolute_import, unicode_literals
@@ -0,0 +1,33 @@
+from __future__ import absolute_import, unicode_literals
+import io
+import unittest
+from cinnabar.authors import (
+    AuthorMap,
+    parse_authors,
+)
+
+
+class TestAuthors(unittest.TestCase):
+    def test_parse_authors(self):
+        authors = parse_authors(io.BytesIO(
+            b'# Comment\n'
+            b'\n'
+            b'foo = Foo <foo@bar>\n'
+            b'  foo@bar=Foo <foo@bar>  \n'
+            b'Foo Bar = Foo <foo@bar>\n'
+            b'invalid\n'
+            b'noemail = Foo\n'
+        ))
+        self.assertEqual(authors, {
+            b'foo': b'Foo <foo@bar>',
+            b'foo@bar': b'Foo <foo@bar>',
+            b'Foo Bar': b'Foo <foo@bar>',
+        })
+
+    def test_author_map(self):
+        author_map = AuthorMap({b'foo': b'Foo <foo@bar>'})
+        self.assertTrue(author_map)
+        self.assertEqual(author_map(b'foo'), b'Foo <foo@bar>')
+        self.assertEqual(author_map(b'bar'), b'bar')
+        self.assertEqual(author_map(b'Foo <foo@bar>'), b'Foo <foo@bar>')
+        self.assertFalse(AuthorMap({}))
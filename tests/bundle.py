@@ -0,0 +1,55 @@
+from __future__ import absolute_import, unicode_literals
+from cinnabar.git import Git
+from cinnabar.hg.bundle import (
+    MANIFEST_CACHE_REF,
+    ManifestCache,
+    manifest_cache_key,
+)
+from fixtures import (
+    GitRepoTestCase,
+    git,
+)
+
+
+class TestManifestCache(GitRepoTestCase):
+    def blobs(self):
+        return dict(
+            (path, sha1) for _, _, sha1, path in (
+                l.split(None, 3) for l in git(
+                    'ls-tree', MANIFEST_CACHE_REF.decode('ascii')
+                ).splitlines()))
+
+    def test_manifest_cache_key(self):
+        key = manifest_cache_key(b'1' * 40, (b'2' * 40,))
+        self.assertNotEqual(key, manifest_cache_key(b'1' * 40, (b'3' * 40,)))
+        self.assertNotEqual(key, manifest_cache_key(b'1' * 40, (b'2' * 40,),
+                                                     b'100'))
+
+    def test_manifest_cache(self):
+        cache = ManifestCache()
+        self.assertIsNone(cache.get(b'ab' + b'0' * 38))
+        cache.write()
+        self.assertIsNone(Git.resolve_ref(MANIFEST_CACHE_REF))
+
+        cache.set(b'ab' + b'0' * 38, b'1' * 40, [b'foo', b'bar'])
+        cache.set(b'ab' + b'1' * 38, b'2' * 40, [])
+        cache.set(b'cd' + b'0' * 38, b'3' * 40, [b'baz'])
+        cache.write()
+        blobs = self.blobs()
+        self.assertEqual(sorted(blobs), [b'ab', b'cd'])
+
+        cache = ManifestCache()
+        self.assertEqual(cache.get(b'ab' + b'0' * 38),
+                         (b'1' * 40, [b'foo', b'bar']))
+        self.assertEqual(cache.get(b'ab' + b'1' * 38), (b'2' * 40, []))
+        self.assertEqual(cache.get(b'cd' + b'0' * 38), (b'3' * 40, [b'baz']))
+
+        # Only the blobs with new entries are written again.
+        cache.set(b'cd' + b'1' * 38, b'4' * 40, [b'qux'])
+        cache.write()
+        new_blobs = self.blobs()
+        self.assertEqual(new_blobs[b'ab'], blobs[b'ab'])
+        self.assertNotEqual(new_blobs[b'cd'], blobs[b'cd'])
+        cache = ManifestCache()
+        self.assertEqual(cache.get(b'cd' + b'1' * 38), (b'4' * 40, [b'qux']))
+        self.assertEqual(cache.get(b'cd' + b'0' * 38), (b'3' * 40, [b'baz']))
@@ -1,6 +1,7 @@
 from __future__ import absolute_import, unicode_literals
 import os
 import unittest
+from cinnabar.cmd.clone import guess_directory
 from cinnabar.cmd.util import Version as CmdVersion
 from cinnabar.git import (
     Git,
@@ -42,3 +43,15 @@ class TestVersion(unittest.TestCase):
             cwd=os.path.join(os.path.dirname(__file__), '..')))
         self.assertEqual(CmdVersion.helper_version()[1],
                          split_ls_tree(helper)[2].decode('ascii'))
+
+
+class TestClone(unittest.TestCase):
+    def test_guess_directory(self):
+        self.assertEqual(
+            guess_directory('https://hg.mozilla.org/mozilla-central'),
+            'mozilla-central')
+        self.assertEqual(
+            guess_directory('https://hg.mozilla.org/mozilla-central/'),
+            'mozilla-central')
+        self.assertEqual(guess_directory('/path/to/repo/.hg'), 'repo')
+        self.assertEqual(guess_directory('hg::ssh://host/foo'), 'foo')
@@ -0,0 +1,85 @@
+from __future__ import absolute_import, unicode_literals
+import os
+import socket
+import unittest
+from importlib import import_module
+from threading import Thread
+from cinnabar.git import NULL_NODE_ID
+from fixtures import GitRepoTestCase
+
+daemon = import_module('cinnabar.cmd.daemon')
+
+
+class FakeApi(object):
+    def __init__(self):
+        self.requests = []
+
+    def hg2git(self, node):
+        self.requests.append((b'hg2git', node))
+        return b'1' * 40 if node.startswith(b'2') else None
+
+    def git2hg(self, commit):
+        self.requests.append((b'git2hg', commit))
+        return b'2' * 40 if commit.startswith(b'1') else None
+
+
+class TestDaemon(GitRepoTestCase):
+    def setUp(self):
+        super(TestDaemon, self).setUp()
+        self.api = daemon.api
+        daemon.api = FakeApi()
+
+    def tearDown(self):
+        daemon.api = self.api
+        super(TestDaemon, self).tearDown()
+
+    def test_handle(self):
+        server = daemon.DaemonServer()
+        self.assertEqual(server.handle(b'hg2git 2222\n'), b'1' * 40)
+        self.assertEqual(server.handle(b'hg2git 3333\n'), NULL_NODE_ID)
+        self.assertEqual(server.handle(b'git2hg %s\n' % (b'1' * 40)),
+                         b'2' * 40)
+        self.assertEqual(server.handle(b'git2hg %s\n' % (b'3' * 40)),
+                         NULL_NODE_ID)
+        for line in (b'git2hg changeset', b'git2hg HEAD', b'git2hg',
+                     b'hg2git 1234 5678', b'git2hg %s' % (b'1' * 41)):
+            self.assertEqual(server.handle(line), b'error invalid request')
+        self.assertEqual(server.handle(b'foo 1234'),
+                         b'error unknown command')
+        self.assertIsNone(server.handle(b'quit'))
+        self.assertEqual(daemon.api.requests, [
+            (b'hg2git', b'2222'),
+            (b'hg2git', b'3333'),
+            (b'git2hg', b'1' * 40),
+            (b'git2hg', b'3' * 40),
+        ])
+
+    def test_daemon_socket(self):
+        path = daemon.daemon_socket()
+        self.assertEqual(path, os.path.join(self.tmpdir,
+                                            'cinnabar-daemon.sock'))
+        self.assertIs(daemon.daemon_socket(), path)
+
+    @unittest.skipIf(not hasattr(socket, 'AF_UNIX'),
+                     'Unix sockets are not supported')
+    def test_concurrent_connections(self):
+        path = os.path.join(self.tmpdir, 'daemon.sock')
+        sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+        sock.bind(path)
+        sock.listen(5)
+        thread = Thread(target=daemon.DaemonServer().serve_socket,
+                        args=(sock,))
+        thread.daemon = True
+        thread.start()
+
+        # An idle client doesn't prevent others from being served.
+        idle = daemon.DaemonClient.connect(path)
+        client = daemon.DaemonClient.connect(path)
+        client._sock.settimeout(10)
+        try:
+            self.assertEqual(client.request(b'hg2git', b'2222'), b'1' * 40)
+            self.assertIsNone(client.request(b'git2hg', b'changeset'))
+        finally:
+            client.close()
+            idle.close()
+            sock.close()
@@ -84,3 +84,16 @@ class TestDag(unittest.TestCase):
         self.assertEqual(set(self.dag.heads('bar')), set('EGH'))
         self.assertEqual(set(self.dag.roots()), set())
         self.assertEqual(set(self.dag.heads()), set())
+
+    def test_sample(self):
+        self.assertEqual(set(self.dag.sample('EGHIJ')), set('BDEFGHIJ'))
+        self.assertEqual(set(self.dag.sample('BC', children=True)),
+                         set('BCDEF'))
+
+        self.dag.tag_nodes_and_parents('D', 'foo')
+        self.assertEqual(set(self.dag.sample('GH')), set('GH'))
+
+        dag = gitdag((str(n), (str(n - 1),) if n else ())
+                     for n in range(20))
+        self.assertEqual(list(dag.sample(['19'])),
+                         ['19', '18', '16', '12', '4'])
@@ -0,0 +1,138 @@
+from __future__ import absolute_import, unicode_literals
+import io
+import sys
+from importlib import import_module
+from fixtures import (
+    GitRepoTestCase,
+    git,
+)
+
+fetch = import_module('cinnabar.cmd.fetch')
+
+
+class FakeStore(object):
+    _broken = False
+
+    def __init__(self):
+        self.closed = False
+
+    def changeset_ref(self, node):
+        return node
+
+    def close(self):
+        self.closed = True
+
+
+class FakeBranchMap(object):
+    def __init__(self, store, branchmap, heads):
+        self._heads = heads
+
+    def heads(self):
+        return self._heads
+
+    def unknown_heads(self):
+        return set(self._heads)
+
+    def names(self):
+        return ()
+
+
+class FakeRepo(object):
+    def __init__(self, remote, namespaces):
+        self.remote = remote
+        self.namespaces = namespaces
+        self.listed = []
+
+    def url(self):
+        return self.remote.url
+
+    def listkeys(self, namespace):
+        self.listed.append(namespace)
+        if namespace == b'namespaces':
+            return dict((n, b'') for n in self.namespaces)
+        return {}
+
+
+class FakeProcess(object):
+    def __init__(self, *args, **kwargs):
+        self.args = args
+
+    def wait(self):
+        return 0
+
+
+class TestUnionFetch(GitRepoTestCase):
+    def setUp(self):
+        super(TestUnionFetch, self).setUp()
+        for name, url in (('native', 'hg://example.com:https/native'),
+                          ('prefixed', 'hg::https://example.com/prefixed'),
+                          ('plain', 'https://example.com/plain.git')):
+            git('remote', 'add', name, url)
+
+        self.repos = {}
+        self.bundles = []
+        self.fail = None
+        self.store = None
+
+        def get_repo(remote):
+            repo = FakeRepo(remote, (b'phases', b'obsolete')
+                            if remote.name == b'native' else (b'phases',))
+            self.repos[remote.name] = repo
+            return repo
+
+        def getbundle(repo, store, heads, names):
+            if repo.remote.name == self.fail:
+                raise Exception('failed')
+            self.bundles.append(repo.remote.name)
+
+        def store():
+            self.store = FakeStore()
+            return self.store
+
+        self.patches = {
+            'BranchMap': FakeBranchMap,
+            'GitHgStore': store,
+            'GitProcess': FakeProcess,
+            'draft_roots': lambda keys: set(),
+            'get_repo': get_repo,
+            'getbundle': getbundle,
+            'remote_heads': lambda repo: ({}, [b'\1' * 20], {}),
+            'store_draft_roots': lambda name, roots: None,
+            'store_lfs_nodes': lambda nodes: None,
+            'store_markers': lambda markers: None,
+            'store_remote_heads': lambda name, heads: None,
+        }
+        for name, value in self.patches.items():
+            self.patches[name] = getattr(fetch, name)
+            setattr(fetch, name, value)
+        self.stderr = sys.stderr
+        sys.stderr = io.StringIO()
+
+    def tearDown(self):
+        sys.stderr = self.stderr
+        for name, value in self.patches.items():
+            setattr(fetch, name, value)
+        super(TestUnionFetch, self).tearDown()
+
+    def test_mercurial_remote(self):
+        remote = fetch.mercurial_remote(b'native')
+        self.assertEqual(remote.git_url, b'hg://example.com:https/native')
+        remote = fetch.mercurial_remote(b'prefixed')
+        self.assertEqual(remote.git_url, b'hg::https://example.com/prefixed')
+        self.assertIsNone(fetch.mercurial_remote(b'plain'))
+        self.assertIsNone(fetch.mercurial_remote(b'unknown'))
+
+    def test_union_fetch(self):
+        self.assertEqual(fetch.union_fetch(
+            [b'native', b'plain', b'prefixed']), 0)
+        self.assertEqual(self.bundles, [b'native', b'prefixed'])
+        self.assertTrue(self.store.closed)
+        self.assertIn(b'obsolete', self.repos[b'native'].listed)
+        self.assertNotIn(b'obsolete', self.repos[b'prefixed'].listed)
+
+    def test_union_fetch_failure(self):
+        self.fail = b'prefixed'
+        with self.assertRaises(Exception):
+            fetch.union_fetch([b'native', b'prefixed'])
+        self.assertEqual(self.bundles, [b'native'])
+        self.assertTrue(self.store.closed)
@@ -0,0 +1,49 @@
+from __future__ import absolute_import, unicode_literals
+import unittest
+from cinnabar.exceptions import Abort
+from cinnabar.filters import (
+    ContentFilter,
+    parse_size,
+)
+
+
+class TestContentFilter(unittest.TestCase):
+    def test_parse_size(self):
+        self.assertEqual(parse_size(b'42'), 42)
+        self.assertEqual(parse_size(b'2k'), 2048)
+        self.assertEqual(parse_size(b'3M'), 3 * 1024 * 1024)
+        self.assertEqual(parse_size(b'1g'), 1024 * 1024 * 1024)
+        for value in (b'', b'k', b'1t', b'-1'):
+            with self.assertRaises(ValueError):
+                parse_size(value)
+
+    def test_spec(self):
+        self.assertFalse(ContentFilter())
+        self.assertFalse(ContentFilter(b' , '))
+        f = ContentFilter(b'crlf, max-size:10m,exclude:*.iso')
+        self.assertTrue(f)
+        self.assertEqual(f.spec, b'exclude:*.iso,max-size:10485760,crlf')
+        self.assertEqual(ContentFilter(f.spec).spec, f.spec)
+        for spec in (b'foo', b'exclude:', b'max-size:big', b'crlf:true'):
+            with self.assertRaises(Abort):
+                ContentFilter(spec)
+
+    def test_excluded(self):
+        f = ContentFilter(b'exclude:*.iso,exclude:build/*.o')
+        self.assertTrue(f.excluded(b'foo.iso'))
+        self.assertTrue(f.excluded(b'dir/foo.iso'))
+        self.assertTrue(f.excluded(b'build/foo.o'))
+        self.assertFalse(f.excluded(b'src/build/foo.o'))
+        self.assertFalse(f.excluded(b'foo.o'))
+        self.assertFalse(f.excluded(b'foo.iso.txt'))
+
+    def test_filter_data(self):
+        f = ContentFilter(b'crlf,max-size:16')
+        self.assertEqual(f.filter_data(b'a\r\nb\r\n', b'sha1'), b'a\nb\n')
+        self.assertEqual(f.filter_data(b'a\0\r\n', b'sha1'), b'a\0\r\n')
+        self.assertEqual(f.filter_data(b'a' * 16, b'sha1'), b'a' * 16)
+        placeholder = f.filter_data(b'a' * 17, b'sha1')
+        self.assertIn(b'Original size: 17 bytes', placeholder)
+        self.assertIn(b'Original git blob: sha1', placeholder)
+        f = ContentFilter(b'exclude:*.iso')
+        self.assertEqual(f.filter_data(b'a\r\n', b'sha1'), b'a\r\n')
@@ -0,0 +1,57 @@
+from __future__ import absolute_import, unicode_literals
+import os
+import shutil
+import subprocess
+import tempfile
+import unittest
+from cinnabar.git import (
+    EMPTY_TREE,
+    Git,
+)
+
+
+def git(*args, **kwargs):
+    # check_output doesn't take an input argument on python 2.
+    proc = subprocess.Popen(('git',) + args, stdin=subprocess.PIPE,
+                            stdout=subprocess.PIPE,
+                            stderr=kwargs.get('stderr'),
+                            cwd=kwargs.get('cwd'))
+    output = proc.communicate(kwargs.get('input', b''))[0]
+    if proc.returncode:
+        raise subprocess.CalledProcessError(proc.returncode, ('git',) + args)
+    return output.strip()
+
+
+def commit_tree(message, *parents, **kwargs):
+    args = ['commit-tree', kwargs.get('tree', EMPTY_TREE), '-m', message]
+    for p in parents:
+        args += ['-p', p.decode('ascii')]
+    return git(*args)
+
+
+class FakeGitCommit(object):
+    '''GitCommit equivalent that doesn't need the helper.'''
+    def __init__(self, sha1):
+        data = git('cat-file', 'commit', sha1.decode('ascii'))
+        header, _, self.body = data.partition(b'\n\n')
+        self.parents = tuple(l.split()[1] for l in header.splitlines()
+                             if l.startswith(b'parent '))
+
+
+class GitRepoTestCase(unittest.TestCase):
+    '''Runs each test against a fresh bare repository set as GIT_DIR.'''
+    def setUp(self):
+        self.environ = dict(os.environ)
+        self.tmpdir = tempfile.mkdtemp()
+        git('init', '-q', '--bare', self.tmpdir)
+        os.environ['GIT_DIR'] = self.tmpdir
+        for var in ('AUTHOR', 'COMMITTER'):
+            os.environ['GIT_%s_NAME' % var] = 'Foo Bar'
+            os.environ['GIT_%s_EMAIL' % var] = 'foo@bar'
+        Git._config = None
+
+    def tearDown(self):
+        os.environ.clear()
+        os.environ.update(self.environ)
+        Git._config = None
+        shutil.rmtree(self.tmpdir)
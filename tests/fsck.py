@@ -0,0 +1,148 @@
+from __future__ import absolute_import, unicode_literals
+import io
+import sys
+from importlib import import_module
+from cinnabar.git import (
+    EMPTY_TREE,
+    NULL_NODE_ID,
+)
+from cinnabar.hg.changegroup import RawRevChunk02
+from cinnabar.hg.objects import File
+from fixtures import (
+    GitRepoTestCase,
+    git,
+)
+
+fsck = import_module('cinnabar.cmd.fsck')
+
+
+def make_file(content, parent=NULL_NODE_ID):
+    f = File()
+    f.parent1 = parent
+    f.content = content
+    f.node = f.sha1
+    return f
+
+
+class FakeChangeset(object):
+    def __init__(self, parents):
+        self.parents = parents
+
+
+class FakeStore(object):
+    def __init__(self, files):
+        self._replace = {}
+        self.files = dict((f.node, f) for f in files)
+
+    def changeset_ref(self, node):
+        return b'c' + node[1:]
+
+    def hg_changeset(self, commit):
+        return b'1' + commit[1:]
+
+    def changeset(self, node, include_parents=False):
+        return FakeChangeset((b'2' * 40, NULL_NODE_ID))
+
+    def git_file_ref(self, node):
+        return node if node in self.files else None
+
+    def file(self, node):
+        return self.files[node]
+
+
+class FakeHelper(object):
+    @staticmethod
+    def rev_list(*args):
+        for arg in args:
+            if not arg.startswith(b'-'):
+                yield arg, None, ()
+
+
+class FakeRepo(object):
+    def __init__(self, chunks):
+        self.chunks = chunks
+        self.bundles = []
+
+    def getbundle(self, name, heads, common):
+        self.bundles.append((heads, common))
+        return iter(([], [], self.chunks))
+
+
+class TestFsckFilesRemote(GitRepoTestCase):
+    def setUp(self):
+        super(TestFsckFilesRemote, self).setUp()
+        self.out = io.StringIO()
+        self.narrow = ([], [])
+        self.repo = FakeRepo([])
+        self.patches = {
+            'GitHgHelper': FakeHelper,
+            'get_repo': lambda remote: self.repo,
+            'narrow_spec': lambda: self.narrow,
+            'stored_lfs_nodes': set,
+            'unbundler': lambda bundle: bundle,
+        }
+        for name, value in self.patches.items():
+            self.patches[name] = getattr(fsck, name)
+            setattr(fsck, name, value)
+        self.stderr = sys.stderr
+        sys.stderr = io.StringIO()
+
+    def tearDown(self):
+        sys.stderr = self.stderr
+        for name, value in self.patches.items():
+            setattr(fsck, name, value)
+        super(TestFsckFilesRemote, self).tearDown()
+
+    def check(self, store, commits=(b'1' * 40,)):
+        status = fsck.FsckStatus(self.out)
+        result = fsck.fsck_files_remote(
+            store, 'hg::https://example.com/', commits, 100, status)
+        return result, status
+
+    def test_no_metadata(self):
+        result, status = self.check(FakeStore([]), ())
+        self.assertFalse(result)
+        self.assertIn('any git-cinnabar metadata', self.out.getvalue())
+        self.assertEqual(self.repo.bundles, [])
+
+    def test_files(self):
+        git('update-ref', 'refs/cinnabar/metadata',
+            git('commit-tree', EMPTY_TREE, '-m', 'metadata'))
+
+        foo1 = make_file(b'foo')
+        foo2 = make_file(b'foo\nbar', foo1.node)
+        unknown = make_file(b'qux')
+        qux = make_file(b'qux\nqux', unknown.node)
+        bar = make_file(b'bar')
+        wrong_bar = make_file(b'baz')
+        wrong_bar.node = bar.node
+        self.repo.chunks = [
+            (b'foo', foo2.to_chunk(RawRevChunk02, foo1)),
+            (b'dir/qux', qux.to_chunk(RawRevChunk02, unknown)),
+            (b'bar', bar.to_chunk(RawRevChunk02)),
+        ]
+        store = FakeStore([foo1, foo2, qux, wrong_bar])
+
+        result, status = self.check(store)
+        self.assertTrue(result)
+        self.assertTrue(status('broken'))
+        self.assertEqual(self.repo.bundles, [([b'\x11' * 20], [b'\x22' * 20])])
+        self.assertEqual(self.out.getvalue().splitlines(), [
+            'Missing file %s, needed to check file %s'
+            % (unknown.node.decode('ascii'), qux.node.decode('ascii')),
+            'Contents mismatch for file %s (bar) in changeset %s'
+            % (bar.node.decode('ascii'), '1' * 40),
+            'Checked 2 files from 1 changesets.',
+        ])
+
+        # Excluded files are skipped before their deltas are resolved.
+        self.out.truncate(0)
+        self.out.seek(0)
+        self.narrow = ([b'.'], [b'dir'])
+        result, status = self.check(store)
+        self.assertTrue(result)
+        self.assertEqual(self.out.getvalue().splitlines(), [
+            'Contents mismatch for file %s (bar) in changeset %s'
+            % (bar.node.decode('ascii'), '1' * 40),
+            'Checked 2 files from 1 changesets.',
+        ])
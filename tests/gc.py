@@ -0,0 +1,95 @@
+from __future__ import absolute_import, unicode_literals
+import io
+import subprocess
+import sys
+from importlib import import_module
+from cinnabar.cmd.util import CLI
+from cinnabar.git import Git
+from fixtures import (
+    FakeGitCommit,
+    GitRepoTestCase,
+    commit_tree,
+    git,
+)
+
+gc = import_module('cinnabar.cmd.gc')
+metadata_cmd = import_module('cinnabar.cmd.metadata')
+rollback = import_module('cinnabar.cmd.rollback')
+
+
+class FakeHelper(object):
+    @staticmethod
+    def cat_file(typ, sha1):
+        return subprocess.check_output(
+            ('git', 'cat-file', typ.decode('ascii'), sha1.decode('ascii')))
+
+    @staticmethod
+    def close(rollback=True):
+        pass
+
+
+def run(*args):
+    args = CLI.parser.parse_args(('gc',) + args)
+    return args.callback(args)
+
+
+class TestGc(GitRepoTestCase):
+    def setUp(self):
+        super(TestGc, self).setUp()
+        # Keep the history of the metadata ref, as with
+        # core.logAllRefUpdates=always.
+        git('config', 'core.logAllRefUpdates', 'always')
+
+        self.generations = []
+        previous = ()
+        for n in range(3):
+            others = [commit_tree('%s %d' % (m, n)) for m in (
+                'changesets', 'manifests', 'hg2git', 'notes', 'files-meta')]
+            metadata = commit_tree('files-meta unified-manifests-v2',
+                                   *(others + list(previous)))
+            self.generations.insert(0, metadata)
+            previous = (metadata,)
+            git('update-ref', 'refs/cinnabar/metadata', metadata)
+
+        self.patches = {}
+        for module, name, value in (
+                (rollback, 'GitCommit', FakeGitCommit),
+                (metadata_cmd, 'GitHgHelper', FakeHelper),
+                (gc, 'GitHgHelper', FakeHelper)):
+            self.patches[(module, name)] = getattr(module, name)
+            setattr(module, name, value)
+        self.stdout = sys.stdout
+        sys.stdout = io.StringIO()
+
+    def tearDown(self):
+        sys.stdout = self.stdout
+        for (module, name), value in self.patches.items():
+            setattr(module, name, value)
+        super(TestGc, self).tearDown()
+
+    def test_gc(self):
+        # The last checked metadata is kept.
+        git('update-ref', 'refs/cinnabar/checked',
+            self.generations[1].decode('ascii'))
+        self.assertEqual(run('--keep', '1'), 0)
+        self.assertIn('Pruned 1 metadata generation.', sys.stdout.getvalue())
+
+        # The oldest kept generation doesn't refer to the pruned one anymore,
+        # and the refs point to the rewritten generations.
+        current = Git.resolve_ref('refs/cinnabar/metadata')
+        checked = Git.resolve_ref('refs/cinnabar/checked')
+        self.assertNotIn(current, self.generations)
+        self.assertNotIn(checked, self.generations)
+        self.assertEqual(FakeGitCommit(current).parents,
+                         FakeGitCommit(self.generations[0]).parents[:5] +
+                         (checked,))
+        self.assertEqual(FakeGitCommit(checked).parents,
+                         FakeGitCommit(self.generations[1]).parents[:5])
+
+        # The reflog doesn't keep the pruned generations around.
+        reflog = git('reflog', 'show', '--format=%H',
+                     'refs/cinnabar/metadata').split()
+        self.assertFalse(set(reflog) & set(self.generations))
+
+        self.assertEqual(run('--keep', '1'), 0)
+        self.assertIn('Nothing to prune.', sys.stdout.getvalue())
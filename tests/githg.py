@@ -1,4 +1,8 @@
 from __future__ import absolute_import, unicode_literals
+import os
+import shutil
+import subprocess
+import tempfile
 import unittest
 from cinnabar.git import NULL_NODE_ID
 from cinnabar.githg import (
@@ -6,8 +10,12 @@ from cinnabar.githg import (
     ChangesetPatcher,
     GitCommit,
     GitHgStore,
+    same_author,
+    same_date,
+    write_commit_graph,
 )
 from cinnabar.helper import GitHgHelper
+from cinnabar.hg.objects import Authorship
 
 
 class FakeGitCommit(GitCommit):
@@ -45,6 +53,45 @@ class TestChangeset(unittest.TestCase):
         self.assertEqual(changeset.files, [])
 
 
+class TestGraftMatching(unittest.TestCase):
+    def test_same_date(self):
+        changeset = Changeset()
+        changeset.author = b'Foo Bar <foo@bar>'
+        changeset.timestamp = b'1482880019'
+        changeset.utcoffset = b'-7200'
+
+        author = Authorship.from_git_str(b'Foo Bar <foo@bar> 1482880019 +0200')
+        self.assertTrue(same_date(author, changeset))
+        author = Authorship.from_git_str(b'Foo Bar <foo@bar> 1482880019 -0200')
+        self.assertTrue(same_date(author, changeset))
+        # Timezone mishandled during conversion.
+        author = Authorship.from_git_str(b'Foo Bar <foo@bar> 1482872819 +0000')
+        self.assertTrue(same_date(author, changeset))
+        author = Authorship.from_git_str(b'Foo Bar <foo@bar> 1482880020 +0200')
+        self.assertFalse(same_date(author, changeset))
+
+    def test_same_author(self):
+        changeset = Changeset()
+        changeset.author = b'Foo Bar <Foo@Bar>'
+        changeset.timestamp = b'0'
+        changeset.utcoffset = b'0'
+
+        author = Authorship.from_git_str(b'Foo Bar <foo@bar> 0 +0000')
+        self.assertTrue(same_author(author, changeset))
+        author = Authorship.from_git_str(b'Foo <foo@bar> 0 +0000')
+        self.assertTrue(same_author(author, changeset))
+        author = Authorship.from_git_str(b'Foo Bar <bar@foo> 0 +0000')
+        self.assertFalse(same_author(author, changeset))
+
+        changeset.author = b'Foo Bar'
+        for email in (b'none@none', b'devnull@localhost', b''):
+            author = Authorship.from_git_str(
+                b'Foo Bar <%s> 0 +0000' % email)
+            self.assertTrue(same_author(author, changeset))
+        author = Authorship.from_git_str(b'Foo <none@none> 0 +0000')
+        self.assertFalse(same_author(author, changeset))
+
+
 class TestChangesetPatcher(unittest.TestCase):
     def compare(self, changeset1, changeset2):
         for field in ('node', 'author', 'timestamp', 'utcoffset', 'body',
@@ -230,6 +277,48 @@ class TestChangesetPatcher(unittest.TestCase):
         )
         self.compare(patcher.apply(changeset), changeset2)
 
+    def test_changeset_extras(self):
+        changeset = Changeset()
+        changeset.author = b'Foo Bar <foo@bar>'
+        changeset.timestamp = b'1482880019'
+        changeset.utcoffset = b'-7200'
+        changeset.body = b'Some commit'
+
+        for extra in (
+            b'amend_source:2a50ebd0f4b26428413d4309b950939616c5bfca',
+            b'branch:foo\0'
+            b'rebase_source:14d43b6cb9272f6dad335ebd7fb8b5e3d77d910f',
+            b'convert_revision:svn:1234-5678/trunk@42',
+            b'transplant_source:\\x9f\\xa4\\x9f\\xbc',
+            b'close:1\0'
+            b'source:9fa49f5cfbaae8b2f1b6e4d3a483a2b2c1a3fed1',
+            # Unsorted
+            b'rebase_source:14d43b6cb9272f6dad335ebd7fb8b5e3d77d910f\0'
+            b'branch:foo',
+            # Duplicate keys
+            b'branch:foo\0branch:bar',
+            # Missing value
+            b'branch:foo\0convert_revision',
+        ):
+            changeset2 = Changeset()
+            changeset2.raw_data = changeset.raw_data.replace(
+                b'-7200', b'-7200 ' + extra, 1)
+            changeset2.node = changeset2.sha1
+            self.assertEqual(changeset2.extra.to_str(), extra)
+
+            patcher = ChangesetPatcher.from_diff(changeset, changeset2)
+            self.assertNotIn(b'\npatch ', patcher)
+            self.assertEqual(patcher.has_extra_raw,
+                             extra != changeset2.extra.normalized())
+            changeset3 = patcher.apply(changeset)
+            self.compare(changeset3, changeset2)
+            self.assertEqual(changeset3.raw_data, changeset2.raw_data)
+            self.assertEqual(changeset3.sha1, changeset2.node)
+
+        changeset2.extra[b'branch'] = b'baz'
+        self.assertEqual(changeset2.extra.to_str(),
+                         b'branch:baz\0convert_revision:')
+
     def test_changeset_conflict(self):
         changeset = Changeset()
         changeset.author = b'Foo Bar <foo@bar>'
@@ -297,3 +386,37 @@ class TestMergeBranches(unittest.TestCase):
                 b'server/dir_a/dir_b/repo',
                 b'metadata',
         ])
+
+
+class TestCommitGraph(unittest.TestCase):
+    def setUp(self):
+        self.tmpdir = tempfile.mkdtemp()
+        self.environ = dict(os.environ)
+        subprocess.check_call(['git', 'init', '-q', self.tmpdir])
+        os.environ['GIT_DIR'] = os.path.join(self.tmpdir, '.git')
+        for var in ('AUTHOR', 'COMMITTER'):
+            os.environ['GIT_%s_NAME' % var] = 'Foo Bar'
+            os.environ['GIT_%s_EMAIL' % var] = 'foo@bar'
+
+    def tearDown(self):
+        os.environ.clear()
+        os.environ.update(self.environ)
+        shutil.rmtree(self.tmpdir)
+
+    def test_write_commit_graph(self):
+        graphs = os.path.join(self.tmpdir, '.git', 'objects', 'info',
+                              'commit-graphs')
+        for n in range(8):
+            subprocess.check_call(['git', 'commit', '-q', '--allow-empty',
+                                   '-m', 'commit %d' % n])
+            self.assertEqual(write_commit_graph(expire_time='now'), 0)
+            with open(os.path.join(graphs, 'commit-graph-chain')) as fh:
+                chain = fh.read().split()
+            files = sorted(f for f in os.listdir(graphs)
+                           if f.endswith('.graph'))
+            # Merged layers are removed.
+            self.assertEqual(files, sorted('graph-%s.graph' % c
+                                           for c in chain))
+            # Each layer is less than half the size of the one below, so
+            # there are at most log2(n) layers.
+            self.assertLessEqual(len(chain), (n + 1).bit_length())
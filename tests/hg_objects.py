@@ -5,6 +5,7 @@ from cinnabar.git import NULL_NODE_ID
 from cinnabar.hg.changegroup import (
     RawRevChunk01,
     RawRevChunk02,
+    rev_chunk_03,
 )
 from cinnabar.hg.objects import (
     Authorship,
@@ -481,3 +482,18 @@ class TestManifest(unittest.TestCase):
             m2.add(i)
         self.assertEqual(m.raw_data, m2.raw_data)
         self.assertEqual(m.items, m2.items)
+
+
+class TestRevChunk03(unittest.TestCase):
+    def test_rev_chunk_03(self):
+        header = b'a' * 20 + b'b' * 20 + b'c' * 20 + b'd' * 20 + b'e' * 20
+        data = b'\0\0\0\0\0\0\0\0\0\0\0\x03foo'
+        chunk = rev_chunk_03(bytearray(header + b'\0\0' + data))
+        self.assertIsInstance(chunk, RawRevChunk02)
+        self.assertEqual(chunk.node, b'61' * 20)
+        self.assertEqual(chunk.delta_node, b'64' * 20)
+        self.assertEqual(chunk.changeset, b'65' * 20)
+        self.assertEqual(chunk.data, data)
+
+        with self.assertRaises(Exception):
+            rev_chunk_03(bytearray(header + b'\x80\0' + data))
@@ -0,0 +1,46 @@
+from __future__ import absolute_import, unicode_literals
+import unittest
+from cinnabar.lfs import (
+    is_git_lfs_pointer,
+    parse_pointer,
+)
+
+OID = b'sha256:' + b'4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca2' \
+    b'4d17e2393'
+
+
+class TestLfsPointers(unittest.TestCase):
+    def test_parse_pointer(self):
+        data = (b'version https://git-lfs.github.com/spec/v1\n'
+                b'oid %s\n'
+                b'size 12345\n' % OID)
+        pointer = parse_pointer(data)
+        self.assertEqual(pointer, {
+            b'version': b'https://git-lfs.github.com/spec/v1',
+            b'oid': OID,
+            b'size': b'12345',
+        })
+        self.assertTrue(is_git_lfs_pointer(pointer))
+
+        pointer = parse_pointer(data + b'x-is-binary 0\n')
+        self.assertEqual(pointer[b'x-is-binary'], b'0')
+        self.assertFalse(is_git_lfs_pointer(pointer))
+
+    def test_not_pointer(self):
+        self.assertIsNone(parse_pointer(b''))
+        self.assertIsNone(parse_pointer(b'foo\n'))
+        self.assertIsNone(parse_pointer(
+            b'version https://git-lfs.github.com/spec/v1\n'
+            b'oid %s\n'
+            b'size 12345' % OID))
+        self.assertIsNone(parse_pointer(
+            b'version https://git-lfs.github.com/spec/v1\n'
+            b'oid %s\n' % OID))
+        self.assertIsNone(parse_pointer(
+            b'version https://git-lfs.github.com/spec/v1\n'
+            b'oid sha1:0123456789abcdef0123456789abcdef01234567\n'
+            b'size 12345\n'))
+        self.assertIsNone(parse_pointer(
+            b'version https://git-lfs.github.com/spec/v1\n'
+            b'oid %s\n'
+            b'size large\n' % OID))
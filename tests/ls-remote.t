@@ -63,6 +63,26 @@
   0000000000000000000000000000000000000000	refs/heads/branches/default/tip
   0000000000000000000000000000000000000000	refs/heads/branches/foo/tip
 
+  $ git -c cinnabar.branch-map=default:main ls-remote hg::$REPO
+  0000000000000000000000000000000000000000	HEAD
+  0000000000000000000000000000000000000000	refs/heads/branches/foo/tip
+  0000000000000000000000000000000000000000	refs/heads/branches/main/636e60525868096cbdc961870493510558f41d2f
+  0000000000000000000000000000000000000000	refs/heads/branches/main/tip
+
+  $ git -c cinnabar.branch-map=default: ls-remote hg::$REPO
+  0000000000000000000000000000000000000000	refs/heads/branches/foo/tip
+
+  $ git -c cinnabar.collapse-branches=true ls-remote hg::$REPO
+  0000000000000000000000000000000000000000	HEAD
+  0000000000000000000000000000000000000000	refs/heads/branches/default/636e60525868096cbdc961870493510558f41d2f
+  0000000000000000000000000000000000000000	refs/heads/branches/default/tip
+  0000000000000000000000000000000000000000	refs/heads/foo
+
+  $ git -c cinnabar.refs=tips -c cinnabar.branch-map=default:main,foo:bar ls-remote hg::$REPO
+  0000000000000000000000000000000000000000	HEAD
+  0000000000000000000000000000000000000000	refs/heads/bar
+  0000000000000000000000000000000000000000	refs/heads/main
+
   $ git clone -q hg::$REPO repo-git
   It is recommended that you set "remote.origin.prune" or "fetch.prune" to "true".
     git config remote.origin.prune true
@@ -0,0 +1,311 @@
+#!/bin/sh
+''':'
+for py in python3 python python2.7 python2; do
+  "$py" -c "import sys" >/dev/null 2>&1 && exec "$py" "$0" "$@"
+done
+exit 1
+'''
+
+from __future__ import absolute_import, print_function, unicode_literals
+import sys
+import zlib
+try:
+    from BaseHTTPServer import BaseHTTPRequestHandler, HTTPServer
+    from urllib import quote
+    from urlparse import parse_qsl, urlparse
+except ImportError:
+    from http.server import BaseHTTPRequestHandler, HTTPServer
+    from urllib.parse import parse_qsl, quote, urlparse
+
+USAGE = '''Mock mercurial server for the tests of the native wire protocol
+implementation.
+
+Usage:
+  mock-hg-server.py add <script> <type> [<data>|-]
+    Appends a record to the given script. The data is read from stdin when
+    it is '-'. For "capabilities" records, the data is a space separated
+    list of capabilities.
+  mock-hg-server.py serve <script> [<arg>...]
+    Serves the script over the stdio protocol. The extra arguments are
+    ignored, so that this can be used as GIT_SSH_COMMAND.
+  mock-hg-server.py serve-http <script>
+    Serves the script over HTTP, printing the url to use on stdout.
+
+Scripts use the same format as the wire logs recorded with
+GIT_CINNABAR_WIRE_LOG, so those can be served too. Each command received
+is answered with the response of the first unused record for the same
+command. Command records containing only a command name match that command
+whatever its arguments. Commands without a record make the server fail.
+
+The received commands are appended to <script>.received, one per line.
+
+Scripts may contain "banner" records right after the capabilities, the
+data of which is sent before anything else over the stdio protocol, and
+"raw" records instead of "response" records, the data of which is sent as
+is, without the response framing, after which the session ends.
+
+The response of "unbundle" commands is the result of the push, or, when it
+starts with "error\\n", the error the server rejects the push with before
+receiving the bundle.
+'''
+
+NULL_PAIR = b'-'.join([b'0' * 40] * 2)
+
+# The arguments of the commands, in the order the stdio protocol sends
+# them. '*' stands for a variable number of extra arguments.
+COMMANDS = {
+    b'batch': (b'cmds', b'*'),
+    b'between': (b'pairs',),
+    b'branchmap': (),
+    b'capabilities': (),
+    b'cinnabarclone': (),
+    b'clonebundles': (),
+    b'getbundle': (b'*',),
+    b'heads': (),
+    b'known': (b'nodes', b'*'),
+    b'listkeys': (b'namespace',),
+    b'lookup': (b'key',),
+    b'pushkey': (b'namespace', b'key', b'old', b'new'),
+    b'unbundle': (b'heads',),
+}
+
+
+def stdio(fh):
+    return getattr(fh, 'buffer', fh)
+
+
+class MockError(Exception):
+    pass
+
+
+def read_records(path):
+    records = []
+    with open(path, 'rb') as fh:
+        while True:
+            header = fh.readline()
+            if not header:
+                return records
+            typ, length = header.rstrip(b'\n').split(b' ')
+            data = fh.read(int(length))
+            if len(data) != int(length) or fh.read(1) != b'\n':
+                raise MockError('truncated script')
+            records.append((typ, data))
+
+
+def write_record(fh, typ, data):
+    fh.write(b'%s %d\n%s\n' % (typ, len(data), data))
+
+
+class Script(object):
+    def __init__(self, path):
+        self._path = path
+        records = read_records(path)
+        if not records or records[0][0] != b'capabilities':
+            raise MockError('script must start with capabilities')
+        self.capabilities = [c for c in records[0][1].split(b'\0') if c]
+        self.banner = b''
+        records = records[1:]
+        while records and records[0][0] == b'banner':
+            self.banner += records.pop(0)[1]
+        # List of (command, response) pairs. The response is a list of
+        # records.
+        self._commands = []
+        for typ, data in records:
+            if typ == b'command':
+                self._commands.append((data, []))
+            elif self._commands:
+                self._commands[-1][1].append((typ, data))
+            else:
+                raise MockError('unexpected %s record' % typ.decode())
+
+    def caps_response(self):
+        def encode(cap):
+            name, sep, value = cap.partition(b'=')
+            if name == b'bundle2':
+                value = quote(value).encode('ascii')
+            return name + sep + value
+        return b' '.join(encode(c) for c in self.capabilities)
+
+    def command(self, name, args):
+        '''Returns the response records to the given command.'''
+        command = b'\n'.join([name] + [b'%s=%s' % a for a in args])
+        with open(self._path + '.received', 'ab') as fh:
+            fh.write(b' '.join([name] + [b'%s=%s' % a for a in args]))
+            fh.write(b'\n')
+        for n, (recorded, response) in enumerate(self._commands):
+            if recorded in (command, name):
+                del self._commands[n]
+                return response
+        raise MockError('unexpected command: %s'
+                        % command.decode('utf-8', 'replace'))
+
+
+def response_data(records, typ):
+    return b''.join(data for t, data in records if t == typ)
+
+
+def serve_stdio(script):
+    stdin = stdio(sys.stdin)
+    stdout = stdio(sys.stdout)
+
+    def respond(data):
+        stdout.write(b'%d\n%s' % (len(data), data))
+        stdout.flush()
+
+    def read_arg():
+        name, length = stdin.readline().rstrip(b'\n').split(b' ')
+        if name == b'*':
+            return name, int(length)
+        return name, stdin.read(int(length))
+
+    def read_args(names):
+        args = []
+        for name in names:
+            arg_name, value = read_arg()
+            if arg_name != name:
+                raise MockError('unexpected argument: %s' % arg_name.decode())
+            if name == b'*':
+                args.extend(read_arg() for _ in range(value))
+            else:
+                args.append((name, value))
+        return args
+
+    stdout.write(script.banner)
+    stdout.flush()
+
+    # The first two commands are always "capabilities" and "between", to
+    # detect old servers. An empty list of capabilities emulates a server
+    # not knowing the "capabilities" command.
+    handshake = [b'capabilities', b'between']
+    while True:
+        name = stdin.readline().rstrip(b'\n')
+        if not name:
+            return
+        if name not in COMMANDS:
+            raise MockError('unknown command: %s' % name.decode())
+        args = read_args(COMMANDS[name])
+        if handshake:
+            if name != handshake.pop(0):
+                raise MockError('unexpected command: %s' % name.decode())
+            if name == b'capabilities':
+                if script.capabilities:
+                    respond(script.caps_response())
+            elif args != [(b'pairs', NULL_PAIR)]:
+                raise MockError('unexpected between arguments')
+            else:
+                respond(b'\n')
+            continue
+        records = script.command(name, args)
+        if name == b'getbundle':
+            stdout.write(response_data(records, b'data'))
+            stdout.flush()
+        elif name == b'unbundle':
+            result = response_data(records, b'response')
+            if result.startswith(b'error\n'):
+                respond(result[6:])
+                continue
+            respond(b'')
+            while True:
+                length = int(stdin.readline())
+                if not length:
+                    break
+                stdin.read(length)
+            respond(b'')
+            respond(result)
+        elif any(t == b'raw' for t, data in records):
+            # The client can't be expected to make sense of what follows,
+            # so end the session.
+            stdout.write(response_data(records, b'raw'))
+            stdout.flush()
+            return
+        else:
+            respond(response_data(records, b'response'))
+
+
+class HTTPHandler(BaseHTTPRequestHandler):
+    def log_message(self, *args):
+        pass
+
+    def respond(self, data, content_type='application/mercurial-0.1'):
+        self.send_response(200)
+        self.send_header('Content-Type', content_type)
+        self.send_header('Content-Length', str(len(data)))
+        self.end_headers()
+        self.wfile.write(data)
+
+    def handle_command(self):
+        script = self.server.script
+        query = parse_qsl(urlparse(self.path).query, keep_blank_values=True)
+        # Arguments may be given in X-HgArg-<n> headers, which are the
+        # continuation of each other.
+        headers = []
+        n = 1
+        while self.headers.get('X-HgArg-%d' % n) is not None:
+            headers.append(self.headers.get('X-HgArg-%d' % n))
+            n += 1
+        if headers:
+            query.extend(parse_qsl(''.join(headers), keep_blank_values=True))
+        args = [(k.encode('utf-8'), v.encode('utf-8')) for k, v in query]
+        if not args or args[0][0] != b'cmd':
+            raise MockError('missing command')
+        name = args.pop(0)[1]
+        if name == b'capabilities':
+            self.respond(script.caps_response())
+            return
+        length = int(self.headers.get('Content-Length') or 0)
+        if length:
+            self.rfile.read(length)
+        records = script.command(name, args)
+        if name == b'getbundle':
+            self.respond(zlib.compress(response_data(records, b'data')))
+        elif name == b'unbundle':
+            result = response_data(records, b'response')
+            if result.startswith(b'error\n'):
+                self.respond(b'0\n' + result[6:])
+            else:
+                self.respond(result + b'\n')
+        else:
+            self.respond(response_data(records, b'response'))
+
+    def do_GET(self):
+        try:
+            self.handle_command()
+        except MockError as e:
+            print('mock-hg-server: %s' % e, file=sys.stderr)
+            self.send_error(500)
+
+    do_POST = do_GET
+
+
+def main(args):
+    if len(args) >= 3 and args[0] == 'add' and len(args) <= 4:
+        typ = args[2].encode('ascii')
+        data = args[3] if len(args) > 3 else ''
+        if data == '-':
+            data = stdio(sys.stdin).read()
+        else:
+            data = data.encode('utf-8')
+        if typ == b'capabilities':
+            data = b''.join(c + b'\0' for c in data.split())
+        with open(args[1], 'ab') as fh:
+            write_record(fh, typ, data)
+    elif len(args) >= 2 and args[0] == 'serve':
+        serve_stdio(Script(args[1]))
+    elif len(args) == 2 and args[0] == 'serve-http':
+        server = HTTPServer(('127.0.0.1', 0), HTTPHandler)
+        server.script = Script(args[1])
+        print('http://127.0.0.1:%d/' % server.server_port)
+        sys.stdout.flush()
+        server.serve_forever()
+    else:
+        print(USAGE.strip(), file=sys.stderr)
+        return 1
+    return 0
+
+
+if __name__ == '__main__':
+    try:
+        sys.exit(main(sys.argv[1:]))
+    except MockError as e:
+        print('mock-hg-server: %s' % e, file=sys.stderr)
+        sys.exit(1)
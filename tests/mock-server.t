@@ -0,0 +1,132 @@
+#!/usr/bin/env cram
+
+Tests of the native wire protocol implementation against a mock server
+with scripted responses.
+
+  $ PATH=$TESTDIR/..:$PATH
+  $ MOCK=$TESTDIR/mock-hg-server.py
+  $ NODE=1234567890abcdef1234567890abcdef12345678
+  $ NULL=0000000000000000000000000000000000000000
+  $ export GIT_SSH_VARIANT=simple
+  $ mock() {
+  >   script=$(pwd)/$1.script
+  >   shift
+  >   GIT_SSH_COMMAND="$MOCK serve $script" git "$@"
+  > }
+
+A server without the "capabilities" command is too old.
+
+  $ $MOCK add old.script capabilities ""
+  $ mock old ls-remote hg::ssh://mock/repo 2>&1 | grep -o 'required "getbundle" capability'
+  required "getbundle" capability
+
+Without the "batch" capability, the repository state is queried with
+separate commands.
+
+  $ $MOCK add nobatch.script capabilities "branchmap getbundle known lookup pushkey unbundle"
+  $ $MOCK add nobatch.script command branchmap
+  $ echo "default $NODE" | $MOCK add nobatch.script response -
+  $ $MOCK add nobatch.script command heads
+  $ echo $NODE | $MOCK add nobatch.script response -
+  $ $MOCK add nobatch.script command "$(printf 'listkeys\nnamespace=bookmarks')"
+  $ $MOCK add nobatch.script response ""
+  $ mock nobatch ls-remote hg::ssh://mock/repo
+  0000000000000000000000000000000000000000	HEAD
+  0000000000000000000000000000000000000000	refs/heads/branches/default/tip
+  $ cat nobatch.script.received
+  branchmap
+  heads
+  listkeys namespace=bookmarks
+
+With the "batch" capability, it is queried with one command.
+
+  $ $MOCK add batch.script capabilities "batch branchmap getbundle known lookup pushkey unbundle"
+  $ $MOCK add batch.script command "$(printf 'batch\ncmds=branchmap ;heads ;listkeys namespace=bookmarks')"
+  $ printf 'default %s\n;%s\n;' $NODE $NODE | $MOCK add batch.script response -
+  $ mock batch ls-remote hg::ssh://mock/repo
+  0000000000000000000000000000000000000000	HEAD
+  0000000000000000000000000000000000000000	refs/heads/branches/default/tip
+  $ cat batch.script.received
+  batch cmds=branchmap ;heads ;listkeys namespace=bookmarks
+
+Output from the remote before the handshake, like a banner, is relayed.
+
+  $ $MOCK add banner.script capabilities "batch branchmap getbundle known lookup pushkey unbundle"
+  $ printf 'Welcome to the server\nBe nice\n' | $MOCK add banner.script banner -
+  $ $MOCK add banner.script command batch
+  $ printf 'default %s\n;%s\n;' $NODE $NODE | $MOCK add banner.script response -
+  $ mock banner ls-remote hg::ssh://mock/repo
+  remote: Welcome to the server
+  remote: Be nice
+  0000000000000000000000000000000000000000	HEAD
+  0000000000000000000000000000000000000000	refs/heads/branches/default/tip
+
+Malformed responses after the handshake are reported.
+
+  $ garbage() {
+  >   rm -f garbage.script
+  >   $MOCK add garbage.script capabilities "batch branchmap getbundle known lookup pushkey unbundle"
+  >   $MOCK add garbage.script command batch
+  >   $MOCK add garbage.script raw -
+  >   mock garbage ls-remote hg::ssh://mock/repo 2>&1 | grep -o -e 'invalid response.*' -e 'remote hung up.*' -e 'The remote sent.*'
+  > }
+  $ printf -- '-1\n' | garbage
+  invalid response from remote to batch: '-1'
+  The remote sent something that is not a mercurial response, like a banner or an error message.
+  $ printf ' 1\n\n' | garbage
+  invalid response from remote to batch: ' 1'
+  The remote sent something that is not a mercurial response, like a banner or an error message.
+  $ printf 'Permission denied\n' | garbage
+  invalid response from remote to batch: 'Permission denied'
+  The remote sent something that is not a mercurial response, like a banner or an error message.
+  $ printf '99999999999999999999999999\n' | garbage
+  invalid response from remote to batch: '99999999999999999999999999'
+  The remote sent something that is not a mercurial response, like a banner or an error message.
+  $ printf '\001\377\n' | garbage
+  invalid response from remote to batch: '\x01\xff'
+  The remote sent something that is not a mercurial response, like a banner or an error message.
+  $ printf '10\nabc' | garbage
+  remote hung up unexpectedly while reading response to batch
+  $ printf '' | garbage
+  remote hung up unexpectedly while reading response to batch
+
+Arguments are sent in headers over HTTP when the server supports it.
+
+  $ $MOCK add http.script capabilities "batch branchmap getbundle httpheader=1024 known lookup pushkey unbundle"
+  $ $MOCK add http.script command "$(printf 'batch\ncmds=branchmap ;heads ;listkeys namespace=bookmarks')"
+  $ printf 'default %s\n;%s\n;' $NODE $NODE | $MOCK add http.script response -
+  $ $MOCK serve-http $(pwd)/http.script > http.url &
+  $ MOCK_PID=$!
+  $ while ! test -s http.url; do sleep 0.1; done
+  $ git -c cinnabar.experiments=wire ls-remote hg::$(cat http.url)
+  0000000000000000000000000000000000000000	HEAD
+  0000000000000000000000000000000000000000	refs/heads/branches/default/tip
+  $ kill $MOCK_PID
+  $ cat http.script.received
+  batch cmds=branchmap ;heads ;listkeys namespace=bookmarks
+
+A push the server rejects before receiving the bundle fails with the
+server message.
+
+  $ $MOCK add push.script capabilities "branchmap getbundle known lookup pushkey unbundle"
+  $ $MOCK add push.script command branchmap
+  $ $MOCK add push.script response ""
+  $ $MOCK add push.script command heads
+  $ echo $NULL | $MOCK add push.script response -
+  $ $MOCK add push.script command "$(printf 'listkeys\nnamespace=bookmarks')"
+  $ $MOCK add push.script response ""
+  $ $MOCK add push.script command "$(printf 'listkeys\nnamespace=phases')"
+  $ $MOCK add push.script response ""
+  $ $MOCK add push.script command unbundle
+  $ printf 'error\nlost the race' | $MOCK add push.script response -
+
+  $ git init -q src
+  $ echo a > src/a
+  $ git -C src add a
+  $ git -C src -c user.name=Nobody -c user.email=nobody@example.com commit -q -m a
+  $ cd src
+  $ mock ../push push hg::ssh://mock/repo HEAD:refs/heads/branches/default/tip 2>&1 | grep 'rejected the push'
+  ERROR The server rejected the push: lost the race
+  $ cd ..
+  $ grep -c '^unbundle ' push.script.received
+  1
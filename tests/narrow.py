@@ -0,0 +1,23 @@
+from __future__ import absolute_import, unicode_literals
+import unittest
+from cinnabar.hg.repo import narrow_match
+
+
+class TestNarrowMatch(unittest.TestCase):
+    def test_include(self):
+        include = [b'foo', b'bar/baz']
+        self.assertTrue(narrow_match(include, [], b'foo/qux'))
+        self.assertTrue(narrow_match(include, [], b'foo'))
+        self.assertTrue(narrow_match(include, [], b'bar/baz/qux'))
+        self.assertFalse(narrow_match(include, [], b'foobar'))
+        self.assertFalse(narrow_match(include, [], b'bar/qux'))
+        self.assertFalse(narrow_match(include, [], b'qux'))
+
+    def test_exclude(self):
+        include = [b'.']
+        exclude = [b'foo/bar']
+        self.assertTrue(narrow_match(include, exclude, b'foo/baz'))
+        self.assertTrue(narrow_match(include, exclude, b'qux'))
+        self.assertFalse(narrow_match(include, exclude, b'foo/bar/baz'))
+        self.assertFalse(narrow_match([b'foo'], exclude, b'foo/bar/baz'))
+        self.assertFalse(narrow_match([b'foo'], exclude, b'qux'))
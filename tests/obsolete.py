@@ -0,0 +1,61 @@
+from __future__ import absolute_import, unicode_literals
+import struct
+import unittest
+from cinnabar.obsolete import (
+    B85CHARS,
+    b85decode,
+    obsolescence_markers,
+    parse_markers,
+    successors,
+)
+
+
+def b85encode(data):
+    result = b''
+    padding = -len(data) % 4
+    data += b'\0' * padding
+    for i in range(0, len(data), 4):
+        acc = struct.unpack('>I', data[i:i + 4])[0]
+        chunk = b''
+        for _ in range(5):
+            acc, c = divmod(acc, 85)
+            chunk = B85CHARS[c:c + 1] + chunk
+        result += chunk
+    return result[:len(result) - padding]
+
+
+def marker(prec, successors, metadata=b''):
+    return struct.pack('>BIB20s', len(successors), len(metadata), 0,
+                       prec) + b''.join(successors) + metadata
+
+
+class TestObsolete(unittest.TestCase):
+    def test_b85decode(self):
+        for data in (b'', b'a', b'ab', b'abc', b'abcd', b'abcde',
+                     b'\0\xff' * 7):
+            self.assertEqual(b85decode(b85encode(data)), data)
+
+    def test_parse_markers(self):
+        data = b'\0' + marker(b'a' * 20, [b'b' * 20, b'c' * 20],
+                              b'user:foo\0date:0 0') + marker(b'd' * 20, [])
+        self.assertEqual(list(parse_markers(data)), [
+            (b'61' * 20, (b'62' * 20, b'63' * 20)),
+            (b'64' * 20, ()),
+        ])
+        with self.assertRaises(Exception):
+            list(parse_markers(b'\1'))
+        with self.assertRaises(Exception):
+            list(parse_markers(data[:-1]))
+
+    def test_obsolescence_markers(self):
+        markers = obsolescence_markers({
+            b'dump0': b85encode(b'\0' + marker(b'a' * 20, [b'b' * 20])),
+            b'dump1': b85encode(b'\0' + marker(b'b' * 20, [])),
+        })
+        self.assertEqual(markers, set((
+            (b'61' * 20, (b'62' * 20,)),
+            (b'62' * 20, ()),
+        )))
+        self.assertEqual(successors(markers, b'61' * 20), (b'62' * 20,))
+        self.assertEqual(successors(markers, b'62' * 20), ())
+        self.assertEqual(successors(markers, b'63' * 20), None)
@@ -0,0 +1,67 @@
+from __future__ import absolute_import, unicode_literals
+from cinnabar import phases
+from cinnabar.phases import draft_commits
+from fixtures import (
+    GitRepoTestCase,
+    commit_tree,
+    git,
+)
+
+
+class FakeHelper(object):
+    '''GitHgHelper equivalent that doesn't need the helper.'''
+    @staticmethod
+    def rev_list(*args):
+        for line in git('rev-list', '--parents',
+                        *(a.decode('ascii') for a in args)).splitlines():
+            commit, _, parents = line.partition(b' ')
+            yield commit, None, parents.split()
+
+
+class FakeStore(object):
+    def __init__(self, changesets):
+        self.changesets = changesets
+
+    def changeset_ref(self, node):
+        return self.changesets.get(node)
+
+
+class TestDraftCommits(GitRepoTestCase):
+    def setUp(self):
+        super(TestDraftCommits, self).setUp()
+        self.helper = phases.GitHgHelper
+        phases.GitHgHelper = FakeHelper
+
+    def tearDown(self):
+        phases.GitHgHelper = self.helper
+        super(TestDraftCommits, self).tearDown()
+
+    def test_draft_commits(self):
+        # a - b - c - e
+        #      \     /
+        #       - d -
+        # f (unrelated root)
+        a = commit_tree('a')
+        b = commit_tree('b', a)
+        c = commit_tree('c', b)
+        d = commit_tree('d', b)
+        e = commit_tree('e', c, d)
+        f = commit_tree('f')
+        store = FakeStore({b'a' * 40: a, b'c' * 40: c, b'd' * 40: d,
+                           b'f' * 40: f})
+
+        self.assertEqual(draft_commits(store, [e], []), set())
+        self.assertEqual(draft_commits(store, [], [b'c' * 40]), set())
+        self.assertEqual(draft_commits(store, [e], [b'c' * 40]),
+                         set((c, e)))
+        self.assertEqual(draft_commits(store, [c], [b'c' * 40]), set((c,)))
+        self.assertEqual(draft_commits(store, [d], [b'c' * 40]), set())
+        self.assertEqual(draft_commits(store, [e], [b'c' * 40, b'd' * 40]),
+                         set((c, d, e)))
+        # Parentless roots only make their descendants draft.
+        self.assertEqual(draft_commits(store, [e], [b'f' * 40]), set())
+        self.assertEqual(draft_commits(store, [e, f], [b'f' * 40]),
+                         set((f,)))
+        self.assertEqual(draft_commits(store, [e], [b'a' * 40]),
+                         set((a, b, c, d, e)))
+        self.assertEqual(draft_commits(store, [e], [b'e' * 40]), set())
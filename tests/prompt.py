@@ -0,0 +1,45 @@
+from __future__ import absolute_import, unicode_literals
+from importlib import import_module
+from cinnabar.git import EMPTY_TREE
+from fixtures import (
+    GitRepoTestCase,
+    git,
+)
+
+prompt = import_module('cinnabar.cmd.prompt')
+
+
+class TestGit2HgNote(GitRepoTestCase):
+    def metadata(self, notes_tree):
+        notes = git('commit-tree', notes_tree, '-m', 'notes')
+        others = [git('commit-tree', EMPTY_TREE, '-m', m)
+                  for m in ('changesets', 'manifests', 'hg2git')]
+        args = ['commit-tree', EMPTY_TREE, '-m', 'metadata']
+        for p in others + [notes]:
+            args += ['-p', p.decode('ascii')]
+        return git(*args)
+
+    def mktree(self, entries):
+        return git('mktree', input=b''.join(
+            b'%s %s %s\t%s\n' % entry for entry in entries))
+
+    def test_git2hg_note(self):
+        commit = git('commit-tree', EMPTY_TREE, '-m', 'commit')
+        other = git('commit-tree', EMPTY_TREE, '-m', 'other')
+        note = git('hash-object', '-w', '--stdin',
+                   input=b'changeset %s\n' % (b'1' * 40))
+
+        flat = self.metadata(self.mktree([
+            (b'100644', b'blob', note, commit),
+        ]))
+        self.assertEqual(prompt.git2hg_note(flat, commit),
+                         b'changeset %s' % (b'1' * 40))
+        self.assertIsNone(prompt.git2hg_note(flat, other))
+
+        subtree = self.mktree([(b'100644', b'blob', note, commit[2:])])
+        fanout = self.metadata(self.mktree([
+            (b'040000', b'tree', subtree, commit[:2]),
+        ]))
+        self.assertEqual(prompt.git2hg_note(fanout, commit),
+                         b'changeset %s' % (b'1' * 40))
+        self.assertIsNone(prompt.git2hg_note(fanout, other))
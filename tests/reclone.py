@@ -0,0 +1,72 @@
+from __future__ import absolute_import, unicode_literals
+from importlib import import_module
+from fixtures import (
+    GitRepoTestCase,
+    commit_tree,
+    git,
+)
+
+reclone = import_module('cinnabar.cmd.reclone')
+
+
+class TestLocalRefs(GitRepoTestCase):
+    def setUp(self):
+        super(TestLocalRefs, self).setUp()
+        self.git2hg = reclone.git2hg
+        self.changesets = {}
+        reclone.git2hg = self.changesets.get
+
+    def tearDown(self):
+        reclone.git2hg = self.git2hg
+        super(TestLocalRefs, self).tearDown()
+
+    def test_local_refs(self):
+        # Changesets a - b - c, and d - e as another root.
+        a = commit_tree('a')
+        b = commit_tree('b', a)
+        c = commit_tree('c', b)
+        d = commit_tree('d')
+        for n, commit in enumerate((a, b, c, d)):
+            self.changesets[commit] = b'%d' % n * 40
+        changesets = commit_tree('changesets', c, d)
+        git('update-ref', 'refs/cinnabar/metadata',
+            commit_tree('metadata', changesets))
+
+        # Local commits on top of b.
+        x = commit_tree('x', b)
+        y = commit_tree('y', x)
+        # A local merge of changesets.
+        m = commit_tree('m', c, d)
+        # A local merge on top of a local commit and a changeset.
+        n = commit_tree('n', x, c)
+        # A local root.
+        r = commit_tree('r')
+        git('update-ref', 'refs/heads/c', c)
+        git('update-ref', 'refs/heads/y', y)
+        git('update-ref', 'refs/heads/y2', y)
+        git('update-ref', 'refs/heads/m', m)
+        git('update-ref', 'refs/heads/n', n)
+        git('update-ref', 'refs/heads/r', r)
+        git('tag', 'lightweight', x)
+        git('tag', '-a', '-m', 'annotated', 'annotated', c)
+        tag = git('rev-parse', 'refs/tags/annotated')
+
+        self.assertEqual(reclone.local_refs(), {
+            b'refs/heads/c': (c, c, b'2' * 40, ()),
+            b'refs/heads/y': (y, b, b'1' * 40, (x, y)),
+            b'refs/heads/y2': (y, b, b'1' * 40, (x, y)),
+            b'refs/heads/m': (m, None, None, (m,)),
+            b'refs/heads/n': (n, None, None, (x, n)),
+            b'refs/heads/r': (r, None, None, (r,)),
+            b'refs/tags/lightweight': (x, b, b'1' * 40, (x,)),
+            b'refs/tags/annotated': (tag, None, None, None),
+        })
+
+    def test_update_ref(self):
+        a = commit_tree('a')
+        b = commit_tree('b', a)
+        git('update-ref', 'refs/heads/foo', a)
+        self.assertIsNotNone(reclone.update_ref(b'refs/heads/foo', b, b))
+        self.assertEqual(git('rev-parse', 'refs/heads/foo'), a)
+        self.assertIsNone(reclone.update_ref(b'refs/heads/foo', b, a))
+        self.assertEqual(git('rev-parse', 'refs/heads/foo'), b)
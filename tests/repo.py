@@ -0,0 +1,514 @@
+from __future__ import absolute_import, unicode_literals
+import logging
+import os
+import shutil
+import struct
+import subprocess
+import tempfile
+import unittest
+from contextlib import contextmanager
+from io import BytesIO
+import cinnabar.hg.repo
+from cinnabar.exceptions import Abort
+from cinnabar.git import (
+    Git,
+    NULL_NODE_ID,
+)
+from cinnabar.hg.changegroup import RawRevChunk02
+from cinnabar.hg.objects import (
+    Changeset,
+    File,
+)
+from cinnabar.hg.repo import (
+    apply_clonebundle,
+    BundleApplier,
+    check_push_policy,
+    ChunksCollection,
+    credentials_from_lines,
+    get_http_config,
+    git_credential,
+    new_heads,
+    store_changegroup,
+    stored_files,
+)
+from cinnabar.util import MemoryLimit
+from fixtures import GitRepoTestCase
+
+
+class TestGitCredential(unittest.TestCase):
+    def setUp(self):
+        self.tmpdir = tempfile.mkdtemp()
+        self.environ = os.environ.get('GIT_CONFIG_PARAMETERS')
+        # A credential helper giving fixed credentials, and recording what
+        # it's asked to store or erase.
+        helper = (
+            '!f() { if [ "$1" = get ]; then'
+            ' echo username=foo; echo password=bar;'
+            ' else cat > "%s/$1"; fi; }; f' % self.tmpdir)
+        os.environ['GIT_CONFIG_PARAMETERS'] = \
+            "'credential.helper=%s'" % helper.replace("'", "'\\''")
+
+    def tearDown(self):
+        if self.environ is None:
+            del os.environ['GIT_CONFIG_PARAMETERS']
+        else:
+            os.environ['GIT_CONFIG_PARAMETERS'] = self.environ
+        shutil.rmtree(self.tmpdir)
+
+    def read(self, action):
+        with open(os.path.join(self.tmpdir, action), 'rb') as fh:
+            return fh.read().splitlines()
+
+    def test_git_credential(self):
+        lines = git_credential('fill', [b'url=https://example.com/repo'])
+        self.assertEqual(credentials_from_lines(lines), (b'foo', b'bar'))
+
+        git_credential('approve', lines)
+        self.assertIn(b'username=foo', self.read('store'))
+        self.assertIn(b'password=bar', self.read('store'))
+
+        git_credential('reject', lines)
+        self.assertIn(b'username=foo', self.read('erase'))
+
+    def test_credentials_from_lines(self):
+        self.assertEqual(credentials_from_lines(()), (None, None))
+        self.assertEqual(
+            credentials_from_lines((b'protocol=https', b'username=foo')),
+            (b'foo', None))
+
+
+class TestHttpConfig(unittest.TestCase):
+    def setUp(self):
+        self.environ = os.environ.get('GIT_CONFIG_PARAMETERS')
+        os.environ['GIT_CONFIG_PARAMETERS'] = (
+            "'http.https://example.com/.proxy=http://proxy:3128'")
+
+    def tearDown(self):
+        if self.environ is None:
+            del os.environ['GIT_CONFIG_PARAMETERS']
+        else:
+            os.environ['GIT_CONFIG_PARAMETERS'] = self.environ
+
+    def test_get_http_config(self):
+        self.assertEqual(
+            get_http_config('http.proxy', b'https://example.com/repo'),
+            b'http://proxy:3128')
+        self.assertEqual(
+            get_http_config('http.proxy', b'https://example.org/repo'),
+            None)
+
+
+class FakeManifestChunk(object):
+    def __init__(self, node):
+        self.node = node
+
+
+class FakeHelper(object):
+    def __init__(self, bad):
+        self.bad = bad
+        self.batches = []
+
+    def check_manifests(self, nodes):
+        self.batches.append(len(nodes))
+        return [n not in self.bad for n in nodes]
+
+    def check_files(self, files):
+        self.batches.append(len(files))
+        return [n not in self.bad for n, _, _ in files]
+
+    def manifest(self, node):
+        return b'reconstructed'
+
+
+class FakeStore(object):
+    _has_metadata = True
+
+    def __init__(self, files):
+        self.files = files
+        self.changesets = []
+
+    def file(self, node):
+        return self.files[node]
+
+    def changeset(self, node):
+        raise KeyError(node)
+
+    def store_changeset(self, changeset):
+        self.changesets.append(changeset.node)
+
+
+class ListHandler(logging.Handler):
+    '''Logging handler keeping the formatted messages, for python 2, which
+    doesn't have TestCase.assertLogs.'''
+    def __init__(self):
+        super(ListHandler, self).__init__(logging.ERROR)
+        self.output = []
+
+    def emit(self, record):
+        self.output.append(self.format(record))
+
+
+@contextmanager
+def capture_logs():
+    handler = ListHandler()
+    logger = logging.getLogger()
+    logger.addHandler(handler)
+    try:
+        yield handler
+    finally:
+        logger.removeHandler(handler)
+
+
+class TestBundleApplier(GitRepoTestCase):
+    def setUp(self):
+        super(TestBundleApplier, self).setUp()
+        self.checks = set()
+        self.helper = FakeHelper(set())
+        self.patches = {
+            'GitHgHelper': self.helper,
+            'check_enabled': lambda name: name in self.checks,
+            'store_changegroup': lambda bundle: bundle,
+        }
+        for name, value in self.patches.items():
+            self.patches[name] = getattr(cinnabar.hg.repo, name)
+            setattr(cinnabar.hg.repo, name, value)
+        self.batch = BundleApplier.VERIFY_BATCH
+        BundleApplier.VERIFY_BATCH = 2
+        stored_files.clear()
+
+        changeset = Changeset()
+        changeset.author = b'Foo Bar <foo@bar>'
+        changeset.timestamp = b'1482880019'
+        changeset.utcoffset = b'-7200'
+        changeset.body = b'Some commit'
+        changeset.node = changeset.sha1
+        self.changeset = changeset
+        self.manifests = [b'%d' % n * 40 for n in range(1, 4)]
+        files = []
+        for content in (b'foo', b'bar', b'baz'):
+            f = File()
+            f.content = content
+            f.node = f.sha1
+            files.append(f)
+        self.files = files
+
+    def tearDown(self):
+        BundleApplier.VERIFY_BATCH = self.batch
+        for name, value in self.patches.items():
+            setattr(cinnabar.hg.repo, name, value)
+        stored_files.clear()
+        super(TestBundleApplier, self).tearDown()
+
+    def apply(self):
+        bundle = iter((
+            [self.changeset.to_chunk(RawRevChunk02)],
+            [FakeManifestChunk(n) for n in self.manifests],
+            [(b'file%d' % n, f.to_chunk(RawRevChunk02))
+             for n, f in enumerate(self.files)],
+        ))
+        self.store = FakeStore(dict((f.node, f) for f in self.files))
+        BundleApplier(bundle, b'https://example.com/')(self.store)
+        return self.store
+
+    def quarantined(self):
+        path = os.path.join(self.tmpdir, 'cinnabar-quarantine')
+        result = {}
+        for name in os.listdir(path):
+            with open(os.path.join(path, name), 'rb') as fh:
+                result[name] = fh.read()
+        return result
+
+    def test_default(self):
+        store = self.apply()
+        self.assertEqual(store.changesets, [self.changeset.node])
+        self.assertEqual(self.helper.batches, [])
+        self.assertEqual(sorted(stored_files),
+                         sorted(f.node for f in self.files))
+
+    def test_fast(self):
+        self.checks.add('fast')
+        store = self.apply()
+        self.assertEqual(store.changesets, [self.changeset.node])
+        self.assertEqual(len(stored_files), 0)
+
+    def test_nodes(self):
+        self.checks.add('nodes')
+        store = self.apply()
+        self.assertEqual(store.changesets, [self.changeset.node])
+        self.assertEqual(self.helper.batches, [2, 1, 2, 1])
+
+        self.helper.bad.update((self.manifests[2], self.files[0].node))
+        self.helper.batches = []
+        with capture_logs() as logs:
+            with self.assertRaises(Abort):
+                self.apply()
+        self.assertEqual(self.store.changesets, [])
+        self.assertEqual(self.helper.batches, [2, 1, 2, 1])
+        self.assertEqual(len(logs.output), 2)
+        self.assertIn('manifest %s from https://example.com/. Its data, as '
+                      'reconstructed by git-cinnabar, was saved'
+                      % self.manifests[2].decode('ascii'), logs.output[0])
+        self.assertIn('file %s from https://example.com/. Its data was saved'
+                      % self.files[0].node.decode('ascii'), logs.output[1])
+        self.assertEqual(self.quarantined(), {
+            'manifest-%s' % self.manifests[2].decode('ascii'):
+                b'reconstructed',
+            'file-%s' % self.files[0].node.decode('ascii'): b'foo',
+        })
+
+    def test_nodes_changeset(self):
+        self.checks.add('nodes')
+        self.changeset.node = b'1' * 40
+        with capture_logs() as logs:
+            with self.assertRaises(Abort):
+                self.apply()
+        self.assertEqual(self.store.changesets, [])
+        self.assertIn('changeset %s' % ('1' * 40), logs.output[0])
+
+
+class TestChunksCollection(unittest.TestCase):
+    def setUp(self):
+        self.memory_limit = cinnabar.hg.repo.memory_limit
+        cinnabar.hg.repo.memory_limit = self.limit = MemoryLimit()
+
+    def tearDown(self):
+        cinnabar.hg.repo.memory_limit = self.memory_limit
+
+    def chunks(self, spill_after=None):
+        for n in range(10):
+            if n == spill_after:
+                self.limit.shrink()
+            yield RawRevChunk02(b'%d' % n * (n + 1))
+
+    def test_chunks_collection(self):
+        expected = [b'%d' % n * (n + 1) for n in range(10)]
+        for spill_after in (None, 0, 5):
+            chunks = ChunksCollection(self.chunks(spill_after))
+            self.assertEqual(chunks._spill is not None,
+                             spill_after is not None)
+            self.assertEqual(self.limit._shrinkers, [])
+            result = list(chunks)
+            self.assertEqual(result, expected)
+            self.assertTrue(all(isinstance(c, RawRevChunk02)
+                                for c in result))
+            self.assertIsNone(chunks._spill)
+
+
+class FakeBranchChangeset(object):
+    def __init__(self, branch):
+        self.branch = branch
+
+
+class FakePushStore(object):
+    '''Store where changesets and commits are named after their branch
+    (b'd' for default, b'f' for foo), and the commit sha1s are the node
+    sha1s in upper case.'''
+    def changeset_ref(self, node):
+        return node.upper() if node[:1] != b'0' else None
+
+    def hg_changeset(self, commit):
+        return commit.lower()
+
+    def changeset(self, node):
+        return FakeBranchChangeset(b'foo' if node[:1] == b'f' else None)
+
+
+class FakePushRepo(object):
+    remote = None
+
+    def url(self):
+        return b'https://example.com/'
+
+
+class FakeBundleHelper(object):
+    @staticmethod
+    def close():
+        pass
+
+
+class TestPushPolicy(GitRepoTestCase):
+    def setUp(self):
+        super(TestPushPolicy, self).setUp()
+        self.is_ancestor = cinnabar.hg.repo.is_ancestor
+        # Commits descend from those with a smaller sha1 on the same branch.
+        cinnabar.hg.repo.is_ancestor = \
+            lambda a, d: a[:1] == d[:1] and a <= d
+        self.store = FakePushStore()
+
+    def tearDown(self):
+        cinnabar.hg.repo.is_ancestor = self.is_ancestor
+        super(TestPushPolicy, self).tearDown()
+
+    def test_new_heads(self):
+        push = [(b'D' * 40, ()), (b'F' * 40, ())]
+        self.assertEqual(new_heads(self.store, push, [b'd' * 20 + b'0' * 20]),
+                         [(b'F' * 40, b'foo', True)])
+        self.assertEqual(new_heads(self.store, push, [b'e' * 40]),
+                         [(b'D' * 40, b'default', False),
+                          (b'F' * 40, b'foo', True)])
+        # The branch of unknown remote heads is unknown, so branches are
+        # not reported as new.
+        self.assertEqual(new_heads(self.store, push,
+                                   [b'd' * 20 + b'0' * 20, b'01' * 20]),
+                         [(b'F' * 40, b'foo', False)])
+        self.assertEqual(new_heads(self.store, push, [NULL_NODE_ID]),
+                         [(b'D' * 40, b'default', True),
+                          (b'F' * 40, b'foo', True)])
+
+    def test_invalid_policy(self):
+        subprocess.check_call(('git', 'config', 'cinnabar.push.allow-new-head',
+                               'maybe'))
+        Git._config = None
+        with self.assertRaises(Abort) as e:
+            check_push_policy(FakePushRepo(), self.store,
+                              [(b'D' * 40, ())], [b'e' * 40])
+        self.assertIn('cinnabar.push.allow-new-head', str(e.exception))
+
+    def test_deny(self):
+        subprocess.check_call(('git', 'config',
+                               'cinnabar.push.allow-new-branch', 'deny'))
+        Git._config = None
+        check_push_policy(FakePushRepo(), self.store,
+                          [(b'D' * 40, ())], [b'd' * 20 + b'0' * 20])
+        with self.assertRaises(Exception):
+            check_push_policy(FakePushRepo(), self.store,
+                              [(b'F' * 40, ())], [b'd' * 20 + b'0' * 20])
+
+
+class FakeChangegroupHelper(object):
+    def __init__(self):
+        self.data = BytesIO()
+
+    @contextmanager
+    def store_changegroup(self, version):
+        yield self.data
+
+
+def failing(iterable, after):
+    for n, item in enumerate(iterable):
+        if n == after:
+            raise Exception('Download failed')
+        yield item
+
+
+class TestStoreChangegroup(unittest.TestCase):
+    def setUp(self):
+        self.helper = cinnabar.hg.repo.GitHgHelper
+        cinnabar.hg.repo.GitHgHelper = FakeChangegroupHelper()
+
+    def tearDown(self):
+        cinnabar.hg.repo.GitHgHelper = self.helper
+
+    def chunk(self, n):
+        return RawRevChunk02(b'%d' % n * 100)
+
+    def changegroup(self, fail_in=None, after=None):
+        sections = [
+            [self.chunk(1), self.chunk(2)],
+            [self.chunk(3)],
+            [(b'foo', self.chunk(4)), (b'foo', self.chunk(5)),
+             (b'bar', self.chunk(6))],
+        ]
+        if fail_in is not None:
+            sections[fail_in] = failing(sections[fail_in], after)
+        return iter(iter(s) for s in sections)
+
+    def read_changegroup(self):
+        data = cinnabar.hg.repo.GitHgHelper.data.getvalue()
+        offset = [0]
+
+        def getchunk():
+            length = struct.unpack('>l', data[offset[0]:offset[0] + 4])[0]
+            chunk = data[offset[0] + 4:offset[0] + max(length, 4)]
+            offset[0] += max(length, 4)
+            return chunk
+
+        def section():
+            return list(iter(getchunk, b''))
+
+        result = [section(), section(), []]
+        while True:
+            name = getchunk()
+            if not name:
+                break
+            result[2].append((name, section()))
+        self.assertEqual(offset[0], len(data))
+        return result
+
+    def apply(self, changegroup):
+        bundle = store_changegroup(changegroup)
+        try:
+            for section in bundle:
+                for _ in section:
+                    pass
+        finally:
+            bundle.close()
+
+    def test_store_changegroup(self):
+        self.apply(self.changegroup())
+        self.assertEqual(self.read_changegroup(), [
+            [self.chunk(1), self.chunk(2)],
+            [self.chunk(3)],
+            [(b'foo', [self.chunk(4), self.chunk(5)]),
+             (b'bar', [self.chunk(6)])],
+        ])
+
+    def test_interrupted_changegroup(self):
+        # Failures when the changegroup is only partially read still send
+        # a complete changegroup to the helper.
+        for fail_in, after, expected in (
+            (0, 1, [[self.chunk(1)], [], []]),
+            (1, 0, [[self.chunk(1), self.chunk(2)], [], []]),
+            (2, 1, [[self.chunk(1), self.chunk(2)], [self.chunk(3)],
+                    [(b'foo', [self.chunk(4)])]]),
+            (2, 2, [[self.chunk(1), self.chunk(2)], [self.chunk(3)],
+                    [(b'foo', [self.chunk(4), self.chunk(5)])]]),
+        ):
+            cinnabar.hg.repo.GitHgHelper = FakeChangegroupHelper()
+            with self.assertRaises(Exception):
+                self.apply(self.changegroup(fail_in, after))
+            self.assertEqual(self.read_changegroup(), expected)
+
+
+class TestApplyClonebundle(unittest.TestCase):
+    def setUp(self):
+        self.patches = {
+            'BundleApplier': self.BundleApplier,
+            'BundleHelper': FakeBundleHelper,
+            'get_clonebundles': lambda repo: iter(self.bundles),
+            'unbundler': lambda bundle: bundle,
+        }
+        for name, value in self.patches.items():
+            self.patches[name] = getattr(cinnabar.hg.repo, name)
+            setattr(cinnabar.hg.repo, name, value)
+        self.applied = []
+        self.logging = cinnabar.hg.repo.logging.getLogger().level
+        cinnabar.hg.repo.logging.getLogger().setLevel(100)
+
+    def tearDown(self):
+        for name, value in self.patches.items():
+            setattr(cinnabar.hg.repo, name, value)
+        cinnabar.hg.repo.logging.getLogger().setLevel(self.logging)
+
+    def BundleApplier(self, bundle, source):
+        def apply(store):
+            if isinstance(bundle, BaseException):
+                raise bundle
+            self.applied.append(bundle)
+        return apply
+
+    def test_apply_clonebundle(self):
+        self.bundles = [(b'url1', Exception('failed')), (b'url2', b'bundle2'),
+                        (b'url3', b'bundle3')]
+        self.assertTrue(apply_clonebundle(FakePushRepo(), None))
+        self.assertEqual(self.applied, [b'bundle2'])
+
+        self.applied = []
+        self.bundles = [(b'url1', Exception('failed'))]
+        self.assertFalse(apply_clonebundle(FakePushRepo(), None))
+        self.assertEqual(self.applied, [])
+
+        self.bundles = [(b'url1', Abort('corrupted')), (b'url2', b'bundle2')]
+        with self.assertRaises(Abort):
+            apply_clonebundle(FakePushRepo(), None)
+        self.assertEqual(self.applied, [])
@@ -0,0 +1,346 @@
+from __future__ import absolute_import, unicode_literals
+import hashlib
+import os
+import shutil
+import struct
+import tempfile
+import unittest
+import zlib
+from binascii import (
+    hexlify,
+    unhexlify,
+)
+from io import BytesIO
+from cinnabar.hg.changegroup import (
+    RawRevChunk01,
+    RevDiff,
+)
+from cinnabar.hg.repo import (
+    chunks_in_changegroup,
+    iterate_files,
+)
+from cinnabar.hg.revlog import (
+    NULL_ID,
+    NULL_REV,
+    Revlog,
+    RevlogRepo,
+    changeset_info,
+    store_path,
+)
+
+
+def hg_node(text, p1=NULL_ID, p2=NULL_ID):
+    p1, p2 = sorted((p1, p2))
+    return hashlib.sha1(p1 + p2 + text).digest()
+
+
+def delta(start, end, data):
+    return struct.pack('>lll', start, end, len(data)) + data
+
+
+def write_revlog(path, revisions, inline=True, generaldelta=False):
+    '''Writes a revlog from a list of (p1rev, p2rev, linkrev, baserev, text,
+    data) tuples, where data is the stored (delta) data, or None to store
+    the full text. Returns the nodes of the revisions.'''
+    index = BytesIO()
+    data = BytesIO()
+    nodes = []
+    offset = 0
+    for rev, (p1, p2, link, base, text, stored) in enumerate(revisions):
+        node = hg_node(text, nodes[p1] if p1 != NULL_REV else NULL_ID,
+                       nodes[p2] if p2 != NULL_REV else NULL_ID)
+        nodes.append(node)
+        if stored is None:
+            stored = text
+        stored = zlib.compress(stored) if rev % 2 else b'u' + stored
+        if rev == 0:
+            header = 1 | (1 << 16 if inline else 0) | \
+                (1 << 17 if generaldelta else 0)
+            offset_flags = header << 32
+        else:
+            offset_flags = offset << 16
+        index.write(struct.pack('>Qiiiiii20s12x', offset_flags, len(stored),
+                                len(text), base, link, p1, p2, node))
+        (index if inline else data).write(stored)
+        offset += len(stored)
+    with open(path, 'wb') as fh:
+        fh.write(index.getvalue())
+    if not inline:
+        with open(path[:-2] + b'.d', 'wb') as fh:
+            fh.write(data.getvalue())
+    return nodes
+
+
+class TestStorePath(unittest.TestCase):
+    def test_store_path(self):
+        self.assertEqual(store_path(b'data/foo.i'), b'data/foo.i')
+        self.assertEqual(store_path(b'data/FOO/Bar.i'),
+                         b'data/_f_o_o/_bar.i')
+        self.assertEqual(store_path(b'data/.foo.i'), b'data/~2efoo.i')
+        self.assertEqual(store_path(b'data/.foo.i', dotencode=False),
+                         b'data/.foo.i')
+        self.assertEqual(store_path(b'data/foo.i/bar.d'),
+                         b'data/foo.i.hg/bar.d')
+        self.assertEqual(
+            store_path(b'data/aux.bla/bla.aux/prn/PRN/lpt/com3/nul/'
+                       b'coma/foo.NUL/normal.c.i'),
+            b'data/au~78.bla/bla.aux/pr~6e/_p_r_n/lpt/co~6d3/nu~6c/'
+            b'coma/foo._n_u_l/normal.c.i')
+
+    def test_hashed_store_path(self):
+        path = b'data/%s.i' % (b'a' * 200)
+        encoded = store_path(path)
+        self.assertTrue(encoded.startswith(b'dh/'))
+        self.assertTrue(encoded.endswith(
+            b'%s.i' % hashlib.sha1(path).hexdigest().encode('ascii')))
+        self.assertTrue(len(encoded) <= 120)
+        self.assertEqual(store_path(path, fncache=False), path)
+
+
+class TestRevlog(unittest.TestCase):
+    def setUp(self):
+        self.tmpdir = tempfile.mkdtemp().encode('utf-8')
+
+    def tearDown(self):
+        shutil.rmtree(self.tmpdir)
+
+    def check_revlog(self, inline, generaldelta):
+        path = os.path.join(self.tmpdir, b'foo.i')
+        texts = [b'foo\n', b'foo\nbar\n', b'foo\nbar\nbaz\n', b'qux\n']
+        if generaldelta:
+            bases = [0, 0, 0, 0]
+            deltas = [None, delta(4, 4, b'bar\n'),
+                      delta(4, 4, b'bar\nbaz\n'), delta(0, 4, b'qux\n')]
+        else:
+            bases = [0, 0, 0, 0]
+            deltas = [None, delta(4, 4, b'bar\n'), delta(8, 8, b'baz\n'),
+                      delta(0, 12, b'qux\n')]
+        nodes = write_revlog(path, [
+            (NULL_REV, NULL_REV, 0, bases[0], texts[0], deltas[0]),
+            (0, NULL_REV, 1, bases[1], texts[1], deltas[1]),
+            (1 if not generaldelta else 0, NULL_REV, 2, bases[2],
+             texts[2], deltas[2]),
+            (0, 2, 3, bases[3], texts[3], deltas[3]),
+        ], inline=inline, generaldelta=generaldelta)
+
+        revlog = Revlog(path)
+        self.assertEqual(len(revlog), 4)
+        for rev, text in enumerate(texts):
+            self.assertEqual(revlog.node(rev), nodes[rev])
+            self.assertEqual(revlog.rev(nodes[rev]), rev)
+            self.assertEqual(revlog.linkrev(rev), rev)
+            self.assertEqual(revlog.rawsize(rev), len(text))
+        # Go through the revisions in reverse order so that the cache
+        # doesn't hold the delta bases.
+        for rev in reversed(range(4)):
+            self.assertEqual(revlog.revision(rev), texts[rev])
+        self.assertEqual(revlog.parents(3), (nodes[0], nodes[2]))
+        self.assertEqual(revlog.rev(NULL_ID), NULL_REV)
+        self.assertEqual(revlog.rev(b'\xff' * 20), None)
+
+    def test_inline(self):
+        self.check_revlog(inline=True, generaldelta=False)
+
+    def test_separate_data(self):
+        self.check_revlog(inline=False, generaldelta=False)
+
+    def test_generaldelta(self):
+        self.check_revlog(inline=True, generaldelta=True)
+        self.check_revlog(inline=False, generaldelta=True)
+
+    def test_empty(self):
+        revlog = Revlog(os.path.join(self.tmpdir, b'missing.i'))
+        self.assertEqual(len(revlog), 0)
+
+    def test_integrity(self):
+        path = os.path.join(self.tmpdir, b'foo.i')
+        write_revlog(path, [
+            (NULL_REV, NULL_REV, 0, 0, b'foo\n', b'bar\n'),
+        ])
+        with self.assertRaises(Exception):
+            Revlog(path).revision(0)
+
+
+def changeset(manifest, files, desc, extra=b''):
+    return b'%s\nfoo <foo@bar>\n0 0%s\n%s\n\n%s' % (
+        manifest, b' ' + extra if extra else b'',
+        b'\n'.join(files), desc)
+
+
+class TestRevlogRepo(unittest.TestCase):
+    def setUp(self):
+        self.tmpdir = tempfile.mkdtemp().encode('utf-8')
+
+    def tearDown(self):
+        shutil.rmtree(self.tmpdir)
+
+    def create_repo(self, requirements=(b'revlogv1', b'store', b'fncache',
+                                        b'dotencode', b'generaldelta')):
+        hg_path = os.path.join(self.tmpdir, b'.hg')
+        store = os.path.join(hg_path, b'store')
+        os.makedirs(os.path.join(store, b'data'))
+        with open(os.path.join(hg_path, b'requires'), 'wb') as fh:
+            fh.write(b''.join(b'%s\n' % r for r in requirements))
+
+        file_texts = [b'a\n', b'b\n']
+        file_nodes = write_revlog(
+            os.path.join(store, b'data', b'_foo.i'), [
+                (NULL_REV, NULL_REV, 0, 0, file_texts[0], None),
+                (0, NULL_REV, 2, 1, file_texts[1], None),
+            ])
+        manifests = [b'Foo\0%s\n' % hexlify(n) for n in file_nodes]
+        manifests.insert(1, manifests[0])
+        manifest_nodes = write_revlog(
+            os.path.join(store, b'00manifest.i'), [
+                (NULL_REV, NULL_REV, 0, 0, manifests[0], None),
+                (0, NULL_REV, 2, 1, manifests[2], None),
+            ], inline=False)
+        changesets = [
+            changeset(hexlify(manifest_nodes[0]), [b'Foo'], b'first'),
+            changeset(hexlify(manifest_nodes[0]), [], b'second',
+                      b'branch:stable'),
+            changeset(hexlify(manifest_nodes[1]), [b'Foo'], b'third'),
+        ]
+        self.changesets = changesets
+        self.nodes = write_revlog(os.path.join(store, b'00changelog.i'), [
+            (NULL_REV, NULL_REV, 0, 0, changesets[0], None),
+            (0, NULL_REV, 1, 1, changesets[1], None),
+            (0, NULL_REV, 2, 2, changesets[2], None),
+        ])
+        self.file_texts = file_texts
+        self.manifests = manifests
+        return store
+
+    def test_open(self):
+        self.assertEqual(RevlogRepo.open(self.tmpdir, b'file:foo'), None)
+        self.create_repo(requirements=(b'revlogv1', b'store', b'fncache',
+                                       b'dotencode', b'unknown'))
+        self.assertEqual(RevlogRepo.open(self.tmpdir, b'file:foo'), None)
+
+    def test_changeset_info(self):
+        self.assertEqual(
+            changeset_info(changeset(b'0' * 40, [b'a', b'b'], b'desc\n\nfoo')),
+            (b'default', [b'a', b'b']))
+        self.assertEqual(
+            changeset_info(changeset(b'0' * 40, [], b'desc',
+                                     b'branch:foo\\\\bar\0close:1')),
+            (b'foo\\bar', []))
+
+    def test_repo(self):
+        self.create_repo()
+        repo = RevlogRepo.open(self.tmpdir, b'file:foo')
+        nodes = self.nodes
+        self.assertEqual(sorted(repo.heads()), sorted(nodes[1:]))
+        self.assertEqual(repo.branchmap(), {
+            b'default': [nodes[2]],
+            b'stable': [nodes[1]],
+        })
+        self.assertEqual(repo.known([nodes[0], b'\xff' * 20]), [True, False])
+        self.assertEqual(repo.lookup(b'stable'), nodes[1])
+        self.assertEqual(repo.lookup(hexlify(nodes[0])[:12]), nodes[0])
+        self.assertEqual(repo.listkeys(b'bookmarks'), {})
+
+    def test_hidden(self):
+        store = self.create_repo()
+        with open(os.path.join(store, b'phaseroots'), 'wb') as fh:
+            fh.write(b'2 %s\n' % hexlify(self.nodes[2]))
+        repo = RevlogRepo.open(self.tmpdir, b'file:foo')
+        self.assertEqual(repo.heads(), [self.nodes[1]])
+        self.assertEqual(repo.known([self.nodes[2]]), [False])
+
+    def test_getbundle(self):
+        self.create_repo()
+        repo = RevlogRepo.open(self.tmpdir, b'file:foo')
+        bundle = repo.getbundle(b'bundle', [self.nodes[2]], [self.nodes[0]])
+
+        def check(chunks, texts, previous):
+            for chunk, text in zip(chunks, texts):
+                self.assertEqual(
+                    RevDiff(chunk.data).apply(previous.get(chunk.delta_node,
+                                                           b'')),
+                    text)
+                self.assertEqual(chunk.node, hexlify(hg_node(
+                    text, unhexlify(chunk.parent1),
+                    unhexlify(chunk.parent2))))
+                previous[chunk.node] = text
+
+        changesets = list(chunks_in_changegroup(RawRevChunk01, bundle))
+        self.assertEqual([c.node for c in changesets],
+                         [hexlify(self.nodes[2])])
+        self.assertEqual(changesets[0].changeset, hexlify(self.nodes[2]))
+        check(changesets, self.changesets[2:], {
+            hexlify(self.nodes[0]): self.changesets[0]})
+
+        manifests = list(chunks_in_changegroup(RawRevChunk01, bundle))
+        self.assertEqual(len(manifests), 1)
+        check(manifests, self.manifests[2:], {
+            manifests[0].parent1: self.manifests[0]})
+
+        files = list(iterate_files(RawRevChunk01, bundle))
+        self.assertEqual([name for name, _ in files], [b'Foo'])
+        check([c for _, c in files], self.file_texts[1:], {
+            files[0][1].parent1: self.file_texts[0]})
+        self.assertEqual(bundle.read(1), b'')
+
+    def test_getbundle_linkrev(self):
+        store = self.create_repo()
+        # A secret changeset introducing the same file and manifest as the
+        # third one, which then have a linkrev pointing to it.
+        file_nodes = write_revlog(
+            os.path.join(store, b'data', b'_foo.i'), [
+                (NULL_REV, NULL_REV, 0, 0, self.file_texts[0], None),
+                (0, NULL_REV, 1, 1, self.file_texts[1], None),
+            ])
+        manifests = [b'Foo\0%s\n' % hexlify(n) for n in file_nodes]
+        manifest_nodes = write_revlog(
+            os.path.join(store, b'00manifest.i'), [
+                (NULL_REV, NULL_REV, 0, 0, manifests[0], None),
+                (0, NULL_REV, 1, 1, manifests[1], None),
+            ])
+        changesets = [
+            changeset(hexlify(manifest_nodes[0]), [b'Foo'], b'first'),
+            changeset(hexlify(manifest_nodes[1]), [b'Foo'], b'secret'),
+            changeset(hexlify(manifest_nodes[1]), [b'Foo'], b'third'),
+        ]
+        nodes = write_revlog(os.path.join(store, b'00changelog.i'), [
+            (NULL_REV, NULL_REV, 0, 0, changesets[0], None),
+            (0, NULL_REV, 1, 1, changesets[1], None),
+            (0, NULL_REV, 2, 2, changesets[2], None),
+        ])
+        with open(os.path.join(store, b'phaseroots'), 'wb') as fh:
+            fh.write(b'2 %s\n' % hexlify(nodes[1]))
+        repo = RevlogRepo.open(self.tmpdir, b'file:foo')
+        bundle = repo.getbundle(b'bundle', [nodes[2]], [nodes[0]])
+
+        changesets = list(chunks_in_changegroup(RawRevChunk01, bundle))
+        self.assertEqual([c.node for c in changesets], [hexlify(nodes[2])])
+        manifests = list(chunks_in_changegroup(RawRevChunk01, bundle))
+        self.assertEqual([(m.node, m.changeset) for m in manifests],
+                         [(hexlify(manifest_nodes[1]), hexlify(nodes[2]))])
+        files = list(iterate_files(RawRevChunk01, bundle))
+        self.assertEqual(
+            [(name, c.node, c.changeset) for name, c in files],
+            [(b'Foo', hexlify(file_nodes[1]), hexlify(nodes[2]))])
+        self.assertEqual(bundle.read(1), b'')
+
+        # Nothing is sent for what the other end already has.
+        bundle = repo.getbundle(b'bundle', [nodes[2]], [nodes[2]])
+        self.assertEqual(
+            list(chunks_in_changegroup(RawRevChunk01, bundle)), [])
+        self.assertEqual(
+            list(chunks_in_changegroup(RawRevChunk01, bundle)), [])
+        self.assertEqual(list(iterate_files(RawRevChunk01, bundle)), [])
+
+    def test_phases(self):
+        store = self.create_repo()
+        repo = RevlogRepo.open(self.tmpdir, b'file:foo')
+        self.assertEqual(repo.listkeys(b'phases'), {b'publishing': b'True'})
+
+        with open(os.path.join(store, b'phaseroots'), 'wb') as fh:
+            fh.write(b'1 %s\n' % hexlify(self.nodes[1]))
+            fh.write(b'2 %s\n' % hexlify(self.nodes[2]))
+        with open(os.path.join(self.tmpdir, b'.hg', b'hgrc'), 'wb') as fh:
+            fh.write(b'[ui]\npublish = true\n[phases]\npublish = False\n')
+        repo = RevlogRepo.open(self.tmpdir, b'file:foo')
+        self.assertEqual(repo.listkeys(b'phases'),
+                         {hexlify(self.nodes[1]): b'1'})
@@ -0,0 +1,104 @@
+from __future__ import absolute_import, unicode_literals
+import io
+import sys
+from importlib import import_module
+from cinnabar.cmd.util import CLI
+from cinnabar.githg import METADATA_LOG_REF
+from fixtures import (
+    FakeGitCommit,
+    GitRepoTestCase,
+    commit_tree,
+    git,
+)
+
+rollback = import_module('cinnabar.cmd.rollback')
+
+
+def run(*args):
+    args = CLI.parser.parse_args(('rollback',) + args)
+    return args.callback(args)
+
+
+class TestRollback(GitRepoTestCase):
+    def setUp(self):
+        super(TestRollback, self).setUp()
+
+        # Three metadata generations: the first with one head, the second
+        # with a descendant of that head, and the third with another head.
+        a = commit_tree('a')
+        b = commit_tree('b', a)
+        d = commit_tree('d')
+        self.heads = {a: b'a' * 40, b: b'b' * 40, d: b'd' * 40}
+        self.generations = []
+        previous = ()
+        for heads in ((a,), (b,), (b, d)):
+            changesets = commit_tree('changesets', *heads)
+            others = [commit_tree(m) for m in ('manifests', 'hg2git', 'notes')]
+            metadata = commit_tree('unified-manifests-v2', changesets,
+                                   *(others + list(previous)))
+            self.generations.insert(0, metadata)
+            previous = (metadata,)
+        git('update-ref', 'refs/cinnabar/metadata', self.generations[0])
+        log = b''.join(b'%s %d fetch origin\n' % (m, 1500000000 + n)
+                       for n, m in enumerate(reversed(self.generations)))
+        git('update-ref', METADATA_LOG_REF.decode('ascii'),
+            git('hash-object', '-w', '--stdin', input=log))
+
+        self.rolled_back = []
+        self.answer = None
+        self.stdout = io.StringIO()
+        nodes = dict((v, k) for k, v in self.heads.items())
+        self.patches = {
+            'GitCommit': FakeGitCommit,
+            'git2hg': lambda c: self.heads.get(c),
+            'hg2git': lambda n: nodes.get(n),
+            'do_rollback': self.rolled_back.append,
+            'prompt': lambda message: self.answer,
+        }
+        for name, value in self.patches.items():
+            self.patches[name] = getattr(rollback, name)
+            setattr(rollback, name, value)
+        self.sys_stdout = sys.stdout
+        sys.stdout = self.stdout
+
+    def tearDown(self):
+        sys.stdout = self.sys_stdout
+        for name, value in self.patches.items():
+            setattr(rollback, name, value)
+        super(TestRollback, self).tearDown()
+
+    def test_list(self):
+        self.assertEqual(run('--list'), 0)
+        self.assertEqual(self.rolled_back, [])
+        lines = self.stdout.getvalue().splitlines()
+        new_heads = ('d', 'b', 'a')
+        self.assertEqual(len(lines), 2 * len(self.generations))
+        for n, metadata in enumerate(self.generations):
+            line = lines[2 * n]
+            self.assertTrue(line.startswith(
+                '%d: %s ' % (n, metadata[:12].decode('ascii'))))
+            self.assertIn(' fetch origin', line)
+            self.assertEqual(line.endswith(' (current)'), n == 0)
+            self.assertEqual(lines[2 * n + 1],
+                             '    1 new head: %s' % (new_heads[n] * 12))
+
+    def test_interactive(self):
+        self.answer = '2'
+        self.assertEqual(run('-i'), None)
+        self.assertEqual(self.rolled_back, [self.generations[2]])
+
+        for answer in ('', '0', '3', 'foo'):
+            self.answer = answer
+            self.assertEqual(run('-i'), 1)
+        self.assertEqual(self.rolled_back, [self.generations[2]])
+
+    def test_to(self):
+        run('--to', 'd' * 40)
+        self.assertEqual(self.rolled_back, [self.generations[1]])
+
+        run('--to', 'b' * 40)
+        self.assertEqual(self.rolled_back[-1], self.generations[2])
+
+        self.assertEqual(run('--to', 'a' * 40), 1)
+        self.assertEqual(run('--to', 'f' * 40), 1)
+        self.assertEqual(len(self.rolled_back), 2)
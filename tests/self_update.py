@@ -0,0 +1,117 @@
+from __future__ import absolute_import, unicode_literals
+import os
+import shutil
+import sys
+import tempfile
+import unittest
+from importlib import import_module
+from cinnabar.cmd.util import CLI
+from fixtures import git
+
+self_update = import_module('cinnabar.cmd.self_update')
+
+
+def run(*args):
+    args = CLI.parser.parse_args(('self-update', '--no-helper') + args)
+    return args.callback(args)
+
+
+class TestSelfUpdate(unittest.TestCase):
+    def setUp(self):
+        self.environ = dict(os.environ)
+        self.argv = sys.argv
+        for var in ('AUTHOR', 'COMMITTER'):
+            os.environ['GIT_%s_NAME' % var] = 'Foo Bar'
+            os.environ['GIT_%s_EMAIL' % var] = 'foo@bar'
+        os.environ.pop('GIT_DIR', None)
+        self.tmpdir = tempfile.mkdtemp()
+        self.upstream = os.path.join(self.tmpdir, 'upstream')
+        self.clone = os.path.join(self.tmpdir, 'clone')
+        git('init', '-q', self.upstream)
+        self.commit(self.upstream, 'git-cinnabar')
+        git('clone', '-q', self.upstream, self.clone)
+        sys.argv = [os.path.join(self.clone, 'git-cinnabar')]
+        # Silence the messages from self-update and the git commands it
+        # runs.
+        self.stdout = sys.stdout
+        self.stderr = os.dup(2)
+        sys.stdout = open(os.devnull, 'w')
+        os.dup2(sys.stdout.fileno(), 2)
+
+    def tearDown(self):
+        os.dup2(self.stderr, 2)
+        os.close(self.stderr)
+        sys.stdout.close()
+        sys.stdout = self.stdout
+        sys.argv = self.argv
+        os.environ.clear()
+        os.environ.update(self.environ)
+        shutil.rmtree(self.tmpdir)
+
+    def commit(self, path, name):
+        with open(os.path.join(path, name), 'w') as fh:
+            fh.write(name)
+        git('add', name, cwd=path)
+        git('commit', '-q', '-m', name, cwd=path)
+        return git('rev-parse', 'HEAD', cwd=path)
+
+    def head(self):
+        return git('rev-parse', 'HEAD', cwd=self.clone)
+
+    def test_update(self):
+        self.assertEqual(run('--check'), 0)
+        head = self.head()
+        new = self.commit(self.upstream, 'foo')
+        self.assertEqual(run(), 0)
+        self.assertNotEqual(self.head(), head)
+        self.assertEqual(self.head(), new)
+        self.assertEqual(run(), 0)
+
+    def test_fetch_failure(self):
+        head = self.head()
+        self.commit(self.upstream, 'foo')
+        git('remote', 'set-url', 'origin',
+            os.path.join(self.tmpdir, 'missing'), cwd=self.clone)
+        self.assertEqual(run(), 1)
+        self.assertEqual(self.head(), head)
+
+    def test_merge_failure(self):
+        head = self.head()
+        self.commit(self.upstream, 'foo')
+        # An untracked file in the way of the update.
+        with open(os.path.join(self.clone, 'foo'), 'w') as fh:
+            fh.write('bar')
+        self.assertEqual(run(), 1)
+        self.assertEqual(self.head(), head)
+
+    def test_verify_signature(self):
+        head = self.head()
+        first = self.commit(self.upstream, 'foo')
+        second = self.commit(self.upstream, 'bar')
+        verified = []
+        unsigned = set()
+
+        class FakeProcess(object):
+            def __init__(self, *args, **kwargs):
+                self.commit = args[2]
+                verified.append(self.commit)
+
+            def wait(self):
+                return 1 if self.commit in unsigned else 0
+
+        process = self_update.Process
+        self_update.Process = FakeProcess
+        try:
+            # An unsigned commit before the tip prevents the update.
+            unsigned.add(first)
+            self.assertEqual(run('--verify-signature'), 1)
+            self.assertEqual(verified, [first])
+            self.assertEqual(self.head(), head)
+
+            unsigned.clear()
+            del verified[:]
+            self.assertEqual(run('--verify-signature'), 0)
+            self.assertEqual(verified, [first, second])
+            self.assertEqual(self.head(), second)
+        finally:
+            self_update.Process = process
@@ -0,0 +1,131 @@
+from __future__ import absolute_import, unicode_literals
+import io
+import os
+import unittest
+from cinnabar.cmd.serve import (
+    escape_batch_arg,
+    HgServer,
+    serve_stdio,
+    unescape_batch_arg,
+)
+from cinnabar.exceptions import Abort
+from cinnabar.git import (
+    EMPTY_TREE,
+    NULL_NODE_ID,
+)
+from io import BytesIO
+from fixtures import (
+    GitRepoTestCase,
+    git,
+)
+
+
+class FakeHgServer(HgServer):
+    def __init__(self):
+        self.calls = []
+        self._branchmap = {b'default': [b'1' * 40]}
+        self._tip = b'2' * 40
+
+    def do_heads(self, args):
+        self.calls.append((b'heads', args))
+        return b'%s\n' % (b'0' * 40)
+
+    def do_known(self, args):
+        self.calls.append((b'known', args))
+        return b'10'
+
+    def do_getbundle(self, args):
+        self.calls.append((b'getbundle', args))
+        if not args.get(b'heads'):
+            raise Abort('No heads')
+        return iter((b'HG20', b'\0' * 4))
+
+
+class TestServe(unittest.TestCase):
+    def test_batch_args(self):
+        for arg in (b'foo', b'a:b', b'a,b;c=d', b':e:s:o:c'):
+            self.assertEqual(unescape_batch_arg(escape_batch_arg(arg)), arg)
+        self.assertEqual(escape_batch_arg(b'a:b,c;d=e'), b'a:cb:oc:sd:ee')
+
+    def test_stdio(self):
+        server = FakeHgServer()
+        stdin = BytesIO(
+            b'hello\n'
+            b'between\npairs 81\n%s-%s'
+            b'protocaps\n'
+            b'batch\ncmds 31\n'
+            b'heads ;known nodes=1111 2222:c3'
+            b'* 0\n'
+            b'getbundle\n* 2\nheads 4\n1111common 0\n'
+            % (b'0' * 40, b'0' * 40))
+        stdout = BytesIO()
+        serve_stdio(server, stdin, stdout, io.StringIO())
+        caps = b'capabilities: %s\n' % server.capabilities()
+        self.assertEqual(
+            stdout.getvalue(),
+            b'%d\n%s'
+            b'1\n\n'
+            b'0\n'
+            b'44\n%s\n;10'
+            b'HG20\0\0\0\0'
+            % (len(caps), caps, b'0' * 40))
+        self.assertEqual(server.calls, [
+            (b'heads', {}),
+            (b'known', {b'nodes': b'1111 2222:3'}),
+            (b'getbundle', {b'heads': b'1111', b'common': b''}),
+        ])
+
+    def test_stdio_abort(self):
+        server = FakeHgServer()
+        stdin = BytesIO(
+            b'getbundle\n* 1\nheads 0\n'
+            b'lookup\nkey 3\ntip')
+        stdout = BytesIO()
+        stderr = io.StringIO()
+        serve_stdio(server, stdin, stdout, stderr)
+        self.assertEqual(stdout.getvalue(),
+                         b'\n43\n1 %s\n' % (b'2' * 40))
+        self.assertEqual(stderr.getvalue(), 'abort: No heads\n-\n')
+
+
+class FakeStore(object):
+    def __init__(self, changesets):
+        self.changesets = changesets
+
+    def branch_heads(self):
+        return {}
+
+    def heads(self):
+        return list(self.changesets)
+
+    def changeset_ref(self, node):
+        return self.changesets[node]
+
+
+class TestHgServer(GitRepoTestCase):
+    def commit(self, message, date, *parents):
+        os.environ['GIT_COMMITTER_DATE'] = '%d +0000' % date
+        args = ['commit-tree', EMPTY_TREE, '-m', message]
+        for p in parents:
+            args += ['-p', p.decode('ascii')]
+        return git(*args)
+
+    def test_tip(self):
+        server = HgServer(FakeStore({}))
+        self.assertEqual(server.do_lookup({b'key': b'tip'}),
+                         b'1 %s\n' % NULL_NODE_ID)
+
+        # The most recent head is not the last one in the order of
+        # heads or nodes.
+        a = self.commit('a', 1500000000)
+        b = self.commit('b', 1500000200, a)
+        c = self.commit('c', 1500000100, a)
+        server = HgServer(FakeStore({
+            b'1' * 40: a,
+            b'2' * 40: b,
+            b'3' * 40: c,
+        }))
+        self.assertEqual(server.do_heads({}),
+                         b'%s %s\n' % (b'2' * 40, b'3' * 40))
+        self.assertEqual(server.do_lookup({b'key': b'tip'}),
+                         b'1 %s\n' % (b'2' * 40))
@@ -0,0 +1,144 @@
+from __future__ import absolute_import, unicode_literals
+import os
+import shutil
+import tempfile
+from importlib import import_module
+from cinnabar.cmd.util import CLI
+from cinnabar.git import Git
+from cinnabar.githg import (
+    SHARED_METADATA_REF,
+    adopt_shared_metadata,
+    sharers,
+)
+from fixtures import (
+    FakeGitCommit,
+    GitRepoTestCase,
+    commit_tree,
+    git,
+)
+
+rollback = import_module('cinnabar.cmd.rollback')
+import_module('cinnabar.cmd.gc')
+
+
+def run(*args):
+    args = CLI.parser.parse_args(args)
+    return args.callback(args)
+
+
+class TestSharedMetadata(GitRepoTestCase):
+    def setUp(self):
+        super(TestSharedMetadata, self).setUp()
+        self.reference = self.tmpdir
+        self.local = tempfile.mkdtemp()
+        git('init', '-q', '--bare', self.local)
+        with open(os.path.join(self.local, 'objects', 'info', 'alternates'),
+                  'w') as fh:
+            fh.write(os.path.join(self.reference, 'objects') + '\n')
+        git('--git-dir', self.local, 'config', 'cinnabar.shared-metadata',
+            self.reference)
+        git('--git-dir', self.reference, 'config', 'cinnabar.shared-by',
+            self.local)
+        self.use(self.reference)
+        self.replaced = commit_tree('replaced')
+        self.previous = None
+
+    def tearDown(self):
+        super(TestSharedMetadata, self).tearDown()
+        shutil.rmtree(self.local)
+
+    def use(self, path):
+        os.environ['GIT_DIR'] = path
+        Git._config = None
+
+    def metadata(self, *heads, **kwargs):
+        '''Creates a metadata generation in the current repository with
+        the given changeset heads, on top of the previous one.'''
+        tree = git('mktree', input=(
+            b'160000 commit %s\t%s\n' % (self.replaced, b'1' * 40)))
+        changesets = commit_tree('changesets', *heads)
+        others = [commit_tree(m) for m in ('manifests', 'hg2git', 'notes')]
+        previous = kwargs.get('previous', self.previous)
+        self.previous = commit_tree(
+            'unified-manifests-v2', changesets,
+            *(others + [previous] if previous else others), tree=tree)
+        git('update-ref', 'refs/cinnabar/metadata', self.previous)
+        return self.previous
+
+    def ref(self, ref):
+        return git('rev-parse', '--revs-only', ref) or None
+
+    def test_adopt(self):
+        a = commit_tree('a')
+        first = self.metadata(a)
+
+        self.use(self.local)
+        adopt_shared_metadata()
+        self.assertEqual(self.ref('refs/cinnabar/metadata'), first)
+        self.assertEqual(self.ref(SHARED_METADATA_REF), first)
+        self.assertEqual(self.ref('refs/cinnabar/replace/%s' % ('1' * 40)),
+                         self.replaced)
+
+        # The local repository follows the reference repository.
+        self.use(self.reference)
+        b = commit_tree('b', a)
+        second = self.metadata(b)
+        self.use(self.local)
+        adopt_shared_metadata()
+        self.assertEqual(self.ref('refs/cinnabar/metadata'), second)
+        self.assertEqual(self.ref(SHARED_METADATA_REF), second)
+
+        # Until it fetches changesets of its own.
+        self.use(self.reference)
+        c = commit_tree('c', b)
+        self.use(self.local)
+        local = self.metadata(c, previous=second)
+        self.use(self.reference)
+        d = commit_tree('d', b)
+        third = self.metadata(d, previous=second)
+        self.assertEqual(sharers(), {self.local.encode('utf-8'): second})
+        self.use(self.local)
+        adopt_shared_metadata()
+        self.assertEqual(self.ref('refs/cinnabar/metadata'), local)
+        self.assertEqual(self.ref(SHARED_METADATA_REF), second)
+
+        # And follows it again once it has them.
+        self.use(self.reference)
+        fourth = self.metadata(c, d, previous=third)
+        self.use(self.local)
+        adopt_shared_metadata()
+        self.assertEqual(self.ref('refs/cinnabar/metadata'), fourth)
+        self.assertEqual(self.ref(SHARED_METADATA_REF), fourth)
+        self.use(self.reference)
+        self.assertEqual(sharers(), {})
+
+    def test_in_use(self):
+        a = commit_tree('a')
+        first = self.metadata(a)
+        b = commit_tree('b', a)
+        second = self.metadata(b)
+        self.use(self.local)
+        adopt_shared_metadata()
+        self.use(self.reference)
+
+        git_commit = rollback.GitCommit
+        rollback.GitCommit = FakeGitCommit
+        try:
+            # As long as the local repository follows the reference
+            # repository, it doesn't prevent anything.
+            self.assertEqual(rollback.generations_in_use([first, second]),
+                             [])
+
+            self.use(self.local)
+            self.metadata(commit_tree('c', b), previous=second)
+            self.use(self.reference)
+            local = self.local.encode('utf-8')
+            self.assertEqual(rollback.generations_in_use([first]), [])
+            self.assertEqual(rollback.generations_in_use([first, second]),
+                             [local])
+            self.assertEqual(run('rollback'), 1)
+            self.assertEqual(run('rollback', first.decode('ascii')), 1)
+            self.assertEqual(run('gc', '--keep', '1'), 1)
+            self.assertEqual(self.ref('refs/cinnabar/metadata'), second)
+        finally:
+            rollback.GitCommit = git_commit
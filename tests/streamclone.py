@@ -0,0 +1,131 @@
+from __future__ import absolute_import, unicode_literals
+import struct
+import unittest
+import zlib
+from io import BytesIO
+from cinnabar.git import NULL_NODE_ID
+from cinnabar.hg.streamclone import (
+    BufferedReader,
+    decodedir,
+    packed1,
+    parse_requirements,
+    unsupported_requirements,
+)
+
+
+def revlog(revisions, generaldelta=False):
+    '''Creates an inline revlog from a list of
+    (node, p1rev, p2rev, linkrev, baserev, data) tuples.'''
+    out = BytesIO()
+    offset = 0
+    for rev, (node, p1, p2, link, base, data) in enumerate(revisions):
+        if rev == 0:
+            header = 1 | (1 << 16) | ((1 << 17) if generaldelta else 0)
+            offset_flags = header << 32
+        else:
+            offset_flags = offset << 16
+        out.write(struct.pack('>Qiiiiii20s12x', offset_flags, len(data),
+                              len(data), base, link, p1, p2, node))
+        out.write(data)
+        offset += len(data)
+    return out.getvalue()
+
+
+def delta(start, end, data):
+    return struct.pack('>lll', start, end, len(data)) + data
+
+
+def packed1_bundle(files, requirements=b'revlogv1'):
+    out = BytesIO()
+    out.write(b'HGS1UN')
+    out.write(struct.pack('>QQH', len(files),
+                          sum(len(d) for _, d in files),
+                          len(requirements) + 1))
+    out.write(requirements + b'\0')
+    for name, data in files:
+        out.write(b'%s\0%d\n' % (name, len(data)))
+        out.write(data)
+    out.seek(4)
+    return out
+
+
+class TestStreamClone(unittest.TestCase):
+    def test_requirements(self):
+        self.assertEqual(parse_requirements(b'generaldelta%2Crevlogv1'),
+                         set((b'generaldelta', b'revlogv1')))
+        self.assertEqual(
+            unsupported_requirements(set((b'revlogv1', b'treemanifest'))),
+            set((b'treemanifest',)))
+
+    def test_decodedir(self):
+        self.assertEqual(decodedir(b'data/foo.i.hg/bar.i'),
+                         b'data/foo.i/bar.i')
+        self.assertEqual(decodedir(b'data/foo.hg.hg/bar.i'),
+                         b'data/foo.hg/bar.i')
+        self.assertEqual(decodedir(b'data/foo/bar.i'), b'data/foo/bar.i')
+
+    def test_uvarint(self):
+        reader = BufferedReader(BytesIO(b'\x05\xac\x02'))
+        self.assertEqual(reader.read_uvarint(), 5)
+        self.assertEqual(reader.read_uvarint(), 300)
+
+    def test_packed1(self):
+        cs1 = b'a' * 20
+        cs2 = b'b' * 20
+        text1 = b'changeset 1\n'
+        text2 = b'changeset 2\n'
+        changelog = revlog([
+            (cs1, -1, -1, 0, 0, b'u' + text1),
+            (cs2, 0, -1, 1, 0,
+             zlib.compress(delta(10, 11, b'2'))),
+        ])
+        mn1 = b'c' * 20
+        manifest = revlog([
+            (mn1, -1, -1, 1, 0, b'u' + b'foo\0' + b'd' * 40 + b'\n'),
+        ])
+        fn1 = b'd' * 20
+        filelog = revlog([
+            (fn1, -1, -1, 1, 0, b'ufoo\n'),
+        ])
+        bundle = packed1_bundle([
+            (b'data/foo.hg.hg/bar.i', filelog),
+            (b'00manifest.i', manifest),
+            (b'00changelog.i', changelog),
+            (b'phaseroots', b''),
+        ])
+        unbundler = packed1(bundle).unbundler()
+
+        changesets = list(next(unbundler))
+        self.assertEqual([c.node for c in changesets], [
+            b'61' * 20,
+            b'62' * 20,
+        ])
+        self.assertEqual([c.parent1 for c in changesets], [
+            NULL_NODE_ID,
+            b'61' * 20,
+        ])
+        self.assertEqual([c.delta_node for c in changesets], [
+            NULL_NODE_ID,
+            NULL_NODE_ID,
+        ])
+        self.assertEqual([c.patch.apply(b'') for c in changesets],
+                         [text1, text2])
+
+        manifests = list(next(unbundler))
+        self.assertEqual(len(manifests), 1)
+        self.assertEqual(manifests[0].node, b'63' * 20)
+        self.assertEqual(manifests[0].changeset, b'62' * 20)
+        self.assertEqual(manifests[0].patch.apply(b''),
+                         b'foo\0' + b'd' * 40 + b'\n')
+
+        files = list(next(unbundler))
+        self.assertEqual([(n, c.node) for n, c in files], [
+            (b'foo.hg/bar', b'64' * 20),
+        ])
+        self.assertEqual(files[0][1].changeset, b'62' * 20)
+        self.assertEqual(files[0][1].patch.apply(b''), b'foo\n')
+
+    def test_unsupported(self):
+        bundle = packed1_bundle([], b'revlogv1,treemanifest')
+        with self.assertRaises(Exception):
+            packed1(bundle)
@@ -0,0 +1,154 @@
+from __future__ import absolute_import, unicode_literals
+import unittest
+from collections import OrderedDict
+from cinnabar import subrepos
+from cinnabar.hg.objects import Changeset
+from cinnabar.subrepos import (
+    GITLINK_MODE,
+    SubrepoConverter,
+    hgsub_sections,
+    parse_hgsub,
+    parse_hgsubstate,
+    source_to_url,
+    url_to_source,
+)
+
+
+class TestSubrepos(unittest.TestCase):
+    def test_parse_hgsub(self):
+        subs = parse_hgsub(
+            b'# Comment\n'
+            b'foo = https://example.com/foo\n'
+            b'bar/baz=[git]https://example.com/baz.git\n'
+            b'\n'
+            b'qux = qux\n'
+            b'[subpaths]\n'
+            b'https://example.com/(.*) = https://mirror/\\1\n'
+        )
+        self.assertEqual(list(subs.items()), [
+            (b'foo', b'https://example.com/foo'),
+            (b'bar/baz', b'[git]https://example.com/baz.git'),
+            (b'qux', b'qux'),
+        ])
+
+    def test_parse_hgsubstate(self):
+        self.assertEqual(parse_hgsubstate(
+            b'%s foo\n%s bar/baz\n' % (b'1' * 40, b'2' * 40)), {
+            b'foo': b'1' * 40,
+            b'bar/baz': b'2' * 40,
+        })
+
+    def test_source_url(self):
+        for source, url, is_hg in (
+            (b'https://example.com/foo', b'hg::https://example.com/foo',
+             True),
+            (b'[git]https://example.com/foo.git',
+             b'https://example.com/foo.git', False),
+            (b'foo', b'./foo', True),
+            (b'../foo', b'../foo', True),
+        ):
+            self.assertEqual(source_to_url(source), url)
+            self.assertEqual(url_to_source(url), (source, is_hg))
+        self.assertIsNone(source_to_url(b'[svn]https://example.com/foo'))
+
+    def test_hgsub_sections(self):
+        self.assertEqual(hgsub_sections(b'foo = foo\n'), b'')
+        self.assertEqual(
+            hgsub_sections(b'foo = foo\n[subpaths]\nfoo = bar\n'),
+            b'[subpaths]\nfoo = bar\n')
+
+
+class FakeStore(object):
+    def hg_changeset(self, commit):
+        return commit.replace(b'c', b'1')
+
+    def changeset_ref(self, node):
+        return node.replace(b'1', b'c')
+
+
+class FakeConverter(SubrepoConverter):
+    def __init__(self, files):
+        super(FakeConverter, self).__init__(FakeStore())
+        self.files = files
+        self.queries = []
+
+    def _ls(self, treeish, path):
+        self.queries.append((treeish, path))
+        sha1 = self.files.get(path)
+        if sha1:
+            return (GITLINK_MODE if path == b'foo' else b'100644', b'blob',
+                    sha1, path)
+
+
+class TestSubrepoConverter(unittest.TestCase):
+    def setUp(self):
+        self.read_gitmodules = subrepos.read_gitmodules
+        # The classmethod itself, not the method bound to the class.
+        self.cat_file = subrepos.GitHgHelper.__dict__['cat_file']
+        subrepos.read_gitmodules = lambda sha1: OrderedDict((
+            (b'foo', b'hg::https://example.com/foo'),
+        ))
+        self.blobs = {}
+        subrepos.GitHgHelper.cat_file = staticmethod(
+            lambda typ, sha1: self.blobs[sha1])
+
+    def tearDown(self):
+        subrepos.read_gitmodules = self.read_gitmodules
+        subrepos.GitHgHelper.cat_file = self.cat_file
+
+    def test_to_hg(self):
+        converter = FakeConverter({
+            b'.gitmodules': b'a' * 40,
+            b'foo': b'c' * 40,
+        })
+        hgsub = b'foo = https://example.com/foo\n'
+        hgsubstate = b'%s foo\n' % (b'1' * 40)
+        self.assertEqual(converter.to_hg(b'commit'), (hgsub, hgsubstate))
+
+        parent_hgsub = (b'# Comment\n' + hgsub +
+                        b'[subpaths]\nhttps://example.com/ = /mirror/\n')
+        self.assertEqual(
+            converter.to_hg(b'commit', (parent_hgsub, hgsubstate)),
+            (parent_hgsub, hgsubstate))
+
+        parent_hgsub = (b'# Comment\nfoo = https://example.com/bar\n'
+                        b'[subpaths]\nhttps://example.com/ = /mirror/\n')
+        self.assertEqual(
+            converter.to_hg(b'commit', (parent_hgsub, b'')),
+            (hgsub + b'\n[subpaths]\nhttps://example.com/ = /mirror/\n',
+             hgsubstate))
+
+    def test_to_git(self):
+        converter = FakeConverter({
+            b'.hgsub': b'a' * 40,
+            b'.hgsubstate': b'b' * 40,
+        })
+        self.blobs[b'a' * 40] = b'foo = https://example.com/foo\n'
+        self.blobs[b'b' * 40] = b'%s foo\n' % (b'1' * 40)
+        expected = (
+            b'[submodule "foo"]\n\tpath = foo\n'
+            b'\turl = hg::https://example.com/foo\n',
+            [(b'foo', b'c' * 40)])
+
+        changesets = []
+        for n, files in enumerate(([b'.hgsub', b'.hgsubstate'], [b'bar'],
+                                   [b'.hgsubstate'])):
+            changeset = Changeset(b'%d' % (n + 1) * 40)
+            changeset.parents = tuple(c.node for c in changesets[-1:])
+            changeset.files = files
+            changesets.append(changeset)
+
+        self.assertEqual(converter.to_git(b'tree0', changesets[0]),
+                         expected)
+        self.assertEqual(converter.queries,
+                         [(b'tree0', b'.hgsub'), (b'tree0', b'.hgsubstate')])
+        self.assertEqual(converter.to_git(b'tree1', changesets[1]),
+                         expected)
+        self.assertEqual(len(converter.queries), 2)
+        self.assertEqual(converter.to_git(b'tree2', changesets[2]),
+                         expected)
+        self.assertEqual(len(converter.queries), 4)
+
+        converter.files = {}
+        self.assertEqual(converter.to_git(b'tree3'), None)
+        self.assertEqual(converter.queries[4:], [(b'tree3', b'.hgsub')])
@@ -3,14 +3,30 @@ try:
     from BaseHTTPServer import BaseHTTPRequestHandler, HTTPServer
 except ImportError:
     from http.server import BaseHTTPRequestHandler, HTTPServer
+from io import BytesIO
 from threading import Thread
 
+import hashlib
+import logging
+import time
 import unittest
+from cinnabar import util
+from cinnabar.exceptions import Abort
 from cinnabar.util import (
+    BandwidthLimit,
+    DigestReader,
     HTTPReader,
     byte_diff,
+    format_duration,
+    log_level,
+    log_names,
     lrucache,
+    memory_usage,
+    MemoryLimit,
+    parse_size,
+    ProgressReader,
     sorted_merge,
+    Stats,
     VersionedDict,
 )
 
@@ -437,6 +453,194 @@ class TestLRUCache(unittest.TestCase):
         foo.invalidate(3)
 
 
+class TestParseSize(unittest.TestCase):
+    def test_parse_size(self):
+        self.assertEqual(parse_size(b'42'), 42)
+        self.assertEqual(parse_size('42'), 42)
+        self.assertEqual(parse_size(b'2k'), 2048)
+        self.assertEqual(parse_size(b'3M'), 3 * 1024 * 1024)
+        self.assertEqual(parse_size(b'1g'), 1024 * 1024 * 1024)
+        with self.assertRaises(ValueError):
+            parse_size(b'foo')
+        with self.assertRaises(ValueError):
+            parse_size(b'-1k')
+
+
+class TestMemoryLimit(unittest.TestCase):
+    def setUp(self):
+        self.memory_usage = util.memory_usage
+        self.usage = []
+        util.memory_usage = lambda: self.usage.pop(0)
+
+    def tearDown(self):
+        util.memory_usage = self.memory_usage
+
+    def test_memory_usage(self):
+        usage = memory_usage()
+        if usage is not None:
+            self.assertGreater(usage, 0)
+
+    def test_memory_limit(self):
+        limit = MemoryLimit()
+        limit._limit = 1000
+        shrunk = []
+        limit.add_shrinker(lambda: shrunk.append(True))
+
+        self.usage = [800]
+        limit.check(force=True)
+        self.assertEqual(shrunk, [])
+
+        self.usage = [950, 800]
+        limit.check(force=True)
+        self.assertEqual(shrunk, [True])
+
+        self.usage = [1100, 1050]
+        with self.assertRaises(Abort):
+            limit.check(force=True)
+        self.assertEqual(shrunk, [True, True])
+
+        # Without a limit, memory usage is not even measured.
+        limit._limit = 0
+        self.usage = []
+        limit.check(force=True)
+
+
+class TestLogConfig(unittest.TestCase):
+    def test_log_level(self):
+        self.assertEqual(log_level(b'debug'), logging.DEBUG)
+        self.assertEqual(log_level(b'INFO'), logging.INFO)
+        self.assertEqual(log_level(b'1'), logging.ERROR)
+        self.assertEqual(log_level(b'2'), logging.WARNING)
+        self.assertEqual(log_level(b'9'), logging.DEBUG)
+        with self.assertRaises(ValueError):
+            log_level(b'foo')
+
+    def test_log_names(self):
+        self.assertEqual(log_names(''), ('',))
+        self.assertEqual(log_names('wire'), ('wire',))
+        self.assertEqual(log_names('wire.getbundle'), ('wire.getbundle',))
+        self.assertEqual(log_names('store'),
+                         ('store', 'wire.store', 'import.store'))
+        self.assertIn('bundle2', log_names('bundle'))
+        self.assertIn('findcommon', log_names('push'))
+
+
+class TestStats(unittest.TestCase):
+    def test_stats(self):
+        stats = Stats()
+        stats._enabled = True
+        with stats.timer('outer'):
+            time.sleep(0.02)
+            with stats.timer('inner'):
+                time.sleep(0.05)
+        self.assertEqual(list(stats.timings), ['inner', 'outer'])
+        self.assertGreaterEqual(stats.timings['inner'], 0.05)
+        # The time spent in the nested timer is not counted in the outer
+        # one.
+        self.assertLess(stats.timings['outer'], 0.05)
+
+        stats.count('changesets')
+        stats.count('changesets', 2)
+        stats.add_size('received', 1024)
+        self.assertEqual(stats.counts, {'changesets': 3})
+        self.assertEqual(stats.sizes, {'received': 1024})
+
+    def test_disabled(self):
+        stats = Stats()
+        stats._enabled = False
+        with stats.timer('foo'):
+            pass
+        self.assertEqual(stats.timings, {})
+
+
+class FakeTime(object):
+    def __init__(self):
+        self.now = 1000.0
+        self.slept = 0
+
+    def time(self):
+        return self.now
+
+    def sleep(self, duration):
+        self.slept += duration
+        self.now += duration
+
+
+class TestBandwidthLimit(unittest.TestCase):
+    def setUp(self):
+        self.time = util.time
+        util.time = FakeTime()
+
+    def tearDown(self):
+        util.time = self.time
+
+    def test_throttle(self):
+        limit = BandwidthLimit()
+        limit._limit = 1000
+        self.assertEqual(limit.chunk_size(100000), limit.CHUNK_SIZE)
+        # The first second worth of data goes without waiting.
+        limit.throttle(1000)
+        self.assertEqual(util.time.slept, 0)
+        limit.throttle(500)
+        self.assertAlmostEqual(util.time.slept, 0.5)
+        limit.throttle(2000)
+        self.assertAlmostEqual(util.time.slept, 2.5)
+        # Time not spent receiving data allows for a new burst, up to one
+        # second worth.
+        util.time.now += 10
+        limit.throttle(1000)
+        self.assertAlmostEqual(util.time.slept, 2.5)
+        limit.throttle(1000)
+        self.assertAlmostEqual(util.time.slept, 3.5)
+
+    def test_unlimited(self):
+        limit = BandwidthLimit()
+        limit._limit = 0
+        self.assertEqual(limit.chunk_size(100000), 100000)
+        limit.throttle(100000)
+        self.assertEqual(util.time.slept, 0)
+
+
+class TestProgress(unittest.TestCase):
+    def test_format_duration(self):
+        self.assertEqual(format_duration(42.5), '42s')
+        self.assertEqual(format_duration(62), '1m02s')
+        self.assertEqual(format_duration(3 * 3600 + 5 * 60), '3h05m')
+
+    def test_progress_reader(self):
+        try:
+            reader = ProgressReader(BytesIO(b'a' * 2048), 4096)
+            self.assertIs(ProgressReader.current, reader)
+            self.assertEqual(reader.read(1024), b'a' * 1024)
+            self.assertEqual(reader.count, 1024)
+            self.assertEqual(reader.status(reader._start),
+                             '25% of 4KiB')
+            self.assertEqual(reader.status(reader._start + 2),
+                             '25% of 4KiB | 512B/s, ETA 6s')
+            reader.read()
+            self.assertEqual(reader.count, 2048)
+        finally:
+            ProgressReader.reset()
+        self.assertIsNone(ProgressReader.current)
+
+
+class TestDigestReader(unittest.TestCase):
+    def test_digest_reader(self):
+        data = b'a' * 100000
+        digest = hashlib.sha256(data).hexdigest().encode('ascii')
+        reader = DigestReader(BytesIO(data), 'sha256', digest.upper())
+        self.assertEqual(reader.read(10), b'a' * 10)
+        reader.verify()
+        self.assertEqual(reader.read(10), b'')
+
+        reader = DigestReader(BytesIO(data[:-1] + b'b'), 'sha256', digest,
+                              name=b'foo')
+        self.assertEqual(reader.read(10), b'a' * 10)
+        with self.assertRaises(Abort) as e:
+            reader.verify()
+        self.assertIn('sha256 digest mismatch for foo', str(e.exception))
+
+
 class TestHTTPReader(unittest.TestCase):
     def test_recovery(self):
         sizes = {}
@@ -0,0 +1,33 @@
+#!/usr/bin/env cram
+
+  $ PATH=$TESTDIR/..:$PATH
+
+  $ n=0
+  $ create() {
+  >   echo $1 > $1
+  >   hg add $1
+  >   hg commit -q -m $1 -u nobody -d "$n 0"
+  >   n=$(expr $n + 1)
+  > }
+
+  $ hg init repo
+  $ REPO=$(pwd)/repo
+  $ cd repo
+  $ for f in a b c; do create $f; done
+  $ cd ..
+
+Record a clone.
+
+  $ GIT_CINNABAR_WIRE_LOG=$(pwd)/wire.log git clone -n -q hg::$REPO recorded
+  $ head -1 wire.log | cut -d ' ' -f 1
+  capabilities
+  $ grep -a -c '^getbundle$' wire.log
+  1
+
+Replay it without the server.
+
+  $ mv repo repo.away
+  $ GIT_CINNABAR_WIRE_REPLAY=$(pwd)/wire.log git clone -n -q hg::$REPO replayed
+  $ git -C recorded rev-parse origin/branches/default/tip > recorded.tip
+  $ git -C replayed rev-parse origin/branches/default/tip > replayed.tip
+  $ cmp recorded.tip replayed.tip